@@ -11,14 +11,18 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use std::fs;
 use std::str::FromStr;
 
+use amplify::Wrapper;
 use internet2::addr::NodeId;
 use lnp::p2p::bolt::{ChannelId, LNP2P_BOLT_PORT};
-use lnp_rpc::{self, Client, CreateChannel, Error, ListenAddr, PayInvoice, RpcMsg, ServiceId};
+use lnp_rpc::{
+    self, Client, CreateChannel, CreateInvoice, Error, ListenAddr, PayInvoice, RpcMsg, ServiceId,
+};
 use microservices::shell::Exec;
 
-use crate::{Command, Opts};
+use crate::{BackupCommand, Command, Opts};
 
 impl Command {
     pub fn action_string(&self) -> String {
@@ -29,10 +33,14 @@ impl Command {
             Command::Info { .. } => s!("Getting info"),
             Command::Funds => s!("Retrieving information about funds"),
             Command::Peers => s!("Retrieving information about peers"),
-            Command::Channels => s!("Retrieving information about channels"),
+            Command::Channels { .. } => s!("Retrieving information about channels"),
             Command::Open { .. } => s!("Opening channel"),
+            Command::Close { .. } => s!("Closing channel"),
             Command::Invoice { .. } => s!("Creating invoice"),
             Command::Pay { .. } => s!("Paying invoice"),
+            Command::Backup { command: BackupCommand::Export { .. } } => {
+                s!("Exporting static channel backup")
+            }
         }
     }
 }
@@ -81,11 +89,34 @@ impl Exec for Opts {
                 runtime.report_response()?;
             }
 
-            Command::Channels => {
+            Command::Channels { detailed: false } => {
                 runtime.request(ServiceId::LnpBroker, RpcMsg::ListChannels)?;
                 runtime.report_response()?;
             }
 
+            Command::Channels { detailed: true } => {
+                runtime.request(ServiceId::LnpBroker, RpcMsg::ListChannels)?;
+                let channel_ids = match runtime.report_failure()? {
+                    RpcMsg::ChannelList(list) => list.into_inner(),
+                    _ => {
+                        return Err(Error::Other(
+                            "Server returned unrecognizable response".to_string(),
+                        ))
+                    }
+                };
+                for channel_id in channel_ids {
+                    runtime.request(ServiceId::Channel(channel_id), RpcMsg::GetInfo)?;
+                    match runtime.report_failure()? {
+                        RpcMsg::ChannelInfo(info) => println!("{}", info),
+                        _ => {
+                            return Err(Error::Other(
+                                "Server returned unrecognizable response".to_string(),
+                            ))
+                        }
+                    }
+                }
+            }
+
             Command::Funds => {
                 runtime.request(ServiceId::LnpBroker, RpcMsg::ListFunds)?;
                 runtime.report_response()?;
@@ -152,7 +183,17 @@ impl Exec for Opts {
                 )?;
                 runtime.report_progress()?;
             }
-            Command::Invoice { .. } => todo!("Implement invoice generation"),
+            Command::Close { channel_id, force } => {
+                runtime.request(ServiceId::Channel(channel_id), RpcMsg::CloseChannel { force })?;
+                runtime.report_progress()?;
+            }
+            Command::Invoice { description, amount_msat, expiry } => {
+                runtime.request(
+                    ServiceId::LnpBroker,
+                    RpcMsg::CreateInvoice(CreateInvoice { amount_msat, description, expiry }),
+                )?;
+                runtime.report_progress()?;
+            }
 
             Command::Pay { invoice, channel: channel_id, amount_msat } => {
                 runtime.request(
@@ -161,6 +202,23 @@ impl Exec for Opts {
                 )?;
                 runtime.report_progress()?;
             }
+
+            Command::Backup { command: BackupCommand::Export { file } } => {
+                runtime.request(ServiceId::LnpBroker, RpcMsg::ExportBackup)?;
+                match runtime.report_failure()? {
+                    RpcMsg::StaticBackup(backup) => {
+                        fs::write(&file, backup).map_err(|err| {
+                            Error::Other(format!("unable to save backup file: {}", err))
+                        })?;
+                        println!("Static channel backup is saved to {}", file.display());
+                    }
+                    _ => {
+                        return Err(Error::Other(
+                            "Server returned unrecognizable response".to_string(),
+                        ))
+                    }
+                }
+            }
         }
         Ok(())
     }
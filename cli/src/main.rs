@@ -34,7 +34,7 @@ use lnp_rpc::Client;
 use microservices::cli::LogStyle;
 use microservices::shell::{Exec, LogLevel};
 
-pub use crate::opts::{Command, Opts};
+pub use crate::opts::{BackupCommand, Command, Opts};
 
 fn main() {
     println!("lnp-cli: command-line tool for working with LNP node");
@@ -12,6 +12,7 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use internet2::addr::{NodeId, PartialNodeAddr, ServiceAddr};
@@ -117,7 +118,15 @@ pub enum Command {
     Peers,
 
     /// Lists existing channels
-    Channels,
+    Channels {
+        /// Provide detailed information about each of the channels, including balances and
+        /// pending HTLCs
+        ///
+        /// Named `--detailed` since `-v`/`--verbose` is a global flag setting the verbosity
+        /// level of the command itself.
+        #[clap(short, long)]
+        detailed: bool,
+    },
 
     /// Opens a new channel with a remote peer, which must be already
     /// connected.
@@ -212,15 +221,34 @@ pub enum Command {
         channel_reserve: Option<u64>,
     },
 
+    /// Closes the channel with a remote peer.
+    ///
+    /// Unless `--force` is used, the channel is closed cooperatively and the remote peer must be
+    /// connected.
+    Close {
+        /// Id of the channel to close
+        channel_id: ChannelId,
+
+        /// Unilaterally close the channel by publishing the latest commitment transaction.
+        ///
+        /// Use when the remote peer is unresponsive. Funds from the local channel output are
+        /// returned to the funding wallet after `to_self_delay` blocks.
+        #[clap(long)]
+        force: bool,
+    },
+
     /// Create an invoice
     Invoice {
-        /// Asset amount to invoice, in atomic unit (satoshis or smallest asset
-        /// unit type)
-        amount: u64,
+        /// Description of the purpose of the payment
+        description: String,
 
-        /// Asset ticker in which the invoice should be issued
-        #[clap(default_value = "btc")]
-        asset: String,
+        /// Amount of milli-satoshis to invoice. If not given, the invoice can be
+        /// paid with any amount.
+        amount_msat: Option<u64>,
+
+        /// Number of seconds after which the invoice expires
+        #[clap(short, long)]
+        expiry: Option<u64>,
     },
 
     /// Pay the invoice
@@ -235,6 +263,28 @@ pub enum Command {
         /// amount. Overrides amount provided by the invoice.
         amount_msat: Option<u64>,
     },
+
+    /// Operations with the static channel backup
+    Backup {
+        #[clap(subcommand)]
+        command: BackupCommand,
+    },
+}
+
+/// Static channel backup commands
+#[derive(Subcommand, Clone, PartialEq, Eq, Debug)]
+pub enum BackupCommand {
+    /// Exports static channel backup of all node channels, encrypted with a key derived from
+    /// the node master key.
+    ///
+    /// The backup does not contain channel balances and can't be used to continue channel
+    /// operations. Instead, it allows to recover channel funds after the loss of the node data
+    /// directory by running `lnpd` with `--restore` option, which asks remote peers to force-close
+    /// the channels.
+    Export {
+        /// File to save the backup to
+        file: PathBuf,
+    },
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display, Error, From)]
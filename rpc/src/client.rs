@@ -18,10 +18,11 @@ use colored::Colorize;
 use internet2::addr::ServiceAddr;
 use internet2::ZmqSocketType;
 use lnp::addr::LnpAddr;
+use lnp::p2p::bolt::ChannelId;
 use microservices::esb::{self, BusId, ClientId};
 use microservices::util::OptionDetails;
 
-use crate::{BusMsg, Error, RpcMsg, ServiceId};
+use crate::{BusMsg, Error, ProposedChannel, RpcMsg, ServiceId};
 
 // We have just a single service bus (RPC), so we can use any id
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Default, Display)]
@@ -139,6 +140,41 @@ impl Client {
         self.request(ServiceId::LnpBroker, RpcMsg::ConnectPeer(remote_peer))?;
         self.report_response()
     }
+
+    /// Subscribes the client as the channel acceptor of the node. Once subscribed, the client
+    /// has to decide on each channel proposal returned by [`Client::next_channel_proposal`]
+    /// before the timeout set in the node configuration.
+    pub fn subscribe_channel_acceptor(&mut self) -> Result<(), Error> {
+        self.request(ServiceId::LnpBroker, RpcMsg::SubscribeChannelAcceptor)?;
+        self.report_failure()?;
+        Ok(())
+    }
+
+    /// Waits for the next channel proposed by a remote peer
+    pub fn next_channel_proposal(&mut self) -> Result<ProposedChannel, Error> {
+        loop {
+            match self.response()? {
+                RpcMsg::ChannelProposal(proposal) => return Ok(proposal),
+                other => debug!("Skipping message {} while waiting for channel proposal", other),
+            }
+        }
+    }
+
+    /// Approves channel proposal with the given temporary channel id
+    pub fn approve_channel(&mut self, channel_id: ChannelId) -> Result<(), Error> {
+        self.request(ServiceId::LnpBroker, RpcMsg::ApproveChannel(channel_id))
+    }
+
+    /// Rejects channel proposal with the given temporary channel id, providing the remote peer
+    /// with the reason of the rejection
+    pub fn reject_channel(
+        &mut self,
+        channel_id: ChannelId,
+        reason: impl ToString,
+    ) -> Result<(), Error> {
+        let reason = reason.to_string();
+        self.request(ServiceId::LnpBroker, RpcMsg::RejectChannel { channel_id, reason })
+    }
 }
 
 pub struct Handler {
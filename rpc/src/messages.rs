@@ -19,12 +19,14 @@ use std::str::FromStr;
 use std::time::Duration;
 
 use amplify::{Slice32, ToYamlString, Wrapper};
+use bitcoin::OutPoint;
 use bitcoin_scripts::address::AddressCompat;
+use bitcoin_scripts::hlc::HashLock;
 use internet2::addr::{InetSocketAddr, NodeAddr, NodeId};
 use lightning_invoice::Invoice;
 use lnp::addr::LnpAddr;
-use lnp::channel::bolt::{AssetsBalance, ChannelState, CommonParams, PeerParams};
-use lnp::p2p::bolt::{ChannelId, ChannelType};
+use lnp::channel::bolt::{AssetsBalance, ChannelState, CommonParams, Lifecycle, PeerParams};
+use lnp::p2p::bolt::{ChannelId, ChannelType, ShortChannelId};
 use lnpbp::chain::AssetId;
 use microservices::esb::ClientId;
 use microservices::rpc;
@@ -86,6 +88,13 @@ pub enum RpcMsg {
     #[display("create_channel({0})")]
     CreateChannel(CreateChannel),
 
+    /// Requests closing of the channel. Sent by a client to the channel daemon.
+    ///
+    /// If `force` is set, the channel is closed unilaterally by publishing the latest commitment
+    /// transaction; otherwise cooperative closing with the remote peer is performed.
+    #[display("close_channel({force})")]
+    CloseChannel { force: bool },
+
     // Can be issued from a `cli` to `routed`
     #[display("send({0})")]
     Send(Send),
@@ -94,6 +103,39 @@ pub enum RpcMsg {
     #[display("pay_invoice({0})")]
     PayInvoice(PayInvoice),
 
+    /// Requests lnpd to issue a new BOLT-11 invoice payable to the local node. The invoice is
+    /// returned as a bech32 string in the details of a success response.
+    #[display("create_invoice({0})")]
+    CreateInvoice(CreateInvoice),
+
+    /// Requests lnpd to export static channel backup of all the node channels, encrypted with the
+    /// node key. Sent by a client to lnpd, which replies with `StaticBackup`.
+    #[display("export_backup()")]
+    ExportBackup,
+
+    // Channel acceptor API
+    // --------------------
+    /// Subscribes the client as an external channel acceptor, which receives channels proposed by
+    /// remote peers and has to approve or reject each of them. Sent by a client to lnpd.
+    #[display("subscribe_channel_acceptor()")]
+    SubscribeChannelAcceptor,
+
+    /// Channel proposed by a remote peer which awaits the decision of the channel acceptor. Sent
+    /// by lnpd to the subscribed client.
+    #[display("channel_proposal({0})")]
+    #[from]
+    ChannelProposal(ProposedChannel),
+
+    /// Approves channel proposal with the given temporary channel id. Sent by the channel acceptor
+    /// to lnpd.
+    #[display("approve_channel({0})")]
+    ApproveChannel(ChannelId),
+
+    /// Rejects channel proposal with the given temporary channel id. The reason is sent to the
+    /// remote peer. Sent by the channel acceptor to lnpd.
+    #[display("reject_channel({channel_id}, \"{reason}\")")]
+    RejectChannel { channel_id: ChannelId, reason: String },
+
     // Responses to CLI
     // ----------------
     #[display("progress(\"{0}\")")]
@@ -130,6 +172,10 @@ pub enum RpcMsg {
     #[display("funds_info({0})", alt = "{0:#}")]
     #[from]
     FundsInfo(FundsInfo),
+
+    /// Encrypted static channel backup
+    #[display("static_backup(...)")]
+    StaticBackup(Vec<u8>),
 }
 
 impl RpcMsg {
@@ -250,6 +296,50 @@ impl StrictDecode for PayInvoice {
     }
 }
 
+/// Request to issue a new invoice originating from a client
+#[derive(Clone, PartialEq, Eq, Debug, Display, NetworkEncode, NetworkDecode)]
+#[display("{amount_msat:?}, \"{description}\"")]
+pub struct CreateInvoice {
+    /// Amount to be paid, in millisatoshis. If not given, the payer may pay any amount.
+    pub amount_msat: Option<u64>,
+
+    /// Description of the purpose of the payment
+    pub description: String,
+
+    /// Number of seconds after which the invoice expires. Defaults to one hour.
+    pub expiry: Option<u64>,
+}
+
+/// Channel proposed by a remote peer, which is forwarded to the channel acceptor client
+#[derive(Clone, PartialEq, Eq, Debug, Display, NetworkEncode, NetworkDecode)]
+#[display("{channel_id}, {remote_id}, {funding_sat}, ...")]
+pub struct ProposedChannel {
+    /// Temporary channel id assigned by the remote peer
+    pub channel_id: ChannelId,
+
+    /// Node proposing the channel
+    pub remote_id: NodeId,
+
+    /// Amount of satoshis funding the channel
+    pub funding_sat: u64,
+
+    /// Amount of millisatoshis pushed to the local node at the channel opening
+    pub push_msat: u64,
+
+    /// Initial fee rate for the commitment transactions, in satoshi per 1000-weight
+    pub feerate_per_kw: u32,
+
+    /// Number of blocks the local node will have to wait to claim its funds from a commitment
+    /// transaction
+    pub to_self_delay: u16,
+
+    /// Whether the channel is going to be announced to the lightning network
+    pub announce_channel: bool,
+
+    /// Channel type as defined in BOLT-2
+    pub channel_type: ChannelType,
+}
+
 #[derive(Clone, PartialEq, Eq, Debug, Display, NetworkEncode, NetworkDecode)]
 #[display("{amount} {asset:?} to {channeld}")]
 pub struct Send {
@@ -303,8 +393,47 @@ pub type RemotePeerMap<T> = BTreeMap<NodeAddr, T>;
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(crate = "serde_crate"))]
 #[display(ChannelInfo::to_yaml_string)]
 pub struct ChannelInfo {
-    pub state: ChannelState,
+    #[serde_as(as = "DisplayFromStr")]
+    pub channel_id: ChannelId,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub short_channel_id: Option<ShortChannelId>,
     pub remote_id: Option<NodeId>,
+    /// Lifecycle stage of the channel
+    pub lifecycle: Lifecycle,
+    /// Current stage of the channel state machine
+    pub stage: String,
+    pub funding_outpoint: Option<OutPoint>,
+    /// Total channel funding, in satoshis
+    pub capacity_sat: u64,
+    /// Local balance in the latest local commitment, excluding pending HTLCs
+    pub local_balance_msat: u64,
+    /// Remote balance in the latest local commitment, excluding pending HTLCs
+    pub remote_balance_msat: u64,
+    /// Reserve which the local node has to keep in the channel, in satoshis
+    pub local_reserve_sat: u64,
+    /// Reserve which the remote peer has to keep in the channel, in satoshis
+    pub remote_reserve_sat: u64,
+    /// HTLCs offered by the local node which are not resolved yet
+    pub offered_htlcs: Vec<HtlcInfo>,
+    /// HTLCs offered by the remote peer which are not resolved yet
+    pub received_htlcs: Vec<HtlcInfo>,
+    #[serde_as(as = "DurationSeconds")]
+    pub uptime: Duration,
+    pub since: u64,
+    /// Raw state of the BOLT channel
+    pub state: ChannelState,
+}
+
+#[cfg_attr(feature = "serde", serde_as)]
+#[derive(Clone, PartialEq, Eq, Debug, Display, NetworkEncode, NetworkDecode)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(crate = "serde_crate"))]
+#[display("{htlc_id}, {amount_msat}, {payment_hash}, {cltv_expiry}")]
+pub struct HtlcInfo {
+    pub htlc_id: u64,
+    pub amount_msat: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub payment_hash: HashLock,
+    pub cltv_expiry: u32,
 }
 
 #[cfg_attr(feature = "serde", serde_as)]
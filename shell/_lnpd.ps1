@@ -39,10 +39,14 @@ Register-ArgumentCompleter -Native -CommandName 'lnpd' -ScriptBlock {
             [CompletionResult]::new('--chain', 'chain', [CompletionResultType]::ParameterName, 'Blockchain to use')
             [CompletionResult]::new('--electrum-server', 'electrum-server', [CompletionResultType]::ParameterName, 'Electrum server to use')
             [CompletionResult]::new('--electrum-port', 'electrum-port', [CompletionResultType]::ParameterName, 'Customize Electrum server port number. By default the wallet will use port matching the selected network')
+            [CompletionResult]::new('--storage', 'storage', [CompletionResultType]::ParameterName, 'Storage backend for channel states')
+            [CompletionResult]::new('--fee-update-threshold', 'fee-update-threshold', [CompletionResultType]::ParameterName, 'Commitment fee rate drift, in percents, which triggers fee update')
             [CompletionResult]::new('-L', 'L', [CompletionResultType]::ParameterName, 'Start daemon in listening mode binding the provided local address(es)')
             [CompletionResult]::new('--listen', 'listen', [CompletionResultType]::ParameterName, 'Start daemon in listening mode binding the provided local address(es)')
             [CompletionResult]::new('--bolt', 'bolt', [CompletionResultType]::ParameterName, 'Use BOLT protocol for listening for the incoming connections. Can optionally specify a custom port number')
             [CompletionResult]::new('--bifrost', 'bifrost', [CompletionResultType]::ParameterName, 'Use Bifrost protocol for listening for the incoming connections. Can optionally specify a custom port number')
+            [CompletionResult]::new('--restore', 'restore', [CompletionResultType]::ParameterName, 'Restore channels from the static channel backup file exported with `lnp-cli backup export`')
+            [CompletionResult]::new('--backup-dir', 'backup-dir', [CompletionResultType]::ParameterName, 'Directory for the encrypted channel backup, which is rewritten each time a channel is opened or closed')
             [CompletionResult]::new('-h', 'h', [CompletionResultType]::ParameterName, 'Print help information')
             [CompletionResult]::new('--help', 'help', [CompletionResultType]::ParameterName, 'Print help information')
             [CompletionResult]::new('-V', 'V', [CompletionResultType]::ParameterName, 'Print version information')
@@ -73,6 +77,8 @@ Register-ArgumentCompleter -Native -CommandName 'lnpd' -ScriptBlock {
             [CompletionResult]::new('--chain', 'chain', [CompletionResultType]::ParameterName, 'Blockchain to use')
             [CompletionResult]::new('--electrum-server', 'electrum-server', [CompletionResultType]::ParameterName, 'Electrum server to use')
             [CompletionResult]::new('--electrum-port', 'electrum-port', [CompletionResultType]::ParameterName, 'Customize Electrum server port number. By default the wallet will use port matching the selected network')
+            [CompletionResult]::new('--storage', 'storage', [CompletionResultType]::ParameterName, 'Storage backend for channel states')
+            [CompletionResult]::new('--fee-update-threshold', 'fee-update-threshold', [CompletionResultType]::ParameterName, 'Commitment fee rate drift, in percents, which triggers fee update')
             [CompletionResult]::new('-h', 'h', [CompletionResultType]::ParameterName, 'Print help information')
             [CompletionResult]::new('--help', 'help', [CompletionResultType]::ParameterName, 'Print help information')
             [CompletionResult]::new('-v', 'v', [CompletionResultType]::ParameterName, 'Set verbosity level')
@@ -96,6 +102,8 @@ Register-ArgumentCompleter -Native -CommandName 'lnpd' -ScriptBlock {
             [CompletionResult]::new('--chain', 'chain', [CompletionResultType]::ParameterName, 'Blockchain to use')
             [CompletionResult]::new('--electrum-server', 'electrum-server', [CompletionResultType]::ParameterName, 'Electrum server to use')
             [CompletionResult]::new('--electrum-port', 'electrum-port', [CompletionResultType]::ParameterName, 'Customize Electrum server port number. By default the wallet will use port matching the selected network')
+            [CompletionResult]::new('--storage', 'storage', [CompletionResultType]::ParameterName, 'Storage backend for channel states')
+            [CompletionResult]::new('--fee-update-threshold', 'fee-update-threshold', [CompletionResultType]::ParameterName, 'Commitment fee rate drift, in percents, which triggers fee update')
             [CompletionResult]::new('-v', 'v', [CompletionResultType]::ParameterName, 'Set verbosity level')
             [CompletionResult]::new('--verbose', 'verbose', [CompletionResultType]::ParameterName, 'Set verbosity level')
             break
@@ -37,6 +37,8 @@ Register-ArgumentCompleter -Native -CommandName 'signd' -ScriptBlock {
             [CompletionResult]::new('--chain', 'chain', [CompletionResultType]::ParameterName, 'Blockchain to use')
             [CompletionResult]::new('--electrum-server', 'electrum-server', [CompletionResultType]::ParameterName, 'Electrum server to use')
             [CompletionResult]::new('--electrum-port', 'electrum-port', [CompletionResultType]::ParameterName, 'Customize Electrum server port number. By default the wallet will use port matching the selected network')
+            [CompletionResult]::new('--storage', 'storage', [CompletionResultType]::ParameterName, 'Storage backend for channel states')
+            [CompletionResult]::new('--fee-update-threshold', 'fee-update-threshold', [CompletionResultType]::ParameterName, 'Commitment fee rate drift, in percents, which triggers fee update')
             [CompletionResult]::new('-h', 'h', [CompletionResultType]::ParameterName, 'Print help information')
             [CompletionResult]::new('--help', 'help', [CompletionResultType]::ParameterName, 'Print help information')
             [CompletionResult]::new('-V', 'V', [CompletionResultType]::ParameterName, 'Print version information')
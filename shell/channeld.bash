@@ -19,7 +19,7 @@ _channeld() {
 
     case "${cmd}" in
         channeld)
-            opts="-h -V -k -r -v -d -c -T -M -X -R -n -t --help --version --key-file --reestablish --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port --threaded <CHANNEL_ID>"
+            opts="-h -V -k -r -v -d -c -T -M -X -R -n -t --help --version --key-file --reestablish --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port --threaded --storage --fee-update-threshold <CHANNEL_ID>"
             if [[ ${cur} == -* || ${COMP_CWORD} -eq 1 ]] ; then
                 COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
                 return 0
@@ -97,6 +97,14 @@ _channeld() {
                     COMPREPLY=($(compgen -f "${cur}"))
                     return 0
                     ;;
+                --storage)
+                    COMPREPLY=($(compgen -W "disk db" -- "${cur}"))
+                    return 0
+                    ;;
+                --fee-update-threshold)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
                 *)
                     COMPREPLY=()
                     ;;
@@ -25,7 +25,7 @@ _lnpd() {
 
     case "${cmd}" in
         lnpd)
-            opts="-h -V -k -v -d -c -T -M -X -R -n -t -L --help --version --key-file --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port --threaded --listen --listen-all --bolt --bifrost init help"
+            opts="-h -V -k -v -d -c -T -M -X -R -n -t -L --help --version --key-file --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port --threaded --storage --fee-update-threshold --listen --listen-all --bolt --bifrost --restore --backup-dir init help"
             if [[ ${cur} == -* || ${COMP_CWORD} -eq 1 ]] ; then
                 COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
                 return 0
@@ -103,6 +103,14 @@ _lnpd() {
                     COMPREPLY=($(compgen -f "${cur}"))
                     return 0
                     ;;
+                --storage)
+                    COMPREPLY=($(compgen -W "disk db" -- "${cur}"))
+                    return 0
+                    ;;
+                --fee-update-threshold)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
                 --listen)
                     COMPREPLY=($(compgen -f "${cur}"))
                     return 0
@@ -119,6 +127,14 @@ _lnpd() {
                     COMPREPLY=($(compgen -f "${cur}"))
                     return 0
                     ;;
+                --restore)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
+                --backup-dir)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
                 *)
                     COMPREPLY=()
                     ;;
@@ -127,7 +143,7 @@ _lnpd() {
             return 0
             ;;
         lnpd__help)
-            opts="-v -d -c -T -M -X -R -n --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port <SUBCOMMAND>..."
+            opts="-v -d -c -T -M -X -R -n --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port --storage --fee-update-threshold <SUBCOMMAND>..."
             if [[ ${cur} == -* || ${COMP_CWORD} -eq 2 ]] ; then
                 COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
                 return 0
@@ -197,6 +213,14 @@ _lnpd() {
                     COMPREPLY=($(compgen -f "${cur}"))
                     return 0
                     ;;
+                --storage)
+                    COMPREPLY=($(compgen -W "disk db" -- "${cur}"))
+                    return 0
+                    ;;
+                --fee-update-threshold)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
                 *)
                     COMPREPLY=()
                     ;;
@@ -205,7 +229,7 @@ _lnpd() {
             return 0
             ;;
         lnpd__init)
-            opts="-h -v -d -c -T -M -X -R -n --help --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port"
+            opts="-h -v -d -c -T -M -X -R -n --help --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port --storage --fee-update-threshold"
             if [[ ${cur} == -* || ${COMP_CWORD} -eq 2 ]] ; then
                 COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
                 return 0
@@ -275,6 +299,14 @@ _lnpd() {
                     COMPREPLY=($(compgen -f "${cur}"))
                     return 0
                     ;;
+                --storage)
+                    COMPREPLY=($(compgen -W "disk db" -- "${cur}"))
+                    return 0
+                    ;;
+                --fee-update-threshold)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
                 *)
                     COMPREPLY=()
                     ;;
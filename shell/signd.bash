@@ -19,7 +19,7 @@ _signd() {
 
     case "${cmd}" in
         signd)
-            opts="-h -V -v -d -c -T -M -X -R -n -t --help --version --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port --threaded"
+            opts="-h -V -v -d -c -T -M -X -R -n -t --help --version --verbose --data-dir --config --tor-proxy --msg --ctl --rpc --chain --electrum-server --electrum-port --threaded --storage --fee-update-threshold"
             if [[ ${cur} == -* || ${COMP_CWORD} -eq 1 ]] ; then
                 COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
                 return 0
@@ -89,6 +89,14 @@ _signd() {
                     COMPREPLY=($(compgen -f "${cur}"))
                     return 0
                     ;;
+                --storage)
+                    COMPREPLY=($(compgen -W "disk db" -- "${cur}"))
+                    return 0
+                    ;;
+                --fee-update-threshold)
+                    COMPREPLY=($(compgen -f "${cur}"))
+                    return 0
+                    ;;
                 *)
                     COMPREPLY=()
                     ;;
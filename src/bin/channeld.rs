@@ -28,9 +28,12 @@
 #[macro_use]
 extern crate log;
 
+use std::path::PathBuf;
+
 use clap::Parser;
 use lnp::p2p::bolt::ActiveChannelId;
 use lnp_node::channeld::{self, Opts};
+use lnp_node::lnpd::read_node_key_file;
 use lnp_node::Config;
 
 fn main() {
@@ -41,7 +44,10 @@ fn main() {
     opts.process();
     trace!("Processed arguments: {:?}", &opts);
 
-    let config: Config = opts.clone().into();
+    let config: Config = opts.clone().try_into().unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1)
+    });
     trace!("Daemon configuration: {:?}", &config);
     debug!("MSG RPC socket {}", &config.msg_endpoint);
     debug!("CTL RPC socket {}", &config.ctl_endpoint);
@@ -62,7 +68,9 @@ fn main() {
     } else {
         ActiveChannelId::Temporary(opts.channel_id.into())
     };
-    channeld::run(config, channel_id).expect("Error running channeld runtime");
+    let key_file = PathBuf::from(opts.key_opts.key_file.clone());
+    let local_node = read_node_key_file(&key_file);
+    channeld::run(config, local_node, channel_id).expect("Error running channeld runtime");
 
     unreachable!()
 }
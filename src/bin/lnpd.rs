@@ -51,7 +51,10 @@ fn main() -> Result<(), Error> {
     opts.process();
     trace!("Processed arguments: {:?}", opts);
 
-    let config: Config = opts.clone().into();
+    let config: Config = opts.clone().try_into().unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1)
+    });
     trace!("Daemon configuration: {:?}", config);
     debug!("MSG socket {}", config.msg_endpoint);
     debug!("CTL socket {}", config.ctl_endpoint);
@@ -94,7 +97,8 @@ fn main() -> Result<(), Error> {
     }
 
     debug!("Starting runtime ...");
-    lnpd::run(config, key_file, &listen).expect("running lnpd runtime");
+    lnpd::run(config, key_file, &listen, opts.restore, opts.backup_dir)
+        .expect("running lnpd runtime");
 
     unreachable!()
 }
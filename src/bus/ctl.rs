@@ -12,13 +12,14 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 use amplify::Slice32;
-use bitcoin::Txid;
-use bitcoin_scripts::hlc::HashLock;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{OutPoint, Transaction, TxOut, Txid};
+use bitcoin_scripts::hlc::{HashLock, HashPreimage};
 use bitcoin_scripts::PubkeyScript;
 use internet2::addr::{NodeAddr, NodeId};
 use internet2::presentation::sphinx::Hop;
 use lnp::channel::bolt::{CommonParams, LocalKeyset, PeerParams, Policy};
-use lnp::p2p::bolt::{ChannelId, OpenChannel, PaymentOnion, TempChannelId};
+use lnp::p2p::bolt::{ChannelId, ChannelType, OpenChannel, PaymentOnion, TempChannelId};
 use lnp::router::gossip::LocalChannelInfo;
 use lnp_rpc::{ChannelInfo, Failure, PeerInfo};
 use microservices::esb::ClientId;
@@ -57,6 +58,16 @@ pub enum CtlMsg {
     #[display("accept_channel_from({0})")]
     AcceptChannelFrom(AcceptChannelFrom),
 
+    /// Initiates recovery of funds from a channel restored from the static channel backup. Sent
+    /// from lnpd to a newly instantiated channeld.
+    #[display("restore_channel({0})")]
+    RestoreChannel(ChannelBackup),
+
+    /// Reports that the external channel acceptor has not decided on the channel proposal in
+    /// time. Sent over the bridge within lnpd.
+    #[display("acceptor_timeout({0})")]
+    AcceptorTimeout(ChannelId),
+
     /// Constructs funding PSBT to fund a locally-created new channel. Sent from peerd to lnpd.
     #[display("construct_funding({0})")]
     ConstructFunding(FundChannel),
@@ -71,6 +82,34 @@ pub enum CtlMsg {
     #[display("publish_funding({0})")]
     PublishFunding,
 
+    // Channel closing API
+    // -------------------
+    /// Requests a script from the funding wallet for receiving funds from a channel which is
+    /// being closed. Sent from channeld to lnpd.
+    #[display("get_payout_script()")]
+    GetPayoutScript,
+
+    /// Provides channeld with a funding wallet script for receiving funds from the channel. Sent
+    /// from lnpd to channeld in response to `GetPayoutScript`.
+    #[display("payout_script({0})")]
+    PayoutScript(PubkeyScript),
+
+    /// Finalizes fully signed transaction spending channel funds (like cooperative closing
+    /// transaction) and publishes it to bitcoin network. Sent from channeld to lnpd.
+    #[display("publish_tx(...)")]
+    PublishTx(Psbt),
+
+    /// Constructs child transaction spending local anchor output of the published commitment
+    /// transaction with the funding wallet UTXOs to bump the commitment fee. Sent from channeld
+    /// to lnpd.
+    #[display("construct_cpfp({0})")]
+    ConstructCpfp(BumpCommitment),
+
+    /// Provides channeld with the child transaction bumping the commitment fee. Sent from lnpd to
+    /// channeld in response to `ConstructCpfp`.
+    #[display("cpfp_constructed(...)")]
+    CpfpConstructed(Psbt),
+
     // On-chain tracking API
     // ---------------------
     /// Asks on-chain tracking service to send updates on the transaction mining status.
@@ -88,16 +127,53 @@ pub enum CtlMsg {
     #[display("tx_found({0})")]
     TxFound(TxStatus),
 
+    /// Asks on-chain tracking service to report a transaction spending the given output. The
+    /// script is required to look up the spending transaction. Sent from channeld to watchd for
+    /// the channel funding output.
+    #[display("track_spending({outpoint}, ...)")]
+    TrackSpending { outpoint: OutPoint, script_pubkey: PubkeyScript },
+
+    /// Asks on-chain tracking service to stop watching for a transaction spending the output
+    #[display("untrack_spending({0})")]
+    UntrackSpending(OutPoint),
+
+    /// Asks on-chain tracking service to report a transaction conflicting with the given
+    /// unconfirmed transaction, i.e. spending any of its inputs. The tracking stops once the
+    /// transaction is mined. Sent from channeld to watchd for the funding transaction of a
+    /// zero-confirmation channel.
+    #[display("track_conflicts({0})")]
+    TrackConflicts(Txid),
+
+    /// Reports that a transaction previously requested to be tracked for conflicts is
+    /// double-spent by another transaction
+    #[display("tx_conflict({txid}, {conflicting_txid})")]
+    TxConflict { txid: Txid, conflicting_txid: Txid },
+
+    /// Reports a transaction spending an output previously requested to be watched by an
+    /// on-chain service
+    #[display("spent({0})")]
+    Spent(TxSpending),
+
+    /// Reports current on-chain fee estimate for fast confirmation, in satoshis per 1000 weight
+    /// units. Sent from watchd to all channels tracking their funding output.
+    #[display("fee_estimate({feerate_per_kw})")]
+    FeeEstimate { feerate_per_kw: u32 },
+
+    /// Reports height of the current chain tip. Sent from watchd to all channels tracking their
+    /// funding output, so they can resolve HTLCs before their expiry.
+    #[display("block_height({0})")]
+    BlockHeight(u32),
+
     // Routing & payments
     /// Request to channel daemon to perform payment using provided route
     #[display("payment(...)")]
     Payment { route: Vec<Hop<PaymentOnion>>, hash_lock: HashLock, enquirer: ClientId },
 
-    /// Notifies routing daemon about a new local channel
+    /// Notifies routing daemon and lnpd about a new local channel
     #[display("channel_created({0})")]
     ChannelCreated(LocalChannelInfo),
 
-    /// Notifies routing daemon to remove information about a local channel
+    /// Notifies routing daemon and lnpd to remove information about a local channel
     #[display("channel_closed({0})")]
     ChannelClosed(ChannelId),
 
@@ -105,6 +181,26 @@ pub enum CtlMsg {
     #[display("channel_balance_update({channel_id}, {local_amount_msat}+{remote_amount_msat})")]
     ChannelBalanceUpdate { channel_id: ChannelId, local_amount_msat: u64, remote_amount_msat: u64 },
 
+    /// Requests routing daemon to write routing graph snapshot to the disk. Sent over the bridge
+    /// within routed by the snapshot timer.
+    #[display("snapshot_graph()")]
+    SnapshotGraph,
+
+    /// Requests routing daemon to write routing graph snapshot and exit after the termination
+    /// signal was received. Sent over the bridge within routed.
+    #[display("terminate()")]
+    Terminate,
+
+    /// Requests information about an invoice issued by the local node, which is required to
+    /// settle an incoming HTLC with the given payment hash. Sent from channeld to lnpd.
+    #[display("get_invoice({0})")]
+    GetInvoice(HashLock),
+
+    /// Provides channeld with the invoice issued by the local node for a payment hash. Sent from
+    /// lnpd to channeld in response to `GetInvoice`.
+    #[display("invoice({0})")]
+    Invoice(InvoiceInfo),
+
     // Key-related tasks
     // -----------------
     #[display("sign(...)")]
@@ -113,13 +209,29 @@ pub enum CtlMsg {
     #[display("signed(...)")]
     Signed(Psbt),
 
+    /// Signs transaction spending outputs of a revoked remote commitment transaction with the
+    /// revocation key, derived from the local revocation basepoint and the per-commitment secret
+    /// revealed by the remote peer. Sent from channeld to signd, which replies with `Signed`.
+    #[display("sign_revoked(...)")]
+    SignRevoked { psbt: Psbt, per_commitment_secret: Slice32 },
+
     // lnpd -> signd
     #[display("derive_keyset({0})")]
     DeriveKeyset(Slice32),
 
-    // signd -> lnpd
+    // signd -> lnpd, channeld: channel keyset and the seed for generating per-commitment secrets
     #[display("keyset({0}, ...)")]
-    Keyset(ServiceId, LocalKeyset),
+    Keyset(ServiceId, LocalKeyset, Slice32),
+
+    /// Requests signd to derive the key encrypting static channel backups from the node master
+    /// key. Sent from lnpd once signd is connected.
+    #[display("derive_backup_key")]
+    DeriveBackupKey,
+
+    /// Key encrypting static channel backups. Sent from signd to lnpd in response to
+    /// `DeriveBackupKey`.
+    #[display("backup_key(...)")]
+    BackupKey(Slice32),
 
     // Responses
     // ---------
@@ -144,8 +256,15 @@ pub enum CtlMsg {
 
     // Channel tasks
     // -----------------
+    /// Requests lnpd to update its routing tables once the channel daemon changes its identity
+    /// from the temporary channel id to the permanent one. Sent from the new identity.
     #[display("channel_update({old_id}, {new_id})")]
     ChannelUpdate { old_id: TempChannelId, new_id: ChannelId },
+
+    /// Confirms to the channel daemon that lnpd has updated its routing tables after the channel
+    /// id change, so the daemon can continue its operations
+    #[display("channel_updated({0})")]
+    ChannelUpdated(ChannelId),
 }
 
 impl CtlMsg {
@@ -189,6 +308,9 @@ pub struct OpenChannelWith {
 
     /// Channel local keyset
     pub local_keys: LocalKeyset,
+
+    /// Seed for generating local per-commitment secrets
+    pub commitment_seed: Slice32,
 }
 
 /// Request configuring newly launched channeld instance
@@ -217,6 +339,33 @@ pub struct AcceptChannelFrom {
     pub channel_req: OpenChannel,
 }
 
+/// Static information about a channel sufficient to recover its funds with the help of the remote
+/// peer after the loss of the channel state
+#[derive(Clone, PartialEq, Eq, Debug, Display, NetworkEncode, NetworkDecode)]
+#[display("{channel_id}, {remote_id}, ...")]
+pub struct ChannelBackup {
+    /// Channel identifier
+    pub channel_id: ChannelId,
+
+    /// Channel funding output
+    pub funding_outpoint: OutPoint,
+
+    /// Remote peer of the channel
+    pub remote_id: NodeId,
+
+    /// Last known address of the remote peer, if any
+    pub remote_addr: Option<NodeAddr>,
+
+    /// Type of the channel, defining scripts of the commitment transaction outputs
+    pub channel_type: ChannelType,
+
+    /// Channel local keyset, including derivation information for each of the keys
+    pub local_keys: LocalKeyset,
+
+    /// Funding public key of the remote peer
+    pub remote_funding_pubkey: PublicKey,
+}
+
 /// Request information about constructing funding transaction
 #[derive(Clone, PartialEq, Eq, Debug, Display, NetworkEncode, NetworkDecode)]
 #[display("{script_pubkey}, {amount}")]
@@ -231,6 +380,64 @@ pub struct FundChannel {
     pub feerate_per_kw: Option<u32>,
 }
 
+/// Request to bump fee of a published commitment transaction with a child transaction spending
+/// its anchor output
+#[derive(Clone, PartialEq, Eq, Debug, Display, NetworkEncode, NetworkDecode)]
+#[display("{anchor}, {commitment_fee}, ...")]
+pub struct BumpCommitment {
+    /// Anchor output of the commitment transaction which belongs to the local node
+    pub anchor: OutPoint,
+
+    /// Value and script of the anchor output
+    pub anchor_txout: TxOut,
+
+    /// Weight of the signed commitment transaction together with the witness spending the
+    /// anchor output
+    pub package_weight: u64,
+
+    /// Fee paid by the commitment transaction
+    pub commitment_fee: u64,
+
+    /// Fee rate which must be paid by the commitment and child transactions together, per
+    /// kilo-weight unit. If absent, the fee rate estimated by the funding wallet is used.
+    pub feerate_per_kw: Option<u32>,
+}
+
+/// Data of an invoice issued by the local node required to settle incoming HTLCs paying it
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, NetworkEncode, NetworkDecode)]
+pub struct IssuedInvoice {
+    /// Preimage of the invoice payment hash
+    pub preimage: HashPreimage,
+
+    /// Secret which must be provided by the payer in the final hop onion payload
+    pub payment_secret: HashPreimage,
+
+    /// Amount requested by the invoice, if any
+    pub amount_msat: Option<u64>,
+
+    /// Minimal number of blocks between the current block height and the expiry of the
+    /// incoming HTLC
+    pub min_final_cltv_expiry: u32,
+
+    /// UNIX timestamp after which the invoice can't be paid anymore
+    pub expires_at: u64,
+}
+
+/// Response to `GetInvoice` request
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, NetworkEncode, NetworkDecode)]
+#[display("{payment_hash}, {height}, ...")]
+pub struct InvoiceInfo {
+    /// Payment hash from the request
+    pub payment_hash: HashLock,
+
+    /// Current block height known to lnpd, or zero if lnpd is unable to learn it; in the latter
+    /// case the invoice is always reported as unknown
+    pub height: u32,
+
+    /// Invoice with the payment hash, if it was issued by the local node and has not expired
+    pub invoice: Option<IssuedInvoice>,
+}
+
 /// TODO: Move to descriptor wallet
 /// Information about block position and transaction position in a block
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display)]
@@ -260,6 +467,21 @@ pub struct TxStatus {
     pub block_pos: Option<BlockPos>,
 }
 
+/// Transaction spending an output tracked by an on-chain service
+#[derive(Clone, PartialEq, Eq, Debug, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{outpoint}, ...")]
+pub struct TxSpending {
+    /// Output previously requested to be tracked
+    pub outpoint: OutPoint,
+
+    /// Transaction spending the output
+    pub tx: Transaction,
+
+    /// Optional block position given only if the transaction is mined
+    pub block_pos: Option<BlockPos>,
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display, NetworkEncode, NetworkDecode)]
 #[display("{client}, {status}")]
 pub struct Report {
@@ -0,0 +1,183 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Anchor outputs of the commitment transactions (BOLT-3 `option_anchors`), which allow each of
+//! the peers to bump the commitment transaction fee by spending its anchor output with a child
+//! transaction (CPFP).
+
+use amplify::DumbDefault;
+use bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_CSV, OP_ENDIF, OP_IF, OP_IFDUP, OP_NOTIF};
+use bitcoin::blockdata::script;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::TxOut;
+use bitcoin_scripts::WitnessScript;
+use lnp::channel::bolt::{self, BoltExt, LocalKeyset, RemoteKeyset};
+use lnp::channel::tx_graph::TxGraph;
+use lnp::p2p::bolt::Messages as LnMsg;
+use lnp::{ChannelExtension, Extension};
+use wallet::psbt::{self, Psbt};
+
+use super::commitment::has_anchors;
+
+/// Amount of each of the anchor outputs, in satoshis
+pub(super) const ANCHOR_OUTPUT_VALUE: u64 = 330;
+
+/// Weight of the commitment transaction with anchor outputs and without HTLC outputs
+pub(super) const ANCHOR_COMMITMENT_WEIGHT: u64 = 1124;
+
+/// Weight of the commitment transaction without anchor and HTLC outputs
+pub(super) const COMMITMENT_WEIGHT: u64 = 724;
+
+/// Weight of the witness spending anchor output with the funding key: a signature and the
+/// witness script.
+pub(super) const ANCHOR_WITNESS_WEIGHT: u64 = 116;
+
+/// Channel extension adding anchor outputs to the commitment transactions.
+///
+/// Replaces anchor outputs extension of the BOLT channel, which is not implemented yet. The
+/// commitment fee and the anchor values are charged to the channel funder by
+/// [`super::commitment::CommitmentOutputs`], which also locks `to_remote` output with a
+/// one-block relative timelock.
+#[derive(Debug)]
+pub(super) struct AnchorOutputs {
+    /// Whether the channel type requires anchor outputs
+    enabled: bool,
+    local_keys: LocalKeyset,
+    remote_keys: RemoteKeyset,
+}
+
+impl Default for AnchorOutputs {
+    fn default() -> Self {
+        AnchorOutputs {
+            enabled: false,
+            local_keys: LocalKeyset::dumb_default(),
+            remote_keys: RemoteKeyset::dumb_default(),
+        }
+    }
+}
+
+impl Extension<BoltExt> for AnchorOutputs {
+    #[inline]
+    fn identity(&self) -> BoltExt { BoltExt::AnchorOutputs }
+
+    fn update_from_local(&mut self, _message: &()) -> Result<(), bolt::Error> {
+        // Nothing to do here: all data are taken from the channel state
+        Ok(())
+    }
+
+    fn update_from_peer(&mut self, _message: &LnMsg) -> Result<(), bolt::Error> {
+        // Nothing to do here: all data are taken from the channel state
+        Ok(())
+    }
+
+    fn load_state(&mut self, state: &bolt::ChannelState) {
+        self.enabled = has_anchors(state.common_params.channel_type);
+        self.local_keys = state.local_keys.clone();
+        self.remote_keys = state.remote_keys.clone();
+    }
+
+    fn store_state(&self, _state: &mut bolt::ChannelState) {
+        // Nothing to do here: the extension does not have its own state
+    }
+}
+
+impl ChannelExtension<BoltExt> for AnchorOutputs {
+    #[inline]
+    fn new() -> Box<dyn ChannelExtension<BoltExt>> { Box::<AnchorOutputs>::default() }
+
+    fn build_graph(&self, tx_graph: &mut TxGraph, as_remote_node: bool) -> Result<(), bolt::Error> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let local_anchor = local_anchor_output(&self.local_keys.funding_pubkey);
+        let remote_anchor = anchor_output(self.remote_keys.funding_pubkey);
+        let (owner_anchor, counterparty_anchor) = match as_remote_node {
+            true => (remote_anchor, local_anchor),
+            false => (local_anchor, remote_anchor),
+        };
+
+        let outs = &mut tx_graph.cmt_outs;
+        let has_to_local = outs.iter().any(is_to_local);
+        let has_to_remote = outs.iter().any(is_to_remote);
+        let has_htlcs = outs.len() > has_to_local as usize + has_to_remote as usize;
+        if has_to_local || has_htlcs {
+            outs.push(owner_anchor);
+        }
+        if has_to_remote || has_htlcs {
+            outs.push(counterparty_anchor);
+        }
+
+        Ok(())
+    }
+}
+
+/// Detects `to_local` output of the commitment transaction, which is the only output with the
+/// witness script starting with `OP_IF`
+pub(super) fn is_to_local(output: &psbt::Output) -> bool {
+    output
+        .witness_script
+        .as_ref()
+        .and_then(|script| script.as_bytes().first().copied())
+        .map(|opcode| opcode == OP_IF.to_u8())
+        .unwrap_or_default()
+}
+
+/// Detects `to_remote` output of the commitment transaction with anchor outputs, which is the only
+/// output with the witness script ending with `OP_CSV`
+fn is_to_remote(output: &psbt::Output) -> bool {
+    output
+        .witness_script
+        .as_ref()
+        .and_then(|script| script.as_bytes().last().copied())
+        .map(|opcode| opcode == OP_CSV.to_u8())
+        .unwrap_or_default()
+}
+
+/// Detects local anchor output of the commitment transaction
+pub(super) fn local_anchor(psbt: &Psbt, funding_pubkey: PublicKey) -> Option<usize> {
+    let witness_script = anchor_script(funding_pubkey);
+    psbt.outputs.iter().position(|out| out.witness_script.as_ref() == Some(&witness_script))
+}
+
+/// Constructs witness script of the anchor output spendable with the funding key of one of the
+/// peers, or by anyone after 16 blocks
+pub(super) fn anchor_script(funding_pubkey: PublicKey) -> WitnessScript {
+    script::Builder::new()
+        .push_key(&bitcoin::PublicKey::new(funding_pubkey))
+        .push_opcode(OP_CHECKSIG)
+        .push_opcode(OP_IFDUP)
+        .push_opcode(OP_NOTIF)
+        .push_int(16)
+        .push_opcode(OP_CSV)
+        .push_opcode(OP_ENDIF)
+        .into_script()
+        .into()
+}
+
+fn anchor_output(funding_pubkey: PublicKey) -> psbt::Output {
+    let witness_script = anchor_script(funding_pubkey);
+    let txout =
+        TxOut { value: ANCHOR_OUTPUT_VALUE, script_pubkey: witness_script.to_p2wsh().into() };
+    let output =
+        bitcoin::psbt::Output { witness_script: Some(witness_script.into()), ..Default::default() };
+    psbt::Output::with(0, output, txout)
+}
+
+/// Constructs anchor output of the local node, providing derivation of the funding key required
+/// for signing transaction spending the anchor
+fn local_anchor_output(funding_pubkey: &bolt::LocalPubkey) -> psbt::Output {
+    let mut output = anchor_output(funding_pubkey.key);
+    output.bip32_derivation = funding_pubkey.to_bip32_derivation_map();
+    output
+}
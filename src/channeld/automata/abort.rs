@@ -0,0 +1,794 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Uncooperative channel closing by publishing the latest local commitment transaction.
+//!
+//! HTLC outputs of the commitment transaction are claimed with HTLC-success transactions, using
+//! payment preimages of the HTLCs fulfilled by the local node, and HTLC-timeout transactions,
+//! which are published once the HTLCs expire. Both are signed by the remote peer in advance.
+//! Outputs of the HTLC transactions are locked for `to_self_delay` blocks, like `to_local` output,
+//! so all of them are swept together once the last one matures.
+
+use amplify::{DumbDefault, Slice32};
+use bitcoin::{
+    EcdsaSig, EcdsaSighashType, OutPoint, PackedLockTime, Sequence, Transaction, TxIn, TxOut,
+    Witness,
+};
+use bitcoin_scripts::PubkeyScript;
+use lnp::channel::bolt::{self, Lifecycle};
+use lnp::p2p::bolt::ActiveChannelId;
+use lnp::Extension;
+use microservices::cli::LogStyle;
+use wallet::psbt::{self, Psbt, PsbtVersion};
+
+use super::close::{add_remote_sig, FUNDING_WITNESS_WEIGHT};
+use super::Error;
+use crate::automata::{Event, StateMachine};
+use crate::bus::{BumpCommitment, BusMsg, CtlMsg, TxSpending};
+use crate::channeld::anchors::{self, ANCHOR_WITNESS_WEIGHT};
+use crate::channeld::commitment::{has_anchors, tweak, Commitment};
+use crate::channeld::runtime::Runtime;
+use crate::channeld::state::{ClaimStage, HtlcClaim};
+use crate::rpc::ServiceId;
+use crate::{Endpoints, Responder};
+
+/// Weight of the witness spending `to_local` output of the commitment transaction via its delayed
+/// branch: a signature, an empty element selecting `OP_ELSE` branch and the witness script.
+const TO_LOCAL_WITNESS_WEIGHT: u64 = 154;
+
+/// Uncooperative channel closing workflow initiated by the local node
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+pub enum ChannelAbort {
+    /// signing the latest local commitment transaction
+    #[display("SIGNING")]
+    Signing,
+
+    /// commitment transaction is published with a fee rate below the on-chain fee estimate,
+    /// awaiting lnpd to construct a child transaction spending our anchor output
+    #[display("BUMPING")]
+    Bumping,
+
+    /// signing child transaction bumping the commitment transaction fee
+    #[display("BUMP_SIGNING")]
+    BumpSigning,
+
+    /// commitment transaction is published, awaiting `to_self_delay` blocks to be mined on top
+    /// of it; HTLC transactions are published once their lock time is reached
+    #[display("MATURING")]
+    Maturing,
+
+    /// awaiting lnpd to provide a script from the funding wallet for the sweep output
+    #[display("PREPARING")]
+    Preparing,
+
+    /// signing transaction sweeping funds from the local commitment output
+    #[display("SWEEPING")]
+    Sweeping,
+
+    /// sweep transaction is published, awaiting it to be mined
+    #[display("SWEPT")]
+    Swept,
+
+    /// signing HTLC transactions spending HTLC outputs of the commitment transaction
+    #[display("HTLC_SIGNING")]
+    HtlcSigning,
+
+    /// commitment transaction has matured, awaiting HTLC outputs to be claimed with HTLC
+    /// transactions and outputs of these transactions to mature
+    #[display("RESOLVING")]
+    Resolving,
+}
+
+impl StateMachine<BusMsg, Runtime> for ChannelAbort {
+    type Error = Error;
+
+    fn next(
+        self,
+        event: Event<BusMsg>,
+        runtime: &mut Runtime,
+    ) -> Result<Option<Self>, Self::Error> {
+        let channel_id = runtime.state.channel.active_channel_id();
+        debug!("ChannelAbort {:#} received {} event", channel_id, event.message);
+        let state = match self {
+            ChannelAbort::Signing => complete_signing(event, runtime),
+            ChannelAbort::Bumping => complete_bumping(event, runtime),
+            ChannelAbort::BumpSigning => complete_bump_signing(event, runtime),
+            ChannelAbort::HtlcSigning => complete_htlc_signing(event, runtime),
+            ChannelAbort::Maturing | ChannelAbort::Resolving => {
+                if let Some(next) = complete_maturing(self, event, runtime)? {
+                    Ok(next)
+                } else {
+                    info!("ChannelAbort {:#} has completed its work", channel_id);
+                    return Ok(None);
+                }
+            }
+            ChannelAbort::Preparing => complete_preparing(event, runtime),
+            ChannelAbort::Sweeping => complete_sweeping(event, runtime),
+            ChannelAbort::Swept => {
+                complete_swept(event, runtime)?;
+                info!("ChannelAbort {:#} has completed its work", channel_id);
+                return Ok(None);
+            }
+        }?;
+        info!("ChannelAbort {:#} switched to {} state", channel_id, state);
+        Ok(Some(state))
+    }
+}
+
+impl ChannelAbort {
+    /// Computes channel lifecycle stage for the current channel abort workflow stage
+    pub fn lifecycle(&self) -> Lifecycle { Lifecycle::Aborting }
+}
+
+// State transitions:
+
+impl ChannelAbort {
+    /// Constructs uncooperative channel closing state machine
+    pub fn with(runtime: &mut Runtime, endpoints: &mut Endpoints) -> Result<ChannelAbort, Error> {
+        if runtime.state.outdated_point.is_some() {
+            return Err(Error::OutdatedState);
+        }
+        if runtime.state.commitment_sig().is_none() {
+            return Err(Error::NoCommitmentSig);
+        }
+        let commitment_psbt = commitment_psbt(runtime)?;
+
+        runtime.state.closing = none!();
+        runtime.state.set_stage(Lifecycle::Aborting);
+        debug!("Signing latest commitment transaction {}", commitment_psbt.to_txid());
+        runtime.send_ctl(endpoints, ServiceId::Signer, CtlMsg::Sign(commitment_psbt))?;
+        Ok(ChannelAbort::Signing)
+    }
+
+    /// Processes transaction spending HTLC output of the published commitment transaction. If
+    /// the output is spent by the remote peer, it is no longer claimed by the local node. Returns
+    /// `None` if there is nothing left to sweep.
+    pub fn spent(
+        self,
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+        spending: &TxSpending,
+    ) -> Result<Option<ChannelAbort>, Error> {
+        let txid = spending.tx.txid();
+        let claim = runtime.state.htlc_claims.iter_mut().find(|claim| {
+            claim.psbt.to_unsigned_tx().input[0].previous_output == spending.outpoint
+        });
+        match claim {
+            Some(claim) if claim.psbt.to_txid() != txid && claim.stage != ClaimStage::Lost => {
+                warn!(
+                    "HTLC output {} of the commitment transaction is claimed by the remote peer \
+                     with transaction {}",
+                    spending.outpoint, txid
+                );
+                claim.stage = ClaimStage::Lost;
+            }
+            // HTLC transactions of the local node are tracked until their outputs mature
+            _ => return Ok(Some(self)),
+        }
+        if self == ChannelAbort::Resolving && htlcs_resolved(runtime) {
+            return prepare_sweep(runtime, endpoints);
+        }
+        Ok(Some(self))
+    }
+
+    /// Construct information message for error and client reporting
+    pub fn info_message(&self, channel_id: ActiveChannelId) -> String {
+        match self {
+            ChannelAbort::Signing => format!(
+                "{} latest commitment transaction locally for channel {:#}",
+                "Signing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelAbort::Bumping => format!(
+                "{} child transaction bumping commitment fee for channel {:#}",
+                "Constructing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelAbort::BumpSigning => format!(
+                "{} child transaction bumping commitment fee locally for channel {:#}",
+                "Signing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelAbort::Maturing => format!(
+                "{} commitment transaction for channel {:#} to mature",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+            ChannelAbort::Preparing => format!(
+                "{} to sweep funds from channel {:#}",
+                "Preparing".announce(),
+                channel_id.announcer()
+            ),
+            ChannelAbort::Sweeping => format!(
+                "{} funds from channel {:#}",
+                "Sweeping".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelAbort::Swept => format!(
+                "{} sweep transaction for channel {:#} to be mined",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+            ChannelAbort::HtlcSigning => format!(
+                "{} HTLC transactions locally for channel {:#}",
+                "Signing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelAbort::Resolving => format!(
+                "{} HTLC outputs of channel {:#} to be resolved",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+        }
+    }
+}
+
+fn complete_signing(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelAbort, Error> {
+    let mut commitment_psbt = match event.message {
+        BusMsg::Ctl(CtlMsg::Signed(psbt)) => psbt,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Aborting, event.source))
+        }
+    };
+
+    let funding_pubkey = runtime.state.channel.funding_pubkey();
+    if !commitment_psbt.inputs[0]
+        .partial_sigs
+        .contains_key(&bitcoin::PublicKey::new(funding_pubkey))
+    {
+        return Err(Error::FundingPsbtUnsigned(funding_pubkey));
+    }
+    let remote_sig = runtime.state.commitment_sig().ok_or(Error::NoCommitmentSig)?;
+    add_remote_sig(runtime, &mut commitment_psbt, remote_sig);
+
+    let depth = maturing_depth(runtime, &commitment_psbt);
+    let bump = bump_request(runtime, &commitment_psbt);
+    runtime.state.htlc_claims = htlc_claims(runtime, &commitment_psbt);
+
+    let txid = commitment_psbt.to_txid();
+    debug!("Publishing commitment transaction {}", txid);
+    trace!("Commitment transaction: {:#?}", commitment_psbt);
+    runtime.state.closing.txid = Some(txid);
+    runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::PublishTx(commitment_psbt))?;
+    watch_htlcs(runtime, event.endpoints)?;
+
+    trace!("Notifying router and lnpd about channel closing");
+    let channel_id = runtime.state.channel.try_channel_id()?;
+    runtime.notify_channel(event.endpoints, CtlMsg::ChannelClosed(channel_id));
+
+    // Sweeping funds takes `to_self_delay` blocks, so we do not make the client wait for it
+    let _ = runtime.report_success(
+        event.endpoints,
+        Some(format!(
+            "Commitment transaction {} for channel {} is published; funds will be returned to the \
+             funding wallet after {} blocks",
+            txid, channel_id, depth
+        )),
+    );
+    runtime.enquirer = None;
+
+    if let Some(bump) = bump {
+        debug!("Requesting CPFP transaction bumping fee of commitment transaction {}", txid);
+        runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::ConstructCpfp(bump))?;
+        return Ok(ChannelAbort::Bumping);
+    }
+    sign_htlcs(runtime, event.endpoints)
+}
+
+fn complete_bumping(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelAbort, Error> {
+    match event.message {
+        BusMsg::Ctl(CtlMsg::CpfpConstructed(mut cpfp_psbt)) => {
+            // Anchor input is added by lnpd, which does not know its witness script and key
+            let funding_pubkey = &runtime.state.channel.constructor().local_keys().funding_pubkey;
+            let input = cpfp_psbt.inputs.last_mut().expect("CPFP transaction spends anchor output");
+            input.witness_script = Some(anchors::anchor_script(funding_pubkey.key));
+            input.bip32_derivation = funding_pubkey.to_bip32_derivation_map();
+
+            debug!("Signing CPFP transaction {}", cpfp_psbt.to_txid());
+            runtime.send_ctl(event.endpoints, ServiceId::Signer, CtlMsg::Sign(cpfp_psbt))?;
+            Ok(ChannelAbort::BumpSigning)
+        }
+        BusMsg::Ctl(CtlMsg::Error { request, error, .. }) if request.starts_with("publish_tx") => {
+            Err(Error::ClosingUnpublished(error))
+        }
+        BusMsg::Ctl(CtlMsg::Error { error, .. }) => {
+            // The commitment transaction is already published, so we just wait for it to be mined
+            warn!("Unable to bump commitment transaction fee: {}", error);
+            sign_htlcs(runtime, event.endpoints)
+        }
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Aborting, event.source)),
+    }
+}
+
+fn complete_bump_signing(
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<ChannelAbort, Error> {
+    let mut cpfp_psbt = match event.message {
+        BusMsg::Ctl(CtlMsg::Signed(psbt)) => psbt,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Aborting, event.source))
+        }
+    };
+
+    // Anchor output is not a miniscript, so we have to finalize the input ourselves
+    let funding_pubkey = runtime.state.channel.funding_pubkey();
+    let input = cpfp_psbt.inputs.last_mut().expect("CPFP transaction spends anchor output");
+    let signature = input
+        .partial_sigs
+        .get(&bitcoin::PublicKey::new(funding_pubkey))
+        .ok_or(Error::AnchorPsbtUnsigned(funding_pubkey))?;
+    let witness_script = input.witness_script.as_ref().expect("anchor input has witness script");
+    input.final_script_witness =
+        Some(Witness::from_vec(vec![signature.to_vec(), witness_script.to_bytes()]));
+
+    let txid = cpfp_psbt.to_txid();
+    debug!("Publishing CPFP transaction {}", txid);
+    trace!("CPFP transaction: {:#?}", cpfp_psbt);
+    runtime.state.closing.cpfp_txid = Some(txid);
+    runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::PublishTx(cpfp_psbt))?;
+    sign_htlcs(runtime, event.endpoints)
+}
+
+fn complete_htlc_signing(
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<ChannelAbort, Error> {
+    let mut htlc_psbt = match event.message {
+        BusMsg::Ctl(CtlMsg::Signed(psbt)) => psbt,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Aborting, event.source))
+        }
+    };
+    let txid = htlc_psbt.to_txid();
+    let index =
+        match runtime.state.htlc_claims.iter().position(|claim| claim.psbt.to_txid() == txid) {
+            Some(index) => index,
+            None => {
+                let msg = BusMsg::Ctl(CtlMsg::Signed(htlc_psbt));
+                return Err(Error::UnexpectedMessage(msg, Lifecycle::Aborting, event.source));
+            }
+        };
+
+    // HTLC outputs are not miniscripts, so we have to finalize the input ourselves. Signd adds
+    // the local signature under the HTLC basepoint, while the remote signature is added under
+    // the remote HTLC key during the transaction construction.
+    let mut state = bolt::ChannelState::dumb_default();
+    runtime.state.channel.store_state(&mut state);
+    let htlc_basepoint = state.local_keys.htlc_basepoint.key;
+    let remote_htlc_pubkey =
+        Commitment::with(&state, &[], &[], false).keys.counterparty_htlc_pubkey;
+    let claim = &mut runtime.state.htlc_claims[index];
+    let input = &mut htlc_psbt.inputs[0];
+    let local_sig = input
+        .partial_sigs
+        .get(&bitcoin::PublicKey::new(htlc_basepoint))
+        .ok_or(Error::HtlcPsbtUnsigned(htlc_basepoint))?;
+    let remote_sig = input
+        .partial_sigs
+        .get(&bitcoin::PublicKey::new(remote_htlc_pubkey))
+        .expect("HTLC transaction is signed by the remote peer");
+    let witness_script = input.witness_script.as_ref().expect("HTLC outputs are P2WSH");
+    // HTLC-timeout transactions select the timeout branch with an empty element
+    let preimage = claim
+        .preimage
+        .map(|preimage| AsRef::<[u8]>::as_ref(&preimage).to_vec())
+        .unwrap_or_default();
+    input.final_script_witness = Some(Witness::from_vec(vec![
+        vec![],
+        remote_sig.to_vec(),
+        local_sig.to_vec(),
+        preimage,
+        witness_script.to_bytes(),
+    ]));
+
+    debug!("HTLC transaction {} is signed", txid);
+    trace!("HTLC transaction: {:#?}", htlc_psbt);
+    claim.psbt = htlc_psbt;
+    claim.stage = ClaimStage::Signed;
+    sign_htlcs(runtime, event.endpoints)
+}
+
+fn complete_maturing(
+    state: ChannelAbort,
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<Option<ChannelAbort>, Error> {
+    let commitment_txid = runtime.state.closing.txid;
+    match event.message {
+        BusMsg::Ctl(CtlMsg::TxFound(status)) if Some(status.txid) == commitment_txid => {
+            debug!("Commitment transaction {} has matured", status.txid);
+            if !htlcs_resolved(runtime) {
+                return Ok(Some(ChannelAbort::Resolving));
+            }
+            prepare_sweep(runtime, event.endpoints)
+        }
+        BusMsg::Ctl(CtlMsg::TxFound(status)) => {
+            let claim = runtime.state.htlc_claims.iter_mut().find(|claim| {
+                claim.psbt.to_txid() == status.txid && claim.stage != ClaimStage::Lost
+            });
+            match claim {
+                Some(claim) => claim.stage = ClaimStage::Matured,
+                None => {
+                    let msg = BusMsg::Ctl(CtlMsg::TxFound(status));
+                    return Err(Error::UnexpectedMessage(msg, Lifecycle::Aborting, event.source));
+                }
+            }
+            debug!("Output of HTLC transaction {} has matured", status.txid);
+            if state == ChannelAbort::Resolving && htlcs_resolved(runtime) {
+                return prepare_sweep(runtime, event.endpoints);
+            }
+            Ok(Some(state))
+        }
+        BusMsg::Ctl(CtlMsg::BlockHeight(height)) => {
+            publish_htlcs(runtime, event.endpoints, height)?;
+            Ok(Some(state))
+        }
+        // Errors on publishing the commitment transaction arrive before the CPFP and HTLC
+        // transactions are published, so once they are published the error may concern only
+        // them. HTLC transactions are rebroadcast with the next block.
+        BusMsg::Ctl(CtlMsg::Error { error, .. })
+            if runtime.state.closing.cpfp_txid.is_some()
+                || runtime.state.htlc_claims.iter().any(|claim| {
+                    matches!(claim.stage, ClaimStage::Published | ClaimStage::Matured)
+                }) =>
+        {
+            warn!("Unable to publish CPFP or HTLC transaction: {}", error);
+            Ok(Some(state))
+        }
+        BusMsg::Ctl(CtlMsg::Error { error, .. }) => Err(Error::ClosingUnpublished(error)),
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Aborting, event.source)),
+    }
+}
+
+fn complete_preparing(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelAbort, Error> {
+    match event.message {
+        BusMsg::Ctl(CtlMsg::PayoutScript(script)) => sign_sweep(runtime, event.endpoints, script),
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Aborting, event.source)),
+    }
+}
+
+fn complete_sweeping(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelAbort, Error> {
+    let mut sweep_psbt = match event.message {
+        BusMsg::Ctl(CtlMsg::Signed(psbt)) => psbt,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Aborting, event.source))
+        }
+    };
+
+    // `to_local` outputs are not miniscripts, so we have to finalize the inputs ourselves
+    let delayed_basepoint =
+        runtime.state.channel.constructor().local_keys().delayed_payment_basepoint.key;
+    for input in &mut sweep_psbt.inputs {
+        let signature = input
+            .partial_sigs
+            .get(&bitcoin::PublicKey::new(delayed_basepoint))
+            .ok_or(Error::SweepPsbtUnsigned(delayed_basepoint))?;
+        let witness_script =
+            input.witness_script.as_ref().expect("sweep transaction spends to_local outputs");
+        input.final_script_witness =
+            Some(Witness::from_vec(vec![signature.to_vec(), vec![], witness_script.to_bytes()]));
+    }
+
+    let txid = sweep_psbt.to_txid();
+    debug!("Publishing sweep transaction {}", txid);
+    trace!("Sweep transaction: {:#?}", sweep_psbt);
+    runtime.state.closing.sweep_txid = Some(txid);
+    runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::PublishTx(sweep_psbt))?;
+    runtime.send_ctl(event.endpoints, ServiceId::Watch, CtlMsg::Track { txid, depth: 0 })?;
+    Ok(ChannelAbort::Swept)
+}
+
+fn complete_swept(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<(), Error> {
+    let sweep_txid = runtime.state.closing.sweep_txid;
+    match event.message {
+        BusMsg::Ctl(CtlMsg::TxFound(status)) if Some(status.txid) == sweep_txid => {
+            info!(
+                "Funds from channel {} are returned to the funding wallet with transaction {}",
+                runtime.state.channel.active_channel_id(),
+                status.txid
+            );
+            runtime.state.set_stage(Lifecycle::Closed);
+            Ok(())
+        }
+        BusMsg::Ctl(CtlMsg::Error { error, .. }) => Err(Error::ClosingUnpublished(error)),
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Aborting, event.source)),
+    }
+}
+
+fn sign_sweep(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    script: PubkeyScript,
+) -> Result<ChannelAbort, Error> {
+    let sweep_psbt = sweep_psbt(runtime, script)?;
+    debug!("Signing sweep transaction {}", sweep_psbt.to_txid());
+    runtime.send_ctl(endpoints, ServiceId::Signer, CtlMsg::Sign(sweep_psbt))?;
+    Ok(ChannelAbort::Sweeping)
+}
+
+/// Constructs the latest local commitment transaction spending channel funding output
+fn commitment_psbt(runtime: &mut Runtime) -> Result<Psbt, Error> {
+    let mut psbt = runtime.state.commitment_tx(false)?;
+    runtime.state.fill_funding_input(&mut psbt.inputs[0])?;
+    Ok(psbt)
+}
+
+/// Asks watchd to report when the published commitment transaction matures
+fn start_maturing(runtime: &mut Runtime, endpoints: &mut Endpoints) -> Result<ChannelAbort, Error> {
+    let commitment_psbt = commitment_psbt(runtime)?;
+    let depth = maturing_depth(runtime, &commitment_psbt);
+    let txid = runtime.state.closing.txid.expect("commitment transaction is published");
+    runtime.send_ctl(endpoints, ServiceId::Watch, CtlMsg::Track { txid, depth })?;
+    Ok(ChannelAbort::Maturing)
+}
+
+/// Computes number of blocks to wait for the commitment transaction `to_local` output to mature.
+/// Without `to_local` output there is nothing to sweep, so we just wait for the commitment
+/// transaction to get into the mempool.
+fn maturing_depth(runtime: &Runtime, commitment_psbt: &Psbt) -> u32 {
+    let to_self_delay = runtime.state.channel.constructor().remote_params().to_self_delay;
+    if to_local_output(commitment_psbt).is_some() {
+        to_self_delay as u32
+    } else {
+        0
+    }
+}
+
+/// Composes request for bumping the commitment transaction fee with a CPFP transaction, if the
+/// commitment transaction has our anchor output and pays fee rate below the on-chain fee estimate
+fn bump_request(runtime: &Runtime, commitment_psbt: &Psbt) -> Option<BumpCommitment> {
+    let funding_pubkey = runtime.state.channel.funding_pubkey();
+    let vout = anchors::local_anchor(commitment_psbt, funding_pubkey)?;
+
+    let weight = commitment_psbt.to_unsigned_tx().weight() as u64 + FUNDING_WITNESS_WEIGHT;
+    let outputs = commitment_psbt.outputs.iter().map(|output| output.amount).sum::<u64>();
+    let commitment_fee = runtime.state.channel.funding().amount().saturating_sub(outputs);
+    let feerate_per_kw = runtime.fee_estimate;
+    if matches!(feerate_per_kw, Some(target) if commitment_fee * 1000 / weight >= target as u64) {
+        return None;
+    }
+
+    Some(BumpCommitment {
+        anchor: OutPoint::new(commitment_psbt.to_txid(), vout as u32),
+        anchor_txout: commitment_psbt.outputs[vout].to_txout(),
+        package_weight: weight + ANCHOR_WITNESS_WEIGHT,
+        commitment_fee,
+        feerate_per_kw,
+    })
+}
+
+/// Constructs HTLC transactions claiming HTLC outputs of the published local commitment
+/// transaction and adds the remote peer signatures to them. HTLC outputs which can't be claimed
+/// by the node are marked as lost and have to be resolved by the node operator.
+fn htlc_claims(runtime: &Runtime, commitment_psbt: &Psbt) -> Vec<HtlcClaim> {
+    let mut state = bolt::ChannelState::dumb_default();
+    runtime.state.channel.store_state(&mut state);
+    let channel_type = state.common_params.channel_type;
+    let hash_ty = match has_anchors(channel_type) {
+        true => EcdsaSighashType::SinglePlusAnyoneCanPay,
+        false => EcdsaSighashType::All,
+    };
+    let spec = &runtime.state.commitments.local;
+    let commitment = Commitment::with(&state, &spec.offered, &spec.received, false);
+    let remote_htlc_pubkey = bitcoin::PublicKey::new(commitment.keys.counterparty_htlc_pubkey);
+
+    let htlc_txs = commitment.htlc_txs(commitment_psbt);
+    htlc_txs
+        .into_iter()
+        .enumerate()
+        .map(|(index, (mut psbt, htlc))| {
+            let timeout = psbt.to_unsigned_tx().lock_time != PackedLockTime::ZERO;
+            let preimage = match timeout {
+                true => None,
+                false => runtime.state.preimages.get(&htlc.payment_hash).copied(),
+            };
+            let sig = runtime.state.htlc_sigs.get(index);
+            if let Some(sig) = sig {
+                psbt.inputs[0]
+                    .partial_sigs
+                    .insert(remote_htlc_pubkey, EcdsaSig { sig: *sig, hash_ty });
+            }
+            let stage = if channel_type.has_anchors_zero_fee_htlc_tx() {
+                warn!(
+                    "HTLC {} for {} msat can't be claimed: HTLC transactions of zero-fee anchor \
+                     channels require extra fee inputs",
+                    htlc.htlc_id, htlc.amount_msat
+                );
+                ClaimStage::Lost
+            } else if sig.is_none() {
+                warn!(
+                    "HTLC {} for {} msat can't be claimed: no remote signature for the HTLC \
+                     transaction",
+                    htlc.htlc_id, htlc.amount_msat
+                );
+                ClaimStage::Lost
+            } else if !timeout && preimage.is_none() {
+                warn!(
+                    "HTLC {} for {} msat can't be claimed: payment preimage is unknown",
+                    htlc.htlc_id, htlc.amount_msat
+                );
+                ClaimStage::Lost
+            } else {
+                ClaimStage::Unsigned
+            };
+            HtlcClaim { psbt, preimage, stage }
+        })
+        .collect()
+}
+
+/// Asks watchd to report transactions spending HTLC outputs of the published commitment
+/// transaction, which may be claimed by the remote peer instead of the local node
+fn watch_htlcs(runtime: &mut Runtime, endpoints: &mut Endpoints) -> Result<(), Error> {
+    let htlc_outputs = runtime
+        .state
+        .htlc_claims
+        .iter()
+        .map(|claim| {
+            let input = &claim.psbt.inputs[0];
+            let script_pubkey =
+                input.witness_utxo.as_ref().expect("HTLC output").script_pubkey.clone();
+            (claim.psbt.to_unsigned_tx().input[0].previous_output, script_pubkey)
+        })
+        .collect::<Vec<_>>();
+    for (outpoint, script_pubkey) in htlc_outputs {
+        runtime.send_ctl(endpoints, ServiceId::Watch, CtlMsg::TrackSpending {
+            outpoint,
+            script_pubkey: script_pubkey.into(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Sends the next HTLC transaction to signd, or starts waiting for the commitment transaction
+/// to mature once all of them are signed
+fn sign_htlcs(runtime: &mut Runtime, endpoints: &mut Endpoints) -> Result<ChannelAbort, Error> {
+    let claim = runtime.state.htlc_claims.iter().find(|claim| claim.stage == ClaimStage::Unsigned);
+    match claim {
+        Some(claim) => {
+            let psbt = claim.psbt.clone();
+            debug!("Signing HTLC transaction {}", psbt.to_txid());
+            runtime.send_ctl(endpoints, ServiceId::Signer, CtlMsg::Sign(psbt))?;
+            Ok(ChannelAbort::HtlcSigning)
+        }
+        None => start_maturing(runtime, endpoints),
+    }
+}
+
+/// Publishes signed HTLC transactions which lock time is reached, rebroadcasting the ones which
+/// are not mined yet. Once published for the first time, watchd is asked to report when the
+/// HTLC transaction output matures.
+fn publish_htlcs(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    height: u32,
+) -> Result<(), Error> {
+    let to_self_delay = runtime.state.channel.constructor().remote_params().to_self_delay as u32;
+    let ready = runtime
+        .state
+        .htlc_claims
+        .iter_mut()
+        .filter(|claim| matches!(claim.stage, ClaimStage::Signed | ClaimStage::Published))
+        .filter(|claim| claim.psbt.to_unsigned_tx().lock_time.0 <= height)
+        .map(|claim| {
+            let first = claim.stage == ClaimStage::Signed;
+            claim.stage = ClaimStage::Published;
+            (claim.psbt.clone(), first)
+        })
+        .collect::<Vec<_>>();
+    for (htlc_psbt, first) in ready {
+        let txid = htlc_psbt.to_txid();
+        debug!("Publishing HTLC transaction {}", txid);
+        runtime.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::PublishTx(htlc_psbt))?;
+        if first {
+            let track = CtlMsg::Track { txid, depth: to_self_delay };
+            runtime.send_ctl(endpoints, ServiceId::Watch, track)?;
+        }
+    }
+    Ok(())
+}
+
+/// Detects whether all HTLC outputs of the commitment transaction are either claimed by matured
+/// HTLC transactions or lost
+fn htlcs_resolved(runtime: &Runtime) -> bool {
+    runtime
+        .state
+        .htlc_claims
+        .iter()
+        .all(|claim| matches!(claim.stage, ClaimStage::Matured | ClaimStage::Lost))
+}
+
+/// Starts sweeping matured outputs of the commitment and HTLC transactions, or completes the
+/// workflow if there is nothing to sweep
+fn prepare_sweep(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+) -> Result<Option<ChannelAbort>, Error> {
+    let commitment_psbt = commitment_psbt(runtime)?;
+    if to_local_output(&commitment_psbt).is_none() && matured_claims(runtime).next().is_none() {
+        debug!("Commitment transaction {} has no outputs to sweep", commitment_psbt.to_txid());
+        runtime.state.set_stage(Lifecycle::Closed);
+        return Ok(None);
+    }
+    if let Some(script) =
+        runtime.state.channel.constructor().local_keys().shutdown_scriptpubkey.clone()
+    {
+        return sign_sweep(runtime, endpoints, script).map(Some);
+    }
+    runtime.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::GetPayoutScript)?;
+    Ok(Some(ChannelAbort::Preparing))
+}
+
+/// HTLC transactions which outputs have matured and can be swept
+fn matured_claims(runtime: &Runtime) -> impl Iterator<Item = &HtlcClaim> {
+    runtime.state.htlc_claims.iter().filter(|claim| claim.stage == ClaimStage::Matured)
+}
+
+/// Detects `to_local` output of the commitment transaction
+fn to_local_output(psbt: &Psbt) -> Option<(usize, &psbt::Output)> {
+    psbt.outputs.iter().enumerate().find(|(_, output)| anchors::is_to_local(output))
+}
+
+/// Constructs transaction sweeping `to_local` output of the latest local commitment transaction
+/// and outputs of the matured HTLC transactions to the provided `script`. All of them are locked
+/// with the same `to_local` script, so the signing key is provided to signd as a delayed payment
+/// basepoint with a tweak from the per-commitment point for each of the inputs.
+fn sweep_psbt(runtime: &mut Runtime, script: PubkeyScript) -> Result<Psbt, Error> {
+    let commitment_psbt = commitment_psbt(runtime)?;
+    let commitment_txid = commitment_psbt.to_txid();
+    let mut outputs = to_local_output(&commitment_psbt)
+        .map(|(vout, to_local)| (OutPoint::new(commitment_txid, vout as u32), to_local.clone()))
+        .into_iter()
+        .collect::<Vec<_>>();
+    outputs.extend(matured_claims(runtime).map(|claim| {
+        let output = claim.psbt.outputs[0].clone();
+        (OutPoint::new(claim.psbt.to_txid(), 0), output)
+    }));
+
+    let mut state = bolt::ChannelState::dumb_default();
+    runtime.state.channel.store_state(&mut state);
+    let to_self_delay = state.remote_params.to_self_delay;
+    let delayed_basepoint = &state.local_keys.delayed_payment_basepoint;
+
+    let amount = outputs.iter().map(|(_, output)| output.amount).sum::<u64>();
+    let mut sweep_tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: outputs
+            .iter()
+            .map(|(outpoint, _)| TxIn {
+                previous_output: *outpoint,
+                script_sig: empty!(),
+                sequence: Sequence::from_height(to_self_delay),
+                witness: empty!(),
+            })
+            .collect(),
+        output: vec![TxOut { value: amount, script_pubkey: script.into() }],
+    };
+    let weight = sweep_tx.weight() as u64 + outputs.len() as u64 * TO_LOCAL_WITNESS_WEIGHT;
+    let fee = weight * state.common_params.feerate_per_kw as u64 / 1000;
+    sweep_tx.output[0].value = amount.checked_sub(fee).ok_or(Error::ClosingFee(fee))?;
+
+    let tweak = tweak(state.local_per_commitment_point, delayed_basepoint.key);
+
+    let mut psbt = Psbt::with(sweep_tx, PsbtVersion::V0)
+        .expect("sweep transaction has empty script_sig and witness");
+    for (input, (_, output)) in psbt.inputs.iter_mut().zip(outputs) {
+        input.witness_utxo = Some(output.to_txout());
+        input.witness_script = output.witness_script.clone();
+        input.bip32_derivation = delayed_basepoint.to_bip32_derivation_map();
+        input.set_p2c_tweak(delayed_basepoint.key, Slice32::from(tweak.to_be_bytes()));
+    }
+    Ok(psbt)
+}
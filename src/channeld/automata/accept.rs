@@ -11,14 +11,15 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use lnp::channel::bolt::Lifecycle;
+use bitcoin::{OutPoint, Txid};
+use lnp::channel::bolt::{CommonParams, Lifecycle};
 use lnp::p2p::bolt::{ActiveChannelId, ChannelId, FundingSigned, Messages as LnMsg};
 use lnp::Extension;
 use lnp_rpc::ServiceId;
 use microservices::cli::LogStyle;
 use microservices::esb::Handler;
 
-use super::Error;
+use super::{penalty, Error};
 use crate::automata::{Event, StateMachine};
 use crate::bus::{AcceptChannelFrom, BusMsg, CtlMsg};
 use crate::channeld::runtime::Runtime;
@@ -91,8 +92,22 @@ impl ChannelAccept {
         endpoints: &mut Endpoints,
         request: AcceptChannelFrom,
     ) -> Result<ChannelAccept, Error> {
+        // Remote parameters are validated against our channel policy, while the fee rate and
+        // channel type are defined by the remote peer as a channel funder
+        let mut policy = request.policy;
+        let mut minimum_depth = request.common_params.minimum_depth;
+        if runtime.is_zeroconf_peer(request.remote_id) {
+            debug!("Remote peer {} is trusted with zero-confirmation channel", request.remote_id);
+            policy.minimum_depth = 0;
+            minimum_depth = 0;
+        }
+        let channel = &mut runtime.state.channel;
+        channel.set_policy(policy);
+        channel.set_local_params(request.local_params);
+        channel.set_common_params(CommonParams::with(&request.channel_req, minimum_depth));
+        channel.set_funding_amount(request.channel_req.funding_satoshis);
         let open_channel = LnMsg::OpenChannel(request.channel_req.clone());
-        runtime.state.channel.update_from_peer(&open_channel)?;
+        channel.update_from_peer(&open_channel)?;
 
         let _ = runtime.send_ctl(
             endpoints,
@@ -125,7 +140,8 @@ impl ChannelAccept {
 
 fn finish_accepted(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelAccept, Error> {
     let accept_event = match event.message {
-        BusMsg::Ctl(CtlMsg::Keyset(_, keys)) => {
+        BusMsg::Ctl(CtlMsg::Keyset(_, mut keys, seed)) => {
+            runtime.state.set_commitment_seed(seed, &mut keys);
             runtime.state.channel.constructor_mut().set_local_keys(keys);
 
             let accept_channel = runtime.state.channel.compose_accept_channel()?;
@@ -154,19 +170,20 @@ fn finish_signed(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelA
             let channel_id = ChannelId::with(funding.funding_txid, funding.funding_output_index);
             debug!("Changing channel id from {} to {}", runtime.identity(), channel_id);
             runtime
-                .set_identity(event.endpoints, channel_id)
+                .set_identity(event.endpoints, old_id, channel_id)
                 .expect("unable to change ZMQ channel identity");
             runtime.state.channel.update_from_peer(&LnMsg::FundingCreated(funding.clone()))?;
-
-            runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::ChannelUpdate {
-                old_id,
-                new_id: channel_id,
-            })?;
+            runtime.state.push_commitment_sig(funding.signature);
+            runtime.state.funding_outpoint =
+                Some(OutPoint::new(funding.funding_txid, funding.funding_output_index as u32));
 
             runtime.send_p2p(
                 event.endpoints,
                 LnMsg::FundingSigned(FundingSigned { channel_id, signature: funding.signature }),
             )?;
+            if runtime.state.minimum_depth() == 0 {
+                lock_unconfirmed(runtime, event.endpoints, funding.funding_txid)?;
+            }
             Ok(ChannelAccept::Funded)
         }
         wrong_msg => {
@@ -182,14 +199,9 @@ fn finish_funded(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelA
         BusMsg::Bolt(LnMsg::FundingLocked(funding)) => {
             // Save next per commitment point
             runtime.state.channel.update_from_peer(&LnMsg::FundingLocked(funding.clone()))?;
-            trace!("Notifying runtime about channel creation");
-            let _ = runtime.send_ctl(
-                event.endpoints,
-                ServiceId::Router,
-                CtlMsg::ChannelCreated(
-                    runtime.state.channel.channel_info(runtime.state.remote_id()),
-                ),
-            );
+            trace!("Notifying router and lnpd about channel creation");
+            let channel_info = runtime.state.channel.channel_info(runtime.state.remote_id());
+            runtime.notify_channel(event.endpoints, CtlMsg::ChannelCreated(channel_info));
 
             // TODO: find the alternative to this. The hello is calling to force running
             // finish_locked method
@@ -207,9 +219,15 @@ fn finish_funded(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelA
 fn finish_locked(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<(), Error> {
     let locked_event = match event.message {
         BusMsg::Ctl(CtlMsg::Hello) => {
-            debug!("Funding transaction mined, notifying remote peer");
-            let funding_locked = runtime.state.channel.compose_funding_locked();
-            runtime.send_p2p(event.endpoints, LnMsg::FundingLocked(funding_locked))?;
+            // Zero-confirmation channel was locked before receiving `funding_locked`
+            if runtime.state.minimum_depth() > 0 {
+                debug!("Funding transaction mined, notifying remote peer");
+                let mut funding_locked = runtime.state.channel.compose_funding_locked();
+                funding_locked.next_per_commitment_point = runtime.state.per_commitment_point(1);
+                runtime.send_p2p(event.endpoints, LnMsg::FundingLocked(funding_locked))?;
+            }
+            penalty::watch_funding(runtime, event.endpoints)?;
+            runtime.track_funding_position(event.endpoints)?;
 
             Ok(())
         }
@@ -219,3 +237,23 @@ fn finish_locked(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<(), Erro
     };
     locked_event
 }
+
+/// Notifies remote peer that the zero-confirmation channel is ready for operation before its
+/// funding transaction is mined, and asks watchd to report double-spending of the funding
+/// transaction.
+///
+/// Short channel id aliases (`option_scid_alias`) are not supported, since `funding_locked`
+/// message has no TLV extensions in lnp2p. Thus, until the funding transaction is mined the
+/// channel has no short channel id and can't be used by other nodes for routing payments.
+pub(super) fn lock_unconfirmed(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    funding_txid: Txid,
+) -> Result<(), Error> {
+    debug!("Zero-confirmation channel is ready, notifying remote peer");
+    let mut funding_locked = runtime.state.channel.compose_funding_locked();
+    funding_locked.next_per_commitment_point = runtime.state.per_commitment_point(1);
+    runtime.send_p2p(endpoints, LnMsg::FundingLocked(funding_locked))?;
+    runtime.send_ctl(endpoints, ServiceId::Watch, CtlMsg::TrackConflicts(funding_txid))?;
+    Ok(())
+}
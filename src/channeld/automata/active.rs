@@ -0,0 +1,473 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use bitcoin::hashes::sha256;
+use bitcoin_scripts::hlc::{HashLock, HashPreimage};
+use internet2::presentation::sphinx::Hop;
+use lnp::channel::bolt::Lifecycle;
+use lnp::p2p::bolt::{
+    ActiveChannelId, CommitmentSigned, HopRealm, Messages as LnMsg, PaymentOnion, RevokeAndAck,
+    UpdateAddHtlc, UpdateFee, UpdateFulfillHtlc,
+};
+use lnp_rpc::FailureCode;
+use microservices::cli::LogStyle;
+use microservices::esb::ClientId;
+use wallet::psbt::Psbt;
+
+use super::Error;
+use crate::automata::{Event, StateMachine};
+use crate::bus::{BusMsg, CtlMsg, InvoiceInfo, IssuedInvoice};
+use crate::channeld::onion::{self, HtlcFailure};
+use crate::channeld::runtime::Runtime;
+use crate::channeld::state::HtlcUpdate;
+use crate::rpc::{Failure, ServiceId};
+use crate::{Endpoints, Responder};
+
+/// Active channel workflow: exchange of HTLC updates and commitment transactions with the remote
+/// peer
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+pub enum ChannelActive {
+    /// channel is operational and there is no remote commitment awaiting to be signed or revoked
+    #[display("READY")]
+    Ready,
+
+    /// signing new remote commitment transaction
+    #[display("SIGNING")]
+    Signing,
+
+    /// new remote commitment is sent, awaiting for the remote peer to revoke the previous one
+    #[display("COMMITTED")]
+    Committed,
+}
+
+impl StateMachine<BusMsg, Runtime> for ChannelActive {
+    type Error = Error;
+
+    fn next(
+        self,
+        event: Event<BusMsg>,
+        runtime: &mut Runtime,
+    ) -> Result<Option<Self>, Self::Error> {
+        let channel_id = runtime.state.channel.active_channel_id();
+        debug!("ChannelActive {:#} received {} event", channel_id, event.message);
+        let Event { endpoints, service: _, source, message } = event;
+        let state = match message {
+            BusMsg::Ctl(CtlMsg::Payment { route, hash_lock, enquirer }) => {
+                offer_htlc(runtime, endpoints, route, hash_lock, enquirer)?;
+                self
+            }
+            BusMsg::Ctl(CtlMsg::FeeEstimate { feerate_per_kw }) => {
+                update_fee(runtime, endpoints, feerate_per_kw)?;
+                self
+            }
+            BusMsg::Ctl(CtlMsg::BlockHeight(height)) => {
+                fail_expiring(runtime, endpoints, height)?;
+                self
+            }
+            BusMsg::Bolt(LnMsg::UpdateFulfillHtlc(update)) => {
+                runtime.state.commitments.resolve_offered(HtlcUpdate::Fulfill(update))?;
+                self
+            }
+            BusMsg::Bolt(LnMsg::UpdateFailHtlc(update)) => {
+                runtime.state.commitments.resolve_offered(HtlcUpdate::Fail(update))?;
+                self
+            }
+            BusMsg::Bolt(LnMsg::UpdateFailMalformedHtlc(update)) => {
+                runtime.state.commitments.resolve_offered(HtlcUpdate::FailMalformed(update))?;
+                self
+            }
+            BusMsg::Bolt(LnMsg::CommitmentSigned(commitment_signed)) => {
+                complete_commitment(runtime, endpoints, commitment_signed)?;
+                self
+            }
+            BusMsg::Bolt(LnMsg::RevokeAndAck(revoke_and_ack))
+                if self == ChannelActive::Committed =>
+            {
+                complete_revocation(runtime, endpoints, revoke_and_ack)?;
+                ChannelActive::Ready
+            }
+            BusMsg::Ctl(CtlMsg::Invoice(invoice_info)) => {
+                settle_received(runtime, endpoints, invoice_info)?;
+                self
+            }
+            BusMsg::Ctl(CtlMsg::Signed(psbt)) if self == ChannelActive::Signing => {
+                match complete_signing(runtime, endpoints, psbt)? {
+                    true => ChannelActive::Committed,
+                    false => ChannelActive::Signing,
+                }
+            }
+            wrong_msg => {
+                return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Active, source))
+            }
+        };
+
+        // We sign a new remote commitment as soon as there are updates which are not signed yet
+        // and the remote peer has revoked its previous commitment
+        let state = if state == ChannelActive::Ready && runtime.state.commitments.needs_signing() {
+            sign_remote(runtime, endpoints)?
+        } else {
+            state
+        };
+        if state != self {
+            info!("ChannelActive {:#} switched to {} state", channel_id, state);
+        }
+        Ok(Some(state))
+    }
+}
+
+impl ChannelActive {
+    /// Computes channel lifecycle stage for the current active channel workflow stage
+    pub fn lifecycle(&self) -> Lifecycle { Lifecycle::Active }
+
+    /// Construct information message for error and client reporting
+    pub fn info_message(&self, channel_id: ActiveChannelId) -> String {
+        match self {
+            ChannelActive::Ready => {
+                format!("Channel {:#} is {}", channel_id.actor(), "active".ended())
+            }
+            ChannelActive::Signing => format!(
+                "{} new remote commitment locally for channel {:#}",
+                "Signing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelActive::Committed => format!(
+                "{} remote peer to revoke its previous commitment for channel {:#}",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+        }
+    }
+}
+
+// State transitions:
+
+fn offer_htlc(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    route: Vec<Hop<PaymentOnion>>,
+    hash_lock: HashLock,
+    enquirer: ClientId,
+) -> Result<(), Error> {
+    let payment = &route.first().expect("routing daemon does not send empty routes").payload;
+    let (amount_msat, cltv_expiry) = (payment.amt_to_forward, payment.outgoing_cltv_value);
+    let update_add_htlc = match runtime.state.channel.compose_add_update_htlc(
+        amount_msat,
+        hash_lock,
+        cltv_expiry,
+        route,
+    )? {
+        LnMsg::UpdateAddHtlc(update_add_htlc) => update_add_htlc,
+        _ => unreachable!("HTLC composition always produces `update_add_htlc` message"),
+    };
+    let update_add_htlc = runtime.state.offer_htlc(update_add_htlc)?;
+    let htlc_id = update_add_htlc.htlc_id;
+
+    runtime.send_p2p(endpoints, LnMsg::UpdateAddHtlc(update_add_htlc))?;
+    runtime.payments.insert(htlc_id, enquirer);
+    let _ = runtime.report_progress(
+        endpoints,
+        format!(
+            "HTLC {} for {} msat with payment hash {} is offered",
+            htlc_id, amount_msat, hash_lock
+        ),
+    );
+    Ok(())
+}
+
+/// Proposes remote peer to update commitment fee rate; must be called only when the local node is
+/// the channel funder
+fn update_fee(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    feerate_per_kw: u32,
+) -> Result<(), Error> {
+    let update_fee =
+        UpdateFee { channel_id: runtime.state.channel.try_channel_id()?, feerate_per_kw };
+    runtime.state.propose_fee(update_fee.clone())?;
+    info!("Updating commitment fee rate to {} sat/kw", feerate_per_kw);
+    runtime.send_p2p(endpoints, LnMsg::UpdateFee(update_fee))?;
+    Ok(())
+}
+
+pub(super) fn sign_remote(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+) -> Result<ChannelActive, Error> {
+    let spec = runtime.state.commitments.sign_remote()?;
+    let psbts = runtime.state.remote_commitment_psbts(&spec)?;
+    debug!(
+        "Signing remote commitment transaction #{} {} with {} HTLC transactions",
+        spec.number,
+        psbts[0].to_txid(),
+        psbts.len() - 1
+    );
+    trace!("Remote commitment: {:#?}", spec);
+    for psbt in &psbts {
+        runtime.send_ctl(endpoints, ServiceId::Signer, CtlMsg::Sign(psbt.clone()))?;
+    }
+    runtime.signing = psbts;
+    Ok(ChannelActive::Signing)
+}
+
+/// Collects remote commitment and HTLC transactions signed by signd and, once all of them are
+/// signed, sends `commitment_signed` to the remote peer. Returns whether the message was sent.
+fn complete_signing(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    psbt: Psbt,
+) -> Result<bool, Error> {
+    let txid = psbt.to_txid();
+    match runtime.signing.iter_mut().find(|pending| pending.to_txid() == txid) {
+        Some(pending) => *pending = psbt,
+        None => warn!("Sign daemon has signed unknown transaction {}", txid),
+    }
+    if runtime.signing.iter().any(|psbt| psbt.inputs[0].partial_sigs.is_empty()) {
+        return Ok(false);
+    }
+
+    let core = runtime.state.channel.constructor();
+    let funding_pubkey = bitcoin::PublicKey::new(core.local_keys().funding_pubkey.key);
+    let htlc_basepoint = bitcoin::PublicKey::new(core.local_keys().htlc_basepoint.key);
+    let (commitment_psbt, htlc_psbts) =
+        runtime.signing.split_first().expect("remote commitment transaction is always signed");
+    let signature = commitment_psbt.inputs[0]
+        .partial_sigs
+        .get(&funding_pubkey)
+        .ok_or(Error::FundingPsbtUnsigned(funding_pubkey.inner))?;
+    let htlc_signatures = htlc_psbts
+        .iter()
+        .map(|psbt| {
+            psbt.inputs[0]
+                .partial_sigs
+                .get(&htlc_basepoint)
+                .map(|sig| sig.sig)
+                .ok_or(Error::HtlcPsbtUnsigned(htlc_basepoint.inner))
+        })
+        .collect::<Result<_, _>>()?;
+
+    let commitment_signed = CommitmentSigned {
+        channel_id: runtime.state.channel.try_channel_id()?,
+        signature: signature.sig,
+        htlc_signatures,
+    };
+    runtime.signing.clear();
+    runtime.state.commitments.sent_commitment = Some(commitment_signed.clone());
+    runtime.state.commitments.revoked_last = false;
+    runtime.send_p2p(endpoints, LnMsg::CommitmentSigned(commitment_signed))?;
+    Ok(true)
+}
+
+/// Applies new local commitment, which signatures are already verified, and revokes the previous
+/// one
+fn complete_commitment(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    commitment_signed: CommitmentSigned,
+) -> Result<(), Error> {
+    let revoke_and_ack = runtime.state.receive_commitment(&commitment_signed)?;
+    debug!("Revoking local commitment transaction #{}", runtime.state.commitments.local.number - 1);
+    runtime.send_p2p(endpoints, LnMsg::RevokeAndAck(revoke_and_ack))?;
+    Ok(())
+}
+
+fn complete_revocation(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    revoke_and_ack: RevokeAndAck,
+) -> Result<(), Error> {
+    let settled = runtime.state.receive_revocation(&revoke_and_ack)?;
+    debug!(
+        "Remote commitment transaction #{} is revoked",
+        runtime.state.commitments.remote.number - 1
+    );
+
+    for update in settled {
+        let htlc_id = match update {
+            HtlcUpdate::Add(update_add_htlc) => {
+                accept_received(runtime, endpoints, update_add_htlc)?;
+                continue;
+            }
+            // Fee update does not affect payments
+            HtlcUpdate::Fee(_) => continue,
+            _ => update.htlc_id().expect("HTLC resolution update"),
+        };
+        // Payment clients are not persisted, so they may be unknown after channeld restart
+        runtime.enquirer = runtime.payments.remove(&htlc_id);
+        match update {
+            HtlcUpdate::Fulfill(fulfill) => {
+                let _ = runtime.report_success(
+                    endpoints,
+                    Some(format!(
+                        "HTLC {} is fulfilled by the remote peer with payment preimage {}",
+                        htlc_id, fulfill.payment_preimage
+                    )),
+                );
+            }
+            HtlcUpdate::Fail(_) | HtlcUpdate::FailMalformed(_) => {
+                let info = format!("HTLC {} is failed by the remote peer", htlc_id);
+                warn!("{}", info);
+                runtime.report_failure(endpoints, Failure { code: FailureCode::Channel, info });
+            }
+            HtlcUpdate::Add(_) | HtlcUpdate::Fee(_) => {
+                unreachable!("incoming HTLCs and fee updates are processed above")
+            }
+        }
+        runtime.enquirer = None;
+    }
+
+    // We swallow error since we do not want to fail the channel if we just can't update the
+    // router
+    let channel_id = runtime.state.channel.try_channel_id()?;
+    let local = &runtime.state.commitments.local;
+    let _ = runtime.send_ctl(endpoints, ServiceId::Router, CtlMsg::ChannelBalanceUpdate {
+        channel_id,
+        local_amount_msat: local.to_local_msat,
+        remote_amount_msat: local.to_remote_msat,
+    });
+    Ok(())
+}
+
+/// Checks onion of an irrevocably committed incoming HTLC and requests lnpd for the invoice it
+/// pays, failing HTLCs which can't be settled by the local node
+fn accept_received(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    update_add_htlc: UpdateAddHtlc,
+) -> Result<(), Error> {
+    let htlc_id = update_add_htlc.htlc_id;
+    let (shared_secret, payload) =
+        onion::unwrap_final(&update_add_htlc, runtime.local_node.private_key());
+    if let Err(failure) = payload {
+        return fail_received(runtime, endpoints, htlc_id, failure, shared_secret);
+    }
+    info!(
+        "Received HTLC {} for {} msat with payment hash {}",
+        htlc_id, update_add_htlc.amount_msat, update_add_htlc.payment_hash
+    );
+    runtime.send_ctl(
+        endpoints,
+        ServiceId::LnpBroker,
+        CtlMsg::GetInvoice(update_add_htlc.payment_hash),
+    )?;
+    Ok(())
+}
+
+/// Fulfills or fails incoming HTLCs paying the invoice provided by lnpd
+fn settle_received(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    invoice_info: InvoiceInfo,
+) -> Result<(), Error> {
+    let InvoiceInfo { payment_hash, height, invoice } = invoice_info;
+    for htlc in runtime.state.commitments.unresolved_received(payment_hash) {
+        let (shared_secret, payload) = onion::unwrap_final(&htlc, runtime.local_node.private_key());
+        let result = payload.and_then(|payload| check_payment(&htlc, payload, invoice, height));
+        match result {
+            Ok(payment_preimage) => {
+                info!(
+                    "HTLC {} with payment hash {} is {}",
+                    htlc.htlc_id,
+                    payment_hash,
+                    "paid".ended()
+                );
+                let fulfill = UpdateFulfillHtlc {
+                    channel_id: runtime.state.channel.try_channel_id()?,
+                    htlc_id: htlc.htlc_id,
+                    payment_preimage,
+                };
+                runtime.state.commitments.resolve_received(HtlcUpdate::Fulfill(fulfill.clone()))?;
+                // The preimage is required to claim the HTLC on-chain if the channel gets closed
+                // before the HTLC is removed from both commitments
+                runtime.state.preimages.insert(payment_hash, payment_preimage);
+                runtime.send_p2p(endpoints, LnMsg::UpdateFulfillHtlc(fulfill))?;
+            }
+            Err(failure) => {
+                fail_received(runtime, endpoints, htlc.htlc_id, failure, shared_secret)?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks final hop payload of the incoming HTLC against the invoice issued by the local node
+/// according to BOLT-4 requirements, returning the payment preimage
+fn check_payment(
+    htlc: &UpdateAddHtlc,
+    payload: PaymentOnion,
+    invoice: Option<IssuedInvoice>,
+    height: u32,
+) -> Result<HashPreimage, HtlcFailure> {
+    if payload.amt_to_forward > htlc.amount_msat {
+        return Err(HtlcFailure::FinalIncorrectHtlcAmount(htlc.amount_msat));
+    }
+    if payload.outgoing_cltv_value > htlc.cltv_expiry {
+        return Err(HtlcFailure::FinalIncorrectCltvExpiry(htlc.cltv_expiry));
+    }
+
+    let unknown =
+        HtlcFailure::IncorrectOrUnknownPaymentDetails { htlc_msat: htlc.amount_msat, height };
+    let invoice = invoice.ok_or(unknown)?;
+    let payment_data = match payload.realm {
+        HopRealm::TlvReceiver(Some(payment_data)) => payment_data,
+        _ => return Err(unknown),
+    };
+    if payment_data.payment_secret != invoice.payment_secret {
+        return Err(unknown);
+    }
+    // Multi-part payments are not supported yet, so the HTLC must pay the whole amount
+    if payment_data.total_msat > htlc.amount_msat {
+        return Err(unknown);
+    }
+    // We accept overpayments up to twice the requested amount, as recommended by BOLT-4
+    if let Some(amount_msat) = invoice.amount_msat {
+        if htlc.amount_msat < amount_msat || htlc.amount_msat > amount_msat.saturating_mul(2) {
+            return Err(unknown);
+        }
+    }
+    if htlc.cltv_expiry < height + invoice.min_final_cltv_expiry {
+        return Err(unknown);
+    }
+    Ok(invoice.preimage)
+}
+
+/// Fails back incoming HTLCs which are not settled yet and are close to their expiry, so the
+/// remote peer does not have to close the channel to claim them on-chain
+fn fail_expiring(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    height: u32,
+) -> Result<(), Error> {
+    for htlc in runtime.state.commitments.expiring_received(height) {
+        let (shared_secret, _) = onion::unwrap_final(&htlc, runtime.local_node.private_key());
+        let failure =
+            HtlcFailure::IncorrectOrUnknownPaymentDetails { htlc_msat: htlc.amount_msat, height };
+        fail_received(runtime, endpoints, htlc.htlc_id, failure, shared_secret)?;
+    }
+    Ok(())
+}
+
+fn fail_received(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    htlc_id: u64,
+    failure: HtlcFailure,
+    shared_secret: sha256::Hash,
+) -> Result<(), Error> {
+    warn!("Failing incoming HTLC {} with code {:#06x}: {}", htlc_id, failure.code(), failure);
+    let channel_id = runtime.state.channel.try_channel_id()?;
+    let update = failure.to_update(channel_id, htlc_id, shared_secret);
+    runtime.state.commitments.resolve_received(update.clone())?;
+    runtime.send_p2p(endpoints, update.into())?;
+    Ok(())
+}
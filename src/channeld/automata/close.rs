@@ -0,0 +1,582 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use bitcoin::secp256k1::ecdsa::Signature;
+use bitcoin::secp256k1::{Message, SECP256K1};
+use bitcoin::util::sighash::SighashCache;
+use bitcoin::{EcdsaSig, EcdsaSighashType, PackedLockTime, Sequence, Transaction, TxIn, TxOut};
+use bitcoin_scripts::PubkeyScript;
+use lnp::channel::bolt::Lifecycle;
+use lnp::p2p::bolt::{ActiveChannelId, ClosingSigned, Messages as LnMsg, Shutdown};
+use microservices::cli::LogStyle;
+use wallet::lex_order::LexOrder;
+use wallet::psbt::{Psbt, PsbtVersion};
+
+use super::Error;
+use crate::automata::{Event, StateMachine};
+use crate::bus::{BusMsg, CtlMsg};
+use crate::channeld::commitment::{base_fee, has_anchors};
+use crate::channeld::runtime::Runtime;
+use crate::rpc::ServiceId;
+use crate::{Endpoints, Responder};
+
+/// Weight of the witness spending 2-of-2 multisig funding output: two signatures, the funding
+/// witness script and an empty element consumed by `OP_CHECKMULTISIG` bug.
+pub(super) const FUNDING_WITNESS_WEIGHT: u64 = 222;
+
+/// Cooperative channel closing workflow
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+pub enum ChannelClose {
+    /// awaiting lnpd to provide a script from the funding wallet for the closing output
+    #[display("PREPARING")]
+    Preparing,
+
+    /// sent `shutdown` message to the remote peer, awaiting its `shutdown` reply
+    #[display("SHUTDOWN")]
+    Shutdown,
+
+    /// signing closing transaction with the proposed fee on our side
+    #[display("SIGNING")]
+    Signing,
+
+    /// negotiating closing transaction fee with the remote peer
+    #[display("NEGOTIATING")]
+    Negotiating,
+
+    /// closing transaction is published, awaiting it to be mined
+    #[display("PUBLISHED")]
+    Published,
+}
+
+impl StateMachine<BusMsg, Runtime> for ChannelClose {
+    type Error = Error;
+
+    fn next(
+        self,
+        event: Event<BusMsg>,
+        runtime: &mut Runtime,
+    ) -> Result<Option<Self>, Self::Error> {
+        let channel_id = runtime.state.channel.active_channel_id();
+        debug!("ChannelClose {:#} received {} event", channel_id, event.message);
+        // Remote peer retransmits its `shutdown` upon channel reestablishment
+        if let BusMsg::Bolt(LnMsg::Shutdown(ref shutdown)) = event.message {
+            if self != ChannelClose::Preparing
+                && runtime.state.closing.remote_script.as_ref() == Some(&shutdown.scriptpubkey)
+            {
+                debug!("Ignoring repeated shutdown from the remote peer");
+                return Ok(Some(self));
+            }
+        }
+        let state = match self {
+            ChannelClose::Preparing => complete_preparing(event, runtime),
+            ChannelClose::Shutdown => complete_shutdown(event, runtime),
+            ChannelClose::Signing => complete_signing(event, runtime),
+            ChannelClose::Negotiating => complete_negotiating(event, runtime),
+            ChannelClose::Published => {
+                if let Some(next) = complete_published(event, runtime)? {
+                    Ok(next)
+                } else {
+                    info!("ChannelClose {:#} has completed its work", channel_id);
+                    return Ok(None);
+                }
+            }
+        }?;
+        info!("ChannelClose {:#} switched to {} state", channel_id, state);
+        Ok(Some(state))
+    }
+}
+
+impl ChannelClose {
+    /// Computes channel lifecycle stage for the current channel closing workflow stage.
+    ///
+    /// The number of negotiation rounds is not a part of the workflow state; it is kept in the
+    /// persistent closing state of the channel instead.
+    pub fn lifecycle(&self) -> Lifecycle {
+        match self {
+            ChannelClose::Preparing | ChannelClose::Shutdown => Lifecycle::Shutdown,
+            ChannelClose::Signing | ChannelClose::Negotiating | ChannelClose::Published => {
+                Lifecycle::Closing { round: 0 }
+            }
+        }
+    }
+}
+
+// State transitions:
+
+impl ChannelClose {
+    /// Constructs channel closing state machine for the closing requested by a local user
+    pub fn with_local(
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+    ) -> Result<ChannelClose, Error> {
+        if runtime.state.commitments.has_pending_htlcs() {
+            return Err(Error::PendingHtlcs);
+        }
+
+        runtime.state.closing = none!();
+        runtime.state.set_stage(Lifecycle::Shutdown);
+        if let Some(script) =
+            runtime.state.channel.constructor().local_keys().shutdown_scriptpubkey.clone()
+        {
+            send_shutdown(runtime, endpoints, script)?;
+            return Ok(ChannelClose::Shutdown);
+        }
+        runtime.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::GetPayoutScript)?;
+        Ok(ChannelClose::Preparing)
+    }
+
+    /// Constructs channel closing state machine for the closing requested by the remote peer
+    pub fn with_remote(
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+        shutdown: Shutdown,
+    ) -> Result<ChannelClose, Error> {
+        runtime.state.closing = none!();
+        runtime.state.set_stage(Lifecycle::Shutdown);
+        accept_shutdown(runtime, shutdown)?;
+        if let Some(script) =
+            runtime.state.channel.constructor().local_keys().shutdown_scriptpubkey.clone()
+        {
+            send_shutdown(runtime, endpoints, script)?;
+            return start_negotiation(runtime, endpoints);
+        }
+        runtime.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::GetPayoutScript)?;
+        Ok(ChannelClose::Preparing)
+    }
+
+    /// Retransmits messages which the remote peer may have not received before the connection
+    /// was lost, as required by BOLT-2 upon channel reestablishment: our `shutdown` and, if we
+    /// are awaiting remote reply on our fee proposal, our last `closing_signed`.
+    pub fn retransmit(
+        &self,
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+    ) -> Result<(), Error> {
+        let closing = &runtime.state.closing;
+        let (local_script, local_fee, local_sig) =
+            (closing.local_script.clone(), closing.local_fee, closing.local_sig);
+        let script = match local_script {
+            Some(script) if *self != ChannelClose::Preparing => script,
+            _ => return Ok(()),
+        };
+        debug!("Retransmitting shutdown to the remote peer");
+        send_shutdown(runtime, endpoints, script)?;
+
+        if *self == ChannelClose::Negotiating {
+            // After reconnection the remote peer is no longer bound by its previous proposal
+            runtime.state.closing.remote_fee = None;
+            runtime.state.closing.remote_sig = None;
+        }
+        if let (ChannelClose::Negotiating, Some(fee_satoshis), Some(signature)) =
+            (self, local_fee, local_sig)
+        {
+            debug!("Retransmitting closing transaction fee proposal of {} sat", fee_satoshis);
+            let channel_id = runtime.state.channel.try_channel_id()?;
+            runtime.send_p2p(
+                endpoints,
+                LnMsg::ClosingSigned(ClosingSigned { channel_id, fee_satoshis, signature }),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Verifies closing transaction fee proposed by the remote peer against BOLT-2 limits: the
+    /// fee must not exceed the base fee of the final commitment transaction, and each remote
+    /// counter-proposal must be strictly between our last proposal and the previous remote one.
+    pub fn verify_fee(&self, runtime: &Runtime, fee: u64) -> Result<(), Error> {
+        if *self != ChannelClose::Negotiating {
+            return Ok(());
+        }
+        let channel_type = runtime.state.channel.constructor().common_params().channel_type;
+        let base_fee = base_fee(runtime.state.channel.feerate_per_kw(), has_anchors(channel_type));
+        let closing = &runtime.state.closing;
+        check_fee(fee, base_fee, closing.local_fee, closing.remote_fee)
+    }
+
+    /// Construct information message for error and client reporting
+    pub fn info_message(&self, channel_id: ActiveChannelId) -> String {
+        match self {
+            ChannelClose::Preparing => {
+                format!("{} to close channel {:#}", "Preparing".announce(), channel_id.announcer())
+            }
+            ChannelClose::Shutdown => format!(
+                "{} for the remote peer to shutdown channel {:#}",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+            ChannelClose::Signing => format!(
+                "{} closing transaction locally for channel {:#}",
+                "Signing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelClose::Negotiating => format!(
+                "{} closing transaction fee for channel {:#}",
+                "Negotiating".announce(),
+                channel_id.announcer()
+            ),
+            ChannelClose::Published => format!(
+                "{} closing transaction for channel {:#} to be mined",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+        }
+    }
+}
+
+fn complete_preparing(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelClose, Error> {
+    match event.message {
+        BusMsg::Ctl(CtlMsg::PayoutScript(script)) => {
+            send_shutdown(runtime, event.endpoints, script)?;
+            if runtime.state.closing.remote_script.is_some() {
+                start_negotiation(runtime, event.endpoints)
+            } else {
+                Ok(ChannelClose::Shutdown)
+            }
+        }
+        BusMsg::Bolt(LnMsg::Shutdown(shutdown)) => {
+            accept_shutdown(runtime, shutdown)?;
+            Ok(ChannelClose::Preparing)
+        }
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Shutdown, event.source)),
+    }
+}
+
+fn complete_shutdown(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelClose, Error> {
+    let shutdown = match event.message {
+        BusMsg::Bolt(LnMsg::Shutdown(shutdown)) => shutdown,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Shutdown, event.source))
+        }
+    };
+
+    accept_shutdown(runtime, shutdown)?;
+    start_negotiation(runtime, event.endpoints)
+}
+
+fn complete_signing(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelClose, Error> {
+    let round = runtime.state.closing.round;
+    let mut closing_psbt = match event.message {
+        BusMsg::Ctl(CtlMsg::Signed(psbt)) => psbt,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(
+                wrong_msg,
+                Lifecycle::Closing { round },
+                event.source,
+            ))
+        }
+    };
+
+    let funding_pubkey = runtime.state.channel.funding_pubkey();
+    let signature = closing_psbt
+        .inputs
+        .first()
+        .expect("closing transaction always has a single input")
+        .partial_sigs
+        .get(&bitcoin::PublicKey::new(funding_pubkey))
+        .ok_or(Error::FundingPsbtUnsigned(funding_pubkey))?
+        .sig;
+
+    let closing = &mut runtime.state.closing;
+    let fee_satoshis = closing.local_fee.expect("closing fee is always known at signing stage");
+    closing.local_sig = Some(signature);
+    closing.round += 1;
+    let round = closing.round;
+    let agreed = closing.remote_fee == Some(fee_satoshis);
+    let remote_sig = closing.remote_sig;
+
+    let channel_id = runtime.state.channel.try_channel_id()?;
+    runtime.send_p2p(
+        event.endpoints,
+        LnMsg::ClosingSigned(ClosingSigned { channel_id, fee_satoshis, signature }),
+    )?;
+    runtime.state.set_stage(Lifecycle::Closing { round });
+
+    if agreed {
+        let remote_sig = remote_sig.expect("remote signature is always known for its fee");
+        add_remote_sig(runtime, &mut closing_psbt, remote_sig);
+        publish(runtime, event.endpoints, closing_psbt)?;
+        return Ok(ChannelClose::Published);
+    }
+
+    Ok(ChannelClose::Negotiating)
+}
+
+fn complete_negotiating(
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<ChannelClose, Error> {
+    let round = runtime.state.closing.round;
+    let closing_signed = match event.message {
+        BusMsg::Bolt(LnMsg::ClosingSigned(closing_signed)) => closing_signed,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(
+                wrong_msg,
+                Lifecycle::Closing { round },
+                event.source,
+            ))
+        }
+    };
+
+    let remote_fee = closing_signed.fee_satoshis;
+    let mut closing_psbt = closing_psbt(runtime, remote_fee)?;
+    verify_remote_sig(runtime, &closing_psbt, &closing_signed.signature)?;
+    debug!("Remote peer proposed closing transaction fee of {} sat", remote_fee);
+
+    let closing = &mut runtime.state.closing;
+    closing.remote_fee = Some(remote_fee);
+    closing.remote_sig = Some(closing_signed.signature);
+
+    if closing.local_fee == Some(remote_fee) {
+        let local_sig = closing.local_sig.expect("local signature is always known for our fee");
+        let funding_pubkey = runtime.state.channel.funding_pubkey();
+        closing_psbt.inputs[0].partial_sigs.insert(
+            bitcoin::PublicKey::new(funding_pubkey),
+            EcdsaSig { sig: local_sig, hash_ty: EcdsaSighashType::All },
+        );
+        add_remote_sig(runtime, &mut closing_psbt, closing_signed.signature);
+        publish(runtime, event.endpoints, closing_psbt)?;
+        return Ok(ChannelClose::Published);
+    }
+
+    // We accept remote fee if it does not deviate from our estimate too much, otherwise we
+    // propose a fee strictly between our last proposal and the remote one, as required by BOLT-2
+    let estimate = closing_fee_estimate(runtime)?;
+    let proposed_fee = runtime.state.closing.local_fee.unwrap_or(estimate);
+    let fee = if (estimate / 2..=estimate * 2).contains(&remote_fee)
+        || proposed_fee.max(remote_fee) - proposed_fee.min(remote_fee) <= 1
+    {
+        remote_fee
+    } else {
+        (proposed_fee + remote_fee) / 2
+    };
+    sign_closing(runtime, event.endpoints, fee)
+}
+
+fn complete_published(
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<Option<ChannelClose>, Error> {
+    let round = runtime.state.closing.round;
+    let closing_txid = runtime.state.closing.txid;
+    match event.message {
+        BusMsg::Ctl(CtlMsg::TxFound(status)) if Some(status.txid) == closing_txid => {
+            debug!("Closing transaction {} is mined", status.txid);
+            let channel_id = runtime.state.channel.try_channel_id()?;
+            trace!("Notifying router and lnpd about channel closing");
+            runtime.notify_channel(event.endpoints, CtlMsg::ChannelClosed(channel_id));
+            runtime.state.set_stage(Lifecycle::Closed);
+            let _ = runtime
+                .report_success(event.endpoints, Some(format!("Channel {} is closed", channel_id)));
+            runtime.enquirer = None;
+            Ok(None)
+        }
+        // Remote peer may repeat its `closing_signed` after we have published the transaction
+        BusMsg::Bolt(LnMsg::ClosingSigned(_)) => Ok(Some(ChannelClose::Published)),
+        BusMsg::Ctl(CtlMsg::Error { error, .. }) => Err(Error::ClosingUnpublished(error)),
+        wrong_msg => {
+            Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Closing { round }, event.source))
+        }
+    }
+}
+
+fn send_shutdown(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    script: PubkeyScript,
+) -> Result<(), Error> {
+    let channel_id = runtime.state.channel.try_channel_id()?;
+    runtime.state.closing.local_script = Some(script.clone());
+    runtime.send_p2p(endpoints, LnMsg::Shutdown(Shutdown { channel_id, scriptpubkey: script }))?;
+    Ok(())
+}
+
+fn accept_shutdown(runtime: &mut Runtime, shutdown: Shutdown) -> Result<(), Error> {
+    let upfront_script = &runtime.state.channel.constructor().remote_keys().shutdown_scriptpubkey;
+    if matches!(upfront_script, Some(script) if *script != shutdown.scriptpubkey) {
+        return Err(Error::ShutdownScriptMismatch(shutdown.scriptpubkey));
+    }
+    debug!("Remote peer will receive its funds to {}", shutdown.scriptpubkey);
+    runtime.state.closing.remote_script = Some(shutdown.scriptpubkey);
+    Ok(())
+}
+
+/// Starts fee negotiation once both peers have exchanged `shutdown` messages. Negotiation is
+/// started by the channel funder, who pays the closing transaction fee.
+fn start_negotiation(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+) -> Result<ChannelClose, Error> {
+    if !runtime.state.channel.constructor().direction().is_outbound() {
+        debug!("Awaiting channel funder to propose closing transaction fee");
+        return Ok(ChannelClose::Negotiating);
+    }
+    let fee = closing_fee_estimate(runtime)?;
+    sign_closing(runtime, endpoints, fee)
+}
+
+fn sign_closing(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    fee: u64,
+) -> Result<ChannelClose, Error> {
+    let closing_psbt = closing_psbt(runtime, fee)?;
+    debug!("Proposing closing transaction {} with {} sat fee", closing_psbt.to_txid(), fee);
+    runtime.state.closing.local_fee = Some(fee);
+    runtime.send_ctl(endpoints, ServiceId::Signer, CtlMsg::Sign(closing_psbt))?;
+    Ok(ChannelClose::Signing)
+}
+
+fn publish(runtime: &mut Runtime, endpoints: &mut Endpoints, psbt: Psbt) -> Result<(), Error> {
+    let txid = psbt.to_txid();
+    debug!("Publishing closing transaction {}", txid);
+    trace!("Closing transaction: {:#?}", psbt);
+    runtime.state.closing.txid = Some(txid);
+    runtime.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::PublishTx(psbt))?;
+    runtime.send_ctl(endpoints, ServiceId::Watch, CtlMsg::Track { txid, depth: 0 })?;
+    Ok(())
+}
+
+pub(super) fn add_remote_sig(runtime: &Runtime, psbt: &mut Psbt, sig: Signature) {
+    let remote_funding_pubkey = runtime.state.channel.constructor().remote_keys().funding_pubkey;
+    psbt.inputs[0].partial_sigs.insert(bitcoin::PublicKey::new(remote_funding_pubkey), EcdsaSig {
+        sig,
+        hash_ty: EcdsaSighashType::All,
+    });
+}
+
+fn verify_remote_sig(runtime: &Runtime, psbt: &Psbt, sig: &Signature) -> Result<(), Error> {
+    let input = &psbt.inputs[0];
+    let prevout = input.witness_utxo.as_ref().expect("closing transaction spends funding output");
+    let witness_script =
+        input.witness_script.as_ref().expect("closing transaction spends funding output");
+    let tx = psbt.to_unsigned_tx();
+    let sighash = SighashCache::new(&tx)
+        .segwit_signature_hash(0, witness_script, prevout.value, EcdsaSighashType::All)
+        .expect("closing transaction always has a single input");
+    let message = Message::from_slice(&sighash[..]).expect("sighash is always 32 bytes long");
+    let remote_funding_pubkey = runtime.state.channel.constructor().remote_keys().funding_pubkey;
+    SECP256K1.verify_ecdsa(&message, sig, &remote_funding_pubkey).map_err(Error::InvalidRemoteSig)
+}
+
+/// Checks remote closing fee proposal against the base fee of the final commitment transaction
+/// and our and remote previous proposals. A proposal matching our last one is always accepted.
+fn check_fee(
+    fee: u64,
+    base_fee: u64,
+    local_fee: Option<u64>,
+    remote_fee: Option<u64>,
+) -> Result<(), Error> {
+    if local_fee == Some(fee) {
+        return Ok(());
+    }
+    if fee > base_fee {
+        return Err(Error::ClosingFeeAboveBase { fee, base_fee });
+    }
+    if let (Some(local_fee), Some(remote_fee)) = (local_fee, remote_fee) {
+        if fee <= local_fee.min(remote_fee) || fee >= local_fee.max(remote_fee) {
+            return Err(Error::ClosingFeeNotBetween { fee, local_fee, remote_fee });
+        }
+    }
+    Ok(())
+}
+
+/// Computes closing transaction fee from the channel fee rate. BOLT-2 does not allow the fee to
+/// exceed the base fee of the final commitment transaction.
+fn closing_fee_estimate(runtime: &Runtime) -> Result<u64, Error> {
+    let weight =
+        closing_psbt(runtime, 0)?.to_unsigned_tx().weight() as u64 + FUNDING_WITNESS_WEIGHT;
+    let feerate_per_kw = runtime.state.channel.feerate_per_kw();
+    let channel_type = runtime.state.channel.constructor().common_params().channel_type;
+    let base_fee = base_fee(feerate_per_kw, has_anchors(channel_type));
+    Ok((weight * feerate_per_kw as u64 / 1000).min(base_fee))
+}
+
+/// Constructs closing transaction paying `fee` from the channel funder output. Outputs below
+/// dust limit of any of the peers are omitted; inputs and outputs are ordered according to
+/// BIP-69.
+fn closing_psbt(runtime: &Runtime, fee: u64) -> Result<Psbt, Error> {
+    let state = &runtime.state;
+    let core = state.channel.constructor();
+    let local_script = state.closing.local_script.clone().expect("shutdown is already sent");
+    let remote_script = state.closing.remote_script.clone().expect("shutdown is already received");
+
+    let mut local_amount = state.channel.local_amount_msat() / 1000;
+    let mut remote_amount = state.channel.remote_amount_msat() / 1000;
+    let funder_amount =
+        if core.direction().is_outbound() { &mut local_amount } else { &mut remote_amount };
+    *funder_amount = funder_amount.checked_sub(fee).ok_or(Error::ClosingFee(fee))?;
+
+    let dust_limit =
+        core.local_params().dust_limit_satoshis.max(core.remote_params().dust_limit_satoshis);
+    let mut output = vec![];
+    for (value, script) in [(local_amount, local_script), (remote_amount, remote_script)] {
+        if value >= dust_limit {
+            output.push(TxOut { value, script_pubkey: script.into() });
+        }
+    }
+
+    let mut closing_tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: state.funding_outpoint.ok_or(Error::NoFundingOutpoint)?,
+            script_sig: empty!(),
+            sequence: Sequence::MAX,
+            witness: empty!(),
+        }],
+        output,
+    };
+    closing_tx.lex_order();
+
+    let mut psbt = Psbt::with(closing_tx, PsbtVersion::V0)
+        .expect("closing transaction has empty script_sig and witness");
+    state.fill_funding_input(&mut psbt.inputs[0])?;
+    Ok(psbt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remote_fee_above_base() {
+        assert!(check_fee(500, 724, None, None).is_ok());
+        assert!(check_fee(724, 724, None, None).is_ok());
+        assert!(matches!(
+            check_fee(725, 724, None, None),
+            Err(Error::ClosingFeeAboveBase { fee: 725, base_fee: 724 })
+        ));
+        assert!(matches!(
+            check_fee(800, 724, Some(600), None),
+            Err(Error::ClosingFeeAboveBase { fee: 800, base_fee: 724 })
+        ));
+    }
+
+    #[test]
+    fn remote_fee_between_proposals() {
+        assert!(check_fee(500, 724, Some(600), Some(400)).is_ok());
+        assert!(check_fee(600, 724, Some(600), Some(400)).is_ok());
+        assert!(check_fee(500, 724, Some(400), Some(600)).is_ok());
+        for fee in [300, 400, 650] {
+            assert!(matches!(
+                check_fee(fee, 724, Some(600), Some(400)),
+                Err(Error::ClosingFeeNotBetween { local_fee: 600, remote_fee: 400, .. })
+            ));
+        }
+        assert!(matches!(
+            check_fee(600, 724, Some(400), Some(600)),
+            Err(Error::ClosingFeeNotBetween { fee: 600, .. })
+        ));
+    }
+}
@@ -11,25 +11,40 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+pub mod abort;
 pub mod accept;
+pub mod active;
+pub mod close;
+pub mod penalty;
 pub mod propose;
+pub mod recover;
+mod reestablish;
 
-use bitcoin::secp256k1;
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::{secp256k1, Txid};
+use bitcoin_scripts::PubkeyScript;
 use lnp::channel;
 use lnp::channel::bolt::Lifecycle;
-use lnp::p2p::bolt::{ActiveChannelId, ChannelReestablish, Messages as LnMsg};
-use lnp_rpc::FailureCode;
+use lnp::p2p::bolt::{
+    self, ActiveChannelId, ChannelReestablish, ChannelType, Messages as LnMsg, ShortChannelId,
+};
+use lnp_rpc::{FailureCode, RpcMsg};
 use microservices::cli::LogStyle;
 use microservices::esb;
 use microservices::esb::Handler;
 use strict_encoding::StrictEncode;
 
+use self::abort::ChannelAbort;
 use self::accept::ChannelAccept;
+use self::active::ChannelActive;
+use self::close::ChannelClose;
+use self::penalty::ChannelPenalize;
 use self::propose::ChannelPropose;
+use self::recover::ChannelRecover;
 use crate::automata::{Event, StateMachine};
-use crate::bus::{BusMsg, CtlMsg};
+use crate::bus::{BusMsg, CtlMsg, TxSpending, TxStatus};
 use crate::channeld::runtime::Runtime;
+use crate::channeld::storage;
 use crate::rpc::{Failure, ServiceId};
 use crate::{Endpoints, Responder};
 
@@ -61,9 +76,156 @@ pub enum Error {
     /// sign daemon produced invalid signature. {0}
     InvalidSig(secp256k1::Error),
 
+    /// remote peer provided invalid signature for the closing transaction. {0}
+    InvalidRemoteSig(secp256k1::Error),
+
+    /// channel funding outpoint is not known
+    NoFundingOutpoint,
+
+    /// remote peer has not provided signature for the latest commitment transaction
+    NoCommitmentSig,
+
+    /// sign daemon was unable to sign sweep transaction for our delayed payment basepoint {0}
+    SweepPsbtUnsigned(PublicKey),
+
+    /// remote peer has accepted channel of type `{accepted}` while `{proposed}` was proposed
+    ChannelTypeMismatch { proposed: ChannelType, accepted: ChannelType },
+
+    /// funding transaction {txid} of the zero-confirmation channel is double-spent by transaction
+    /// {conflicting_txid}
+    FundingDoubleSpent { txid: Txid, conflicting_txid: Txid },
+
+    /// sign daemon was unable to sign HTLC transaction for our HTLC basepoint {0}
+    HtlcPsbtUnsigned(PublicKey),
+
+    /// channel has pending HTLCs which must be resolved before closing the channel
+    PendingHtlcs,
+
+    /// remote peer requested to close the channel paying to {0}, which does not match upfront
+    /// shutdown script
+    ShutdownScriptMismatch(PubkeyScript),
+
+    /// channel balance is insufficient to offer HTLC of {0} msat
+    InsufficientFunds(u64),
+
+    /// HTLC with id {0} is not known or is already resolved
+    UnknownHtlc(u64),
+
+    /// remote peer provided preimage which does not match payment hash of HTLC {0}
+    InvalidPreimage(u64),
+
+    /// remote peer signed commitment transaction which does not include any updates
+    EmptyCommitment,
+
+    /// remote peer revoked commitment transaction which was not superseded by a new one
+    UnexpectedRevocation,
+
+    /// remote peer provided per-commitment secret which does not match its commitment point
+    InvalidRevocation,
+
+    /// remote peer revealed per-commitment secret for commitment {0}, which does not allow to
+    /// derive secrets it has revealed before
+    InconsistentRevocation(u64),
+
+    /// remote peer offered HTLC with id {received} while HTLC id {expected} was expected
+    UnexpectedHtlcId { expected: u64, received: u64 },
+
+    /// HTLC {htlc_id} offered to the remote peer has expired at block {cltv_expiry} and was not
+    /// removed by the remote peer
+    HtlcTimedOut { htlc_id: u64, cltv_expiry: u32 },
+
+    /// HTLC of {amount_msat} msat is below the minimal HTLC value of {minimum_msat} msat
+    HtlcBelowMinimum { amount_msat: u64, minimum_msat: u64 },
+
+    /// HTLC exceeds the limit of {0} HTLCs which may be offered to the peer
+    TooManyHtlcs(u16),
+
+    /// HTLC brings total value of the HTLCs offered to the peer to {in_flight_msat} msat, which
+    /// exceeds the limit of {max_msat} msat
+    HtlcValueInFlight { in_flight_msat: u64, max_msat: u64 },
+
+    /// remote peer provided invalid signature for the local commitment transaction. {0}
+    InvalidCommitmentSig(secp256k1::Error),
+
+    /// remote peer provided {received} HTLC signatures for the local commitment transaction with
+    /// {expected} HTLC outputs
+    HtlcSigCount { expected: usize, received: usize },
+
+    /// remote peer provided invalid signature for HTLC transaction {0}. {1}
+    InvalidHtlcSig(Txid, secp256k1::Error),
+
+    /// remote peer expects next commitment number {received} while {expected} was expected
+    InvalidCommitmentNumber { expected: u64, received: u64 },
+
+    /// remote peer expects next revocation number {received} while {expected} was expected
+    InvalidRevocationNumber { expected: u64, received: u64 },
+
+    /// remote peer provided invalid per-commitment secret for local commitment {0} during channel
+    /// reestablishment
+    InvalidLastSecret(u64),
+
+    /// local channel state is outdated: remote peer has revoked local commitment #{remote} while
+    /// the latest known local commitment is #{local}. The channel must be closed by the remote
+    /// peer
+    DataLoss { local: u64, remote: u64 },
+
+    /// channel state is outdated, and publishing local commitment transaction will result in a
+    /// loss of funds
+    OutdatedState,
+
+    /// local channel state is lost and the channel is restored from the static backup; please
+    /// close the channel by publishing the latest commitment transaction
+    StateLost,
+
+    /// closing transaction fee of {0} sat exceeds channel funder balance
+    ClosingFee(u64),
+
+    /// unable to publish closing transaction. Details: {0}
+    ClosingUnpublished(String),
+
+    /// remote peer proposed closing transaction fee of {fee} sat, which exceeds the base fee of
+    /// {base_fee} sat of the final commitment transaction
+    ClosingFeeAboveBase { fee: u64, base_fee: u64 },
+
+    /// remote peer proposed closing transaction fee of {fee} sat, which is not strictly between
+    /// our last proposal of {local_fee} sat and its previous proposal of {remote_fee} sat
+    ClosingFeeNotBetween { fee: u64, local_fee: u64, remote_fee: u64 },
+
+    /// sign daemon was unable to sign CPFP transaction for our funding key {0}
+    AnchorPsbtUnsigned(PublicKey),
+
+    /// revoked commitment transaction {0} has no outputs which can be claimed with the
+    /// revocation key
+    NoRevocableOutput(Txid),
+
+    /// sign daemon was unable to sign justice transaction for our revocation basepoint {0}
+    JusticePsbtUnsigned(PublicKey),
+
+    /// sign daemon was unable to sign transaction sweeping funds of the restored channel for our
+    /// payment basepoint {0}
+    RecoveryPsbtUnsigned(PublicKey),
+
+    /// remote peer has sent commitment fee update while it is not a channel funder
+    FeeUpdateFromFundee,
+
+    /// remote peer proposed commitment fee rate {0} sat/kw, which is below minimal relay fee
+    FeeTooLow(u32),
+
+    /// remote peer proposed commitment fee rate {proposed} sat/kw, which is unreasonable given
+    /// the current on-chain fee estimate of {estimate} sat/kw
+    UnreasonableFee { proposed: u32, estimate: u32 },
+
+    /// channel funder balance can't afford commitment transaction fee of {0} sat while keeping
+    /// the channel reserve
+    UnaffordableFee(u64),
+
     /// failed to save channel state. Details: {0}
     #[from]
     Persistence(strict_encoding::Error),
+
+    /// channel state storage failure. Details: {0}
+    #[from]
+    Storage(storage::Error),
 }
 
 impl Error {
@@ -84,8 +246,51 @@ impl Error {
             Error::InvalidState { .. } => 4001,
             Error::FundingPsbtUnsigned(_) => 5001,
             Error::InvalidSig(_) => 5002,
+            Error::InvalidRemoteSig(_) => 5003,
+            Error::NoFundingOutpoint => 5004,
+            Error::NoCommitmentSig => 5005,
+            Error::SweepPsbtUnsigned(_) => 5006,
+            Error::ChannelTypeMismatch { .. } => 5007,
+            Error::FundingDoubleSpent { .. } => 5008,
+            Error::HtlcPsbtUnsigned(_) => 5009,
+            Error::PendingHtlcs => 5101,
+            Error::ShutdownScriptMismatch(_) => 5102,
+            Error::ClosingFee(_) => 5103,
+            Error::ClosingUnpublished(_) => 5104,
+            Error::AnchorPsbtUnsigned(_) => 5105,
+            Error::ClosingFeeAboveBase { .. } => 5106,
+            Error::ClosingFeeNotBetween { .. } => 5107,
+            Error::InsufficientFunds(_) => 5201,
+            Error::UnknownHtlc(_) => 5202,
+            Error::InvalidPreimage(_) => 5203,
+            Error::EmptyCommitment => 5204,
+            Error::UnexpectedRevocation => 5205,
+            Error::InvalidRevocation => 5206,
+            Error::UnexpectedHtlcId { .. } => 5207,
+            Error::InconsistentRevocation(_) => 5208,
+            Error::HtlcTimedOut { .. } => 5209,
+            Error::InvalidCommitmentSig(_) => 5210,
+            Error::HtlcSigCount { .. } => 5211,
+            Error::InvalidHtlcSig(..) => 5212,
+            Error::HtlcBelowMinimum { .. } => 5213,
+            Error::TooManyHtlcs(_) => 5214,
+            Error::HtlcValueInFlight { .. } => 5215,
+            Error::InvalidCommitmentNumber { .. } => 5301,
+            Error::InvalidRevocationNumber { .. } => 5302,
+            Error::InvalidLastSecret(_) => 5303,
+            Error::DataLoss { .. } => 5304,
+            Error::OutdatedState => 5305,
+            Error::StateLost => 5306,
+            Error::NoRevocableOutput(_) => 5401,
+            Error::JusticePsbtUnsigned(_) => 5402,
+            Error::RecoveryPsbtUnsigned(_) => 5403,
+            Error::FeeUpdateFromFundee => 5501,
+            Error::FeeTooLow(_) => 5502,
+            Error::UnreasonableFee { .. } => 5503,
+            Error::UnaffordableFee(_) => 5504,
             Error::Persistence(_) => 6000,
             Error::NoPersistantData => 6001,
+            Error::Storage(_) => 6002,
         }
     }
 }
@@ -108,24 +313,37 @@ pub enum ChannelStateMachine {
     Accept(ChannelAccept),
 
     /// active channel operations
-    #[display("ACTIVE")]
-    Active,
+    #[display(inner)]
+    #[from]
+    Active(ChannelActive),
 
     /// reestablishing channel
     #[display("REESTABLISHING")]
     Reestablishing,
 
     /// cooperatively closing channel
-    #[display("CLOSING")]
-    Closing,
+    #[display(inner)]
+    #[from]
+    Closing(ChannelClose),
+
+    /// channel is closed
+    #[display("CLOSED")]
+    Closed,
 
     /// uncooperative channel closing initiated by thyself
-    #[display("ABORT")]
-    Abort,
+    #[display(inner)]
+    #[from]
+    Abort(ChannelAbort),
 
     /// reacting to an uncooperative channel close from remote
-    #[display("PENALIZE")]
-    Penalize,
+    #[display(inner)]
+    #[from]
+    Penalize(ChannelPenalize),
+
+    /// recovering funds from a channel restored from the static backup
+    #[display(inner)]
+    #[from]
+    Recover(ChannelRecover),
 }
 
 // TODO: Replace with method checking persistence data on the disk and initializing state machine
@@ -142,12 +360,13 @@ impl ChannelStateMachine {
             ChannelStateMachine::Launch => Lifecycle::Initial,
             ChannelStateMachine::Propose(state_machine) => state_machine.lifecycle(),
             ChannelStateMachine::Accept(state_machine) => state_machine.lifecycle(),
-            ChannelStateMachine::Active => Lifecycle::Active,
+            ChannelStateMachine::Active(state_machine) => state_machine.lifecycle(),
             ChannelStateMachine::Reestablishing => Lifecycle::Reestablishing,
-            // TODO: use state machine
-            ChannelStateMachine::Closing => Lifecycle::Closing { round: 0 },
-            ChannelStateMachine::Abort => Lifecycle::Aborting,
-            ChannelStateMachine::Penalize => Lifecycle::Penalize,
+            ChannelStateMachine::Closing(state_machine) => state_machine.lifecycle(),
+            ChannelStateMachine::Closed => Lifecycle::Closed,
+            ChannelStateMachine::Abort(state_machine) => state_machine.lifecycle(),
+            ChannelStateMachine::Penalize(state_machine) => state_machine.lifecycle(),
+            ChannelStateMachine::Recover(state_machine) => state_machine.lifecycle(),
         }
     }
 
@@ -156,16 +375,74 @@ impl ChannelStateMachine {
             ChannelStateMachine::Launch => s!("Launching channel daemon"),
             ChannelStateMachine::Propose(state_machine) => state_machine.info_message(channel_id),
             ChannelStateMachine::Accept(state_machine) => state_machine.info_message(channel_id),
-            ChannelStateMachine::Active => s!("Channel is active"),
+            ChannelStateMachine::Active(state_machine) => state_machine.info_message(channel_id),
             ChannelStateMachine::Reestablishing => s!("Reestablishing channel"),
-            ChannelStateMachine::Closing => s!("Closing channel"),
-            ChannelStateMachine::Abort => s!("Unilaterally closing the channel"),
-            ChannelStateMachine::Penalize => s!("Penalizing incorrect channel"),
+            ChannelStateMachine::Closing(state_machine) => state_machine.info_message(channel_id),
+            ChannelStateMachine::Closed => s!("Channel is closed"),
+            ChannelStateMachine::Abort(state_machine) => state_machine.info_message(channel_id),
+            ChannelStateMachine::Penalize(state_machine) => state_machine.info_message(channel_id),
+            ChannelStateMachine::Recover(state_machine) => state_machine.info_message(channel_id),
+        }
+    }
+
+    /// Returns state of the machine after the connection with the remote peer is lost, which
+    /// happens at least when the channel daemon is restarted
+    pub fn disconnected(self) -> Self {
+        match self {
+            ChannelStateMachine::Active(_) => ChannelStateMachine::Reestablishing,
+            other => other,
         }
     }
 }
 
 impl Runtime {
+    /// Resumes watching funding output of the channel relaunched with a persisted state, so the
+    /// remote peer can't cheat while the channel waits for the peer to reconnect
+    pub(super) fn resume_watching(&mut self, endpoints: &mut Endpoints) -> Result<(), Error> {
+        if self.state.state_machine == ChannelStateMachine::Closed
+            || self.state.funding_outpoint.is_none()
+        {
+            return Ok(());
+        }
+        penalty::watch_funding(self, endpoints)?;
+        self.track_funding_position(endpoints)
+    }
+
+    /// Asks watchd to report the funding transaction once it is mined, so the short channel id
+    /// can be derived from its position in the blockchain
+    pub(super) fn track_funding_position(
+        &mut self,
+        endpoints: &mut Endpoints,
+    ) -> Result<(), Error> {
+        if self.state.short_channel_id.is_some() {
+            return Ok(());
+        }
+        let txid = self.state.funding_outpoint.ok_or(Error::NoFundingOutpoint)?.txid;
+        self.send_ctl(endpoints, ServiceId::Watch, CtlMsg::Track { txid, depth: 1 })?;
+        Ok(())
+    }
+
+    /// Derives short channel id once the funding transaction is mined. Returns whether the
+    /// transaction status concerns the funding transaction.
+    fn funding_mined(&mut self, status: &TxStatus) -> bool {
+        let outpoint = match self.state.funding_outpoint {
+            Some(outpoint) if outpoint.txid == status.txid => outpoint,
+            _ => return false,
+        };
+        let block_pos = match status.block_pos {
+            Some(block_pos) => block_pos,
+            None => return true,
+        };
+        match ShortChannelId::with(block_pos.height, block_pos.pos, outpoint.vout as u16) {
+            Ok(short_channel_id) => {
+                info!("Channel short id is {}", short_channel_id);
+                self.state.short_channel_id = Some(short_channel_id);
+            }
+            Err(_) => warn!("Funding transaction position {} exceeds short channel id", block_pos),
+        }
+        true
+    }
+
     /// Processes incoming RPC or peer requests updating state - and switching to a new state, if
     /// necessary. Returns bool indicating whether a successful state update happened
     pub fn process(
@@ -235,10 +512,15 @@ impl Runtime {
 
     fn process_event(&mut self, event: Event<BusMsg>) -> Result<(), Error> {
         // We have to handle channel reestablishment separately, since this is
-        // shared across multiple channel states
+        // shared across multiple channel states. Restored channels reestablish within their own
+        // workflow.
         if let BusMsg::Bolt(LnMsg::ChannelReestablish(ref remote_channel_reestablish)) =
             event.message
         {
+            if let ChannelStateMachine::Recover(channel_recover) = self.state.state_machine {
+                self.state.state_machine = self.process_recover(event, channel_recover)?;
+                return Ok(());
+            }
             self.state.state_machine = self.complete_reestablish(
                 event.endpoints,
                 event.source,
@@ -247,6 +529,31 @@ impl Runtime {
             return Ok(());
         }
 
+        // Funding transaction is reported by watchd once it is mined; only the channel proposal
+        // workflow waits for it
+        if let BusMsg::Ctl(CtlMsg::TxFound(ref status)) = event.message {
+            if self.funding_mined(status)
+                && self.state.state_machine != ChannelPropose::Published.into()
+            {
+                return Ok(());
+            }
+        }
+
+        // Spending of the funding output, or of the revoked commitment outputs tracked by the
+        // penalty workflow, may be reported by watchd at any channel state
+        if let BusMsg::Ctl(CtlMsg::Spent(ref spending)) = event.message {
+            self.state.state_machine = self.complete_spent(event.endpoints, spending)?;
+            return Ok(());
+        }
+
+        // Funding transaction of a zero-confirmation channel may be double-spent while the
+        // channel is already operational
+        if let BusMsg::Ctl(CtlMsg::TxConflict { txid, conflicting_txid }) = event.message {
+            self.state.state_machine =
+                self.complete_conflict(event.endpoints, txid, conflicting_txid)?;
+            return Ok(());
+        }
+
         self.state.state_machine = match self.state.state_machine {
             ChannelStateMachine::Launch => self.complete_launch(event),
             ChannelStateMachine::Propose(channel_propose) => {
@@ -255,13 +562,23 @@ impl Runtime {
             ChannelStateMachine::Accept(channel_accept) => {
                 self.process_accept(event, channel_accept)
             }
-            ChannelStateMachine::Active => Ok(ChannelStateMachine::Active), // TODO
+            ChannelStateMachine::Active(channel_active) => {
+                self.process_active(event, channel_active)
+            }
             // This is when we were launched by lnpd with a aim of re-establishing channel;
             // the state is valid _before_ we receive channel_reestablish from the peer.
-            ChannelStateMachine::Reestablishing => todo!(),
-            ChannelStateMachine::Closing => todo!(),
-            ChannelStateMachine::Abort => todo!(),
-            ChannelStateMachine::Penalize => todo!(),
+            ChannelStateMachine::Reestablishing => self.process_reestablishing(event),
+            ChannelStateMachine::Closing(channel_close) => self.process_close(event, channel_close),
+            ChannelStateMachine::Closed => {
+                Err(Error::UnexpectedMessage(event.message, Lifecycle::Closed, event.source))
+            }
+            ChannelStateMachine::Abort(channel_abort) => self.process_abort(event, channel_abort),
+            ChannelStateMachine::Penalize(channel_penalize) => {
+                self.process_penalize(event, channel_penalize)
+            }
+            ChannelStateMachine::Recover(channel_recover) => {
+                self.process_recover(event, channel_recover)
+            }
         }?;
         Ok(())
     }
@@ -272,21 +589,139 @@ impl Runtime {
         source: ServiceId,
         remote_channel_reestablish: &ChannelReestablish,
     ) -> Result<ChannelStateMachine, Error> {
-        let local_channel_reestablish =
-            self.state.channel.compose_reestablish_channel(remote_channel_reestablish)?;
+        let state_machine = self.state.state_machine;
+        if !matches!(
+            state_machine,
+            ChannelStateMachine::Active(_)
+                | ChannelStateMachine::Reestablishing
+                | ChannelStateMachine::Closing(_)
+        ) {
+            return Err(Error::UnexpectedMessage(
+                BusMsg::Bolt(LnMsg::ChannelReestablish(*remote_channel_reestablish)),
+                state_machine.lifecycle(),
+                source,
+            ));
+        }
+
         let remote_id =
             source.to_remote_id().expect("channel reestablish BOLT message from non-remoter peer");
         self.state.remote_id = Some(remote_id);
-        self.send_p2p(endpoints, LnMsg::ChannelReestablish(local_channel_reestablish))?;
+        let channel_active = reestablish::complete(self, endpoints, remote_channel_reestablish)?;
+        penalty::watch_funding(self, endpoints)?;
+        if let ChannelStateMachine::Closing(channel_close) = state_machine {
+            channel_close.retransmit(self, endpoints)?;
+        }
 
-        // We swallow error since we do not want to fail the channel if we just can't add it to the
-        // router
-        trace!("Notifying remote peer about channel reestablishing");
+        trace!("Notifying router and lnpd about channel reestablishing");
         let remote_id = self.state.remote_id();
         let message = CtlMsg::ChannelCreated(self.state.channel.channel_info(remote_id));
-        let _ = self.send_ctl(endpoints, ServiceId::Router, message);
+        self.notify_channel(endpoints, message);
+
+        Ok(match state_machine {
+            // Cooperative closing continues from its current stage
+            ChannelStateMachine::Closing(_) => state_machine,
+            _ => channel_active.into(),
+        })
+    }
+
+    fn complete_spent(
+        &mut self,
+        endpoints: &mut Endpoints,
+        spending: &TxSpending,
+    ) -> Result<ChannelStateMachine, Error> {
+        let state_machine = self.state.state_machine;
+        let txid = spending.tx.txid();
+        if let ChannelStateMachine::Penalize(channel_penalize) = state_machine {
+            // Penalty workflow also tracks HTLC outputs of the revoked commitment
+            return Ok(channel_penalize.spent(self, endpoints, spending)?.into());
+        }
+        if let ChannelStateMachine::Abort(channel_abort) = state_machine {
+            if Some(spending.outpoint.txid) == self.state.closing.txid {
+                // Abort workflow tracks HTLC outputs of the published commitment
+                return Ok(match channel_abort.spent(self, endpoints, spending)? {
+                    None => ChannelStateMachine::Closed,
+                    Some(channel_abort) => channel_abort.into(),
+                });
+            }
+        }
+        if Some(txid) == self.state.closing.txid || state_machine == ChannelStateMachine::Closed {
+            // The transaction is already processed by one of closing workflows
+            return Ok(state_machine);
+        }
+        if let ChannelStateMachine::Recover(channel_recover) = state_machine {
+            return Ok(match channel_recover.spent(self, endpoints, spending)? {
+                None => ChannelStateMachine::Closed,
+                Some(channel_recover) => channel_recover.into(),
+            });
+        }
+        if penalty::revoked_secret(self, &spending.tx).is_some() {
+            return Ok(ChannelPenalize::with(self, endpoints, spending)?.into());
+        }
+        if let ChannelStateMachine::Closing(_) = state_machine {
+            // Cooperative closing transaction is tracked by the closing workflow
+            return Ok(state_machine);
+        }
+
+        warn!("Channel funding output is spent by remote peer with transaction {}", txid);
+        let channel_id = self.state.channel.try_channel_id()?;
+        self.notify_channel(endpoints, CtlMsg::ChannelClosed(channel_id));
+        Ok(match ChannelRecover::sweep_remote(self, endpoints, spending)? {
+            None => ChannelStateMachine::Closed,
+            Some(channel_recover) => channel_recover.into(),
+        })
+    }
+
+    /// Fails zero-confirmation channel whose funding transaction got double-spent before being
+    /// mined. Since the funding output will never exist, there is no commitment transaction to
+    /// publish and the channel is closed right away.
+    fn complete_conflict(
+        &mut self,
+        endpoints: &mut Endpoints,
+        txid: Txid,
+        conflicting_txid: Txid,
+    ) -> Result<ChannelStateMachine, Error> {
+        let state_machine = self.state.state_machine;
+        if state_machine == ChannelStateMachine::Closed {
+            return Ok(state_machine);
+        }
 
-        Ok(ChannelStateMachine::Active)
+        let err = Error::FundingDoubleSpent { txid, conflicting_txid };
+        error!("{}: {}", "Failing channel".err(), err.err_details());
+        let channel_id = self.state.channel.try_channel_id()?;
+        let message = bolt::Error { channel_id, data: err.to_string().into_bytes() };
+        self.send_p2p(endpoints, LnMsg::Error(message))?;
+        self.report_failure(endpoints, Failure {
+            code: FailureCode::Channel,
+            info: err.to_string(),
+        });
+
+        let outpoint = self.state.funding_outpoint.ok_or(Error::NoFundingOutpoint)?;
+        self.send_ctl(endpoints, ServiceId::Watch, CtlMsg::UntrackSpending(outpoint))?;
+        self.state.set_stage(Lifecycle::Closed);
+        self.notify_channel(endpoints, CtlMsg::ChannelClosed(channel_id));
+        Ok(ChannelStateMachine::Closed)
+    }
+
+    fn process_reestablishing(
+        &mut self,
+        event: Event<BusMsg>,
+    ) -> Result<ChannelStateMachine, Error> {
+        match event.message {
+            BusMsg::Rpc(RpcMsg::CloseChannel { force: true }) => {
+                Ok(ChannelAbort::with(self, event.endpoints)?.into())
+            }
+            // The remote peer is not connected, so it can't be notified about the channel failure
+            BusMsg::Ctl(CtlMsg::BlockHeight(height)) => match self.timed_out_htlc(height) {
+                Some(err) => {
+                    error!("{}: {}", "Failing channel".err(), err.err_details());
+                    Ok(ChannelAbort::with(self, event.endpoints)?.into())
+                }
+                None => Ok(ChannelStateMachine::Reestablishing),
+            },
+            wrong_msg => {
+                Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Reestablishing, event.source))
+            }
+        }
     }
 
     fn complete_launch(&mut self, event: Event<BusMsg>) -> Result<ChannelStateMachine, Error> {
@@ -298,6 +733,9 @@ impl Runtime {
             BusMsg::Ctl(CtlMsg::AcceptChannelFrom(accept_channel_from)) => {
                 ChannelAccept::with(self, endpoints, accept_channel_from)?.into()
             }
+            BusMsg::Ctl(CtlMsg::RestoreChannel(channel_backup)) => {
+                ChannelRecover::with(self, endpoints, channel_backup)?.into()
+            }
             wrong_msg => {
                 return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Initial, source))
             }
@@ -310,7 +748,10 @@ impl Runtime {
         channel_propose: ChannelPropose,
     ) -> Result<ChannelStateMachine, Error> {
         Ok(match channel_propose.next(event, self)? {
-            None => ChannelStateMachine::Active,
+            None => {
+                self.state.activate();
+                ChannelActive::Ready.into()
+            }
             Some(channel_propose) => ChannelStateMachine::Propose(channel_propose),
         })
     }
@@ -321,8 +762,142 @@ impl Runtime {
         channel_accept: ChannelAccept,
     ) -> Result<ChannelStateMachine, Error> {
         Ok(match channel_accept.next(event, self)? {
-            None => ChannelStateMachine::Active,
+            None => {
+                self.state.activate();
+                ChannelActive::Ready.into()
+            }
             Some(channel_accept) => ChannelStateMachine::Accept(channel_accept),
         })
     }
+
+    fn process_active(
+        &mut self,
+        event: Event<BusMsg>,
+        channel_active: ChannelActive,
+    ) -> Result<ChannelStateMachine, Error> {
+        if let BusMsg::Ctl(CtlMsg::BlockHeight(height)) = event.message {
+            if let Some(err) = self.timed_out_htlc(height) {
+                return self.fail_channel(event.endpoints, err);
+            }
+        }
+        // Remote signatures must be verified before we revoke the previous local commitment
+        if let BusMsg::Bolt(LnMsg::CommitmentSigned(ref commitment_signed)) = event.message {
+            if let Err(err) = self.state.verify_commitment(commitment_signed) {
+                return self.fail_channel(event.endpoints, err);
+            }
+        }
+        Ok(match event.message {
+            BusMsg::Rpc(RpcMsg::CloseChannel { force: true }) => {
+                ChannelAbort::with(self, event.endpoints)?.into()
+            }
+            BusMsg::Rpc(RpcMsg::CloseChannel { force: false }) => {
+                ChannelClose::with_local(self, event.endpoints)?.into()
+            }
+            BusMsg::Bolt(LnMsg::Shutdown(shutdown)) => {
+                ChannelClose::with_remote(self, event.endpoints, shutdown)?.into()
+            }
+            BusMsg::Bolt(LnMsg::UpdateFee(update_fee)) => {
+                match self.state.receive_fee(update_fee, self.fee_estimate) {
+                    Ok(()) => channel_active.into(),
+                    Err(err) => self.fail_channel(event.endpoints, err)?,
+                }
+            }
+            BusMsg::Bolt(LnMsg::UpdateAddHtlc(update_add_htlc)) => {
+                match self.state.receive_htlc(update_add_htlc) {
+                    Ok(()) => channel_active.into(),
+                    Err(err) => self.fail_channel(event.endpoints, err)?,
+                }
+            }
+            _ => match channel_active.next(event, self)? {
+                None => unreachable!("active channel workflow never completes by itself"),
+                Some(channel_active) => ChannelStateMachine::Active(channel_active),
+            },
+        })
+    }
+
+    /// Detects HTLC offered by the local node which has timed out at the given block height. Such
+    /// HTLC can be claimed back only on-chain, so the channel has to be failed.
+    pub(super) fn timed_out_htlc(&self, height: u32) -> Option<Error> {
+        self.state.commitments.timed_out_offered(height).map(|htlc| Error::HtlcTimedOut {
+            htlc_id: htlc.htlc_id,
+            cltv_expiry: htlc.cltv_expiry,
+        })
+    }
+
+    /// Fails the channel due to the remote peer misbehavior, notifying the remote peer with an
+    /// error message and closing the channel unilaterally
+    fn fail_channel(
+        &mut self,
+        endpoints: &mut Endpoints,
+        err: Error,
+    ) -> Result<ChannelStateMachine, Error> {
+        error!("{}: {}", "Failing channel".err(), err.err_details());
+        let message = bolt::Error {
+            channel_id: self.state.channel.try_channel_id()?,
+            data: err.to_string().into_bytes(),
+        };
+        self.send_p2p(endpoints, LnMsg::Error(message))?;
+        self.report_failure(endpoints, Failure {
+            code: FailureCode::Channel,
+            info: err.to_string(),
+        });
+        Ok(ChannelAbort::with(self, endpoints)?.into())
+    }
+
+    fn process_close(
+        &mut self,
+        event: Event<BusMsg>,
+        channel_close: ChannelClose,
+    ) -> Result<ChannelStateMachine, Error> {
+        // Cooperative closing may get stuck if the remote peer became unresponsive, so we allow to
+        // force-close the channel at any stage before the closing transaction is published
+        if let BusMsg::Rpc(RpcMsg::CloseChannel { force: true }) = event.message {
+            if channel_close != ChannelClose::Published {
+                return Ok(ChannelAbort::with(self, event.endpoints)?.into());
+            }
+        }
+        // Remote fee proposals violating BOLT-2 limits fail the channel
+        if let BusMsg::Bolt(LnMsg::ClosingSigned(ref closing_signed)) = event.message {
+            if let Err(err) = channel_close.verify_fee(self, closing_signed.fee_satoshis) {
+                return self.fail_channel(event.endpoints, err);
+            }
+        }
+        Ok(match channel_close.next(event, self)? {
+            None => ChannelStateMachine::Closed,
+            Some(channel_close) => ChannelStateMachine::Closing(channel_close),
+        })
+    }
+
+    fn process_abort(
+        &mut self,
+        event: Event<BusMsg>,
+        channel_abort: ChannelAbort,
+    ) -> Result<ChannelStateMachine, Error> {
+        Ok(match channel_abort.next(event, self)? {
+            None => ChannelStateMachine::Closed,
+            Some(channel_abort) => ChannelStateMachine::Abort(channel_abort),
+        })
+    }
+
+    fn process_penalize(
+        &mut self,
+        event: Event<BusMsg>,
+        channel_penalize: ChannelPenalize,
+    ) -> Result<ChannelStateMachine, Error> {
+        Ok(match channel_penalize.next(event, self)? {
+            None => ChannelStateMachine::Closed,
+            Some(channel_penalize) => ChannelStateMachine::Penalize(channel_penalize),
+        })
+    }
+
+    fn process_recover(
+        &mut self,
+        event: Event<BusMsg>,
+        channel_recover: ChannelRecover,
+    ) -> Result<ChannelStateMachine, Error> {
+        Ok(match channel_recover.next(event, self)? {
+            None => ChannelStateMachine::Closed,
+            Some(channel_recover) => ChannelStateMachine::Recover(channel_recover),
+        })
+    }
 }
@@ -0,0 +1,599 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Detection of revoked remote commitment transactions spending channel funding output and their
+//! penalization with justice transactions, which claim revocable outputs with the revocation key
+//! before the remote peer is able to spend them after `to_self_delay`.
+//!
+//! HTLC outputs of the revoked commitment may be spent by the remote peer with second-stage HTLC
+//! transactions before the justice transaction is mined. Outputs of these transactions are locked
+//! with the same script as `to_local` output, so the justice transaction is replaced to claim them
+//! instead.
+
+use amplify::{DumbDefault, Slice32, Wrapper};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, SecretKey, SECP256K1};
+use bitcoin::{OutPoint, PackedLockTime, Sequence, Transaction, TxIn, TxOut, Witness};
+use bitcoin_scripts::{PubkeyScript, WitnessScript};
+use lnp::channel::bolt::{self, Lifecycle, ScriptGenerators};
+use lnp::p2p::bolt::ActiveChannelId;
+use lnp::Extension;
+use microservices::cli::LogStyle;
+use wallet::psbt::{self, Psbt, PsbtVersion};
+
+use super::Error;
+use crate::automata::{Event, StateMachine};
+use crate::bus::{BusMsg, CtlMsg, TxSpending};
+use crate::channeld::commitment::{has_anchors, CommitmentKeys};
+use crate::channeld::runtime::Runtime;
+use crate::rpc::ServiceId;
+use crate::{Endpoints, Responder};
+
+/// Weight of the witness spending `to_local` output of the commitment transaction via its
+/// revocation branch: a signature, a non-empty element selecting `OP_IF` branch and the witness
+/// script.
+const JUSTICE_WITNESS_WEIGHT: u64 = 155;
+
+/// Weight of the witness spending HTLC output of the commitment transaction via its revocation
+/// branch, excluding the witness script itself: number of the witness elements, a signature, the
+/// revocation public key and the length of the witness script.
+const JUSTICE_HTLC_WITNESS_WEIGHT: u64 = 110;
+
+/// Mask for the lower 48 bits of the commitment number
+const LOWER_48_BITS: u64 = 0x0000_FFFF_FFFF_FFFF;
+
+/// Number of blocks the justice transaction may stay unmined before it is replaced with one
+/// paying a higher fee
+const JUSTICE_BUMP_BLOCKS: u32 = 3;
+
+/// Workflow penalizing the remote peer for publishing revoked commitment transaction
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+pub enum ChannelPenalize {
+    /// awaiting lnpd to provide a script from the funding wallet for the justice transaction
+    /// output
+    #[display("PREPARING")]
+    Preparing,
+
+    /// signing justice transaction with the revocation key
+    #[display("SIGNING")]
+    Signing,
+
+    /// justice transaction is published, awaiting it to be mined; the transaction is
+    /// rebroadcast with each new block and its fee is bumped if it is not mined for a while
+    #[display("PUBLISHED")]
+    Published,
+}
+
+impl StateMachine<BusMsg, Runtime> for ChannelPenalize {
+    type Error = Error;
+
+    fn next(
+        self,
+        event: Event<BusMsg>,
+        runtime: &mut Runtime,
+    ) -> Result<Option<Self>, Self::Error> {
+        let channel_id = runtime.state.channel.active_channel_id();
+        debug!("ChannelPenalize {:#} received {} event", channel_id, event.message);
+        let state = match self {
+            ChannelPenalize::Preparing => complete_preparing(event, runtime),
+            ChannelPenalize::Signing => complete_signing(event, runtime),
+            ChannelPenalize::Published => {
+                if let Some(next) = complete_published(event, runtime)? {
+                    Ok(next)
+                } else {
+                    info!("ChannelPenalize {:#} has completed its work", channel_id);
+                    return Ok(None);
+                }
+            }
+        }?;
+        info!("ChannelPenalize {:#} switched to {} state", channel_id, state);
+        Ok(Some(state))
+    }
+}
+
+impl ChannelPenalize {
+    /// Computes channel lifecycle stage for the current channel penalty workflow stage
+    pub fn lifecycle(&self) -> Lifecycle { Lifecycle::Penalize }
+}
+
+// State transitions:
+
+impl ChannelPenalize {
+    /// Constructs channel penalty state machine for the revoked remote commitment transaction
+    /// spending channel funding output
+    pub fn with(
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+        spending: &TxSpending,
+    ) -> Result<ChannelPenalize, Error> {
+        let txid = spending.tx.txid();
+        error!(
+            "{} published revoked commitment transaction {}",
+            "Remote peer".err(),
+            txid.err_details()
+        );
+
+        let to_self_delay = runtime.state.channel.constructor().local_params().to_self_delay;
+        let depth = spending.block_pos.map(|pos| pos.depth).unwrap_or_default();
+        if depth >= to_self_delay as u32 {
+            warn!(
+                "Revoked commitment transaction {} is mined {} blocks ago, so the remote peer may \
+                 have already swept its funds",
+                txid, depth
+            );
+        }
+
+        runtime.state.closing.txid = Some(txid);
+        runtime.state.closing.breach_tx = Some(spending.tx.clone());
+        runtime.state.set_stage(Lifecycle::Penalize);
+        watch_htlcs(runtime, endpoints)?;
+
+        trace!("Notifying router and lnpd about channel closing");
+        let channel_id = runtime.state.channel.try_channel_id()?;
+        runtime.notify_channel(endpoints, CtlMsg::ChannelClosed(channel_id));
+
+        if let Some(script) =
+            runtime.state.channel.constructor().local_keys().shutdown_scriptpubkey.clone()
+        {
+            return sign_justice(runtime, endpoints, script);
+        }
+        runtime.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::GetPayoutScript)?;
+        Ok(ChannelPenalize::Preparing)
+    }
+
+    /// Processes transaction spending one of the outputs tracked by the penalty workflow. When
+    /// the remote peer spends HTLC outputs of the revoked commitment with second-stage HTLC
+    /// transactions, these are recorded and the published justice transaction is replaced to
+    /// claim their outputs instead.
+    pub fn spent(
+        self,
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+        spending: &TxSpending,
+    ) -> Result<ChannelPenalize, Error> {
+        let txid = spending.tx.txid();
+        if Some(spending.outpoint.txid) != runtime.state.closing.txid
+            || Some(txid) == runtime.state.closing.sweep_txid
+            || runtime.state.justice.replaced_txids.contains(&txid)
+            || runtime.state.htlc_spends.iter().any(|tx| tx.txid() == txid)
+        {
+            // Revoked commitment spending the funding output and justice transactions spending
+            // HTLC outputs are already known
+            return Ok(self);
+        }
+
+        warn!(
+            "Remote peer has spent HTLC output {} of the revoked commitment with transaction {}",
+            spending.outpoint, txid
+        );
+        runtime.state.htlc_spends.push(spending.tx.clone());
+        if self != ChannelPenalize::Published {
+            // Justice transaction which is not signed yet will claim the second-stage output,
+            // while the one being signed will fail to be mined and get replaced
+            return Ok(self);
+        }
+        let justice_psbt = runtime.state.justice.psbt.as_ref().expect("justice is published");
+        let script = justice_psbt.to_unsigned_tx().output[0].script_pubkey.clone().into();
+        sign_justice(runtime, endpoints, script)
+    }
+
+    /// Construct information message for error and client reporting
+    pub fn info_message(&self, channel_id: ActiveChannelId) -> String {
+        match self {
+            ChannelPenalize::Preparing => format!(
+                "{} to penalize remote peer of channel {:#}",
+                "Preparing".announce(),
+                channel_id.announcer()
+            ),
+            ChannelPenalize::Signing => format!(
+                "{} justice transaction locally for channel {:#}",
+                "Signing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelPenalize::Published => format!(
+                "{} justice transaction for channel {:#} to be mined",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+        }
+    }
+}
+
+fn complete_preparing(
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<ChannelPenalize, Error> {
+    match event.message {
+        BusMsg::Ctl(CtlMsg::PayoutScript(script)) => sign_justice(runtime, event.endpoints, script),
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Penalize, event.source)),
+    }
+}
+
+fn complete_signing(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelPenalize, Error> {
+    let mut justice_psbt = match event.message {
+        BusMsg::Ctl(CtlMsg::Signed(psbt)) => psbt,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Penalize, event.source))
+        }
+    };
+
+    // Signd adds signature created with the revocation key under the revocation basepoint, and
+    // neither `to_local` nor HTLC outputs are miniscripts, so we have to finalize the inputs
+    // ourselves
+    let revocation_basepoint =
+        runtime.state.channel.constructor().local_keys().revocation_basepoint.key;
+    let revoked = revoked_outputs(runtime)?;
+    for input in &mut justice_psbt.inputs {
+        let signature = input
+            .partial_sigs
+            .get(&bitcoin::PublicKey::new(revocation_basepoint))
+            .ok_or(Error::JusticePsbtUnsigned(revocation_basepoint))?;
+        let witness_script =
+            input.witness_script.as_ref().expect("justice transaction spends P2WSH outputs");
+        // Revocation branch of `to_local` script is selected with a non-empty element, while
+        // HTLC scripts select it by the revocation key
+        let selector = match witness_script == &revoked.to_local_script {
+            true => vec![1],
+            false => revoked.revocation_pubkey.serialize().to_vec(),
+        };
+        input.final_script_witness =
+            Some(Witness::from_vec(vec![signature.to_vec(), selector, witness_script.to_bytes()]));
+    }
+
+    let txid = justice_psbt.to_txid();
+    debug!("Publishing justice transaction {}", txid);
+    trace!("Justice transaction: {:#?}", justice_psbt);
+    let justice = &mut runtime.state.justice;
+    // Replaced transactions are kept tracked, since any of them still may be mined
+    if let Some(replaced_txid) = runtime.state.closing.sweep_txid.replace(txid) {
+        justice.replaced_txids.push(replaced_txid);
+    }
+    justice.psbt = Some(justice_psbt.clone());
+    justice.height = None;
+    runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::PublishTx(justice_psbt))?;
+    runtime.send_ctl(event.endpoints, ServiceId::Watch, CtlMsg::Track { txid, depth: 1 })?;
+    Ok(ChannelPenalize::Published)
+}
+
+fn complete_published(
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<Option<ChannelPenalize>, Error> {
+    let justice_txid = runtime.state.closing.sweep_txid;
+    let replaced_txids = &runtime.state.justice.replaced_txids;
+    match event.message {
+        BusMsg::Ctl(CtlMsg::TxFound(status))
+            if Some(status.txid) == justice_txid || replaced_txids.contains(&status.txid) =>
+        {
+            info!(
+                "Funds from channel {} are claimed with justice transaction {}",
+                runtime.state.channel.active_channel_id(),
+                status.txid
+            );
+            runtime.state.set_stage(Lifecycle::Closed);
+            Ok(None)
+        }
+        BusMsg::Ctl(CtlMsg::BlockHeight(height)) => {
+            rebroadcast_justice(runtime, event.endpoints, height).map(Some)
+        }
+        // The transaction is rebroadcast with the next block, and replaced if it is not accepted
+        // for a while
+        BusMsg::Ctl(CtlMsg::Error { error, .. }) => {
+            warn!("Unable to publish justice transaction: {}", error);
+            Ok(Some(ChannelPenalize::Published))
+        }
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Penalize, event.source)),
+    }
+}
+
+/// Rebroadcasts unmined justice transaction, replacing it with one paying a higher fee once it
+/// stays unmined for [`JUSTICE_BUMP_BLOCKS`]
+fn rebroadcast_justice(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    height: u32,
+) -> Result<ChannelPenalize, Error> {
+    let justice = &mut runtime.state.justice;
+    let justice_psbt = justice.psbt.clone().expect("justice transaction is published");
+    let published_height = *justice.height.get_or_insert(height);
+
+    if height.saturating_sub(published_height) >= JUSTICE_BUMP_BLOCKS {
+        let script = justice_psbt.to_unsigned_tx().output[0].script_pubkey.clone().into();
+        match sign_justice(runtime, endpoints, script) {
+            Err(Error::ClosingFee(fee)) => {
+                warn!("Justice transaction fee can't be bumped to {} sats", fee)
+            }
+            result => return result,
+        }
+    }
+
+    debug!("Rebroadcasting justice transaction {}", justice_psbt.to_txid());
+    runtime.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::PublishTx(justice_psbt))?;
+    Ok(ChannelPenalize::Published)
+}
+
+fn sign_justice(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    script: PubkeyScript,
+) -> Result<ChannelPenalize, Error> {
+    let feerate_per_kw = justice_feerate(runtime);
+    let (justice_psbt, per_commitment_secret) = justice_psbt(runtime, script, feerate_per_kw)?;
+    runtime.state.justice.feerate_per_kw = Some(feerate_per_kw);
+    debug!(
+        "Signing justice transaction {} paying {} sat/kw",
+        justice_psbt.to_txid(),
+        feerate_per_kw
+    );
+    runtime.send_ctl(endpoints, ServiceId::Signer, CtlMsg::SignRevoked {
+        psbt: justice_psbt,
+        per_commitment_secret: Slice32::from_inner(per_commitment_secret.secret_bytes()),
+    })?;
+    Ok(ChannelPenalize::Signing)
+}
+
+/// Computes fee rate for the justice transaction. The first transaction pays the greater of the
+/// channel and on-chain fee rates, and each replacement raises the previous fee rate by a quarter,
+/// or up to the on-chain fee estimate.
+fn justice_feerate(runtime: &Runtime) -> u32 {
+    let fee_estimate = runtime.fee_estimate.unwrap_or_default();
+    match runtime.state.justice.feerate_per_kw {
+        None => {
+            runtime.state.channel.constructor().common_params().feerate_per_kw.max(fee_estimate)
+        }
+        Some(feerate_per_kw) => (feerate_per_kw + feerate_per_kw / 4).max(fee_estimate),
+    }
+}
+
+/// Asks watchd to report transactions spending HTLC outputs of the revoked commitment, which may
+/// be second-stage HTLC transactions of the remote peer
+fn watch_htlcs(runtime: &mut Runtime, endpoints: &mut Endpoints) -> Result<(), Error> {
+    let revoked = revoked_outputs(runtime)?;
+    for output in revoked.outputs {
+        if output.witness_script == revoked.to_local_script {
+            continue;
+        }
+        debug!("Watching for transactions spending HTLC output {}", output.outpoint);
+        runtime.send_ctl(endpoints, ServiceId::Watch, CtlMsg::TrackSpending {
+            outpoint: output.outpoint,
+            script_pubkey: output.txout.script_pubkey.into(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Asks watchd to report transaction spending channel funding output
+pub(super) fn watch_funding(runtime: &mut Runtime, endpoints: &mut Endpoints) -> Result<(), Error> {
+    let outpoint = runtime.state.funding_outpoint.ok_or(Error::NoFundingOutpoint)?;
+    let core = runtime.state.channel.constructor();
+    let funding_output = psbt::Output::ln_funding(
+        runtime.state.channel.funding().amount(),
+        &core.local_keys().funding_pubkey,
+        core.remote_keys().funding_pubkey,
+    );
+    let script_pubkey = funding_output.to_txout().script_pubkey.into();
+    debug!("Watching for transactions spending channel funding output {}", outpoint);
+    runtime
+        .send_ctl(endpoints, ServiceId::Watch, CtlMsg::TrackSpending { outpoint, script_pubkey })?;
+    Ok(())
+}
+
+/// Detects whether the transaction is a remote commitment transaction which was revoked, and
+/// returns its commitment number together with the per-commitment secret revealed by the remote
+/// peer for it.
+pub(super) fn revoked_secret(runtime: &Runtime, tx: &Transaction) -> Option<(u64, SecretKey)> {
+    let number = commitment_number(runtime, tx)?;
+    runtime.state.commitments.remote_secrets.secret(number).map(|secret| (number, secret))
+}
+
+/// Recovers number of the commitment transaction from its lock time and input sequence, where it
+/// is stored obscured according to BOLT-3. Returns `None` if the transaction is not a commitment
+/// transaction.
+pub(super) fn commitment_number(runtime: &Runtime, tx: &Transaction) -> Option<u64> {
+    let lock_time = tx.lock_time.0;
+    let sequence = tx.input.first()?.sequence.0;
+    if lock_time >> 24 != 0x20 || sequence >> 24 != 0x80 {
+        return None;
+    }
+    let obscured = ((sequence as u64 & 0xFF_FFFF) << 24) | (lock_time as u64 & 0xFF_FFFF);
+    Some(obscured ^ obscuring_factor(runtime))
+}
+
+/// Computes factor obscuring commitment numbers from the payment basepoints of the channel
+/// funder and fundee
+fn obscuring_factor(runtime: &Runtime) -> u64 {
+    let mut state = bolt::ChannelState::dumb_default();
+    runtime.state.channel.store_state(&mut state);
+    let local = state.local_keys.payment_basepoint.key;
+    let remote = state.remote_keys.payment_basepoint;
+
+    let mut engine = sha256::Hash::engine();
+    if state.direction.is_inbound() {
+        engine.input(&remote.serialize());
+        engine.input(&local.serialize());
+    } else {
+        engine.input(&local.serialize());
+        engine.input(&remote.serialize());
+    }
+    let hash = sha256::Hash::from_engine(engine);
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&hash[24..]);
+    u64::from_be_bytes(buf) & LOWER_48_BITS
+}
+
+/// Output claimed by the justice transaction with the revocation key
+struct RevocableOutput {
+    outpoint: OutPoint,
+    txout: TxOut,
+    witness_script: WitnessScript,
+}
+
+/// Outputs of the revoked remote commitment transaction, and of the second-stage HTLC
+/// transactions spending it, which can be claimed with the revocation key
+struct RevokedOutputs {
+    /// Per-commitment secret revealed by the remote peer for the revoked commitment
+    per_commitment_secret: SecretKey,
+
+    /// Revocation public key of the revoked commitment
+    revocation_pubkey: PublicKey,
+
+    /// Witness script of `to_local` output, which also locks outputs of the second-stage HTLC
+    /// transactions
+    to_local_script: WitnessScript,
+
+    outputs: Vec<RevocableOutput>,
+}
+
+/// Detects outputs of the revoked remote commitment transaction which can be claimed with the
+/// revocation key: `to_local` output and HTLC outputs. HTLC outputs which are already spent by the
+/// second-stage HTLC transactions of the remote peer are replaced with the outputs of these
+/// transactions.
+fn revoked_outputs(runtime: &Runtime) -> Result<RevokedOutputs, Error> {
+    let breach_tx = runtime
+        .state
+        .closing
+        .breach_tx
+        .as_ref()
+        .expect("penalty is performed only for a known revoked commitment");
+    let txid = breach_tx.txid();
+    let (number, per_commitment_secret) =
+        revoked_secret(runtime, breach_tx).ok_or(Error::NoRevocableOutput(txid))?;
+    let per_commitment_point = PublicKey::from_secret_key(SECP256K1, &per_commitment_secret);
+
+    let mut state = bolt::ChannelState::dumb_default();
+    runtime.state.channel.store_state(&mut state);
+    let keys = CommitmentKeys::with_point(&state, true, per_commitment_point);
+    let anchors = has_anchors(state.common_params.channel_type);
+    let to_local_script = WitnessScript::ln_to_local(
+        0,
+        keys.revocation_pubkey,
+        keys.owner_delayed_pubkey,
+        state.local_params.to_self_delay,
+    );
+    let htlc_scripts = runtime
+        .state
+        .revoked_htlcs
+        .get(&number)
+        .into_iter()
+        .flatten()
+        .map(|htlc| keys.htlc_script(htlc.offered, &htlc.payment_hash, htlc.cltv_expiry, anchors))
+        .collect::<Vec<_>>();
+
+    let mut outputs = vec![];
+    for (vout, txout) in breach_tx.output.iter().enumerate() {
+        let outpoint = OutPoint::new(txid, vout as u32);
+        if txout.script_pubkey == to_local_script.to_v0_p2wsh() {
+            outputs.push(RevocableOutput {
+                outpoint,
+                txout: txout.clone(),
+                witness_script: to_local_script.clone(),
+            });
+            continue;
+        }
+        let witness_script =
+            match htlc_scripts.iter().find(|script| script.to_v0_p2wsh() == txout.script_pubkey) {
+                Some(script) => script.clone(),
+                None => continue,
+            };
+        let spending = runtime.state.htlc_spends.iter().find_map(|tx| {
+            let index = tx.input.iter().position(|txin| txin.previous_output == outpoint)?;
+            Some((tx, index))
+        });
+        match spending {
+            None => {
+                outputs.push(RevocableOutput { outpoint, txout: txout.clone(), witness_script })
+            }
+            // Each output of the second-stage HTLC transactions corresponds to the input with the
+            // same index
+            Some((tx, index)) => match tx.output.get(index) {
+                Some(txout) if txout.script_pubkey == to_local_script.to_v0_p2wsh() => outputs
+                    .push(RevocableOutput {
+                        outpoint: OutPoint::new(tx.txid(), index as u32),
+                        txout: txout.clone(),
+                        witness_script: to_local_script.clone(),
+                    }),
+                _ => warn!(
+                    "HTLC output {} is spent by transaction {} which is not a second-stage HTLC \
+                     transaction",
+                    outpoint,
+                    tx.txid()
+                ),
+            },
+        }
+    }
+
+    Ok(RevokedOutputs {
+        per_commitment_secret,
+        revocation_pubkey: keys.revocation_pubkey,
+        to_local_script,
+        outputs,
+    })
+}
+
+/// Constructs transaction claiming `to_local` and HTLC outputs of the revoked remote commitment
+/// transaction, or outputs of the second-stage HTLC transactions spending them, to the provided
+/// `script` with the given fee rate. Returns the transaction together with the remote
+/// per-commitment secret, which is required by signd to derive the revocation key.
+///
+/// `to_remote` output already pays to the local node, so it is not claimed.
+fn justice_psbt(
+    runtime: &mut Runtime,
+    script: PubkeyScript,
+    feerate_per_kw: u32,
+) -> Result<(Psbt, SecretKey), Error> {
+    let revoked = revoked_outputs(runtime)?;
+    if revoked.outputs.is_empty() {
+        let txid = runtime.state.closing.txid.expect("revoked commitment is known");
+        return Err(Error::NoRevocableOutput(txid));
+    }
+
+    let value = revoked.outputs.iter().map(|output| output.txout.value).sum::<u64>();
+    let mut justice_tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: revoked
+            .outputs
+            .iter()
+            .map(|output| TxIn {
+                previous_output: output.outpoint,
+                script_sig: empty!(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: empty!(),
+            })
+            .collect(),
+        output: vec![TxOut { value, script_pubkey: script.into() }],
+    };
+    let witness_weight = revoked
+        .outputs
+        .iter()
+        .map(|output| match output.witness_script == revoked.to_local_script {
+            true => JUSTICE_WITNESS_WEIGHT,
+            false => JUSTICE_HTLC_WITNESS_WEIGHT + output.witness_script.len() as u64,
+        })
+        .sum::<u64>();
+    let weight = justice_tx.weight() as u64 + witness_weight;
+    let fee = weight * feerate_per_kw as u64 / 1000;
+    justice_tx.output[0].value = value.checked_sub(fee).ok_or(Error::ClosingFee(fee))?;
+
+    let mut psbt = Psbt::with(justice_tx, PsbtVersion::V0)
+        .expect("justice transaction has empty script_sig and witness");
+    let revocation_basepoint =
+        &runtime.state.channel.constructor().local_keys().revocation_basepoint;
+    for (input, output) in psbt.inputs.iter_mut().zip(revoked.outputs) {
+        input.witness_utxo = Some(output.txout);
+        input.witness_script = Some(output.witness_script);
+        input.bip32_derivation = revocation_basepoint.to_bip32_derivation_map();
+    }
+    Ok((psbt, revoked.per_commitment_secret))
+}
@@ -18,7 +18,7 @@ use lnp::Extension;
 use microservices::cli::LogStyle;
 use microservices::esb::Handler;
 
-use super::Error;
+use super::{accept, penalty, Error};
 use crate::automata::{Event, StateMachine};
 use crate::bus::{BusMsg, CtlMsg, FundChannel, OpenChannelWith};
 use crate::channeld::automata;
@@ -110,8 +110,9 @@ impl ChannelPropose {
     pub fn with(
         runtime: &mut Runtime,
         endpoints: &mut Endpoints,
-        request: OpenChannelWith,
+        mut request: OpenChannelWith,
     ) -> Result<ChannelPropose, automata::Error> {
+        runtime.state.set_commitment_seed(request.commitment_seed, &mut request.local_keys);
         let open_channel = LnMsg::OpenChannel(runtime.state.channel.compose_open_channel(
             request.funding_sat,
             request.push_msat,
@@ -173,7 +174,17 @@ fn complete_proposed(
     };
 
     let channel = &mut runtime.state.channel;
+    let proposed = channel.constructor().common_params().channel_type;
+    let accepted = accept_channel.channel_type.unwrap_or_default();
+    if accepted != proposed {
+        return Err(Error::ChannelTypeMismatch { proposed, accepted });
+    }
+    let minimum_depth = accept_channel.minimum_depth;
     channel.update_from_peer(&LnMsg::AcceptChannel(accept_channel))?;
+    // Remote peer defines the number of confirmations required for the channel funding
+    let mut common_params = channel.constructor().common_params();
+    common_params.minimum_depth = minimum_depth;
+    channel.set_common_params(common_params);
 
     let fund_channel = FundChannel {
         script_pubkey: channel.funding_script_pubkey(),
@@ -210,8 +221,9 @@ fn complete_accepted(
     trace!("Funding transaction: {:#?}", funding_psbt);
     debug!("Funding transaction id is {}", funding_psbt.to_txid());
 
-    let channel = &mut runtime.state.channel;
-    let refund_psbt = channel.refund_tx(funding_psbt, false)?;
+    runtime.state.channel.set_funding(funding_psbt)?;
+    let refund_psbt = runtime.state.commitment_tx(false)?;
+    let channel = &runtime.state.channel;
 
     trace!("Refund transaction: {:#?}", refund_psbt);
     trace!("Local keyset: {:#}", channel.constructor().local_keys());
@@ -245,6 +257,7 @@ fn complete_signing(
 
     let funding = channel.funding();
     let (funding_txid, funding_output_index) = (funding.txid(), funding.output());
+    let funding_outpoint = funding.outpoint();
     let funding_created = FundingCreated {
         temporary_channel_id: channel
             .temp_channel_id()
@@ -253,14 +266,13 @@ fn complete_signing(
         funding_output_index,
         signature: signature.sig,
     };
+    runtime.state.funding_outpoint = Some(funding_outpoint);
 
     let channel_id = ChannelId::with(funding_txid, funding_output_index);
     debug!("Changing channel id from {} to {}", runtime.identity(), channel_id);
     runtime
-        .set_identity(event.endpoints, channel_id)
+        .set_identity(event.endpoints, funding_created.temporary_channel_id, channel_id)
         .expect("unable to change ZMQ channel identity");
-    // needed to update ESB routing map
-    runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::Hello)?;
 
     runtime.send_p2p(event.endpoints, LnMsg::FundingCreated(funding_created))?;
     Ok(ChannelPropose::Funding)
@@ -283,6 +295,11 @@ fn complete_funding(
     runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::PublishFunding)?;
 
     let txid = runtime.state.channel.funding().txid();
+    if runtime.state.minimum_depth() == 0 && runtime.is_zeroconf_peer(runtime.state.remote_id()) {
+        accept::lock_unconfirmed(runtime, event.endpoints, txid)?;
+        return Ok(ChannelPropose::Locked);
+    }
+
     debug!("Waiting for funding transaction {} to be mined", txid);
     runtime.send_ctl(event.endpoints, ServiceId::Watch, CtlMsg::Track { txid, depth: 0 })?;
 
@@ -296,7 +313,8 @@ fn complete_published(
     let published_event = match event.message {
         BusMsg::Ctl(CtlMsg::TxFound(_)) => {
             debug!("Funding transaction mined, notifying remote peer");
-            let funding_locked = runtime.state.channel.compose_funding_locked();
+            let mut funding_locked = runtime.state.channel.compose_funding_locked();
+            funding_locked.next_per_commitment_point = runtime.state.per_commitment_point(1);
             runtime.send_p2p(event.endpoints, LnMsg::FundingLocked(funding_locked))?;
             Ok(Some(ChannelPropose::Locked))
         }
@@ -313,18 +331,15 @@ fn complete_locked(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<(), au
         }
     };
 
-    // We swallow error since we do not want to fail the channel if we just can't add it to the
-    // router
-    trace!("Notifying remote peer about channel creation");
-    let _ = runtime.send_ctl(
-        event.endpoints,
-        ServiceId::Router,
-        CtlMsg::ChannelCreated(runtime.state.channel.channel_info(runtime.state.remote_id())),
-    );
+    trace!("Notifying router and lnpd about channel creation");
+    let channel_info = runtime.state.channel.channel_info(runtime.state.remote_id());
+    runtime.notify_channel(event.endpoints, CtlMsg::ChannelCreated(channel_info));
 
     debug!("Remote peer confirmed that channel funding got mined");
     // Save next per commitment point
     runtime.state.channel.update_from_peer(&LnMsg::FundingLocked(funding_locked))?;
+    penalty::watch_funding(runtime, event.endpoints)?;
+    runtime.track_funding_position(event.endpoints)?;
     info!("Channel {} is active", runtime.state.channel.active_channel_id());
 
     Ok(())
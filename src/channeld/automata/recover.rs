@@ -0,0 +1,820 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Recovery of funds from a channel restored from the static channel backup after the loss of the
+//! channel state. Using BOLT-2 data loss protection we prove the remote peer that our state is
+//! outdated and ask it to close the channel with its latest commitment transaction, from which we
+//! sweep our output to the funding wallet.
+//!
+//! The same sweeping is performed when the remote peer closes an operational channel by
+//! publishing its latest commitment transaction, since `to_remote` output of such transaction is
+//! not controlled by the funding wallet. HTLC outputs of such transaction are claimed as well:
+//! HTLCs offered by the remote peer are swept together with `to_remote` output using payment
+//! preimages known to the local node, and HTLCs offered by the local node are reclaimed with a
+//! separate transaction once all of them expire.
+
+use amplify::{DumbDefault, Slice32, Wrapper};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Scalar, SECP256K1};
+use bitcoin::{OutPoint, PackedLockTime, Sequence, Transaction, TxIn, TxOut, Witness};
+use bitcoin_scripts::hlc::HashPreimage;
+use bitcoin_scripts::{PubkeyScript, WitnessScript};
+use lnp::channel::bolt::{ChannelState, Lifecycle, ScriptGenerators};
+use lnp::p2p::bolt::{self, ActiveChannelId, ChannelReestablish, Messages as LnMsg};
+use lnp::Extension;
+use microservices::cli::LogStyle;
+use wallet::psbt::{Psbt, PsbtVersion};
+
+use super::{penalty, Error};
+use crate::automata::{Event, StateMachine};
+use crate::bus::{BusMsg, ChannelBackup, CtlMsg, TxSpending};
+use crate::channeld::commitment::{has_anchors, tweak, CommitmentKeys};
+use crate::channeld::runtime::Runtime;
+use crate::rpc::ServiceId;
+use crate::{Endpoints, Responder};
+
+/// Weight of the witness spending P2WPKH `to_remote` output: a signature and a public key
+const P2WPKH_WITNESS_WEIGHT: u64 = 109;
+
+/// Weight of the witness spending `to_remote` output of the anchor channels: a signature and the
+/// witness script.
+const TO_REMOTE_V2_WITNESS_WEIGHT: u64 = 113;
+
+/// Weight of the witness spending HTLC output offered by the remote peer with the payment
+/// preimage, without the witness script: a signature and the preimage.
+const HTLC_PREIMAGE_WITNESS_WEIGHT: u64 = 109;
+
+/// Weight of the witness spending expired HTLC output offered by the local node, without the
+/// witness script: a signature and an empty element selecting the timeout branch.
+const HTLC_TIMEOUT_WITNESS_WEIGHT: u64 = 77;
+
+/// Workflow recovering funds from a channel restored from the static channel backup
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+pub enum ChannelRecover {
+    /// awaiting remote peer to reestablish the channel
+    #[display("REESTABLISHING")]
+    Reestablishing,
+
+    /// remote peer is asked to close the channel, awaiting its commitment transaction
+    #[display("AWAITING")]
+    Awaiting,
+
+    /// awaiting lnpd to provide a script from the funding wallet for the sweep output
+    #[display("PREPARING")]
+    Preparing,
+
+    /// signing transaction sweeping local output of the remote commitment transaction
+    #[display("SWEEPING")]
+    Sweeping,
+
+    /// sweep transaction is published, awaiting it to be mined
+    #[display("SWEPT")]
+    Swept,
+
+    /// remote commitment transaction is published, awaiting it to be mined, since `to_remote`
+    /// and HTLC outputs of the anchor channels can be spent only one block after that
+    #[display("MATURING")]
+    Maturing,
+
+    /// awaiting HTLCs offered by the local node to expire, so they can be reclaimed from the
+    /// remote commitment transaction
+    #[display("EXPIRING")]
+    Expiring,
+
+    /// signing transaction reclaiming expired HTLCs from the remote commitment transaction
+    #[display("RECLAIMING")]
+    Reclaiming,
+
+    /// reclaiming transaction is published, awaiting it to be mined
+    #[display("RECLAIMED")]
+    Reclaimed,
+}
+
+impl StateMachine<BusMsg, Runtime> for ChannelRecover {
+    type Error = Error;
+
+    fn next(
+        self,
+        event: Event<BusMsg>,
+        runtime: &mut Runtime,
+    ) -> Result<Option<Self>, Self::Error> {
+        let channel_id = runtime.state.channel.active_channel_id();
+        debug!("ChannelRecover {:#} received {} event", channel_id, event.message);
+        let state = match self {
+            ChannelRecover::Reestablishing | ChannelRecover::Awaiting => {
+                complete_awaiting(self, event, runtime).map(Some)
+            }
+            ChannelRecover::Maturing => complete_maturing(event, runtime),
+            ChannelRecover::Preparing => complete_preparing(event, runtime).map(Some),
+            ChannelRecover::Sweeping | ChannelRecover::Reclaiming => {
+                complete_sweeping(self, event, runtime).map(Some)
+            }
+            ChannelRecover::Expiring => complete_expiring(event, runtime).map(Some),
+            ChannelRecover::Swept | ChannelRecover::Reclaimed => {
+                complete_swept(self, event, runtime)
+            }
+        }?;
+        let state = match state {
+            Some(state) => state,
+            None => {
+                info!("ChannelRecover {:#} has completed its work", channel_id);
+                return Ok(None);
+            }
+        };
+        info!("ChannelRecover {:#} switched to {} state", channel_id, state);
+        Ok(Some(state))
+    }
+}
+
+impl ChannelRecover {
+    /// Computes channel lifecycle stage for the current channel recovery workflow stage
+    pub fn lifecycle(&self) -> Lifecycle {
+        match self {
+            ChannelRecover::Reestablishing | ChannelRecover::Awaiting => Lifecycle::Reestablishing,
+            ChannelRecover::Maturing
+            | ChannelRecover::Preparing
+            | ChannelRecover::Sweeping
+            | ChannelRecover::Swept
+            | ChannelRecover::Expiring
+            | ChannelRecover::Reclaiming
+            | ChannelRecover::Reclaimed => Lifecycle::Closed,
+        }
+    }
+}
+
+// State transitions:
+
+impl ChannelRecover {
+    /// Constructs channel recovery state machine for the channel restored from the static backup
+    pub fn with(
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+        backup: ChannelBackup,
+    ) -> Result<ChannelRecover, Error> {
+        info!(
+            "{} channel {} with {} from the static backup",
+            "Recovering".announce(),
+            backup.channel_id.announcer(),
+            backup.remote_id
+        );
+        runtime.state.restore(&backup);
+        penalty::watch_funding(runtime, endpoints)?;
+
+        // If the remote peer is not connected yet, it will send us `channel_reestablish` once the
+        // connection is established, and we will reply with ours
+        let reestablish = data_loss_reestablish(runtime)?;
+        runtime.send_p2p(endpoints, LnMsg::ChannelReestablish(reestablish))?;
+        Ok(ChannelRecover::Reestablishing)
+    }
+
+    /// Processes remote transaction spending channel funding output, which must be the latest
+    /// remote commitment transaction. Returns `None` if the transaction has no output belonging
+    /// to the local node, so there is nothing to recover.
+    pub fn spent(
+        self,
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+        spending: &TxSpending,
+    ) -> Result<Option<ChannelRecover>, Error> {
+        if !matches!(self, ChannelRecover::Reestablishing | ChannelRecover::Awaiting) {
+            // The transaction is already being swept
+            return Ok(Some(self));
+        }
+        info!("Remote peer has closed channel with commitment transaction {}", spending.tx.txid());
+        ChannelRecover::sweep_remote(runtime, endpoints, spending)
+    }
+
+    /// Starts sweeping local and HTLC outputs of the remote commitment transaction spending
+    /// channel funding output. Returns `None` if the transaction has no outputs which can be
+    /// claimed by the local node, so there is nothing to sweep.
+    pub fn sweep_remote(
+        runtime: &mut Runtime,
+        endpoints: &mut Endpoints,
+        spending: &TxSpending,
+    ) -> Result<Option<ChannelRecover>, Error> {
+        let txid = spending.tx.txid();
+        runtime.state.closing.txid = Some(txid);
+        runtime.state.closing.remote_tx = Some(spending.tx.clone());
+        runtime.state.set_stage(Lifecycle::Closed);
+        let output = local_output(runtime, &spending.tx);
+        let htlcs = htlc_outputs(runtime, &spending.tx);
+        if output.is_none() && htlcs.is_empty() {
+            warn!(
+                "Commitment transaction {} has no outputs belonging to the local node, so there \
+                 are no funds to recover",
+                txid
+            );
+            return Ok(None);
+        }
+
+        let anchors = has_anchors(runtime.state.channel.constructor().common_params().channel_type);
+        let csv_locked = matches!(output, Some(LocalOutput { witness_script: Some(_), .. }))
+            || (anchors && !htlcs.is_empty());
+        if csv_locked && spending.block_pos.is_none() {
+            debug!("Waiting for commitment transaction {} to be mined", txid);
+            runtime.send_ctl(endpoints, ServiceId::Watch, CtlMsg::Track { txid, depth: 1 })?;
+            return Ok(Some(ChannelRecover::Maturing));
+        }
+        start_sweep(runtime, endpoints)
+    }
+
+    /// Construct information message for error and client reporting
+    pub fn info_message(&self, channel_id: ActiveChannelId) -> String {
+        match self {
+            ChannelRecover::Reestablishing => format!(
+                "{} remote peer to reestablish restored channel {:#}",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+            ChannelRecover::Awaiting => format!(
+                "{} remote peer to close restored channel {:#}",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+            ChannelRecover::Maturing => format!(
+                "{} remote commitment transaction for channel {:#} to be mined",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+            ChannelRecover::Preparing => format!(
+                "{} to sweep funds from channel {:#}",
+                "Preparing".announce(),
+                channel_id.announcer()
+            ),
+            ChannelRecover::Sweeping => format!(
+                "{} sweep transaction locally for channel {:#}",
+                "Signing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelRecover::Swept => format!(
+                "{} sweep transaction for channel {:#} to be mined",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+            ChannelRecover::Expiring => format!(
+                "{} HTLCs offered to channel {:#} to expire",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+            ChannelRecover::Reclaiming => format!(
+                "{} transaction reclaiming expired HTLCs locally for channel {:#}",
+                "Signing".announcer(),
+                channel_id.announcer()
+            ),
+            ChannelRecover::Reclaimed => format!(
+                "{} transaction reclaiming expired HTLCs for channel {:#} to be mined",
+                "Awaiting".announce(),
+                channel_id.announcer()
+            ),
+        }
+    }
+}
+
+fn complete_awaiting(
+    state: ChannelRecover,
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<ChannelRecover, Error> {
+    match event.message {
+        // The remote peer reestablishes the channel each time it reconnects, so we repeat our
+        // request to close the channel until its commitment transaction is published
+        BusMsg::Bolt(LnMsg::ChannelReestablish(remote)) => {
+            request_closing(runtime, event.endpoints, &remote)?;
+            Ok(ChannelRecover::Awaiting)
+        }
+        // The remote peer is not connected; we will proceed once it connects
+        BusMsg::Ctl(CtlMsg::EsbError { .. }) => Ok(state),
+        wrong_msg => {
+            Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Reestablishing, event.source))
+        }
+    }
+}
+
+fn complete_maturing(
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<Option<ChannelRecover>, Error> {
+    let commitment_txid = runtime.state.closing.txid;
+    match event.message {
+        BusMsg::Ctl(CtlMsg::TxFound(status)) if Some(status.txid) == commitment_txid => {
+            debug!("Commitment transaction {} is mined", status.txid);
+            start_sweep(runtime, event.endpoints)
+        }
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Closed, event.source)),
+    }
+}
+
+fn complete_preparing(
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<ChannelRecover, Error> {
+    match event.message {
+        BusMsg::Ctl(CtlMsg::PayoutScript(script)) => {
+            // The script is reused for reclaiming expired HTLCs
+            runtime.state.closing.local_script = Some(script.clone());
+            sign_sweep(runtime, event.endpoints, script)
+        }
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Closed, event.source)),
+    }
+}
+
+fn complete_expiring(event: Event<BusMsg>, runtime: &mut Runtime) -> Result<ChannelRecover, Error> {
+    match event.message {
+        BusMsg::Ctl(CtlMsg::BlockHeight(height)) if reclaim_height(runtime) <= Some(height) => {
+            debug!("HTLCs offered by the local node have expired at block {}", height);
+            prepare_sweep(runtime, event.endpoints)
+        }
+        BusMsg::Ctl(CtlMsg::BlockHeight(_)) => Ok(ChannelRecover::Expiring),
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Closed, event.source)),
+    }
+}
+
+fn complete_sweeping(
+    state: ChannelRecover,
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<ChannelRecover, Error> {
+    let mut sweep_psbt = match event.message {
+        BusMsg::Ctl(CtlMsg::Signed(psbt)) => psbt,
+        wrong_msg => {
+            return Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Closed, event.source))
+        }
+    };
+    finalize_claim(runtime, &mut sweep_psbt)?;
+
+    let txid = sweep_psbt.to_txid();
+    debug!("Publishing sweep transaction {}", txid);
+    trace!("Sweep transaction: {:#?}", sweep_psbt);
+    runtime.state.closing.sweep_txid = Some(txid);
+    runtime.send_ctl(event.endpoints, ServiceId::LnpBroker, CtlMsg::PublishTx(sweep_psbt))?;
+    runtime.send_ctl(event.endpoints, ServiceId::Watch, CtlMsg::Track { txid, depth: 1 })?;
+    Ok(match state {
+        ChannelRecover::Reclaiming => ChannelRecover::Reclaimed,
+        _ => ChannelRecover::Swept,
+    })
+}
+
+fn complete_swept(
+    state: ChannelRecover,
+    event: Event<BusMsg>,
+    runtime: &mut Runtime,
+) -> Result<Option<ChannelRecover>, Error> {
+    let sweep_txid = runtime.state.closing.sweep_txid;
+    match event.message {
+        BusMsg::Ctl(CtlMsg::TxFound(status)) if Some(status.txid) == sweep_txid => {
+            info!(
+                "Funds from channel {} are returned to the funding wallet with transaction {}",
+                runtime.state.channel.active_channel_id(),
+                status.txid
+            );
+            if state == ChannelRecover::Swept {
+                return Ok(start_expiring(runtime));
+            }
+            Ok(None)
+        }
+        // Expired HTLCs may still be claimed by the remote peer with the payment preimage
+        BusMsg::Ctl(CtlMsg::Error { error, .. }) if state == ChannelRecover::Reclaimed => {
+            warn!("Unable to reclaim expired HTLCs: {}", error);
+            Ok(None)
+        }
+        BusMsg::Ctl(CtlMsg::Error { error, .. }) => Err(Error::ClosingUnpublished(error)),
+        wrong_msg => Err(Error::UnexpectedMessage(wrong_msg, Lifecycle::Closed, event.source)),
+    }
+}
+
+/// Composes `channel_reestablish` message of a node which has lost its channel state: it claims
+/// that no commitments were received and no secrets were revealed by the remote peer, which
+/// makes the remote peer to detect that our state is outdated
+fn data_loss_reestablish(runtime: &Runtime) -> Result<ChannelReestablish, Error> {
+    let local_keys = runtime.state.channel.constructor().local_keys();
+    Ok(ChannelReestablish {
+        channel_id: runtime.state.channel.try_channel_id()?,
+        next_commitment_number: 1,
+        next_revocation_number: 0,
+        your_last_per_commitment_secret: Slice32::from_inner([0u8; 32]),
+        my_current_per_commitment_point: local_keys.first_per_commitment_point.key,
+    })
+}
+
+/// Replies to the remote `channel_reestablish` with our data loss reestablishment and asks the
+/// remote peer to close the channel.
+///
+/// The remote peer reports its current per-commitment point, which is required to detect our
+/// output in its commitment transaction unless the channel uses static remote key.
+fn request_closing(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    remote: &ChannelReestablish,
+) -> Result<(), Error> {
+    runtime.state.outdated_point = Some(remote.my_current_per_commitment_point);
+    let reestablish = data_loss_reestablish(runtime)?;
+    runtime.send_p2p(endpoints, LnMsg::ChannelReestablish(reestablish))?;
+
+    info!("Asking remote peer to close channel {}", reestablish.channel_id);
+    let message = bolt::Error {
+        channel_id: reestablish.channel_id,
+        data: Error::StateLost.to_string().into_bytes(),
+    };
+    runtime.send_p2p(endpoints, LnMsg::Error(message))?;
+    Ok(())
+}
+
+/// Starts sweeping `to_remote` output together with HTLC outputs claimed with payment preimages,
+/// or awaits HTLCs offered by the local node to expire if there is nothing to sweep before that.
+/// Returns `None` if there is nothing to sweep at all.
+fn start_sweep(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+) -> Result<Option<ChannelRecover>, Error> {
+    if has_sweep_outputs(runtime) {
+        return prepare_sweep(runtime, endpoints).map(Some);
+    }
+    Ok(start_expiring(runtime))
+}
+
+/// Awaits HTLCs offered by the local node to expire, if the remote commitment transaction has
+/// any of them
+fn start_expiring(runtime: &Runtime) -> Option<ChannelRecover> {
+    let height = reclaim_height(runtime)?;
+    debug!("Awaiting HTLCs offered by the local node to expire at block {}", height);
+    Some(ChannelRecover::Expiring)
+}
+
+/// Requests lnpd to provide a script for the sweep output, unless the local node has committed to
+/// the shutdown script or the script is already provided
+fn prepare_sweep(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+) -> Result<ChannelRecover, Error> {
+    if let Some(script) = runtime
+        .state
+        .channel
+        .constructor()
+        .local_keys()
+        .shutdown_scriptpubkey
+        .clone()
+        .or_else(|| runtime.state.closing.local_script.clone())
+    {
+        return sign_sweep(runtime, endpoints, script);
+    }
+    runtime.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::GetPayoutScript)?;
+    Ok(ChannelRecover::Preparing)
+}
+
+/// Signs either the transaction sweeping `to_remote` and HTLC outputs claimed with payment
+/// preimages or, once it is mined, the transaction reclaiming expired HTLCs
+fn sign_sweep(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    script: PubkeyScript,
+) -> Result<ChannelRecover, Error> {
+    if runtime.state.closing.sweep_txid.is_some() || !has_sweep_outputs(runtime) {
+        let reclaim_psbt = reclaim_psbt(runtime, script)?;
+        debug!("Signing transaction {} reclaiming expired HTLCs", reclaim_psbt.to_txid());
+        runtime.send_ctl(endpoints, ServiceId::Signer, CtlMsg::Sign(reclaim_psbt))?;
+        return Ok(ChannelRecover::Reclaiming);
+    }
+    let sweep_psbt = sweep_psbt(runtime, script)?;
+    debug!("Signing sweep transaction {}", sweep_psbt.to_txid());
+    runtime.send_ctl(endpoints, ServiceId::Signer, CtlMsg::Sign(sweep_psbt))?;
+    Ok(ChannelRecover::Sweeping)
+}
+
+/// Detects whether the remote commitment transaction has `to_remote` output or HTLC outputs
+/// claimed with payment preimages, which are swept right away
+fn has_sweep_outputs(runtime: &Runtime) -> bool {
+    let commitment_tx = remote_commitment(runtime).expect("remote commitment transaction is known");
+    local_output(runtime, commitment_tx).is_some() || !preimage_htlcs(runtime).is_empty()
+}
+
+/// Output of the remote commitment transaction paying to the local node
+struct LocalOutput {
+    vout: usize,
+    txout: TxOut,
+    /// Key locking the output
+    pubkey: PublicKey,
+    /// Tweak applied to the payment basepoint to get the output key
+    tweak: Option<sha256::Hash>,
+    /// Witness script of the `to_remote` output of the anchor channels
+    witness_script: Option<WitnessScript>,
+}
+
+fn remote_commitment(runtime: &Runtime) -> Option<&Transaction> {
+    runtime.state.closing.remote_tx.as_ref()
+}
+
+/// Detects output of the remote commitment transaction paying to the local node.
+///
+/// Since the exact set of the channel options negotiated with the remote peer is lost, we check
+/// `to_remote` output for all channel types: with the payment key tweaked by the remote
+/// per-commitment point, with the static payment key and with the anchor outputs script.
+///
+/// For the restored or outdated channel the per-commitment point is reported by the remote peer
+/// during channel reestablishment. For an operational channel the remote peer may publish either
+/// of its two unrevoked commitments, so we try per-commitment points of both of them.
+fn local_output(runtime: &Runtime, tx: &Transaction) -> Option<LocalOutput> {
+    let payment_basepoint = runtime.state.channel.constructor().local_keys().payment_basepoint.key;
+
+    let mut candidates = vec![
+        (PubkeyScript::ln_to_remote_v1(0, payment_basepoint), payment_basepoint, None, None),
+        (
+            PubkeyScript::ln_to_remote_v2(0, payment_basepoint),
+            payment_basepoint,
+            None,
+            Some(WitnessScript::ln_to_remote_v2(0, payment_basepoint)),
+        ),
+    ];
+    let commitments = &runtime.state.commitments;
+    let per_commitment_points =
+        [runtime.state.outdated_point, commitments.remote_point, commitments.remote_next_point];
+    for per_commitment_point in per_commitment_points.into_iter().flatten() {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&per_commitment_point.serialize());
+        engine.input(&payment_basepoint.serialize());
+        let tweak = sha256::Hash::from_engine(engine);
+        let pubkey = payment_basepoint
+            .add_exp_tweak(
+                SECP256K1,
+                &Scalar::from_be_bytes(tweak.into_inner()).expect("negligible probability"),
+            )
+            .expect("negligible probability");
+        candidates.push((PubkeyScript::ln_to_remote_v1(0, pubkey), pubkey, Some(tweak), None));
+    }
+
+    tx.output.iter().enumerate().find_map(|(vout, txout)| {
+        candidates.iter().find(|(script, ..)| script.as_inner() == &txout.script_pubkey).map(
+            |(_, pubkey, tweak, witness_script)| LocalOutput {
+                vout,
+                txout: txout.clone(),
+                pubkey: *pubkey,
+                tweak: *tweak,
+                witness_script: witness_script.clone(),
+            },
+        )
+    })
+}
+
+/// HTLC output of the remote commitment transaction claimed by the local node
+struct HtlcOutput {
+    outpoint: OutPoint,
+    txout: TxOut,
+    witness_script: WitnessScript,
+    /// Preimage of the HTLC offered by the remote peer; HTLCs offered by the local node are
+    /// claimed after their expiry instead
+    preimage: Option<HashPreimage>,
+    cltv_expiry: u32,
+    /// Tweak applied to the HTLC basepoint to get the local HTLC key
+    tweak: Slice32,
+}
+
+/// Detects HTLC outputs of the remote commitment transaction which can be claimed by the local
+/// node: HTLCs offered by the remote peer, for which the local node knows the payment preimage,
+/// and HTLCs offered by the local node, which are claimed once they expire.
+///
+/// The remote peer may publish either of its two unrevoked commitments, which are distinguished
+/// by the commitment number. For the channel restored from the static backup HTLCs are unknown.
+fn htlc_outputs(runtime: &Runtime, tx: &Transaction) -> Vec<HtlcOutput> {
+    let commitments = &runtime.state.commitments;
+    let number = penalty::commitment_number(runtime, tx);
+    let spec_point = if Some(commitments.remote.number) == number {
+        commitments.remote_point.map(|point| (&commitments.remote, point))
+    } else {
+        commitments
+            .remote_next
+            .as_ref()
+            .filter(|spec| Some(spec.number) == number)
+            .zip(commitments.remote_next_point)
+    };
+    let (spec, per_commitment_point) = match spec_point {
+        Some(spec_point) => spec_point,
+        None => return vec![],
+    };
+
+    let mut state = ChannelState::dumb_default();
+    runtime.state.channel.store_state(&mut state);
+    let anchors = has_anchors(state.common_params.channel_type);
+    let keys = CommitmentKeys::with_point(&state, true, per_commitment_point);
+    let htlc_basepoint = state.local_keys.htlc_basepoint.key;
+    let tweak = Slice32::from(tweak(per_commitment_point, htlc_basepoint).to_be_bytes());
+
+    // HTLCs received by the local node are offered by the commitment owner
+    let mut htlcs = spec
+        .received
+        .iter()
+        .map(|htlc| (true, htlc))
+        .chain(spec.offered.iter().map(|htlc| (false, htlc)))
+        .map(|(offered, htlc)| {
+            let script = keys.htlc_script(offered, &htlc.payment_hash, htlc.cltv_expiry, anchors);
+            (script, offered, htlc)
+        })
+        .collect::<Vec<_>>();
+    let txid = tx.txid();
+    tx.output
+        .iter()
+        .enumerate()
+        .filter_map(|(vout, txout)| {
+            let index = htlcs
+                .iter()
+                .position(|(script, ..)| script.to_p2wsh().as_inner() == &txout.script_pubkey)?;
+            let (witness_script, offered, htlc) = htlcs.remove(index);
+            let preimage = match offered {
+                true => match runtime.state.preimages.get(&htlc.payment_hash) {
+                    Some(preimage) => Some(*preimage),
+                    None => {
+                        warn!(
+                            "HTLC {} for {} msat can't be claimed: payment preimage is unknown",
+                            htlc.htlc_id, htlc.amount_msat
+                        );
+                        return None;
+                    }
+                },
+                false => None,
+            };
+            Some(HtlcOutput {
+                outpoint: OutPoint::new(txid, vout as u32),
+                txout: txout.clone(),
+                witness_script,
+                preimage,
+                cltv_expiry: htlc.cltv_expiry,
+                tweak,
+            })
+        })
+        .collect()
+}
+
+/// HTLC outputs of the remote commitment transaction claimed with payment preimages, which are
+/// swept together with `to_remote` output
+fn preimage_htlcs(runtime: &Runtime) -> Vec<HtlcOutput> {
+    let mut htlcs =
+        remote_commitment(runtime).map(|tx| htlc_outputs(runtime, tx)).unwrap_or_default();
+    htlcs.retain(|htlc| htlc.preimage.is_some());
+    htlcs
+}
+
+/// HTLC outputs of the remote commitment transaction offered by the local node, which are
+/// reclaimed once all of them expire
+fn expiring_htlcs(runtime: &Runtime) -> Vec<HtlcOutput> {
+    let mut htlcs =
+        remote_commitment(runtime).map(|tx| htlc_outputs(runtime, tx)).unwrap_or_default();
+    htlcs.retain(|htlc| htlc.preimage.is_none());
+    htlcs
+}
+
+/// Block height after which all HTLCs offered by the local node can be reclaimed
+fn reclaim_height(runtime: &Runtime) -> Option<u32> {
+    expiring_htlcs(runtime).iter().map(|htlc| htlc.cltv_expiry).max()
+}
+
+/// Constructs transaction sweeping local output of the remote commitment transaction together
+/// with HTLC outputs claimed with payment preimages to the provided `script`
+fn sweep_psbt(runtime: &Runtime, script: PubkeyScript) -> Result<Psbt, Error> {
+    let commitment_tx = remote_commitment(runtime).expect("remote commitment transaction is known");
+    let output = local_output(runtime, commitment_tx);
+    claim_psbt(runtime, script, output, preimage_htlcs(runtime), 0)
+}
+
+/// Constructs transaction reclaiming expired HTLCs offered by the local node from the remote
+/// commitment transaction to the provided `script`
+fn reclaim_psbt(runtime: &Runtime, script: PubkeyScript) -> Result<Psbt, Error> {
+    let lock_time = reclaim_height(runtime).unwrap_or_default();
+    claim_psbt(runtime, script, None, expiring_htlcs(runtime), lock_time)
+}
+
+/// Constructs transaction spending outputs of the remote commitment transaction to the provided
+/// `script`. `to_remote` output key is provided to signd as a payment basepoint and HTLC keys as
+/// a HTLC basepoint, both with an optional tweak from the remote per-commitment point.
+fn claim_psbt(
+    runtime: &Runtime,
+    script: PubkeyScript,
+    output: Option<LocalOutput>,
+    htlcs: Vec<HtlcOutput>,
+    lock_time: u32,
+) -> Result<Psbt, Error> {
+    let commitment_tx = remote_commitment(runtime).expect("remote commitment transaction is known");
+    let commitment_txid = commitment_tx.txid();
+    let core = runtime.state.channel.constructor();
+    let payment_basepoint = &core.local_keys().payment_basepoint;
+    let htlc_basepoint = &core.local_keys().htlc_basepoint;
+    // HTLC outputs of anchor channels are locked for one block
+    let htlc_sequence = match has_anchors(core.common_params().channel_type) {
+        true => Sequence::from_height(1),
+        false => Sequence::ENABLE_RBF_NO_LOCKTIME,
+    };
+
+    let mut input = vec![];
+    let mut witness_weight = 0;
+    if let Some(ref output) = output {
+        // `to_remote` output of anchor channels is locked for one block
+        let (sequence, weight) = match output.witness_script {
+            Some(_) => (Sequence::from_height(1), TO_REMOTE_V2_WITNESS_WEIGHT),
+            None => (Sequence::ENABLE_RBF_NO_LOCKTIME, P2WPKH_WITNESS_WEIGHT),
+        };
+        input.push(TxIn {
+            previous_output: OutPoint::new(commitment_txid, output.vout as u32),
+            script_sig: empty!(),
+            sequence,
+            witness: empty!(),
+        });
+        witness_weight += weight;
+    }
+    for htlc in &htlcs {
+        input.push(TxIn {
+            previous_output: htlc.outpoint,
+            script_sig: empty!(),
+            sequence: htlc_sequence,
+            witness: empty!(),
+        });
+        witness_weight += match htlc.preimage {
+            Some(_) => HTLC_PREIMAGE_WITNESS_WEIGHT,
+            None => HTLC_TIMEOUT_WITNESS_WEIGHT,
+        } + htlc.witness_script.len() as u64;
+    }
+
+    let amount = output.iter().map(|output| output.txout.value).sum::<u64>()
+        + htlcs.iter().map(|htlc| htlc.txout.value).sum::<u64>();
+    let mut sweep_tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(lock_time),
+        input,
+        output: vec![TxOut { value: amount, script_pubkey: script.into() }],
+    };
+    let feerate_per_kw = runtime.fee_estimate.unwrap_or(core.common_params().feerate_per_kw);
+    let weight = sweep_tx.weight() as u64 + witness_weight;
+    let fee = weight * feerate_per_kw as u64 / 1000;
+    sweep_tx.output[0].value = amount.checked_sub(fee).ok_or(Error::ClosingFee(fee))?;
+
+    let mut psbt = Psbt::with(sweep_tx, PsbtVersion::V0)
+        .expect("sweep transaction has empty script_sig and witness");
+    let mut inputs = psbt.inputs.iter_mut();
+    if let Some(output) = output {
+        let input = inputs.next().expect("to_remote input");
+        input.witness_utxo = Some(output.txout);
+        input.witness_script = output.witness_script;
+        input.bip32_derivation = payment_basepoint.to_bip32_derivation_map();
+        if let Some(tweak) = output.tweak {
+            input.set_p2c_tweak(payment_basepoint.key, Slice32::from(tweak.into_inner()));
+        }
+    }
+    for (input, htlc) in inputs.zip(htlcs) {
+        input.witness_utxo = Some(htlc.txout);
+        input.witness_script = Some(htlc.witness_script);
+        input.bip32_derivation = htlc_basepoint.to_bip32_derivation_map();
+        input.set_p2c_tweak(htlc_basepoint.key, htlc.tweak);
+    }
+    Ok(psbt)
+}
+
+/// Finalizes inputs of the signed transaction spending outputs of the remote commitment
+/// transaction. Signd adds signatures under the basepoints even if they were tweaked, and
+/// neither `to_remote` output of anchor channels nor HTLC outputs are miniscripts.
+fn finalize_claim(runtime: &Runtime, psbt: &mut Psbt) -> Result<(), Error> {
+    let commitment_tx = remote_commitment(runtime).expect("remote commitment transaction is known");
+    let output = local_output(runtime, commitment_tx);
+    let htlcs = htlc_outputs(runtime, commitment_tx);
+    let local_keys = runtime.state.channel.constructor().local_keys();
+    let payment_basepoint = local_keys.payment_basepoint.key;
+    let htlc_basepoint = local_keys.htlc_basepoint.key;
+
+    let outpoints = psbt.to_unsigned_tx().input.into_iter().map(|txin| txin.previous_output);
+    for (input, outpoint) in psbt.inputs.iter_mut().zip(outpoints) {
+        let witness = match htlcs.iter().find(|htlc| htlc.outpoint == outpoint) {
+            Some(htlc) => {
+                let signature = input
+                    .partial_sigs
+                    .get(&bitcoin::PublicKey::new(htlc_basepoint))
+                    .ok_or(Error::HtlcPsbtUnsigned(htlc_basepoint))?;
+                // Expired HTLCs select the timeout branch with an empty element
+                let preimage = htlc
+                    .preimage
+                    .map(|preimage| AsRef::<[u8]>::as_ref(&preimage).to_vec())
+                    .unwrap_or_default();
+                vec![signature.to_vec(), preimage, htlc.witness_script.to_bytes()]
+            }
+            None => {
+                let output = output.as_ref().expect("sweep transaction spends to_remote output");
+                let signature = input
+                    .partial_sigs
+                    .get(&bitcoin::PublicKey::new(payment_basepoint))
+                    .ok_or(Error::RecoveryPsbtUnsigned(payment_basepoint))?;
+                let last_item = match output.witness_script {
+                    Some(ref witness_script) => witness_script.to_bytes(),
+                    None => output.pubkey.serialize().to_vec(),
+                };
+                vec![signature.to_vec(), last_item]
+            }
+        };
+        input.final_script_witness = Some(Witness::from_vec(witness));
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Channel reestablishment after reconnection with the remote peer according to BOLT-2: detection
+//! and retransmission of the messages lost during disconnection and protection from using
+//! outdated channel state.
+
+use amplify::{Slice32, Wrapper};
+use lnp::p2p::bolt::{self, ChannelReestablish, Messages as LnMsg};
+
+use super::active::{self, ChannelActive};
+use super::{ChannelStateMachine, Error};
+use crate::channeld::runtime::Runtime;
+use crate::channeld::state::COMMITMENT_NUMBER_LIMIT;
+use crate::Endpoints;
+
+/// Compares channel state reported by the remote peer with the local one, retransmitting updates,
+/// commitment signature and revocation which the remote peer has not received. Returns the state
+/// of the active channel workflow to continue with.
+pub(super) fn complete(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    remote: &ChannelReestablish,
+) -> Result<ChannelActive, Error> {
+    // Remote updates which were not signed by the remote peer will be retransmitted by it
+    runtime.state.commitments.forget_uncommitted();
+    let local = runtime.state.compose_reestablish(remote)?;
+    runtime.send_p2p(endpoints, LnMsg::ChannelReestablish(local))?;
+
+    let local_number = runtime.state.commitments.local.number;
+    if remote.next_revocation_number >= COMMITMENT_NUMBER_LIMIT {
+        return Err(Error::InvalidRevocationNumber {
+            expected: local_number,
+            received: remote.next_revocation_number,
+        });
+    }
+
+    let expected_secret = match remote.next_revocation_number {
+        0 => [0u8; 32],
+        number => runtime.state.per_commitment_secret(number - 1).secret_bytes(),
+    };
+    if remote.your_last_per_commitment_secret != Slice32::from_inner(expected_secret) {
+        return Err(Error::InvalidLastSecret(remote.next_revocation_number.saturating_sub(1)));
+    }
+
+    let revoke_and_ack = match remote.next_revocation_number {
+        number if number == local_number => None,
+        // Our last `revoke_and_ack` was lost
+        number if number + 1 == local_number => Some(runtime.state.compose_revocation(number)?),
+        number if number > local_number => return fail_outdated(runtime, endpoints, remote),
+        number => {
+            return Err(Error::InvalidRevocationNumber { expected: local_number, received: number })
+        }
+    };
+
+    let commitments = &runtime.state.commitments;
+    let expected_commitment =
+        commitments.remote_next.as_ref().unwrap_or(&commitments.remote).number;
+    let resend_commitment = match remote.next_commitment_number {
+        number if number == expected_commitment + 1 => false,
+        // Our last `commitment_signed` was lost
+        number if number == expected_commitment && commitments.remote_next.is_some() => true,
+        number => {
+            return Err(Error::InvalidCommitmentNumber {
+                expected: expected_commitment + 1,
+                received: number,
+            })
+        }
+    };
+    if resend_commitment && commitments.sent_commitment.is_none() {
+        // The remote commitment was not signed before disconnection, so we sign it anew, including
+        // all updates which were proposed since then
+        runtime.state.commitments.cancel_signing();
+    }
+
+    let commitments = &runtime.state.commitments;
+    let mut messages = vec![];
+    if let Some(commitment_signed) =
+        commitments.sent_commitment.as_ref().filter(|_| resend_commitment)
+    {
+        messages.extend(commitments.local_signed.iter().cloned().map(LnMsg::from));
+        messages.push(LnMsg::CommitmentSigned(commitment_signed.clone()));
+    }
+    // Revocation and commitment signature must be retransmitted in the same order as they were
+    // originally sent
+    match revoke_and_ack {
+        Some(revoke_and_ack) if commitments.revoked_last => {
+            messages.push(LnMsg::RevokeAndAck(revoke_and_ack))
+        }
+        Some(revoke_and_ack) => messages.insert(0, LnMsg::RevokeAndAck(revoke_and_ack)),
+        None => {}
+    }
+    messages.extend(commitments.local_proposed.iter().cloned().map(LnMsg::from));
+
+    debug!("Retransmitting {} messages lost during disconnection", messages.len());
+    for message in messages {
+        runtime.send_p2p(endpoints, message)?;
+    }
+
+    if runtime.state.commitments.remote_next.is_some() {
+        Ok(ChannelActive::Committed)
+    } else if runtime.state.commitments.needs_signing() {
+        active::sign_remote(runtime, endpoints)
+    } else {
+        Ok(ChannelActive::Ready)
+    }
+}
+
+/// Handles the case when the remote peer has proven that the local channel state is outdated.
+///
+/// We can't use the channel anymore and must not publish our latest commitment transaction, since
+/// the remote peer has a revocation secret for it. Instead, we persist the remote per-commitment
+/// point and ask the remote peer to close the channel.
+fn fail_outdated(
+    runtime: &mut Runtime,
+    endpoints: &mut Endpoints,
+    remote: &ChannelReestablish,
+) -> Result<ChannelActive, Error> {
+    let err = Error::DataLoss {
+        local: runtime.state.commitments.local.number,
+        remote: remote.next_revocation_number - 1,
+    };
+    error!("Channel state is outdated: {}", err);
+
+    // The channel is not operational anymore, and we have to persist this fact since the
+    // state is not saved by the state machine runtime on errors
+    runtime.state.outdated_point = Some(remote.my_current_per_commitment_point);
+    runtime.state.state_machine = ChannelStateMachine::Reestablishing;
+    runtime.save_state()?;
+
+    let message = bolt::Error {
+        channel_id: runtime.state.channel.try_channel_id()?,
+        data: err.to_string().into_bytes(),
+    };
+    runtime.send_p2p(endpoints, LnMsg::Error(message))?;
+    Err(err)
+}
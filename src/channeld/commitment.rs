@@ -0,0 +1,773 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Outputs of the commitment transactions and HTLC transactions spending them, constructed
+//! according to BOLT-3.
+
+use amplify::{DumbDefault, Slice32};
+use bitcoin::blockdata::opcodes::all::{
+    OP_CHECKMULTISIG, OP_CHECKSIG, OP_CLTV, OP_CSV, OP_DROP, OP_DUP, OP_ELSE, OP_ENDIF, OP_EQUAL,
+    OP_EQUALVERIFY, OP_HASH160, OP_IF, OP_NOTIF, OP_SIZE, OP_SWAP,
+};
+use bitcoin::blockdata::script;
+use bitcoin::hashes::{hash160, ripemd160, sha256, Hash, HashEngine};
+use bitcoin::psbt::PsbtSighashType;
+use bitcoin::secp256k1::ecdsa::Signature;
+use bitcoin::secp256k1::{self, Message, PublicKey, Scalar, SECP256K1};
+use bitcoin::util::sighash::SighashCache;
+use bitcoin::{EcdsaSighashType, OutPoint, PackedLockTime, Sequence, Transaction, TxIn, TxOut};
+use bitcoin_scripts::hlc::HashLock;
+use bitcoin_scripts::WitnessScript;
+use lnp::channel::bolt::{self, BoltExt, LocalPubkey, ScriptGenerators};
+use lnp::channel::tx_graph::TxGraph;
+use lnp::p2p::bolt::{ChannelType, Messages as LnMsg, UpdateAddHtlc};
+use lnp::{ChannelExtension, Extension};
+use wallet::psbt::{self, Psbt, PsbtVersion};
+
+use super::anchors::{ANCHOR_COMMITMENT_WEIGHT, ANCHOR_OUTPUT_VALUE, COMMITMENT_WEIGHT};
+
+/// Weight added to the commitment transaction by each of the HTLC outputs
+const HTLC_OUTPUT_WEIGHT: u64 = 172;
+
+/// Weight of HTLC-timeout transaction, used to compute its fee
+const HTLC_TIMEOUT_WEIGHT: u64 = 663;
+
+/// Weight of HTLC-success transaction, used to compute its fee
+const HTLC_SUCCESS_WEIGHT: u64 = 703;
+
+/// Weight of HTLC-timeout transaction of the channels with anchor outputs
+const ANCHOR_HTLC_TIMEOUT_WEIGHT: u64 = 666;
+
+/// Weight of HTLC-success transaction of the channels with anchor outputs
+const ANCHOR_HTLC_SUCCESS_WEIGHT: u64 = 706;
+
+/// Detects whether commitment transactions of the channel type have anchor outputs
+pub(super) fn has_anchors(channel_type: ChannelType) -> bool {
+    channel_type.has_anchor_outputs() || channel_type.has_anchors_zero_fee_htlc_tx()
+}
+
+/// Fee of the commitment transaction with the given number of untrimmed HTLC outputs, in
+/// satoshis, together with the values of the anchor outputs, if the channel has them. Both are
+/// paid by the channel funder.
+pub(super) fn commitment_fee(htlc_count: usize, feerate_per_kw: u32, anchors: bool) -> u64 {
+    let htlc_weight = htlc_count as u64 * HTLC_OUTPUT_WEIGHT;
+    let (weight, anchor_values) = match anchors {
+        true => (ANCHOR_COMMITMENT_WEIGHT, 2 * ANCHOR_OUTPUT_VALUE),
+        false => (COMMITMENT_WEIGHT, 0),
+    };
+    (weight + htlc_weight) * feerate_per_kw as u64 / 1000 + anchor_values
+}
+
+/// Base fee of the commitment transaction without HTLC outputs, in satoshis, as defined by
+/// BOLT-3. Anchor output values are not a part of the base fee.
+pub(super) fn base_fee(feerate_per_kw: u32, anchors: bool) -> u64 {
+    let weight = if anchors { ANCHOR_COMMITMENT_WEIGHT } else { COMMITMENT_WEIGHT };
+    weight * feerate_per_kw as u64 / 1000
+}
+
+/// Computes `SHA256(first || second)` used for tweaking the keys according to BOLT-3
+pub(super) fn tweak(first: PublicKey, second: PublicKey) -> Scalar {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&first.serialize());
+    engine.input(&second.serialize());
+    let tweak = sha256::Hash::from_engine(engine);
+    Scalar::from_be_bytes(tweak.into_inner()).expect("negligible probability")
+}
+
+/// Derives public key from the basepoint and per-commitment point according to BOLT-3
+pub(super) fn derive_pubkey(basepoint: PublicKey, per_commitment_point: PublicKey) -> PublicKey {
+    basepoint
+        .add_exp_tweak(SECP256K1, &tweak(per_commitment_point, basepoint))
+        .expect("negligible probability")
+}
+
+/// Derives revocation public key from the revocation basepoint of one peer and per-commitment
+/// point of the other peer according to BOLT-3
+pub(super) fn revocation_pubkey(
+    basepoint: PublicKey,
+    per_commitment_point: PublicKey,
+) -> PublicKey {
+    let tweaked_basepoint = basepoint
+        .mul_tweak(SECP256K1, &tweak(basepoint, per_commitment_point))
+        .expect("negligible probability");
+    let tweaked_point = per_commitment_point
+        .mul_tweak(SECP256K1, &tweak(per_commitment_point, basepoint))
+        .expect("negligible probability");
+    tweaked_basepoint.combine(&tweaked_point).expect("negligible probability")
+}
+
+/// Verifies signature of the first PSBT input, computing signature hash with the input sighash
+/// type
+pub(super) fn verify_input_sig(
+    psbt: &Psbt,
+    sig: &Signature,
+    pubkey: &PublicKey,
+) -> Result<(), secp256k1::Error> {
+    let input = &psbt.inputs[0];
+    let prevout =
+        input.witness_utxo.as_ref().expect("commitment transactions spend segwit outputs");
+    let witness_script =
+        input.witness_script.as_ref().expect("commitment transactions spend P2WSH outputs");
+    let sighash_type = input
+        .sighash_type
+        .and_then(|sighash_type| sighash_type.ecdsa_hash_ty().ok())
+        .unwrap_or(EcdsaSighashType::All);
+    let tx = psbt.to_unsigned_tx();
+    let sighash = SighashCache::new(&tx)
+        .segwit_signature_hash(0, witness_script, prevout.value, sighash_type)
+        .expect("commitment and HTLC transactions always have a single input");
+    let message = Message::from_slice(&sighash[..]).expect("sighash is always 32 bytes long");
+    SECP256K1.verify_ecdsa(&message, sig, pubkey)
+}
+
+/// Keys of a single commitment transaction from the point of view of its owner, i.e. the peer
+/// which is able to publish it
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(super) struct CommitmentKeys {
+    /// Per-commitment point of the commitment owner
+    pub per_commitment_point: PublicKey,
+
+    /// Key allowing the counterparty to claim the owner outputs once the commitment is revoked
+    pub revocation_pubkey: PublicKey,
+
+    /// Key of the owner for spending its outputs after `to_self_delay`
+    pub owner_delayed_pubkey: PublicKey,
+
+    /// HTLC key of the commitment owner
+    pub owner_htlc_pubkey: PublicKey,
+
+    /// HTLC key of the counterparty
+    pub counterparty_htlc_pubkey: PublicKey,
+
+    /// Key of the counterparty receiving its `to_remote` output
+    pub counterparty_payment_pubkey: PublicKey,
+}
+
+impl CommitmentKeys {
+    /// Derives keys of the local (or, with `as_remote_node` set, remote) commitment transaction
+    /// from the current per-commitment point of its owner
+    pub fn with(state: &bolt::ChannelState, as_remote_node: bool) -> CommitmentKeys {
+        let per_commitment_point = match as_remote_node {
+            false => state.local_per_commitment_point,
+            true => state.remote_per_commitment_point,
+        };
+        CommitmentKeys::with_point(state, as_remote_node, per_commitment_point)
+    }
+
+    /// Derives keys of the local (or, with `as_remote_node` set, remote) commitment transaction
+    /// with the given per-commitment point of its owner, which may belong to an older commitment
+    pub fn with_point(
+        state: &bolt::ChannelState,
+        as_remote_node: bool,
+        per_commitment_point: PublicKey,
+    ) -> CommitmentKeys {
+        let (local, remote) = (&state.local_keys, &state.remote_keys);
+        let (
+            revocation_basepoint,
+            delayed_basepoint,
+            owner_htlc_basepoint,
+            counterparty_htlc_basepoint,
+            payment_basepoint,
+        ) = match as_remote_node {
+            false => (
+                remote.revocation_basepoint,
+                local.delayed_payment_basepoint.key,
+                local.htlc_basepoint.key,
+                remote.htlc_basepoint,
+                remote.payment_basepoint,
+            ),
+            true => (
+                local.revocation_basepoint.key,
+                remote.delayed_payment_basepoint,
+                remote.htlc_basepoint,
+                local.htlc_basepoint.key,
+                local.payment_basepoint.key,
+            ),
+        };
+        let counterparty_payment_pubkey =
+            match state.common_params.channel_type.has_static_remotekey() {
+                true => payment_basepoint,
+                false => derive_pubkey(payment_basepoint, per_commitment_point),
+            };
+        CommitmentKeys {
+            per_commitment_point,
+            revocation_pubkey: revocation_pubkey(revocation_basepoint, per_commitment_point),
+            owner_delayed_pubkey: derive_pubkey(delayed_basepoint, per_commitment_point),
+            owner_htlc_pubkey: derive_pubkey(owner_htlc_basepoint, per_commitment_point),
+            counterparty_htlc_pubkey: derive_pubkey(
+                counterparty_htlc_basepoint,
+                per_commitment_point,
+            ),
+            counterparty_payment_pubkey,
+        }
+    }
+
+    /// Constructs witness script of the offered (from the commitment owner point of view) or
+    /// received HTLC output
+    pub fn htlc_script(
+        &self,
+        offered: bool,
+        payment_hash: &HashLock,
+        cltv_expiry: u32,
+        anchors: bool,
+    ) -> WitnessScript {
+        let revocation_hash = hash160::Hash::hash(&self.revocation_pubkey.serialize());
+        let payment_hash = ripemd160::Hash::hash(payment_hash.as_ref());
+        let owner_htlc_pubkey = bitcoin::PublicKey::new(self.owner_htlc_pubkey);
+        let counterparty_htlc_pubkey = bitcoin::PublicKey::new(self.counterparty_htlc_pubkey);
+
+        let builder = script::Builder::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(&revocation_hash[..])
+            .push_opcode(OP_EQUAL)
+            .push_opcode(OP_IF)
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_ELSE)
+            .push_key(&counterparty_htlc_pubkey)
+            .push_opcode(OP_SWAP)
+            .push_opcode(OP_SIZE)
+            .push_int(32)
+            .push_opcode(OP_EQUAL);
+        let builder = match offered {
+            true => builder
+                .push_opcode(OP_NOTIF)
+                .push_opcode(OP_DROP)
+                .push_int(2)
+                .push_opcode(OP_SWAP)
+                .push_key(&owner_htlc_pubkey)
+                .push_int(2)
+                .push_opcode(OP_CHECKMULTISIG)
+                .push_opcode(OP_ELSE)
+                .push_opcode(OP_HASH160)
+                .push_slice(&payment_hash[..])
+                .push_opcode(OP_EQUALVERIFY)
+                .push_opcode(OP_CHECKSIG)
+                .push_opcode(OP_ENDIF),
+            false => builder
+                .push_opcode(OP_IF)
+                .push_opcode(OP_HASH160)
+                .push_slice(&payment_hash[..])
+                .push_opcode(OP_EQUALVERIFY)
+                .push_int(2)
+                .push_opcode(OP_SWAP)
+                .push_key(&owner_htlc_pubkey)
+                .push_int(2)
+                .push_opcode(OP_CHECKMULTISIG)
+                .push_opcode(OP_ELSE)
+                .push_opcode(OP_DROP)
+                .push_int(cltv_expiry as i64)
+                .push_opcode(OP_CLTV)
+                .push_opcode(OP_DROP)
+                .push_opcode(OP_CHECKSIG)
+                .push_opcode(OP_ENDIF),
+        };
+        let builder = match anchors {
+            true => builder.push_int(1).push_opcode(OP_CSV).push_opcode(OP_DROP),
+            false => builder,
+        };
+        builder.push_opcode(OP_ENDIF).into_script().into()
+    }
+}
+
+/// Single commitment transaction from the point of view of its owner
+#[derive(Clone, Debug)]
+pub(super) struct Commitment {
+    /// Keys of the commitment transaction
+    pub keys: CommitmentKeys,
+
+    /// Amount belonging to the commitment owner
+    to_local_msat: u64,
+
+    /// Amount belonging to the counterparty
+    to_remote_msat: u64,
+
+    /// HTLCs offered by the commitment owner
+    offered: Vec<UpdateAddHtlc>,
+
+    /// HTLCs offered by the counterparty
+    received: Vec<UpdateAddHtlc>,
+
+    /// Fee rate of the commitment transaction, in satoshis per 1000 weight units
+    feerate_per_kw: u32,
+
+    /// Delay of the owner outputs, which is required by the counterparty
+    to_self_delay: u16,
+
+    /// Dust limit of the commitment owner
+    dust_limit_sat: u64,
+
+    /// Whether the commitment owner is the channel funder paying the commitment fee
+    funder: bool,
+
+    channel_type: ChannelType,
+
+    /// Local HTLC basepoint, used for signing HTLC transactions
+    htlc_basepoint: LocalPubkey,
+}
+
+impl Commitment {
+    /// Constructs local (or, with `as_remote_node` set, remote) commitment for the channel state
+    /// and HTLCs offered and received by the local node
+    pub fn with(
+        state: &bolt::ChannelState,
+        offered: &[UpdateAddHtlc],
+        received: &[UpdateAddHtlc],
+        as_remote_node: bool,
+    ) -> Commitment {
+        let local_amounts = (state.local_amount_msat, state.remote_amount_msat);
+        let local_htlcs = (offered.to_vec(), received.to_vec());
+        let ((to_local_msat, to_remote_msat), (offered, received), owner, counterparty) =
+            match as_remote_node {
+                false => (local_amounts, local_htlcs, &state.local_params, &state.remote_params),
+                true => (
+                    (local_amounts.1, local_amounts.0),
+                    (local_htlcs.1, local_htlcs.0),
+                    &state.remote_params,
+                    &state.local_params,
+                ),
+            };
+        Commitment {
+            keys: CommitmentKeys::with(state, as_remote_node),
+            to_local_msat,
+            to_remote_msat,
+            offered,
+            received,
+            feerate_per_kw: state.common_params.feerate_per_kw,
+            to_self_delay: counterparty.to_self_delay,
+            dust_limit_sat: owner.dust_limit_satoshis,
+            funder: state.direction.is_outbound() != as_remote_node,
+            channel_type: state.common_params.channel_type,
+            htlc_basepoint: state.local_keys.htlc_basepoint.clone(),
+        }
+    }
+
+    /// Fee of HTLC-timeout (for HTLCs offered by the commitment owner) or HTLC-success
+    /// transaction. HTLC transactions of zero-fee anchor channels pay their fee with extra inputs.
+    fn htlc_tx_fee(&self, offered: bool) -> u64 {
+        if self.channel_type.has_anchors_zero_fee_htlc_tx() {
+            return 0;
+        }
+        let weight = match (offered, self.channel_type.has_anchor_outputs()) {
+            (true, false) => HTLC_TIMEOUT_WEIGHT,
+            (false, false) => HTLC_SUCCESS_WEIGHT,
+            (true, true) => ANCHOR_HTLC_TIMEOUT_WEIGHT,
+            (false, true) => ANCHOR_HTLC_SUCCESS_WEIGHT,
+        };
+        weight * self.feerate_per_kw as u64 / 1000
+    }
+
+    /// HTLCs which are not trimmed as dust, ordered by their expiry, together with the flag
+    /// whether the HTLC is offered by the commitment owner
+    fn htlcs(&self) -> Vec<(bool, &UpdateAddHtlc)> {
+        let mut htlcs = self
+            .offered
+            .iter()
+            .map(|htlc| (true, htlc))
+            .chain(self.received.iter().map(|htlc| (false, htlc)))
+            .filter(|(offered, htlc)| {
+                htlc.amount_msat / 1000 >= self.dust_limit_sat + self.htlc_tx_fee(*offered)
+            })
+            .collect::<Vec<_>>();
+        htlcs.sort_by_key(|(_, htlc)| htlc.cltv_expiry);
+        htlcs
+    }
+
+    /// Constructs outputs of the commitment transaction, except anchor outputs, which are added
+    /// by [`super::anchors::AnchorOutputs`]. The outputs are not ordered: this is done by the
+    /// BIP-69 channel modifier, which sorts outputs stably, keeping HTLC outputs with the same
+    /// amount and script in the order of their expiry.
+    pub fn outputs(&self) -> Vec<psbt::Output> {
+        let anchors = has_anchors(self.channel_type);
+        let fee = commitment_fee(self.htlcs().len(), self.feerate_per_kw, anchors);
+        let mut to_local = self.to_local_msat / 1000;
+        let mut to_remote = self.to_remote_msat / 1000;
+        let funder_amount = if self.funder { &mut to_local } else { &mut to_remote };
+        *funder_amount = funder_amount.saturating_sub(fee);
+
+        let mut outputs = self
+            .htlcs()
+            .into_iter()
+            .map(|(offered, htlc)| self.htlc_output(offered, htlc))
+            .collect::<Vec<_>>();
+        if to_local >= self.dust_limit_sat {
+            outputs.push(psbt::Output::ln_to_local(
+                to_local,
+                self.keys.revocation_pubkey,
+                self.keys.owner_delayed_pubkey,
+                self.to_self_delay,
+            ));
+        }
+        if to_remote >= self.dust_limit_sat {
+            let payment_pubkey = self.keys.counterparty_payment_pubkey;
+            outputs.push(match anchors {
+                true => psbt::Output::ln_to_remote_v2(to_remote, payment_pubkey),
+                false => psbt::Output::ln_to_remote_v1(to_remote, payment_pubkey),
+            });
+        }
+        outputs
+    }
+
+    fn htlc_output(&self, offered: bool, htlc: &UpdateAddHtlc) -> psbt::Output {
+        let witness_script = self.htlc_script(offered, htlc);
+        let txout = TxOut {
+            value: htlc.amount_msat / 1000,
+            script_pubkey: witness_script.to_p2wsh().into(),
+        };
+        let output = bitcoin::psbt::Output {
+            witness_script: Some(witness_script.into()),
+            ..Default::default()
+        };
+        psbt::Output::with(0, output, txout)
+    }
+
+    /// Constructs witness script of the offered (from the commitment owner point of view) or
+    /// received HTLC output
+    fn htlc_script(&self, offered: bool, htlc: &UpdateAddHtlc) -> WitnessScript {
+        let anchors = has_anchors(self.channel_type);
+        self.keys.htlc_script(offered, &htlc.payment_hash, htlc.cltv_expiry, anchors)
+    }
+
+    /// Constructs HTLC-timeout (for HTLCs offered by the commitment owner) and HTLC-success
+    /// transactions spending HTLC outputs of the commitment transaction, in the order of the
+    /// outputs. The transactions are prepared for signing with the local HTLC key.
+    pub fn htlc_psbts(&self, commitment_psbt: &Psbt) -> Vec<Psbt> {
+        self.htlc_txs(commitment_psbt).into_iter().map(|(psbt, _)| psbt).collect()
+    }
+
+    /// Constructs HTLC transactions in the same way as [`Self::htlc_psbts`], returning each of
+    /// them together with the HTLC it claims
+    pub fn htlc_txs(&self, commitment_psbt: &Psbt) -> Vec<(Psbt, &UpdateAddHtlc)> {
+        let txid = commitment_psbt.to_txid();
+        let mut htlcs = self
+            .htlcs()
+            .into_iter()
+            .map(|(offered, htlc)| (self.htlc_script(offered, htlc), offered, htlc))
+            .collect::<Vec<_>>();
+        commitment_psbt
+            .outputs
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, output)| {
+                let script = output.witness_script.as_ref()?;
+                let index = htlcs.iter().position(|(htlc_script, ..)| htlc_script == script)?;
+                let (_, offered, htlc) = htlcs.remove(index);
+                let outpoint = OutPoint::new(txid, vout as u32);
+                Some((self.htlc_psbt(outpoint, output, offered, htlc), htlc))
+            })
+            .collect()
+    }
+
+    fn htlc_psbt(
+        &self,
+        outpoint: OutPoint,
+        htlc_output: &psbt::Output,
+        offered: bool,
+        htlc: &UpdateAddHtlc,
+    ) -> Psbt {
+        let anchors = has_anchors(self.channel_type);
+        let output = psbt::Output::ln_to_local(
+            htlc_output.amount - self.htlc_tx_fee(offered),
+            self.keys.revocation_pubkey,
+            self.keys.owner_delayed_pubkey,
+            self.to_self_delay,
+        );
+        let htlc_tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(if offered { htlc.cltv_expiry } else { 0 }),
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: empty!(),
+                sequence: if anchors { Sequence(1) } else { Sequence::ZERO },
+                witness: empty!(),
+            }],
+            output: vec![output.to_txout()],
+        };
+
+        let mut psbt = Psbt::with(htlc_tx, PsbtVersion::V0)
+            .expect("HTLC transaction has empty script_sig and witness");
+        psbt.outputs[0].witness_script = output.witness_script;
+        let input = &mut psbt.inputs[0];
+        input.witness_utxo = Some(htlc_output.to_txout());
+        input.witness_script = htlc_output.witness_script.clone();
+        if anchors {
+            input.sighash_type =
+                Some(PsbtSighashType::from(EcdsaSighashType::SinglePlusAnyoneCanPay));
+        }
+        input.bip32_derivation = self.htlc_basepoint.to_bip32_derivation_map();
+        let tweak = tweak(self.keys.per_commitment_point, self.htlc_basepoint.key);
+        input.set_p2c_tweak(self.htlc_basepoint.key, Slice32::from(tweak.to_be_bytes()));
+        psbt
+    }
+}
+
+/// Channel extension constructing commitment transaction outputs according to BOLT-3.
+///
+/// Replaces `to_local` and `to_remote` outputs constructed by the BOLT channel, which does not
+/// account HTLCs in the commitment fee and does not use the keys of the commitment owner, and
+/// HTLC extension of the BOLT channel, which does not construct valid HTLC outputs. HTLCs are
+/// tracked by the channel daemon, so they are provided to the extension before each commitment
+/// transaction construction.
+#[derive(Debug)]
+pub(super) struct CommitmentOutputs {
+    state: bolt::ChannelState,
+    /// HTLCs offered by the local node
+    offered: Vec<UpdateAddHtlc>,
+    /// HTLCs offered by the remote node
+    received: Vec<UpdateAddHtlc>,
+}
+
+impl Default for CommitmentOutputs {
+    fn default() -> Self {
+        CommitmentOutputs {
+            state: bolt::ChannelState::dumb_default(),
+            offered: vec![],
+            received: vec![],
+        }
+    }
+}
+
+impl CommitmentOutputs {
+    pub fn with(offered: &[UpdateAddHtlc], received: &[UpdateAddHtlc]) -> CommitmentOutputs {
+        CommitmentOutputs { offered: offered.to_vec(), received: received.to_vec(), ..default!() }
+    }
+}
+
+impl Extension<BoltExt> for CommitmentOutputs {
+    #[inline]
+    fn identity(&self) -> BoltExt { BoltExt::Htlc }
+
+    fn update_from_local(&mut self, _message: &()) -> Result<(), bolt::Error> {
+        // Nothing to do here: all data are taken from the channel state
+        Ok(())
+    }
+
+    fn update_from_peer(&mut self, _message: &LnMsg) -> Result<(), bolt::Error> {
+        // Nothing to do here: HTLCs are tracked by the channel daemon
+        Ok(())
+    }
+
+    fn load_state(&mut self, state: &bolt::ChannelState) { self.state = state.clone(); }
+
+    fn store_state(&self, _state: &mut bolt::ChannelState) {
+        // Nothing to do here: the extension does not have its own state
+    }
+}
+
+impl ChannelExtension<BoltExt> for CommitmentOutputs {
+    #[inline]
+    fn new() -> Box<dyn ChannelExtension<BoltExt>> { Box::<CommitmentOutputs>::default() }
+
+    fn build_graph(&self, tx_graph: &mut TxGraph, as_remote_node: bool) -> Result<(), bolt::Error> {
+        let commitment =
+            Commitment::with(&self.state, &self.offered, &self.received, as_remote_node);
+        tx_graph.cmt_outs = commitment.outputs();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::Wrapper;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::Txid;
+    use bitcoin_scripts::hlc::{HashLock, HashPreimage};
+    use lightning_encoding::LightningDecode;
+    use lnp::channel::bolt::Direction;
+
+    use super::*;
+    use crate::channeld::ChannelState;
+
+    fn secret(hex: &str) -> SecretKey {
+        SecretKey::from_slice(&Vec::from_hex(hex).unwrap()).unwrap()
+    }
+
+    fn pubkey(hex: &str) -> PublicKey { PublicKey::from_secret_key(SECP256K1, &secret(hex)) }
+
+    fn signature(hex: &str) -> Signature {
+        Signature::from_der(&Vec::from_hex(hex).unwrap()).unwrap()
+    }
+
+    fn htlc(id: u64, amount_msat: u64, cltv_expiry: u32) -> UpdateAddHtlc {
+        // HTLC with an empty onion packet: version, session key, sphinx packet and HMAC
+        let mut data = vec![0u8; 32 + 8 + 8 + 32 + 4 + 1];
+        data.extend(pubkey(&"01".repeat(32)).serialize());
+        data.extend([0u8; 1300 + 32]);
+        let mut htlc = UpdateAddHtlc::lightning_deserialize(data).unwrap();
+        htlc.htlc_id = id;
+        htlc.amount_msat = amount_msat;
+        htlc.cltv_expiry = cltv_expiry;
+        let preimage = HashPreimage::from_inner(Slice32::from_inner([id as u8; 32]));
+        htlc.payment_hash = HashLock::from(preimage);
+        htlc
+    }
+
+    /// Channel from BOLT-3 Appendix C "Commitment and HTLC Transaction Test Vectors", funded by
+    /// the local node, with the local commitment #42
+    fn bolt3_channel(
+        channel_type: ChannelType,
+        feerate_per_kw: u32,
+        to_local_msat: u64,
+        to_remote_msat: u64,
+    ) -> ChannelState {
+        let mut channel = ChannelState::default();
+        let mut state = bolt::ChannelState::dumb_default();
+        channel.channel.store_state(&mut state);
+        let local_keys = &mut state.local_keys;
+        local_keys.funding_pubkey.key =
+            pubkey("30ff4956bbdd3222d44cc5e8a1261dab1e07957bdac5ae88fe3261ef321f3749");
+        local_keys.revocation_basepoint.key = pubkey(&format!("0f{}", "ff".repeat(31)));
+        local_keys.payment_basepoint.key = pubkey(&"11".repeat(32));
+        local_keys.delayed_payment_basepoint.key = pubkey(&"33".repeat(32));
+        local_keys.htlc_basepoint.key = pubkey(&"11".repeat(32));
+        let remote_keys = &mut state.remote_keys;
+        remote_keys.funding_pubkey =
+            pubkey("1552dfba4f6cf29a62a0af13c8d6981d36d0ef8d61ba10fb0fe90da7634d7e13");
+        remote_keys.revocation_basepoint = PublicKey::from_slice(
+            &Vec::from_hex("02466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f27")
+                .unwrap(),
+        )
+        .unwrap();
+        remote_keys.payment_basepoint = pubkey(&"44".repeat(32));
+        remote_keys.delayed_payment_basepoint =
+            pubkey("1552dfba4f6cf29a62a0af13c8d6981d36d0ef8d61ba10fb0fe90da7634d7e13");
+        remote_keys.htlc_basepoint = pubkey(&"44".repeat(32));
+        state.local_per_commitment_point =
+            pubkey("1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100");
+        state.direction = Direction::Outbount;
+        state.commitment_number = 42;
+        state.local_amount_msat = to_local_msat;
+        state.remote_amount_msat = to_remote_msat;
+        state.common_params.feerate_per_kw = feerate_per_kw;
+        state.common_params.channel_type = channel_type;
+        state.local_params.dust_limit_satoshis = 546;
+        state.remote_params.to_self_delay = 144;
+        channel.channel.load_state(&state);
+        channel.channel.set_funding_amount(10_000_000);
+        channel.funding_outpoint = Some(OutPoint::new(
+            Txid::from_hex("8984484a580b825b9972d7adb15050b3ab624ccd731946b3eeddb92f4e7ef6be")
+                .unwrap(),
+            0,
+        ));
+        channel
+    }
+
+    fn commitment_psbt(channel: &mut ChannelState) -> Psbt {
+        let mut psbt = channel.commitment_tx(false).unwrap();
+        channel.fill_funding_input(&mut psbt.inputs[0]).unwrap();
+        psbt
+    }
+
+    fn verify_commitment(channel: &ChannelState, psbt: &Psbt, txid: &str, sig: &str) {
+        assert_eq!(psbt.to_txid(), Txid::from_hex(txid).unwrap());
+        let remote_funding_pubkey = channel.channel.constructor().remote_keys().funding_pubkey;
+        verify_input_sig(psbt, &signature(sig), &remote_funding_pubkey).unwrap();
+    }
+
+    #[test]
+    fn commitment_without_htlcs() {
+        let mut channel =
+            bolt3_channel(ChannelType::StaticRemotekey, 15000, 7_000_000_000, 3_000_000_000);
+        let psbt = commitment_psbt(&mut channel);
+        verify_commitment(
+            &channel,
+            &psbt,
+            "35af2c90e84decff1c178c6d600bc0e9de29af15a11b3711db623f960f24ae11",
+            "3045022100c3127b33dcc741dd6b05b1e63cbd1a9a7d816f37af9b6756fa2376b056f03237022040\
+             8b96279808fe57eb7e463710804cdf4f108388bc5cf722d8c848d2c7f9f3b0",
+        );
+
+        let mut channel = bolt3_channel(
+            ChannelType::AnchorOutputsStaticRemotekey,
+            15000,
+            7_000_000_000,
+            3_000_000_000,
+        );
+        let psbt = commitment_psbt(&mut channel);
+        verify_commitment(
+            &channel,
+            &psbt,
+            "5b2e0d84b783d8487bb40701979898275285c1409168a0e1fa26c6aef471b64b",
+            "3045022100f89034eba16b2be0e5581f750a0a6309192b75cce0f202f0ee2b4ec0cc39485002207\
+             6c65dc507fe42276152b7a3d90e961e678adbe966e916ecfe85e64d430e75f3",
+        );
+    }
+
+    #[test]
+    fn commitment_with_htlcs() {
+        // BOLT-3 "commitment tx with seven outputs untrimmed (maximum feerate)"
+        let mut channel =
+            bolt3_channel(ChannelType::StaticRemotekey, 647, 6_988_000_000, 3_000_000_000);
+        channel.commitments.local.offered = vec![htlc(2, 2_000_000, 502), htlc(3, 3_000_000, 503)];
+        channel.commitments.local.received =
+            vec![htlc(0, 1_000_000, 500), htlc(1, 2_000_000, 501), htlc(4, 4_000_000, 504)];
+        let psbt = commitment_psbt(&mut channel);
+        verify_commitment(
+            &channel,
+            &psbt,
+            "feb2a9af08b9a7da4bd127b5ac35599b10b26c6b99f2381d8806c288473efb2c",
+            "3045022100a135f9e8a5ed25f7277446c67956b00ce6f610ead2bdec2c2f686155b781477202205\
+             9f1f6e1a8b336a68efcc1af3fe4d422d4827332b5b067501b099c47b7b5b5ee",
+        );
+
+        let mut state = bolt::ChannelState::dumb_default();
+        channel.channel.store_state(&mut state);
+        let spec = &channel.commitments.local;
+        let commitment = Commitment::with(&state, &spec.offered, &spec.received, false);
+        let htlc_psbts = commitment.htlc_psbts(&psbt);
+        let vectors = [
+            (
+                "495ca3dda7525a3d6e20c064ad5a321c0b8f364726a965ed1650fef3c3d75b81",
+                "30450221008437627f9ad84ac67052e2a414a4367b8556fd1f94d8b02590f89f50525cd3350220\
+                 5b9c21ff6e7fc864f2352746ad8ba59182510819acb644e25b8a12fc37bbf24f",
+            ),
+            (
+                "9e4b9db6fc02b1a6c1af68d46493972d7188bd61f3fc6698577ade393ae63cfd",
+                "304402205a67f92bf6845cf2892b48d874ac1daf88a36495cf8a06f93d83180d930a6f75022031\
+                 da1621d95c3f335cc06a3056cf960199dae600b7cf89088f65fc53cdbef28c",
+            ),
+            (
+                "01e54968418e45319f77ee9e681df2ca79cf5513035382e730c47bfb3de603ef",
+                "30440220437e21766054a3eef7f65690c5bcfa9920babbc5af92b819f772f6ea96df6c74022071\
+                 73622024bd97328cfb26c6665e25c2f5d67c319443ccdc60c903217005d8c8",
+            ),
+            (
+                "163d432f247f43a32449fd121284456332c0bb076d950c06f5362ef8ab2bcf09",
+                "304402207436e10737e4df499fc051686d3e11a5bb2310e4d1f1e691d287cef66514791202207c\
+                 b58e71a6b7a42dd001b7e3ae672ea4f71ea3e1cd412b742e9124abb0739c64",
+            ),
+            (
+                "32171e359a8e89138bdddacba1b1056802d99f20d1d27716393e8479729a4a27",
+                "30450221009acd6a827a76bfee50806178dfe0495cd4e1d9c58279c194c7b01520fe68cb8d0220\
+                 24d439047c368883e570997a7d40f0b430cb5a742f507965e7d3063ae3feccca",
+            ),
+        ];
+        assert_eq!(htlc_psbts.len(), vectors.len());
+        for (psbt, (txid, sig)) in htlc_psbts.iter().zip(vectors) {
+            assert_eq!(psbt.to_txid(), Txid::from_hex(txid).unwrap());
+            verify_input_sig(psbt, &signature(sig), &commitment.keys.counterparty_htlc_pubkey)
+                .unwrap();
+        }
+
+        // Scripts of the revoked commitment HTLC outputs are reconstructed from the
+        // per-commitment point and HTLC terms only
+        let keys = CommitmentKeys::with_point(&state, false, state.local_per_commitment_point);
+        assert_eq!(keys, commitment.keys);
+        for (offered, htlc) in commitment.htlcs() {
+            let script = keys.htlc_script(offered, &htlc.payment_hash, htlc.cltv_expiry, false);
+            let script = Some(script);
+            assert!(psbt.outputs.iter().any(|output| output.witness_script == script));
+        }
+    }
+}
@@ -11,15 +11,18 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+mod anchors;
 pub(self) mod automata;
+mod commitment;
 #[cfg(feature = "server")]
 mod opts;
+mod onion;
 mod runtime;
 mod state;
-pub(self) mod storage;
+pub mod storage;
 
 pub use automata::Error;
 #[cfg(feature = "server")]
 pub use opts::Opts;
-pub use runtime::run;
+pub use runtime::{channel_backup, is_channel_persisted, persisted_channels, run};
 pub(self) use state::ChannelState;
@@ -0,0 +1,206 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Processing of onion packets of incoming HTLCs and construction of BOLT-4 failure messages
+//! returned to the payment origin.
+
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::SecretKey;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use internet2::presentation::sphinx::Onion;
+use lightning_encoding::LightningEncode;
+use lnp::p2p::bolt::{
+    ChannelId, HopRealm, PaymentOnion, UpdateAddHtlc, UpdateFailHtlc, UpdateFailMalformedHtlc,
+};
+
+use super::state::HtlcUpdate;
+
+const BADONION: u16 = 0x8000;
+const PERM: u16 = 0x4000;
+
+/// Minimal length of the failure message in the failure packet, to which shorter messages are
+/// padded
+const FAILURE_MSG_PADDED_LEN: usize = 256;
+
+/// Reasons for failing an incoming HTLC, as defined in BOLT-4
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display(doc_comments)]
+pub(super) enum HtlcFailure {
+    /// onion packet has unsupported version
+    InvalidOnionVersion(sha256::Hash),
+
+    /// onion packet HMAC does not commit to the packet data
+    InvalidOnionHmac(sha256::Hash),
+
+    /// onion payload can't be parsed
+    InvalidOnionPayload,
+
+    /// payment forwarding is not supported by the node
+    UnknownNextPeer,
+
+    /// payment hash, amount, expiry or secret do not match any invoice issued by the node
+    IncorrectOrUnknownPaymentDetails { htlc_msat: u64, height: u32 },
+
+    /// HTLC expiry {0} is below outgoing CLTV value from the onion payload
+    FinalIncorrectCltvExpiry(u32),
+
+    /// HTLC amount {0} msat is below amount to forward from the onion payload
+    FinalIncorrectHtlcAmount(u64),
+}
+
+impl HtlcFailure {
+    /// Failure code of the failure message
+    pub fn code(self) -> u16 {
+        match self {
+            HtlcFailure::InvalidOnionVersion(_) => BADONION | PERM | 4,
+            HtlcFailure::InvalidOnionHmac(_) => BADONION | PERM | 5,
+            HtlcFailure::InvalidOnionPayload => PERM | 22,
+            HtlcFailure::UnknownNextPeer => PERM | 10,
+            HtlcFailure::IncorrectOrUnknownPaymentDetails { .. } => PERM | 15,
+            HtlcFailure::FinalIncorrectCltvExpiry(_) => 18,
+            HtlcFailure::FinalIncorrectHtlcAmount(_) => 19,
+        }
+    }
+
+    /// Failure-specific data following failure code in the failure message
+    fn data(self) -> Vec<u8> {
+        match self {
+            HtlcFailure::InvalidOnionVersion(sha256_of_onion)
+            | HtlcFailure::InvalidOnionHmac(sha256_of_onion) => sha256_of_onion.to_vec(),
+            // We do not track which TLV record has failed, so we report zero type and offset
+            HtlcFailure::InvalidOnionPayload => vec![0u8; 3],
+            HtlcFailure::UnknownNextPeer => vec![],
+            HtlcFailure::IncorrectOrUnknownPaymentDetails { htlc_msat, height } => {
+                let mut data = htlc_msat.to_be_bytes().to_vec();
+                data.extend(height.to_be_bytes());
+                data
+            }
+            HtlcFailure::FinalIncorrectCltvExpiry(cltv_expiry) => {
+                cltv_expiry.to_be_bytes().to_vec()
+            }
+            HtlcFailure::FinalIncorrectHtlcAmount(htlc_msat) => htlc_msat.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Constructs channel update failing the HTLC.
+    ///
+    /// Shared secret with the payment origin is required to encrypt failure reason; it is not
+    /// used for malformed onion failures, since the origin can't be identified in that case.
+    pub fn to_update(
+        self,
+        channel_id: ChannelId,
+        htlc_id: u64,
+        shared_secret: sha256::Hash,
+    ) -> HtlcUpdate {
+        match self {
+            HtlcFailure::InvalidOnionVersion(sha256_of_onion)
+            | HtlcFailure::InvalidOnionHmac(sha256_of_onion) => {
+                HtlcUpdate::FailMalformed(UpdateFailMalformedHtlc {
+                    channel_id,
+                    htlc_id,
+                    sha256_of_onion,
+                    failure_code: self.code(),
+                })
+            }
+            _ => HtlcUpdate::Fail(UpdateFailHtlc {
+                channel_id,
+                htlc_id,
+                reason: self.failure_packet(shared_secret),
+            }),
+        }
+    }
+
+    /// Constructs failure packet encrypted for the payment origin according to BOLT-4
+    fn failure_packet(self, shared_secret: sha256::Hash) -> Vec<u8> {
+        let mut failure_msg = self.code().to_be_bytes().to_vec();
+        failure_msg.extend(self.data());
+        let pad_len = FAILURE_MSG_PADDED_LEN.saturating_sub(failure_msg.len());
+
+        let mut payload = (failure_msg.len() as u16).to_be_bytes().to_vec();
+        payload.extend(failure_msg);
+        payload.extend((pad_len as u16).to_be_bytes());
+        payload.extend(vec![0u8; pad_len]);
+
+        let mut engine = HmacEngine::<sha256::Hash>::new(&generate_key(b"um", shared_secret));
+        engine.input(&payload);
+        let mut packet = Hmac::from_engine(engine).into_inner().to_vec();
+        packet.extend(payload);
+
+        let mut cipher =
+            ChaCha20::new_from_slices(&generate_key(b"ammag", shared_secret), &[0u8; 12])
+                .expect("incorrect ChaCha20 initialization");
+        cipher.apply_keystream(&mut packet);
+        packet
+    }
+}
+
+fn generate_key(key_type: &[u8], shared_secret: sha256::Hash) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key_type);
+    engine.input(&shared_secret[..]);
+    Hmac::from_engine(engine).into_inner()
+}
+
+/// Unwraps outer layer of the onion of the incoming HTLC, which must contain the final hop
+/// payload.
+///
+/// Returns payload together with the secret shared with the payment origin. In case of a
+/// failure, the shared secret is still returned when it can be computed, so the failure can be
+/// reported back to the origin.
+pub(super) fn unwrap_final(
+    htlc: &UpdateAddHtlc,
+    node_secret: SecretKey,
+) -> (sha256::Hash, Result<PaymentOnion, HtlcFailure>) {
+    let sha256_of_onion = sha256::Hash::hash(
+        &htlc.onion_routing_packet.lightning_serialize().expect("onion encoding can't fail"),
+    );
+    let mut packet = match htlc.onion_routing_packet {
+        Onion::Onion(packet) => packet,
+        // We do not accept non-standard pre-unwrapped onions from remote peers
+        Onion::Unfolded { onion, .. } => {
+            return (
+                onion.shared_secret(node_secret),
+                Err(HtlcFailure::InvalidOnionVersion(sha256_of_onion)),
+            )
+        }
+    };
+    let shared_secret = packet.shared_secret(node_secret);
+    if packet.version != 0 {
+        return (shared_secret, Err(HtlcFailure::InvalidOnionVersion(sha256_of_onion)));
+    }
+    if !packet.check_hmac(node_secret, htlc.payment_hash.as_ref()) {
+        return (shared_secret, Err(HtlcFailure::InvalidOnionHmac(sha256_of_onion)));
+    }
+    let payload = match packet.unfold::<PaymentOnion>(node_secret) {
+        Ok(payload) => payload,
+        Err(err) => {
+            debug!("Unable to parse onion payload of HTLC {}: {}", htlc.htlc_id, err);
+            return (shared_secret, Err(HtlcFailure::InvalidOnionPayload));
+        }
+    };
+    // Zero HMAC for the next hop designates the final node
+    let is_final = packet.hmac == Hmac::from_inner([0u8; 32]);
+    match payload.realm {
+        // Legacy payload for the final node lacks payment secret, so the payment will be rejected
+        // by the invoice checks
+        HopRealm::TlvReceiver(_) | HopRealm::Legacy(_) if is_final => (shared_secret, Ok(payload)),
+        HopRealm::TlvIntermediary(_) if is_final => {
+            (shared_secret, Err(HtlcFailure::InvalidOnionPayload))
+        }
+        HopRealm::TlvReceiver(_) => (shared_secret, Err(HtlcFailure::InvalidOnionPayload)),
+        HopRealm::Legacy(_) | HopRealm::TlvIntermediary(_) => {
+            (shared_secret, Err(HtlcFailure::UnknownNextPeer))
+        }
+    }
+}
@@ -11,82 +11,186 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::io::Seek;
-use std::time::SystemTime;
-use std::{fs, io};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
 
 use amplify::{DumbDefault, Wrapper};
-use internet2::addr::NodeId;
+use internet2::addr::{LocalNode, NodeId};
 use lnp::channel::bolt;
-use lnp::p2p::bolt::{ActiveChannelId, ChannelId, Messages as LnMsg};
+use lnp::p2p::bolt::{ActiveChannelId, ChannelId, Messages as LnMsg, TempChannelId, UpdateAddHtlc};
 use lnp::Extension;
-use lnp_rpc::{ChannelInfo, RpcMsg};
+use lnp_rpc::{ChannelInfo, HtlcInfo, RpcMsg};
 use microservices::esb::{self, ClientId, Handler};
-use strict_encoding::{StrictDecode, StrictEncode};
+use strict_encoding::StrictEncode;
+use wallet::psbt::Psbt;
 
+use super::automata::abort::ChannelAbort;
+use super::automata::penalty::ChannelPenalize;
+use super::automata::recover::ChannelRecover;
+use super::automata::ChannelStateMachine;
+use super::state::ClaimStage;
 use super::storage::{self, Driver};
 use super::ChannelState;
-use crate::bus::{self, BusMsg, CtlMsg, ServiceBus};
-use crate::routed::PaymentError;
+use crate::bus::{self, BusMsg, ChannelBackup, CtlMsg, ServiceBus};
 use crate::rpc::ServiceId;
 use crate::{channeld, Config, Endpoints, Error, Responder, Service};
 
-pub fn run(config: Config, channel_id: ActiveChannelId) -> Result<(), Error> {
-    // TODO: use node configuration to provide custom policy & parameters
-
-    // check and read channel file
-    let channel_file = config.channel_file(channel_id);
-    let (state, file) =
-        if let Ok(file) = fs::OpenOptions::new().read(true).write(true).open(&channel_file) {
-            debug!("Restoring channel state from {}", channel_file.display());
-            let state = ChannelState::strict_decode(&file).map_err(Error::Persistence)?;
-            info!("Channel state is restored from persistent storage");
-            let mut inner_state = bolt::ChannelState::dumb_default();
-            state.channel.store_state(&mut inner_state);
-            trace!("Restored state: {}", inner_state);
-            (state, file)
-        } else if let Some(temp_channel_id) = channel_id.temp_channel_id() {
-            debug!("Establishing channel de novo");
-            let state = ChannelState::with(temp_channel_id, &config.chain);
-            fs::create_dir_all(config.channel_dir())?;
-            let file = fs::File::create(channel_file)?;
-            (state, file)
-        } else {
-            error!(
-                "Requested to re-establish channel {}, but its state has not persisted on disk. \
-                 You may compose a channel",
-                channel_id
+pub fn run(
+    config: Config,
+    local_node: LocalNode,
+    channel_id: ActiveChannelId,
+) -> Result<(), Error> {
+    let mut storage = open_storage(&config, ChannelId::from_inner(channel_id.as_slice32()))?;
+
+    // check and read channel state
+    let state = if let Some((version, data)) = storage.load()? {
+        debug!("Restoring channel {} state from persistent storage", channel_id);
+        let mut state = ChannelState::from_stored(version, &data).map_err(Error::Persistence)?;
+        // Connection with the remote peer does not survive daemon restart, so the channel has
+        // to be reestablished before it can be operated
+        state.state_machine = state.state_machine.disconnected();
+        info!("Channel state is restored from persistent storage");
+        if version < storage::VERSION {
+            info!(
+                "Upgrading stored channel state from version {} to {}",
+                version,
+                storage::VERSION
             );
-            return Err(Error::Channel(channeld::Error::NoPersistantData));
-        };
+            storage.store(&state.strict_serialize()?, &state.history_record())?;
+        }
+        let mut inner_state = bolt::ChannelState::dumb_default();
+        state.channel.store_state(&mut inner_state);
+        trace!("Restored state: {}", inner_state);
+        state
+    } else if let Some(temp_channel_id) = channel_id.temp_channel_id() {
+        debug!("Establishing channel de novo");
+        ChannelState::with(temp_channel_id, &config.chain, &config.channel)
+    } else {
+        error!(
+            "Requested to re-establish channel {}, but its state has not persisted on disk. You \
+             may compose a channel",
+            channel_id
+        );
+        return Err(Error::Channel(channeld::Error::NoPersistantData));
+    };
 
     let channel_id = ChannelId::from_inner(channel_id.as_slice32());
     let runtime = Runtime {
         identity: ServiceId::Channel(channel_id),
         config: config.clone(),
+        local_node,
         state,
-        file,
         started: SystemTime::now(),
         enquirer: None,
-        storage: Box::new(storage::DiskDriver::init(
+        payments: none!(),
+        fee_estimate: None,
+        signing: none!(),
+        renaming: None,
+        storage,
+    };
+
+    Service::run(config, runtime, false)
+}
+
+/// Opens persistent storage of the channel state with the configured backend
+fn open_storage(config: &Config, channel_id: ChannelId) -> Result<Box<dyn Driver>, Error> {
+    Ok(match config.storage {
+        storage::Backend::Disk => Box::new(storage::DiskDriver::init(
+            channel_id,
+            Box::new(storage::DiskConfig { path: config.channel_dir() }),
+        )?),
+        storage::Backend::Db => Box::new(storage::DbDriver::init(
             channel_id,
-            Box::new(storage::DiskConfig { path: Default::default() }),
+            Box::new(storage::DbConfig { path: config.channel_db() }),
         )?),
+    })
+}
+
+/// Lists funded channels persisted by the node which are not closed yet, together with their
+/// remote peers. Channels with unreadable state are skipped.
+pub fn persisted_channels(config: &Config) -> Result<Vec<(ChannelId, Option<NodeId>)>, Error> {
+    let channel_ids = match config.storage {
+        storage::Backend::Disk => {
+            storage::DiskDriver::list(Box::new(storage::DiskConfig { path: config.channel_dir() }))?
+        }
+        storage::Backend::Db => {
+            storage::DbDriver::list(Box::new(storage::DbConfig { path: config.channel_db() }))?
+        }
     };
+    let mut channels = vec![];
+    for channel_id in channel_ids {
+        let state = match open_storage(config, channel_id)?.load() {
+            Ok(Some((version, data))) => {
+                ChannelState::from_stored(version, &data).map_err(Error::Persistence)
+            }
+            Ok(None) => continue,
+            Err(err) => Err(err.into()),
+        };
+        match state {
+            Ok(state)
+                if state.state_machine != ChannelStateMachine::Closed
+                    && state.funding_outpoint.is_some() =>
+            {
+                channels.push((channel_id, state.remote_id))
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Unable to read state of channel {}: {}", channel_id, err),
+        }
+    }
+    Ok(channels)
+}
 
-    Service::run(config, runtime, false)
+/// Checks whether the channel state is persisted by the node
+pub fn is_channel_persisted(config: &Config, channel_id: ChannelId) -> Result<bool, Error> {
+    Ok(open_storage(config, channel_id)?.load()?.is_some())
+}
+
+/// Reads static backup of the channel from its persisted state. Returns `None` if the channel
+/// state is not persisted or there are no funds to recover from the channel.
+pub fn channel_backup(
+    config: &Config,
+    channel_id: ChannelId,
+) -> Result<Option<ChannelBackup>, Error> {
+    let (version, data) = match open_storage(config, channel_id)?.load()? {
+        Some(stored) => stored,
+        None => return Ok(None),
+    };
+    let state = ChannelState::from_stored(version, &data).map_err(Error::Persistence)?;
+    Ok(state.backup())
 }
 
 pub struct Runtime {
     identity: ServiceId,
     config: Config,
+    /// Local node key, used for decoding onion packets of incoming HTLCs
+    pub(super) local_node: LocalNode,
     pub(super) state: ChannelState,
-    pub(super) file: fs::File,
     started: SystemTime,
     /// Client which is made an enquiry starting the current workflow run by the active state
     /// machine. It is not a part of the state of the machine since it should not persist.
-    enquirer: Option<ClientId>,
-    storage: Box<dyn storage::Driver>,
+    pub(super) enquirer: Option<ClientId>,
+    /// Clients which have requested payments, indexed by the id of the offered HTLC. They are
+    /// notified once the HTLC is resolved.
+    pub(super) payments: BTreeMap<u64, ClientId>,
+    /// Latest on-chain fee estimate reported by watchd, in satoshis per 1000 weight units
+    pub(super) fee_estimate: Option<u32>,
+    /// Remote commitment transaction followed by its HTLC transactions, which are being signed
+    /// by signd
+    pub(super) signing: Vec<Psbt>,
+    /// Messages held while lnpd updates its routing tables after the channel id change; `None`
+    /// unless the daemon waits for lnpd to confirm the change
+    renaming: Option<RenameQueue>,
+    storage: Box<dyn Driver>,
+}
+
+/// Messages held while the channel daemon waits for lnpd to confirm the channel id change
+#[derive(Debug, Default)]
+struct RenameQueue {
+    /// Messages to the remote peer, which must not learn the new channel id before lnpd is able
+    /// to route it
+    outgoing: Vec<LnMsg>,
+    /// Messages received by the daemon in the meantime
+    incoming: Vec<(ServiceBus, ServiceId, BusMsg)>,
 }
 
 impl Responder for Runtime {
@@ -100,6 +204,11 @@ impl esb::Handler<ServiceBus> for Runtime {
 
     fn identity(&self) -> ServiceId { self.identity.clone() }
 
+    fn on_ready(&mut self, endpoints: &mut Endpoints) -> Result<(), Self::Error> {
+        self.resume_watching(endpoints)?;
+        Ok(())
+    }
+
     fn handle(
         &mut self,
         endpoints: &mut Endpoints,
@@ -107,6 +216,17 @@ impl esb::Handler<ServiceBus> for Runtime {
         source: ServiceId,
         message: BusMsg,
     ) -> Result<(), Self::Error> {
+        if let Some(queue) = &mut self.renaming {
+            if let (ServiceBus::Ctl, BusMsg::Ctl(CtlMsg::ChannelUpdated(channel_id))) =
+                (bus, &message)
+            {
+                return self.complete_rename(endpoints, *channel_id);
+            }
+            trace!("Holding {} from {} until lnpd confirms channel id change", message, source);
+            queue.incoming.push((bus, source, message));
+            return Ok(());
+        }
+
         match (bus, message, source) {
             (ServiceBus::Msg, BusMsg::Bolt(msg), ServiceId::PeerBolt(remote_id)) => {
                 self.handle_p2p(endpoints, remote_id, msg)
@@ -138,17 +258,17 @@ impl esb::Handler<ServiceBus> for Runtime {
 }
 
 impl Runtime {
+    /// Changes daemon identity on the service buses from the temporary channel id to the permanent
+    /// one. The daemon asks lnpd to update its routing tables and, until lnpd confirms the change,
+    /// holds messages to the remote peer and defers processing of the incoming messages.
     pub(super) fn set_identity(
         &mut self,
         endpoints: &mut Endpoints,
+        old_id: TempChannelId,
         channel_id: ChannelId,
     ) -> Result<(), Error> {
-        let prev_id = self.state.channel.active_channel_id();
-
-        self.file.sync_all()?;
-        let file_name = self.config.channel_file(ActiveChannelId::Static(channel_id));
-        self.file = fs::File::create(file_name)?;
-        self.save_state().map_err(Error::Persistence)?;
+        self.storage.rename(channel_id)?;
+        self.save_state()?;
 
         let identity = ServiceId::Channel(channel_id);
         endpoints.set_identity(ServiceBus::Ctl, identity.clone())?;
@@ -156,21 +276,51 @@ impl Runtime {
         endpoints.set_identity(ServiceBus::Rpc, identity.clone())?;
         self.identity = identity;
 
-        // TODO: Remove after fix update identity
-        // the set_identity method causes the lose reference between services, the thread::sleep is
-        // a workaround to that.
-        std::thread::sleep(core::time::Duration::from_secs(2));
-
-        fs::remove_file(self.config.channel_file(prev_id))?;
+        // The request is sent under the new identity, so lnpd is able to route its confirmation
+        self.renaming = Some(RenameQueue::default());
+        self.send_ctl(endpoints, ServiceId::LnpBroker, CtlMsg::ChannelUpdate {
+            old_id,
+            new_id: channel_id,
+        })?;
+        Ok(())
+    }
 
+    /// Completes channel id change once lnpd confirms it, sending held messages to the remote
+    /// peer and processing messages received in the meantime
+    fn complete_rename(
+        &mut self,
+        endpoints: &mut Endpoints,
+        channel_id: ChannelId,
+    ) -> Result<(), Error> {
+        debug!("Channel id change to {} is confirmed by lnpd", channel_id);
+        let queue = self.renaming.take().unwrap_or_default();
+        for message in queue.outgoing {
+            self.send_p2p(endpoints, message)?;
+        }
+        for (bus, source, message) in queue.incoming {
+            if let Err(err) = self.handle(endpoints, bus, source.clone(), message) {
+                error!("Unable to process held message from {}: {}", source, err);
+            }
+        }
         Ok(())
     }
 
+    /// Notifies router and lnpd about the channel creation or closing. We swallow errors since we
+    /// do not want to fail the channel if we just can't update the router or the channel backup.
+    pub(super) fn notify_channel(&mut self, endpoints: &mut Endpoints, message: CtlMsg) {
+        let _ = self.send_ctl(endpoints, ServiceId::Router, message.clone());
+        let _ = self.send_ctl(endpoints, ServiceId::LnpBroker, message);
+    }
+
     pub fn send_p2p(
-        &self,
+        &mut self,
         endpoints: &mut Endpoints,
         message: LnMsg,
     ) -> Result<(), esb::Error<ServiceId>> {
+        if let Some(queue) = &mut self.renaming {
+            queue.outgoing.push(message);
+            return Ok(());
+        }
         let remote_peer = self.state.remote_id.clone().expect("unset remote peer in channeld");
         endpoints.send_to(
             ServiceBus::Msg,
@@ -204,7 +354,16 @@ impl Runtime {
             | LnMsg::AcceptChannel(_)
             | LnMsg::FundingCreated(_)
             | LnMsg::FundingSigned(_)
-            | LnMsg::FundingLocked(_) => {
+            | LnMsg::FundingLocked(_)
+            | LnMsg::Shutdown(_)
+            | LnMsg::ClosingSigned(_)
+            | LnMsg::UpdateAddHtlc(_)
+            | LnMsg::UpdateFulfillHtlc(_)
+            | LnMsg::UpdateFailHtlc(_)
+            | LnMsg::UpdateFailMalformedHtlc(_)
+            | LnMsg::UpdateFee(_)
+            | LnMsg::CommitmentSigned(_)
+            | LnMsg::RevokeAndAck(_) => {
                 self.process(endpoints, ServiceId::PeerBolt(remote_id), BusMsg::Bolt(message))?;
             }
             _ => {
@@ -229,6 +388,7 @@ impl Runtime {
                 self.enquirer = open_channel_with.report_to;
                 // Updating state only if the request was processed
                 self.state.remote_id = Some(remote_peer.id);
+                self.state.remote_addr = Some(remote_peer);
                 self.process(endpoints, source, BusMsg::Ctl(request))?;
             }
 
@@ -242,30 +402,39 @@ impl Runtime {
                 }
             }
 
-            CtlMsg::FundingConstructed(_)
+            // Recovering funds from a channel restored from the static backup
+            CtlMsg::RestoreChannel(_)
+            | CtlMsg::FundingConstructed(_)
             | CtlMsg::TxFound(_)
+            | CtlMsg::Spent(_)
+            | CtlMsg::TxConflict { .. }
             | CtlMsg::Signed(_)
             | CtlMsg::Keyset(..)
+            | CtlMsg::PayoutScript(_)
+            | CtlMsg::Invoice(_)
             | CtlMsg::Error { .. }
             | CtlMsg::EsbError { .. }
             | CtlMsg::Hello => {
                 self.process(endpoints, source, BusMsg::Ctl(request))?;
             }
 
-            CtlMsg::Payment { route, hash_lock, enquirer } => {
-                // TODO: Move into a state machine
+            CtlMsg::FeeEstimate { feerate_per_kw } => {
+                self.fee_estimate = Some(feerate_per_kw);
+                if self.needs_fee_update(feerate_per_kw) {
+                    self.process(endpoints, source, BusMsg::Ctl(request))?;
+                }
+            }
+
+            CtlMsg::BlockHeight(height) => {
+                if self.needs_block_height(height) {
+                    self.process(endpoints, source, BusMsg::Ctl(request))?;
+                }
+            }
+
+            CtlMsg::Payment { enquirer, .. } => {
                 self.enquirer = Some(enquirer);
-                let payment = &route.get(0).ok_or(PaymentError::RouteNotFound)?.payload;
-                let message = self.state.channel.compose_add_update_htlc(
-                    payment.amt_to_forward,
-                    hash_lock,
-                    payment.outgoing_cltv_value,
-                    route,
-                )?;
-                self.send_p2p(endpoints, message)?;
-                // TODO: Report progress here, wait for new commitment to be signed before reporting
-                //       success. Do not clear enquirer
-                let _ = self.report_success(endpoints, Some("HTLC added to the channel"));
+                self.process(endpoints, source, BusMsg::Ctl(request))?;
+                // The client is notified about payment completion once the HTLC is resolved
                 self.enquirer = None;
             }
 
@@ -285,11 +454,13 @@ impl Runtime {
     ) -> Result<(), Error> {
         match request {
             RpcMsg::GetInfo => {
-                let mut state = bolt::ChannelState::dumb_default();
-                self.state.channel.store_state(&mut state);
-                let channel_info = ChannelInfo { state, remote_id: self.state.remote_id };
+                let channel_info = self.channel_info();
                 self.send_rpc(endpoints, client_id, channel_info)?;
             }
+            RpcMsg::CloseChannel { .. } => {
+                self.enquirer = Some(client_id);
+                self.process(endpoints, ServiceId::Client(client_id), BusMsg::Rpc(request))?;
+            }
             RpcMsg::Send(_) => todo!("payments are not yet implemented"),
             wrong_request => {
                 error!("Request is not supported by the RPC interface");
@@ -300,11 +471,92 @@ impl Runtime {
         Ok(())
     }
 
-    // TODO: Use storage drivers
-    pub fn save_state(&mut self) -> Result<(), strict_encoding::Error> {
-        self.file.seek(io::SeekFrom::Start(0))?;
-        self.state.strict_encode(&self.file)?;
-        self.file.sync_all().map_err(strict_encoding::Error::from)?;
+    /// Summarizes channel state for RPC clients. Balances and HTLCs are reported for the latest
+    /// local commitment.
+    fn channel_info(&self) -> ChannelInfo {
+        let mut state = bolt::ChannelState::dumb_default();
+        self.state.channel.store_state(&mut state);
+        let local = &self.state.commitments.local;
+        ChannelInfo {
+            channel_id: ChannelId::from_inner(self.state.channel.active_channel_id().as_slice32()),
+            short_channel_id: self.state.short_channel_id,
+            remote_id: self.state.remote_id,
+            lifecycle: self.state.state_machine.lifecycle(),
+            stage: self.state.state_machine.to_string(),
+            funding_outpoint: self.state.funding_outpoint,
+            capacity_sat: self.state.channel.funding().amount(),
+            local_balance_msat: state.local_amount_msat,
+            remote_balance_msat: state.remote_amount_msat,
+            local_reserve_sat: state.remote_params.channel_reserve_satoshis,
+            remote_reserve_sat: state.local_params.channel_reserve_satoshis,
+            offered_htlcs: local.offered.iter().map(htlc_info).collect(),
+            received_htlcs: local.received.iter().map(htlc_info).collect(),
+            uptime: SystemTime::now()
+                .duration_since(self.started)
+                .unwrap_or_else(|_| Duration::from_secs(0)),
+            since: self
+                .started
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_else(|_| Duration::from_secs(0))
+                .as_secs(),
+            state,
+        }
+    }
+
+    /// Detects whether the remote peer is trusted with zero-confirmation channels
+    pub(super) fn is_zeroconf_peer(&self, remote_id: NodeId) -> bool {
+        self.config.channel.zeroconf_peers.contains(&remote_id)
+    }
+
+    /// Detects whether the local node, being a channel funder, has to update commitment fee rate
+    /// since the on-chain fee estimate has drifted from it beyond the configured threshold
+    fn needs_fee_update(&self, feerate_per_kw: u32) -> bool {
+        if !matches!(self.state.state_machine, ChannelStateMachine::Active(_))
+            || !self.state.channel.constructor().direction().is_outbound()
+        {
+            return false;
+        }
+        let current = self.state.commitments.proposed_feerate() as u64;
+        let proposed = feerate_per_kw as u64;
+        let drift = proposed.max(current) - proposed.min(current);
+        drift * 100 > current * self.config.fee_update_threshold as u64
+    }
+
+    /// Detects whether the new block height requires failing back incoming HTLCs close to their
+    /// expiry, failing the channel due to a timed out offered HTLC, (re)broadcasting justice or
+    /// HTLC transactions which are not mined yet or claiming expired HTLCs of the remote
+    /// commitment transaction
+    fn needs_block_height(&self, height: u32) -> bool {
+        let commitments = &self.state.commitments;
+        match self.state.state_machine {
+            ChannelStateMachine::Active(_) => {
+                commitments.timed_out_offered(height).is_some()
+                    || !commitments.expiring_received(height).is_empty()
+            }
+            ChannelStateMachine::Reestablishing => commitments.timed_out_offered(height).is_some(),
+            ChannelStateMachine::Penalize(ChannelPenalize::Published) => true,
+            ChannelStateMachine::Abort(ChannelAbort::Maturing | ChannelAbort::Resolving) => self
+                .state
+                .htlc_claims
+                .iter()
+                .any(|claim| matches!(claim.stage, ClaimStage::Signed | ClaimStage::Published)),
+            ChannelStateMachine::Recover(ChannelRecover::Expiring) => true,
+            _ => false,
+        }
+    }
+
+    pub fn save_state(&mut self) -> Result<(), channeld::Error> {
+        let data = self.state.strict_serialize()?;
+        self.storage.store(&data, &self.state.history_record())?;
         Ok(())
     }
 }
+
+fn htlc_info(htlc: &UpdateAddHtlc) -> HtlcInfo {
+    HtlcInfo {
+        htlc_id: htlc.htlc_id,
+        amount_msat: htlc.amount_msat,
+        payment_hash: htlc.payment_hash,
+        cltv_expiry: htlc.cltv_expiry,
+    }
+}
@@ -11,15 +11,59 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use amplify::{DumbDefault, Slice32};
-use bitcoin::hashes::Hash;
-use internet2::addr::NodeId;
-use lnp::channel::bolt::{BoltExt, CommonParams, LocalKeyset, PeerParams, Policy};
-use lnp::p2p::bolt::TempChannelId;
-use lnp::Channel;
+use std::collections::BTreeMap;
+use std::{io, mem};
+
+use amplify::{DumbDefault, Slice32, Wrapper};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::ecdsa::Signature;
+use bitcoin::secp256k1::{PublicKey, SecretKey, SECP256K1};
+use bitcoin::{OutPoint, Transaction, Txid};
+use bitcoin_scripts::hlc::{HashLock, HashPreimage};
+use bitcoin_scripts::PubkeyScript;
+use internet2::addr::{NodeAddr, NodeId};
+use lnp::channel::bolt::{self, BoltExt, Lifecycle, LocalKeyset, ScriptGenerators};
+use lnp::p2p::bolt::{
+    ActiveChannelId, ChannelReestablish, CommitmentSigned, Messages as LnMsg, RevokeAndAck,
+    ShortChannelId, TempChannelId, UpdateAddHtlc, UpdateFailHtlc, UpdateFailMalformedHtlc,
+    UpdateFee, UpdateFulfillHtlc,
+};
+use lnp::{Channel, ChannelExtension, Extension};
 use lnpbp::chain::Chain;
+use strict_encoding::StrictDecode;
+use wallet::psbt::{self, Psbt};
+
+use super::anchors::AnchorOutputs;
+use super::automata::accept::ChannelAccept;
+use super::automata::active::ChannelActive;
+use super::automata::propose::ChannelPropose;
+use super::automata::{ChannelStateMachine, Error};
+use super::commitment::{
+    self, derive_pubkey, has_anchors, verify_input_sig, Commitment, CommitmentOutputs,
+};
+use super::storage::{self, HistoryRecord, HtlcRecord};
+use crate::bus::ChannelBackup;
+use crate::{ChannelConf, FEERATE_PER_KW_MIN};
+
+/// Maximal ratio between commitment fee rate proposed by the remote peer and the on-chain fee
+/// estimate, in either direction, which we accept
+const FEERATE_MAX_DEVIATION: u32 = 10;
+
+/// Number of blocks before the expiry of an unresolved incoming HTLC at which we fail it back,
+/// leaving the remote peer enough time to remove the HTLC from the commitments off-chain
+const RECEIVED_HTLC_FAIL_DELTA: u32 = 12;
+
+/// Number of blocks after the expiry of an offered HTLC at which we fail the channel, if the
+/// remote peer has not removed the HTLC by then (`G` deadline from BOLT-2)
+const OFFERED_HTLC_GRACE: u32 = 1;
 
-use super::automata::ChannelStateMachine;
+/// Maximal number of HTLCs a peer may accept, which keeps the commitment transaction within the
+/// standard transaction weight, according to BOLT-2
+const MAX_ACCEPTED_HTLCS: u16 = 483;
+
+/// Number of per-commitment secrets which can be generated from a single seed according to BOLT-3,
+/// bounding commitment numbers of the channel
+pub(super) const COMMITMENT_NUMBER_LIMIT: u64 = 1 << 48;
 
 /// State of the channel runtime which can persists and which evolution is automated with
 /// different state machines.
@@ -34,23 +78,1531 @@ pub(super) struct ChannelState {
     /// Runtime-specific (but persistable) part of the channel state: remote peer which is a
     /// counterparty of this channel.
     pub remote_id: Option<NodeId>,
+
+    /// Outpoint of the channel funding transaction output.
+    ///
+    /// Funding PSBT is known only to the node which have proposed the channel, so we keep the
+    /// outpoint separately to be able to spend funding output from both sides of the channel.
+    pub funding_outpoint: Option<OutPoint>,
+
+    /// Information collected during cooperative or unilateral channel closing
+    pub closing: ClosingState,
+
+    /// Seed for generating local per-commitment secrets, provided by signd together with the
+    /// channel keyset
+    pub commitment_seed: Slice32,
+
+    /// HTLC updates exchanged with the remote peer and commitments including them
+    pub commitments: CommitmentState,
+
+    /// Current per-commitment point reported by the remote peer during channel reestablishment,
+    /// which has proven that the local channel state is outdated. Once set, the local commitment
+    /// transaction must never be published, since it is already revoked.
+    pub outdated_point: Option<PublicKey>,
+
+    /// Address of the remote peer, if the channel was opened by connecting to it. Kept for the
+    /// static channel backup.
+    pub remote_addr: Option<NodeAddr>,
+
+    /// Short channel id, derived from the position of the funding transaction in the blockchain
+    /// once the transaction is mined
+    pub short_channel_id: Option<ShortChannelId>,
+
+    /// Justice transaction penalizing the remote peer, which is rebroadcast until it is mined
+    pub justice: JusticeState,
+
+    /// Remote signatures for HTLC transactions of the latest local commitment, in the order of
+    /// the commitment transaction HTLC outputs
+    pub htlc_sigs: Vec<Signature>,
+
+    /// HTLCs of the revoked remote commitments, indexed by the commitment number. Required to
+    /// reconstruct scripts of the HTLC outputs claimed by the justice transaction.
+    pub revoked_htlcs: BTreeMap<u64, Vec<RevokedHtlc>>,
+
+    /// Second-stage HTLC transactions published by the remote peer spending HTLC outputs of its
+    /// revoked commitment transaction
+    pub htlc_spends: Vec<Transaction>,
+
+    /// Preimages of the HTLCs fulfilled by the local node, required to claim HTLC outputs of the
+    /// commitment transactions published on-chain
+    pub preimages: BTreeMap<HashLock, HashPreimage>,
+
+    /// HTLC transactions claiming HTLC outputs of the local commitment transaction published by
+    /// the channel abort workflow
+    pub htlc_claims: Vec<HtlcClaim>,
+}
+
+/// Channel state machine as it was stored in the legacy channel files (storage format version 0)
+#[derive(StrictDecode)]
+enum LegacyStateMachine {
+    Launch,
+    Propose(ChannelPropose),
+    Accept(ChannelAccept),
+    Active,
+    Reestablishing,
+    Closing,
+    Abort,
+    Penalize,
+}
+
+/// Persistent part of the channel closing workflows
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub(super) struct ClosingState {
+    /// Script receiving local funds, which we have sent in our `shutdown` message
+    pub local_script: Option<PubkeyScript>,
+
+    /// Script receiving remote funds, which we have received in the remote `shutdown` message
+    pub remote_script: Option<PubkeyScript>,
+
+    /// Fee which we have proposed for the closing transaction in our last `closing_signed`
+    pub local_fee: Option<u64>,
+
+    /// Our signature for the closing transaction with `local_fee`
+    pub local_sig: Option<Signature>,
+
+    /// Fee which remote peer has proposed in its last `closing_signed`
+    pub remote_fee: Option<u64>,
+
+    /// Remote signature for the closing transaction with `remote_fee`
+    pub remote_sig: Option<Signature>,
+
+    /// Number of `closing_signed` messages which we have sent
+    pub round: usize,
+
+    /// Id of the published closing transaction; for unilateral channel closing this is the
+    /// latest local commitment transaction, and for the channel penalty - revoked remote
+    /// commitment transaction
+    pub txid: Option<Txid>,
+
+    /// Id of the published transaction sweeping funds from the local commitment output; for the
+    /// channel penalty this is the justice transaction
+    pub sweep_txid: Option<Txid>,
+
+    /// Id of the published child transaction spending local anchor output of the commitment
+    /// transaction to bump its fee
+    pub cpfp_txid: Option<Txid>,
+
+    /// Revoked remote commitment transaction published by the remote peer
+    pub breach_tx: Option<Transaction>,
+
+    /// Commitment transaction published by the remote peer for the channel restored from the
+    /// static backup, from which the local funds are swept
+    pub remote_tx: Option<Transaction>,
+}
+
+/// Justice transaction published by the channel penalty workflow
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub(super) struct JusticeState {
+    /// Signed justice transaction, which is rebroadcast until it is mined
+    pub psbt: Option<Psbt>,
+
+    /// Fee rate of the justice transaction, in satoshis per 1000 weight units
+    pub feerate_per_kw: Option<u32>,
+
+    /// Block height at which the justice transaction was first seen unmined after its
+    /// publication
+    pub height: Option<u32>,
+
+    /// Ids of the justice transactions replaced with ones paying a higher fee, which still may be
+    /// mined instead of the replacements
+    pub replaced_txids: Vec<Txid>,
+}
+
+/// HTLC-timeout or HTLC-success transaction claiming HTLC output of the published local
+/// commitment transaction
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub(super) struct HtlcClaim {
+    /// HTLC transaction signed by the remote peer, which is finalized once it is signed by the
+    /// local node
+    pub psbt: Psbt,
+
+    /// Payment preimage required by HTLC-success transaction
+    pub preimage: Option<HashPreimage>,
+
+    pub stage: ClaimStage,
+}
+
+/// Stage of claiming HTLC output of the local commitment transaction
+#[derive(Copy, Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub(super) enum ClaimStage {
+    /// HTLC transaction is not signed by the local node yet
+    Unsigned,
+
+    /// HTLC transaction is signed and awaits its lock time to be published
+    Signed,
+
+    /// HTLC transaction is published and is rebroadcast until its output matures
+    Published,
+
+    /// output of the mined HTLC transaction is locked for `to_self_delay` blocks, which have
+    /// passed, so it can be swept
+    Matured,
+
+    /// HTLC output can't be claimed by the local node or is already claimed by the remote peer
+    Lost,
+}
+
+/// Update of the channel balances or commitment fee proposed by one of the peers
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub(super) enum HtlcUpdate {
+    /// New HTLC offered by the peer proposing the update
+    Add(UpdateAddHtlc),
+
+    /// HTLC offered to the peer proposing the update is fulfilled with the payment preimage
+    Fulfill(UpdateFulfillHtlc),
+
+    /// HTLC offered to the peer proposing the update is failed
+    Fail(UpdateFailHtlc),
+
+    /// HTLC offered to the peer proposing the update is failed since its onion was malformed
+    FailMalformed(UpdateFailMalformedHtlc),
+
+    /// Commitment fee rate is updated by the channel funder
+    Fee(UpdateFee),
+}
+
+impl From<HtlcUpdate> for LnMsg {
+    fn from(update: HtlcUpdate) -> Self {
+        match update {
+            HtlcUpdate::Add(update) => LnMsg::UpdateAddHtlc(update),
+            HtlcUpdate::Fulfill(update) => LnMsg::UpdateFulfillHtlc(update),
+            HtlcUpdate::Fail(update) => LnMsg::UpdateFailHtlc(update),
+            HtlcUpdate::FailMalformed(update) => LnMsg::UpdateFailMalformedHtlc(update),
+            HtlcUpdate::Fee(update) => LnMsg::UpdateFee(update),
+        }
+    }
+}
+
+impl HtlcUpdate {
+    /// Returns id of the HTLC affected by the update, if the update is related to an HTLC
+    pub fn htlc_id(&self) -> Option<u64> {
+        match self {
+            HtlcUpdate::Add(update) => Some(update.htlc_id),
+            HtlcUpdate::Fulfill(update) => Some(update.htlc_id),
+            HtlcUpdate::Fail(update) => Some(update.htlc_id),
+            HtlcUpdate::FailMalformed(update) => Some(update.htlc_id),
+            HtlcUpdate::Fee(_) => None,
+        }
+    }
+
+    /// Returns fee rate set by the update, if this is a fee update
+    pub fn feerate_per_kw(&self) -> Option<u32> {
+        match self {
+            HtlcUpdate::Fee(update) => Some(update.feerate_per_kw),
+            _ => None,
+        }
+    }
+}
+
+/// HTLC of a revoked remote commitment transaction
+#[derive(Copy, Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub(super) struct RevokedHtlc {
+    /// Whether the HTLC is offered by the remote node, i.e. by the commitment owner
+    pub offered: bool,
+
+    /// Payment hash locking the HTLC
+    pub payment_hash: HashLock,
+
+    /// Block height at which the HTLC expires
+    pub cltv_expiry: u32,
+}
+
+impl RevokedHtlc {
+    fn with(offered: bool, htlc: &UpdateAddHtlc) -> RevokedHtlc {
+        RevokedHtlc { offered, payment_hash: htlc.payment_hash, cltv_expiry: htlc.cltv_expiry }
+    }
+}
+
+/// Balances and HTLCs of a single commitment transaction from the local node point of view
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub(super) struct CommitmentSpec {
+    /// Number of the commitment transaction
+    pub number: u64,
+
+    /// Amount belonging to the local node
+    pub to_local_msat: u64,
+
+    /// Amount belonging to the remote node
+    pub to_remote_msat: u64,
+
+    /// HTLCs offered by the local node
+    pub offered: Vec<UpdateAddHtlc>,
+
+    /// HTLCs offered by the remote node
+    pub received: Vec<UpdateAddHtlc>,
+
+    /// Fee rate of the commitment transaction, in satoshis per 1000 weight units
+    pub feerate_per_kw: u32,
+}
+
+impl CommitmentSpec {
+    /// Constructs the next commitment applying updates proposed by the local and remote nodes
+    fn reduce(&self, local: &[HtlcUpdate], remote: &[HtlcUpdate]) -> Result<CommitmentSpec, Error> {
+        let mut spec = self.clone();
+        spec.number += 1;
+        // Only the channel funder may update the fee, so the latest proposed fee rate wins
+        if let Some(feerate_per_kw) =
+            local.iter().chain(remote).filter_map(HtlcUpdate::feerate_per_kw).next_back()
+        {
+            spec.feerate_per_kw = feerate_per_kw;
+        }
+        for update in local {
+            if let HtlcUpdate::Add(add) = update {
+                spec.to_local_msat = spec
+                    .to_local_msat
+                    .checked_sub(add.amount_msat)
+                    .ok_or(Error::InsufficientFunds(add.amount_msat))?;
+                spec.offered.push(add.clone());
+            }
+        }
+        for update in remote {
+            if let HtlcUpdate::Add(add) = update {
+                spec.to_remote_msat = spec
+                    .to_remote_msat
+                    .checked_sub(add.amount_msat)
+                    .ok_or(Error::InsufficientFunds(add.amount_msat))?;
+                spec.received.push(add.clone());
+            }
+        }
+        for update in local {
+            let (htlc_id, success) = match update {
+                HtlcUpdate::Add(_) | HtlcUpdate::Fee(_) => continue,
+                HtlcUpdate::Fulfill(fulfill) => (fulfill.htlc_id, true),
+                HtlcUpdate::Fail(fail) => (fail.htlc_id, false),
+                HtlcUpdate::FailMalformed(fail) => (fail.htlc_id, false),
+            };
+            let htlc = remove_htlc(&mut spec.received, htlc_id)?;
+            match success {
+                true => spec.to_local_msat += htlc.amount_msat,
+                false => spec.to_remote_msat += htlc.amount_msat,
+            }
+        }
+        for update in remote {
+            let (htlc_id, success) = match update {
+                HtlcUpdate::Add(_) | HtlcUpdate::Fee(_) => continue,
+                HtlcUpdate::Fulfill(fulfill) => (fulfill.htlc_id, true),
+                HtlcUpdate::Fail(fail) => (fail.htlc_id, false),
+                HtlcUpdate::FailMalformed(fail) => (fail.htlc_id, false),
+            };
+            let htlc = remove_htlc(&mut spec.offered, htlc_id)?;
+            match success {
+                true => spec.to_remote_msat += htlc.amount_msat,
+                false => spec.to_local_msat += htlc.amount_msat,
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Fee of the commitment transaction, in satoshis, paid by the channel funder together with
+    /// the values of the anchor outputs, if the channel has them.
+    ///
+    /// All HTLCs are counted as commitment outputs: trimming of dust HTLCs depends on the
+    /// commitment owner, so this gives the upper bound of the fee for both commitments.
+    pub fn commitment_fee(&self, anchors: bool) -> u64 {
+        let htlc_count = self.offered.len() + self.received.len();
+        commitment::commitment_fee(htlc_count, self.feerate_per_kw, anchors)
+    }
+}
+
+/// Terms of the channel defining whether the channel funder can afford a commitment fee
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(super) struct FeeTerms {
+    /// Channel reserve which the funder must keep on its side after paying the fee, in satoshis
+    pub funder_reserve_sat: u64,
+
+    /// Whether commitment transactions have anchor outputs paid by the funder
+    pub anchors: bool,
+}
+
+impl FeeTerms {
+    /// Checks that the funder balance covers the commitment fee and the funder channel reserve
+    fn check(&self, spec: &CommitmentSpec, funder_msat: u64) -> Result<(), Error> {
+        let fee = spec.commitment_fee(self.anchors);
+        if funder_msat / 1000 < fee + self.funder_reserve_sat {
+            return Err(Error::UnaffordableFee(fee));
+        }
+        Ok(())
+    }
+}
+
+/// Limits for HTLCs offered to a peer, which the peer has set in its `open_channel` or
+/// `accept_channel` message
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(super) struct HtlcTerms {
+    /// Maximal number of HTLCs offered to the peer
+    pub max_accepted_htlcs: u16,
+
+    /// Minimal value of an HTLC offered to the peer, in millisatoshis
+    pub htlc_minimum_msat: u64,
+
+    /// Maximal total value of HTLCs offered to the peer, in millisatoshis
+    pub max_htlc_value_in_flight_msat: u64,
+}
+
+impl HtlcTerms {
+    /// Checks a new HTLC and all HTLCs offered to the peer in the commitment including it against
+    /// the limits
+    fn check(&self, add: &UpdateAddHtlc, htlcs: &[UpdateAddHtlc]) -> Result<(), Error> {
+        if add.amount_msat == 0 || add.amount_msat < self.htlc_minimum_msat {
+            return Err(Error::HtlcBelowMinimum {
+                amount_msat: add.amount_msat,
+                minimum_msat: self.htlc_minimum_msat.max(1),
+            });
+        }
+        let max_accepted = self.max_accepted_htlcs.min(MAX_ACCEPTED_HTLCS);
+        if htlcs.len() > max_accepted as usize {
+            return Err(Error::TooManyHtlcs(max_accepted));
+        }
+        let in_flight_msat =
+            htlcs.iter().fold(0u64, |sum, htlc| sum.saturating_add(htlc.amount_msat));
+        if in_flight_msat > self.max_htlc_value_in_flight_msat {
+            return Err(Error::HtlcValueInFlight {
+                in_flight_msat,
+                max_msat: self.max_htlc_value_in_flight_msat,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn remove_htlc(htlcs: &mut Vec<UpdateAddHtlc>, htlc_id: u64) -> Result<UpdateAddHtlc, Error> {
+    let pos =
+        htlcs.iter().position(|htlc| htlc.htlc_id == htlc_id).ok_or(Error::UnknownHtlc(htlc_id))?;
+    Ok(htlcs.remove(pos))
+}
+
+/// Converts commitment number into the index of its per-commitment secret, which are generated
+/// in descending order starting from 2^48 - 1. Returns `None` for numbers outside of this range.
+fn commitment_index(number: u64) -> Option<u64> {
+    (COMMITMENT_NUMBER_LIMIT - 1).checked_sub(number)
+}
+
+/// Derives secret with the given index from the secret which was generated for the index with
+/// `bits` least significant bits set to zero, according to BOLT-3
+fn derive_secret(base: Slice32, bits: u8, index: u64) -> Slice32 {
+    let mut secret = base.into_inner();
+    for bit in (0..bits as usize).rev() {
+        if (index >> bit) & 1 == 1 {
+            secret[bit / 8] ^= 1 << (bit % 8);
+            secret = sha256::Hash::hash(&secret).into_inner();
+        }
+    }
+    Slice32::from_inner(secret)
+}
+
+/// Secret revealed by the remote peer, stored at the position matching the number of trailing
+/// zeros in its index
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+struct KnownSecret {
+    index: u64,
+    secret: Slice32,
+}
+
+/// Compact storage of per-commitment secrets revealed by the remote peer, defined in BOLT-3.
+///
+/// Since the remote peer generates its secrets from a single seed, the storage needs to keep at
+/// most 49 secrets, from which all previously revealed secrets can be derived.
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub(super) struct RevocationStore {
+    known: Vec<KnownSecret>,
+}
+
+impl RevocationStore {
+    /// Adds secret revealed for the remote commitment with the given number, checking that all
+    /// previously revealed secrets can be derived from it
+    pub fn insert(&mut self, number: u64, secret: &SecretKey) -> Result<(), Error> {
+        let index = commitment_index(number).ok_or(Error::InconsistentRevocation(number))?;
+        let secret = Slice32::from_inner(secret.secret_bytes());
+        let pos = index.trailing_zeros().min(48) as usize;
+        if pos > self.known.len() {
+            return Err(Error::InconsistentRevocation(number));
+        }
+        for known in self.known.iter().take(pos) {
+            if derive_secret(secret, pos as u8, known.index) != known.secret {
+                debug!(
+                    "Per-commitment secret #{} can't be derived from the revealed secret #{}",
+                    commitment_index(known.index).expect("stored index is within the range"),
+                    number
+                );
+                return Err(Error::InconsistentRevocation(number));
+            }
+        }
+        let known = KnownSecret { index, secret };
+        match self.known.get_mut(pos) {
+            Some(prev) => *prev = known,
+            None => self.known.push(known),
+        }
+        Ok(())
+    }
+
+    /// Reconstructs secret revealed for the remote commitment with the given number, if it was
+    /// revealed
+    pub fn secret(&self, number: u64) -> Option<SecretKey> {
+        let index = commitment_index(number)?;
+        self.known.iter().enumerate().find_map(|(bits, known)| {
+            let mask = !((1u64 << bits) - 1);
+            (index & mask == known.index).then(|| {
+                let secret = derive_secret(known.secret, bits as u8, index);
+                SecretKey::from_slice(secret.as_inner()).expect("negligible probability")
+            })
+        })
+    }
+}
+
+/// Persistent part of the active channel workflow: HTLC updates proposed by both peers and the
+/// commitment transactions including them.
+///
+/// Each update is first signed into the commitment transaction of the peer which has not proposed
+/// it. Once that peer revokes its previous commitment, the update is acknowledged and gets signed
+/// into the commitment of the proposing peer. The update becomes irrevocable when both previous
+/// commitments are revoked.
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub(super) struct CommitmentState {
+    /// Latest local commitment signed by the remote peer
+    pub local: CommitmentSpec,
+
+    /// Latest remote commitment which is not revoked yet
+    pub remote: CommitmentSpec,
+
+    /// Remote commitment signed by us, for which we await the remote peer to revoke the previous
+    /// one
+    pub remote_next: Option<CommitmentSpec>,
+
+    /// Per-commitment point of the latest remote commitment which is not revoked yet
+    pub remote_point: Option<PublicKey>,
+
+    /// Per-commitment point which will be used for the next remote commitment
+    pub remote_next_point: Option<PublicKey>,
+
+    /// Id for the next HTLC offered by the local node
+    pub next_htlc_id: u64,
+
+    /// Id expected for the next HTLC offered by the remote node
+    pub remote_next_htlc_id: u64,
+
+    /// Local updates which are not signed yet
+    pub local_proposed: Vec<HtlcUpdate>,
+
+    /// Local updates signed into the remote commitment which is not revoked yet
+    pub local_signed: Vec<HtlcUpdate>,
+
+    /// Local updates which must be included into the next local commitment
+    pub local_acked: Vec<HtlcUpdate>,
+
+    /// Remote updates which are not signed yet
+    pub remote_proposed: Vec<HtlcUpdate>,
+
+    /// Remote updates included into the local commitment, which must be signed into the next
+    /// remote commitment
+    pub remote_acked: Vec<HtlcUpdate>,
+
+    /// Remote updates signed into the remote commitment which is not revoked yet
+    pub remote_signed: Vec<HtlcUpdate>,
+
+    /// Our `commitment_signed` message for the `remote_next` commitment, kept until the remote
+    /// peer revokes its previous commitment, so it can be retransmitted after reconnection
+    pub sent_commitment: Option<CommitmentSigned>,
+
+    /// Whether our latest `revoke_and_ack` was sent after our latest `commitment_signed`, which
+    /// defines the order of their retransmission after reconnection
+    pub revoked_last: bool,
+
+    /// Per-commitment secrets revealed by the remote peer for its revoked commitments
+    pub remote_secrets: RevocationStore,
+}
+
+impl CommitmentState {
+    /// Detects whether the channel has HTLCs or updates which are not irrevocably committed
+    pub fn has_pending_htlcs(&self) -> bool {
+        let specs = [Some(&self.local), Some(&self.remote), self.remote_next.as_ref()];
+        specs
+            .into_iter()
+            .flatten()
+            .any(|spec| !spec.offered.is_empty() || !spec.received.is_empty())
+            || !self.local_proposed.is_empty()
+            || !self.local_signed.is_empty()
+            || !self.local_acked.is_empty()
+            || !self.remote_proposed.is_empty()
+            || !self.remote_acked.is_empty()
+            || !self.remote_signed.is_empty()
+    }
+
+    /// Detects whether there are updates which should be signed into a new remote commitment
+    pub fn needs_signing(&self) -> bool {
+        self.remote_next.is_none()
+            && (!self.local_proposed.is_empty() || !self.remote_acked.is_empty())
+    }
+
+    /// Registers a new HTLC offered by the local node, assigning it a next HTLC id and checking
+    /// it against the limits set by the remote peer
+    pub fn offer_htlc(
+        &mut self,
+        mut add: UpdateAddHtlc,
+        terms: HtlcTerms,
+    ) -> Result<UpdateAddHtlc, Error> {
+        add.htlc_id = self.next_htlc_id;
+        let mut local = self.local_proposed.clone();
+        local.push(HtlcUpdate::Add(add.clone()));
+        let spec =
+            self.remote_next.as_ref().unwrap_or(&self.remote).reduce(&local, &self.remote_acked)?;
+        terms.check(&add, &spec.offered)?;
+        self.next_htlc_id += 1;
+        self.local_proposed.push(HtlcUpdate::Add(add.clone()));
+        Ok(add)
+    }
+
+    /// Returns the latest commitment fee rate proposed by the local node as a channel funder
+    pub fn proposed_feerate(&self) -> u32 {
+        self.local_proposed
+            .iter()
+            .filter_map(HtlcUpdate::feerate_per_kw)
+            .next_back()
+            .unwrap_or_else(|| self.remote_next.as_ref().unwrap_or(&self.remote).feerate_per_kw)
+    }
+
+    /// Registers commitment fee update proposed by the local node as a channel funder, checking
+    /// that we can afford the new commitment fee
+    pub fn propose_fee(&mut self, update_fee: UpdateFee, terms: FeeTerms) -> Result<(), Error> {
+        let mut local = self.local_proposed.clone();
+        local.push(HtlcUpdate::Fee(update_fee.clone()));
+        let spec =
+            self.remote_next.as_ref().unwrap_or(&self.remote).reduce(&local, &self.remote_acked)?;
+        terms.check(&spec, spec.to_local_msat)?;
+        self.local_proposed.push(HtlcUpdate::Fee(update_fee));
+        Ok(())
+    }
+
+    /// Registers commitment fee update proposed by the remote peer as a channel funder, checking
+    /// that the remote peer can afford the new commitment fee
+    pub fn receive_fee(&mut self, update_fee: UpdateFee, terms: FeeTerms) -> Result<(), Error> {
+        let mut remote = self.remote_proposed.clone();
+        remote.push(HtlcUpdate::Fee(update_fee.clone()));
+        let spec = self.local.reduce(&self.local_acked, &remote)?;
+        terms.check(&spec, spec.to_remote_msat)?;
+        self.remote_proposed.push(HtlcUpdate::Fee(update_fee));
+        Ok(())
+    }
+
+    /// Registers remote peer update fulfilling or failing HTLC offered by the local node
+    pub fn resolve_offered(&mut self, update: HtlcUpdate) -> Result<(), Error> {
+        let htlc_id = update.htlc_id().expect("HTLC resolution update");
+        let htlc = self
+            .local
+            .offered
+            .iter()
+            .find(|htlc| htlc.htlc_id == htlc_id)
+            .ok_or(Error::UnknownHtlc(htlc_id))?;
+        match &update {
+            HtlcUpdate::Add(_) | HtlcUpdate::Fee(_) => {
+                unreachable!("HTLC addition and fee update do not resolve an HTLC")
+            }
+            HtlcUpdate::Fulfill(fulfill)
+                if HashLock::from(fulfill.payment_preimage) != htlc.payment_hash =>
+            {
+                return Err(Error::InvalidPreimage(htlc_id))
+            }
+            _ => {}
+        }
+        if self.remote_proposed.iter().any(|proposed| proposed.htlc_id() == Some(htlc_id)) {
+            return Err(Error::UnknownHtlc(htlc_id));
+        }
+        self.remote_proposed.push(update);
+        Ok(())
+    }
+
+    /// Registers a new HTLC offered by the remote peer, checking it against the limits set by the
+    /// local node
+    pub fn receive_htlc(&mut self, add: UpdateAddHtlc, terms: HtlcTerms) -> Result<(), Error> {
+        if add.htlc_id != self.remote_next_htlc_id {
+            return Err(Error::UnexpectedHtlcId {
+                expected: self.remote_next_htlc_id,
+                received: add.htlc_id,
+            });
+        }
+        let mut remote = self.remote_proposed.clone();
+        remote.push(HtlcUpdate::Add(add.clone()));
+        let spec = self.local.reduce(&self.local_acked, &remote)?;
+        terms.check(&add, &spec.received)?;
+        self.remote_next_htlc_id += 1;
+        self.remote_proposed.push(HtlcUpdate::Add(add));
+        Ok(())
+    }
+
+    /// Lists irrevocably committed HTLCs offered by the remote peer with the given payment hash,
+    /// which are not resolved by the local node yet
+    pub fn unresolved_received(&self, payment_hash: HashLock) -> Vec<UpdateAddHtlc> {
+        self.remote
+            .received
+            .iter()
+            .filter(|htlc| htlc.payment_hash == payment_hash)
+            .filter(|htlc| !self.is_resolved_locally(htlc.htlc_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Lists irrevocably committed HTLCs offered by the remote peer, which are not resolved by the
+    /// local node yet and are about to expire at the given block height
+    pub fn expiring_received(&self, height: u32) -> Vec<UpdateAddHtlc> {
+        self.remote
+            .received
+            .iter()
+            .filter(|htlc| htlc.cltv_expiry <= height + RECEIVED_HTLC_FAIL_DELTA)
+            .filter(|htlc| !self.is_resolved_locally(htlc.htlc_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Finds HTLC offered by the local node which is present in any of the current commitments
+    /// and has timed out at the given block height, requiring the channel to be failed
+    pub fn timed_out_offered(&self, height: u32) -> Option<&UpdateAddHtlc> {
+        [Some(&self.local), Some(&self.remote), self.remote_next.as_ref()]
+            .into_iter()
+            .flatten()
+            .flat_map(|spec| &spec.offered)
+            .find(|htlc| htlc.cltv_expiry + OFFERED_HTLC_GRACE <= height)
+    }
+
+    fn is_resolved_locally(&self, htlc_id: u64) -> bool {
+        self.local_proposed.iter().chain(&self.local_signed).chain(&self.local_acked).any(
+            |update| update.htlc_id() == Some(htlc_id) && !matches!(update, HtlcUpdate::Add(_)),
+        )
+    }
+
+    /// Registers local update fulfilling or failing HTLC offered by the remote peer
+    pub fn resolve_received(&mut self, update: HtlcUpdate) -> Result<(), Error> {
+        let htlc_id = update.htlc_id().expect("HTLC resolution update");
+        let htlc = self
+            .remote
+            .received
+            .iter()
+            .find(|htlc| htlc.htlc_id == htlc_id)
+            .ok_or(Error::UnknownHtlc(htlc_id))?;
+        match &update {
+            HtlcUpdate::Add(_) | HtlcUpdate::Fee(_) => {
+                unreachable!("HTLC addition and fee update do not resolve an HTLC")
+            }
+            HtlcUpdate::Fulfill(fulfill)
+                if HashLock::from(fulfill.payment_preimage) != htlc.payment_hash =>
+            {
+                return Err(Error::InvalidPreimage(htlc_id))
+            }
+            _ => {}
+        }
+        if self.is_resolved_locally(htlc_id) {
+            return Err(Error::UnknownHtlc(htlc_id));
+        }
+        self.local_proposed.push(update);
+        Ok(())
+    }
+
+    /// Discards remote updates which were not signed by the remote peer, since the remote peer
+    /// forgets them on reconnection and sends them once again
+    pub fn forget_uncommitted(&mut self) {
+        let adds = self
+            .remote_proposed
+            .iter()
+            .filter(|update| matches!(update, HtlcUpdate::Add(_)))
+            .count() as u64;
+        self.remote_next_htlc_id -= adds;
+        self.remote_proposed.clear();
+    }
+
+    /// Returns updates signed into the `remote_next` commitment back to the list of updates
+    /// awaiting signature. Used when our `commitment_signed` for it was never sent to the remote
+    /// peer.
+    pub fn cancel_signing(&mut self) {
+        if self.remote_next.take().is_none() {
+            return;
+        }
+        self.sent_commitment = None;
+        self.local_signed.append(&mut self.local_proposed);
+        self.local_proposed = mem::take(&mut self.local_signed);
+        self.remote_signed.append(&mut self.remote_acked);
+        self.remote_acked = mem::take(&mut self.remote_signed);
+    }
+
+    /// Constructs the next remote commitment including all local updates and remote updates
+    /// acknowledged by us. Returns the commitment, which must be signed and sent to the remote
+    /// peer.
+    pub fn sign_remote(&mut self) -> Result<CommitmentSpec, Error> {
+        let spec = self.remote.reduce(&self.local_proposed, &self.remote_acked)?;
+        self.local_signed = mem::take(&mut self.local_proposed);
+        self.remote_signed = mem::take(&mut self.remote_acked);
+        self.remote_next = Some(spec.clone());
+        Ok(spec)
+    }
+
+    /// Constructs the next local commitment, which includes all remote updates and local updates
+    /// acknowledged by the remote peer
+    pub fn next_local(&self) -> Result<CommitmentSpec, Error> {
+        if self.remote_proposed.is_empty() && self.local_acked.is_empty() {
+            return Err(Error::EmptyCommitment);
+        }
+        self.local.reduce(&self.local_acked, &self.remote_proposed)
+    }
+
+    /// Applies new local commitment signed by the remote peer (see [`Self::next_local`]).
+    /// Returns number of the local commitment which must be revoked.
+    pub fn receive_commitment(&mut self) -> Result<u64, Error> {
+        let spec = self.next_local()?;
+        let revoked = mem::replace(&mut self.local, spec).number;
+        self.local_acked.clear();
+        self.remote_acked.append(&mut self.remote_proposed);
+        self.revoked_last = true;
+        Ok(revoked)
+    }
+
+    /// Applies revocation of the previous remote commitment. Returns remote updates which became
+    /// irrevocably committed.
+    pub fn receive_revocation(
+        &mut self,
+        secret: &SecretKey,
+        next_point: PublicKey,
+    ) -> Result<Vec<HtlcUpdate>, Error> {
+        let remote_next = self.remote_next.take().ok_or(Error::UnexpectedRevocation)?;
+        if Some(PublicKey::from_secret_key(SECP256K1, secret)) != self.remote_point {
+            self.remote_next = Some(remote_next);
+            return Err(Error::InvalidRevocation);
+        }
+        if let Err(err) = self.remote_secrets.insert(self.remote.number, secret) {
+            self.remote_next = Some(remote_next);
+            return Err(err);
+        }
+        self.remote = remote_next;
+        self.remote_point = self.remote_next_point;
+        self.remote_next_point = Some(next_point);
+        self.sent_commitment = None;
+        self.local_acked.append(&mut self.local_signed);
+        Ok(mem::take(&mut self.remote_signed))
+    }
 }
 
 impl ChannelState {
-    pub fn with(temp_channel_id: TempChannelId, chain: &Chain) -> ChannelState {
+    pub fn with(temp_channel_id: TempChannelId, chain: &Chain, conf: &ChannelConf) -> ChannelState {
         let chain_hash = chain.as_genesis_hash().into_inner();
         let channel = Channel::with(
             temp_channel_id,
             Slice32::from(chain_hash),
-            Policy::default(),
-            CommonParams::default(),
-            PeerParams::default(),
+            conf.policy(),
+            conf.common_params(),
+            conf.local_params(),
             LocalKeyset::dumb_default(), // we do not have keyset derived at this stage
         );
-        ChannelState { state_machine: Default::default(), channel, remote_id: None }
+        ChannelState {
+            state_machine: Default::default(),
+            channel,
+            remote_id: None,
+            funding_outpoint: None,
+            closing: none!(),
+            commitment_seed: none!(),
+            commitments: none!(),
+            outdated_point: None,
+            remote_addr: None,
+            short_channel_id: None,
+            justice: none!(),
+            htlc_sigs: none!(),
+            revoked_htlcs: none!(),
+            htlc_spends: none!(),
+            preimages: none!(),
+            htlc_claims: none!(),
+        }
+    }
+
+    /// Decodes channel state stored with the given version of the storage format (see
+    /// [`storage::VERSION`]). Fields missing from the older versions are left empty.
+    pub fn from_stored(version: u16, data: &[u8]) -> Result<ChannelState, strict_encoding::Error> {
+        let mut reader = io::Cursor::new(data);
+        if version == 0 {
+            return Self::from_legacy(reader);
+        }
+        if version > storage::VERSION {
+            return Err(strict_encoding::Error::UnsupportedDataStructure(
+                "channel state of unknown version",
+            ));
+        }
+        let mut state = ChannelState {
+            state_machine: StrictDecode::strict_decode(&mut reader)?,
+            channel: StrictDecode::strict_decode(&mut reader)?,
+            remote_id: StrictDecode::strict_decode(&mut reader)?,
+            funding_outpoint: StrictDecode::strict_decode(&mut reader)?,
+            closing: StrictDecode::strict_decode(&mut reader)?,
+            commitment_seed: StrictDecode::strict_decode(&mut reader)?,
+            commitments: StrictDecode::strict_decode(&mut reader)?,
+            outdated_point: StrictDecode::strict_decode(&mut reader)?,
+            ..Default::default()
+        };
+        if version >= 2 {
+            state.remote_addr = StrictDecode::strict_decode(&mut reader)?;
+            state.short_channel_id = StrictDecode::strict_decode(&mut reader)?;
+            state.justice = StrictDecode::strict_decode(&mut reader)?;
+            state.htlc_sigs = StrictDecode::strict_decode(&mut reader)?;
+            state.revoked_htlcs = StrictDecode::strict_decode(&mut reader)?;
+            state.htlc_spends = StrictDecode::strict_decode(&mut reader)?;
+            state.preimages = StrictDecode::strict_decode(&mut reader)?;
+            state.htlc_claims = StrictDecode::strict_decode(&mut reader)?;
+        }
+        if reader.position() != data.len() as u64 {
+            return Err(strict_encoding::Error::DataNotEntirelyConsumed);
+        }
+        Ok(state)
+    }
+
+    /// Decodes channel state from the legacy channel file. These files were overwritten in place,
+    /// so they may contain leftovers of a longer previous state after the encoded one.
+    fn from_legacy(mut reader: impl io::Read) -> Result<ChannelState, strict_encoding::Error> {
+        let state_machine = match LegacyStateMachine::strict_decode(&mut reader)? {
+            LegacyStateMachine::Launch => ChannelStateMachine::Launch,
+            LegacyStateMachine::Propose(propose) => ChannelStateMachine::Propose(propose),
+            LegacyStateMachine::Accept(accept) => ChannelStateMachine::Accept(accept),
+            LegacyStateMachine::Active => ChannelStateMachine::Active(ChannelActive::Ready),
+            LegacyStateMachine::Reestablishing => ChannelStateMachine::Reestablishing,
+            LegacyStateMachine::Closing
+            | LegacyStateMachine::Abort
+            | LegacyStateMachine::Penalize => {
+                return Err(strict_encoding::Error::UnsupportedDataStructure(
+                    "legacy channel state of a closing channel",
+                ))
+            }
+        };
+        Ok(ChannelState {
+            state_machine,
+            channel: StrictDecode::strict_decode(&mut reader)?,
+            remote_id: StrictDecode::strict_decode(&mut reader)?,
+            ..Default::default()
+        })
+    }
+
+    /// Composes static backup of the channel. Returns `None` for closed channels and channels
+    /// which are not funded yet, since there are no funds to recover.
+    pub fn backup(&self) -> Option<ChannelBackup> {
+        if self.state_machine == ChannelStateMachine::Closed {
+            return None;
+        }
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        Some(ChannelBackup {
+            channel_id: state.active_channel_id.channel_id()?,
+            funding_outpoint: self.funding_outpoint?,
+            remote_id: self.remote_id?,
+            remote_addr: self.remote_addr,
+            channel_type: state.common_params.channel_type,
+            local_keys: state.local_keys,
+            remote_funding_pubkey: state.remote_keys.funding_pubkey,
+        })
+    }
+
+    /// Initializes blank channel state with the data from the static channel backup. The restored
+    /// state is sufficient only for recovering funds after the remote peer closes the channel.
+    pub fn restore(&mut self, backup: &ChannelBackup) {
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        state.active_channel_id = ActiveChannelId::Static(backup.channel_id);
+        state.common_params.channel_type = backup.channel_type;
+        state.local_keys = backup.local_keys.clone();
+        state.remote_keys.funding_pubkey = backup.remote_funding_pubkey;
+        state.stage = Lifecycle::Reestablishing;
+        self.channel.load_state(&state);
+        self.remote_id = Some(backup.remote_id);
+        self.remote_addr = backup.remote_addr;
+        self.funding_outpoint = Some(backup.funding_outpoint);
+    }
+
+    /// Summarizes current channel state for the channel history
+    pub fn history_record(&self) -> HistoryRecord {
+        let local = &self.commitments.local;
+        HistoryRecord {
+            state: self.state_machine.to_string(),
+            lifecycle: self.state_machine.lifecycle().to_string(),
+            local_commitment: local.number,
+            remote_commitment: self.commitments.remote.number,
+            to_local_msat: local.to_local_msat,
+            to_remote_msat: local.to_remote_msat,
+            offered: local.offered.iter().map(HtlcRecord::from).collect(),
+            received: local.received.iter().map(HtlcRecord::from).collect(),
+        }
     }
 
     pub fn remote_id(&self) -> NodeId {
         self.remote_id.expect("remote peer must be present at this stage")
     }
+
+    /// Returns number of confirmations of the funding transaction required before the channel
+    /// becomes operational; zero for zero-confirmation channels
+    pub fn minimum_depth(&self) -> u32 { self.channel.constructor().common_params().minimum_depth }
+
+    /// Updates lifecycle stage of the BOLT channel, which is not updated by the channel itself
+    /// for the stages following channel activation.
+    pub fn set_stage(&mut self, stage: Lifecycle) {
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        state.stage = stage;
+        self.channel.load_state(&state);
+    }
+
+    /// Returns remote signature for the latest local commitment transaction, if known
+    pub fn commitment_sig(&self) -> Option<Signature> {
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        state.commitment_sigs.last().copied()
+    }
+
+    /// Saves remote signature for the latest local commitment transaction, which is not saved
+    /// by the BOLT channel itself when it is received within `funding_created` message.
+    pub fn push_commitment_sig(&mut self, signature: Signature) {
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        state.commitment_sigs.push(signature);
+        self.channel.load_state(&state);
+    }
+
+    /// Saves seed for generating local per-commitment secrets and updates the keyset with the
+    /// first per-commitment point produced from the seed
+    pub fn set_commitment_seed(&mut self, seed: Slice32, keyset: &mut LocalKeyset) {
+        self.commitment_seed = seed;
+        keyset.first_per_commitment_point.key = self.per_commitment_point(0);
+    }
+
+    /// Generates local per-commitment secret for the commitment with the given number according
+    /// to BOLT-3
+    pub fn per_commitment_secret(&self, number: u64) -> SecretKey {
+        let index = commitment_index(number).expect("commitment number exceeds 2^48 - 1");
+        let secret = derive_secret(self.commitment_seed, 48, index);
+        SecretKey::from_slice(secret.as_inner()).expect("negligible probability")
+    }
+
+    /// Computes local per-commitment point for the commitment with the given number
+    pub fn per_commitment_point(&self, number: u64) -> PublicKey {
+        PublicKey::from_secret_key(SECP256K1, &self.per_commitment_secret(number))
+    }
+
+    /// Initializes HTLC and commitment tracking once the channel becomes active
+    pub fn activate(&mut self) {
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        let spec = CommitmentSpec {
+            number: state.commitment_number,
+            to_local_msat: state.local_amount_msat,
+            to_remote_msat: state.remote_amount_msat,
+            offered: none!(),
+            received: none!(),
+            feerate_per_kw: state.common_params.feerate_per_kw,
+        };
+        self.commitments = CommitmentState {
+            local: spec.clone(),
+            remote: spec,
+            remote_point: Some(state.remote_keys.first_per_commitment_point),
+            remote_next_point: Some(state.remote_per_commitment_point),
+            ..none!()
+        };
+        state.stage = Lifecycle::Active;
+        state.local_per_commitment_point = self.per_commitment_point(state.commitment_number);
+        self.channel.load_state(&state);
+    }
+
+    /// Constructs remote commitment transaction matching the provided commitment spec, followed
+    /// by HTLC transactions spending its HTLC outputs
+    pub fn remote_commitment_psbts(&mut self, spec: &CommitmentSpec) -> Result<Vec<Psbt>, Error> {
+        self.commitment_psbts(spec, true)
+    }
+
+    /// Constructs local (or, with `remote` set, remote) commitment transaction matching the
+    /// provided commitment spec, followed by HTLC transactions spending its HTLC outputs. Local
+    /// commitment uses local per-commitment point for the spec number, and remote one - the
+    /// latest per-commitment point provided by the remote peer.
+    fn commitment_psbts(
+        &mut self,
+        spec: &CommitmentSpec,
+        remote: bool,
+    ) -> Result<Vec<Psbt>, Error> {
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        let mut spec_state = state.clone();
+        spec_state.local_amount_msat = spec.to_local_msat;
+        spec_state.remote_amount_msat = spec.to_remote_msat;
+        spec_state.commitment_number = spec.number;
+        spec_state.common_params.feerate_per_kw = spec.feerate_per_kw;
+        spec_state.local_per_commitment_point = self.per_commitment_point(spec.number);
+        self.channel.load_state(&spec_state);
+        self.install_extensions(&spec.offered, &spec.received);
+        let psbt = self.channel.commitment_tx(remote);
+        self.channel.load_state(&state);
+        let mut psbt = psbt?;
+        self.fill_funding_input(&mut psbt.inputs[0])?;
+
+        let commitment = Commitment::with(&spec_state, &spec.offered, &spec.received, remote);
+        let htlc_psbts = commitment.htlc_psbts(&psbt);
+        let mut psbts = vec![psbt];
+        psbts.extend(htlc_psbts);
+        Ok(psbts)
+    }
+
+    /// Constructs local (or, with `remote` set, remote) commitment transaction for the current
+    /// channel state
+    pub fn commitment_tx(&mut self, remote: bool) -> Result<Psbt, Error> {
+        let spec = if remote { &self.commitments.remote } else { &self.commitments.local };
+        let (offered, received) = (spec.offered.clone(), spec.received.clone());
+        self.install_extensions(&offered, &received);
+        Ok(self.channel.commitment_tx(remote)?)
+    }
+
+    /// Replaces HTLC and anchor outputs extensions of the BOLT channel, which do not construct
+    /// valid outputs, with our own ones. Since extensions are not persisted together with the
+    /// channel, this has to be done before each commitment transaction construction.
+    fn install_extensions(&mut self, offered: &[UpdateAddHtlc], received: &[UpdateAddHtlc]) {
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        let mut outputs = CommitmentOutputs::with(offered, received);
+        outputs.load_state(&state);
+        self.channel.add_extender(Box::new(outputs));
+        if !has_anchors(state.common_params.channel_type) {
+            return;
+        }
+        let mut anchors = AnchorOutputs::new();
+        anchors.load_state(&state);
+        self.channel.add_extender(anchors);
+    }
+
+    /// Verifies remote signatures for the next local commitment transaction and its HTLC
+    /// transactions provided within `commitment_signed` message
+    pub fn verify_commitment(&mut self, commitment_signed: &CommitmentSigned) -> Result<(), Error> {
+        let spec = self.commitments.next_local()?;
+        let psbts = self.commitment_psbts(&spec, false)?;
+        let (commitment_psbt, htlc_psbts) =
+            psbts.split_first().expect("commitment transaction is always constructed");
+
+        let remote_keys = self.channel.constructor().remote_keys();
+        verify_input_sig(
+            commitment_psbt,
+            &commitment_signed.signature,
+            &remote_keys.funding_pubkey,
+        )
+        .map_err(Error::InvalidCommitmentSig)?;
+
+        let htlc_sigs = &commitment_signed.htlc_signatures;
+        if htlc_sigs.len() != htlc_psbts.len() {
+            return Err(Error::HtlcSigCount {
+                expected: htlc_psbts.len(),
+                received: htlc_sigs.len(),
+            });
+        }
+        let per_commitment_point = self.per_commitment_point(spec.number);
+        let htlc_pubkey = derive_pubkey(remote_keys.htlc_basepoint, per_commitment_point);
+        for (psbt, sig) in htlc_psbts.iter().zip(htlc_sigs) {
+            verify_input_sig(psbt, sig, &htlc_pubkey)
+                .map_err(|err| Error::InvalidHtlcSig(psbt.to_txid(), err))?;
+        }
+        Ok(())
+    }
+
+    /// Applies new local commitment signed by the remote peer and returns revocation of the
+    /// previous local commitment which must be sent to the remote peer. The signatures must be
+    /// verified with [`Self::verify_commitment`] beforehand.
+    pub fn receive_commitment(
+        &mut self,
+        commitment_signed: &CommitmentSigned,
+    ) -> Result<RevokeAndAck, Error> {
+        let revoked = self.commitments.receive_commitment()?;
+
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        state.local_amount_msat = self.commitments.local.to_local_msat;
+        state.remote_amount_msat = self.commitments.local.to_remote_msat;
+        state.commitment_number = self.commitments.local.number;
+        state.common_params.feerate_per_kw = self.commitments.local.feerate_per_kw;
+        state.local_per_commitment_point = self.per_commitment_point(self.commitments.local.number);
+        state.commitment_sigs.push(commitment_signed.signature);
+        self.channel.load_state(&state);
+        self.htlc_sigs = commitment_signed.htlc_signatures.clone();
+
+        self.compose_revocation(revoked)
+    }
+
+    /// Validates commitment fee update received from the remote peer against the on-chain fee
+    /// estimate, if known, and registers it
+    pub fn receive_fee(
+        &mut self,
+        update_fee: UpdateFee,
+        fee_estimate: Option<u32>,
+    ) -> Result<(), Error> {
+        if self.channel.constructor().direction().is_outbound() {
+            return Err(Error::FeeUpdateFromFundee);
+        }
+        let proposed = update_fee.feerate_per_kw;
+        if proposed < FEERATE_PER_KW_MIN {
+            return Err(Error::FeeTooLow(proposed));
+        }
+        if let Some(estimate) = fee_estimate {
+            if proposed / FEERATE_MAX_DEVIATION > estimate
+                || proposed.saturating_mul(FEERATE_MAX_DEVIATION) < estimate
+            {
+                return Err(Error::UnreasonableFee { proposed, estimate });
+            }
+        }
+        let terms = self.fee_terms();
+        self.commitments.receive_fee(update_fee, terms)
+    }
+
+    /// Registers commitment fee update proposed by the local node as a channel funder
+    pub fn propose_fee(&mut self, update_fee: UpdateFee) -> Result<(), Error> {
+        let terms = self.fee_terms();
+        self.commitments.propose_fee(update_fee, terms)
+    }
+
+    /// Registers a new HTLC offered by the local node, checking it against the limits set by the
+    /// remote peer
+    pub fn offer_htlc(&mut self, add: UpdateAddHtlc) -> Result<UpdateAddHtlc, Error> {
+        let terms = self.htlc_terms(true);
+        self.commitments.offer_htlc(add, terms)
+    }
+
+    /// Registers a new HTLC offered by the remote peer, checking it against the limits set by the
+    /// local node
+    pub fn receive_htlc(&mut self, add: UpdateAddHtlc) -> Result<(), Error> {
+        let terms = self.htlc_terms(false);
+        self.commitments.receive_htlc(add, terms)
+    }
+
+    /// Returns limits for HTLCs offered to the remote peer (if `remote` is set) or to the local
+    /// node
+    fn htlc_terms(&self, remote: bool) -> HtlcTerms {
+        let constructor = self.channel.constructor();
+        // Each peer sets the limits for HTLCs offered to it by its counterparty
+        let params = match remote {
+            true => constructor.remote_params(),
+            false => constructor.local_params(),
+        };
+        HtlcTerms {
+            max_accepted_htlcs: params.max_accepted_htlcs,
+            htlc_minimum_msat: params.htlc_minimum_msat,
+            max_htlc_value_in_flight_msat: params.max_htlc_value_in_flight_msat,
+        }
+    }
+
+    fn fee_terms(&self) -> FeeTerms {
+        let constructor = self.channel.constructor();
+        // Each peer keeps the channel reserve required by its counterparty
+        let funder_params = match constructor.direction().is_outbound() {
+            true => constructor.remote_params(),
+            false => constructor.local_params(),
+        };
+        FeeTerms {
+            funder_reserve_sat: funder_params.channel_reserve_satoshis,
+            anchors: has_anchors(constructor.common_params().channel_type),
+        }
+    }
+
+    /// Constructs `revoke_and_ack` message revoking local commitment with the given number
+    pub fn compose_revocation(&self, revoked: u64) -> Result<RevokeAndAck, Error> {
+        Ok(RevokeAndAck {
+            channel_id: self.channel.try_channel_id()?,
+            per_commitment_secret: self.per_commitment_secret(revoked),
+            next_per_commitment_point: self.per_commitment_point(revoked + 2),
+        })
+    }
+
+    /// Constructs `channel_reestablish` message in response to the one received from the remote
+    /// peer, reporting the local view of the commitment exchange
+    pub fn compose_reestablish(
+        &mut self,
+        remote: &ChannelReestablish,
+    ) -> Result<ChannelReestablish, Error> {
+        let mut reestablish = self.channel.compose_reestablish_channel(remote)?;
+        let last_secret = self
+            .commitments
+            .remote
+            .number
+            .checked_sub(1)
+            .and_then(|revoked| self.commitments.remote_secrets.secret(revoked))
+            .map(|secret| secret.secret_bytes())
+            .unwrap_or([0u8; 32]);
+        reestablish.next_commitment_number = self.commitments.local.number + 1;
+        reestablish.next_revocation_number = self.commitments.remote.number;
+        reestablish.your_last_per_commitment_secret = Slice32::from_inner(last_secret);
+        reestablish.my_current_per_commitment_point =
+            self.per_commitment_point(self.commitments.local.number);
+        Ok(reestablish)
+    }
+
+    /// Applies revocation of the previous remote commitment and returns remote updates which
+    /// became irrevocably committed. HTLCs of the revoked commitment are kept to penalize the
+    /// remote peer if it publishes the commitment.
+    pub fn receive_revocation(
+        &mut self,
+        revoke_and_ack: &RevokeAndAck,
+    ) -> Result<Vec<HtlcUpdate>, Error> {
+        let revoked = self.commitments.remote.clone();
+        let settled = self.commitments.receive_revocation(
+            &revoke_and_ack.per_commitment_secret,
+            revoke_and_ack.next_per_commitment_point,
+        )?;
+        // HTLCs offered by the local node are received by the remote commitment owner
+        let htlcs = revoked
+            .received
+            .iter()
+            .map(|htlc| RevokedHtlc::with(true, htlc))
+            .chain(revoked.offered.iter().map(|htlc| RevokedHtlc::with(false, htlc)))
+            .collect::<Vec<_>>();
+        if !htlcs.is_empty() {
+            self.revoked_htlcs.insert(revoked.number, htlcs);
+        }
+        let mut state = bolt::ChannelState::dumb_default();
+        self.channel.store_state(&mut state);
+        state.remote_per_commitment_point = revoke_and_ack.next_per_commitment_point;
+        self.channel.load_state(&state);
+        Ok(settled)
+    }
+
+    /// Fills PSBT input spending channel funding output with the data required to sign it: the
+    /// funding outpoint, previous output, witness script and derivation of the local funding key.
+    pub fn fill_funding_input(&self, input: &mut psbt::Input) -> Result<(), Error> {
+        let outpoint = self.funding_outpoint.ok_or(Error::NoFundingOutpoint)?;
+        let core = self.channel.constructor();
+        let funding_output = psbt::Output::ln_funding(
+            self.channel.funding().amount(),
+            &core.local_keys().funding_pubkey,
+            core.remote_keys().funding_pubkey,
+        );
+        input.previous_outpoint = outpoint;
+        input.witness_utxo = Some(funding_output.to_txout());
+        input.witness_script = funding_output.witness_script;
+        input.bip32_derivation = funding_output.bip32_derivation;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::hex::FromHex;
+    use lightning_encoding::LightningDecode;
+    use strict_encoding::StrictEncode;
+
+    use super::*;
+
+    // Per-commitment secrets from BOLT-3 Appendix D "Storage Tests", generated from the seeds of
+    // all ones and all zeros bytes for the commitment numbers 0 to 7
+    const SECRETS: [&str; 8] = [
+        "7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc",
+        "c7518c8ae4660ed02894df8976fa1a3659c1a8b4b5bec0c4b872abeba4cb8964",
+        "2273e227a5b7449b6e70f1fb4652864038b1cbf9cd7c043a7d6456b7fc275ad8",
+        "27cddaa5624534cb6cb9d7da077cf2b22ab21e9b506fd4998a51d54502e99116",
+        "c65716add7aa98ba7acb236352d665cab17345fe45b55fb879ff80e6bd0c41dd",
+        "969660042a28f32d9be17344e09374b379962d03db1574df5a8a5a47e19ce3f2",
+        "a5a64476122ca0925fb344bdc1854c1c0a59fc614298e50a33e331980a220f32",
+        "05cde6323d949933f7f7b78776bcc1ea6d9b31447732e3802e1f7ac44b650e17",
+    ];
+    const WRONG_SECRETS: [&str; 8] = [
+        "02a40c85b6f28da08dfdbe0926c53fab2de6d28c10301f8f7c4073d5e42e3148",
+        "dddc3a8d14fddf2b68fa8c7fbad2748274937479dd0f8930d5ebb4ab6bd866a3",
+        "c51a18b13e8527e579ec56365482c62f180b7d5760b46e9477dae59e87ed423a",
+        "ba65d7b0ef55a3ba300d4e87af29868f394f8f138d78a7011669c79b37b936f4",
+        "631373ad5f9ef654bb3dade742d09504c567edd24320d2fcd68e3cc47e2ff6a6",
+        "b7e76a83668bde38b373970155c868a653304308f9896692f904a23731224bb1",
+        "e7971de736e01da8ed58b94c2fc216cb1dca9e326f3a96e7194fe8ea8af6c0a3",
+        "a7efbc61aac46d34f77778bac22c8a20c6a46ca460addc49009bda875ec88fa4",
+    ];
+
+    fn secret(hex: &str) -> SecretKey {
+        SecretKey::from_slice(&Vec::from_hex(hex).unwrap()).unwrap()
+    }
+
+    /// Inserts secrets for the commitments 0 to 7, taking secrets for the commitment numbers in
+    /// `wrong` from an unrelated seed. Returns the number of the first rejected secret.
+    fn insert_secrets(wrong: &[u64]) -> Result<RevocationStore, u64> {
+        let mut store = RevocationStore::default();
+        for number in 0..8u64 {
+            let secrets = if wrong.contains(&number) { WRONG_SECRETS } else { SECRETS };
+            store.insert(number, &secret(secrets[number as usize])).map_err(|_| number)?;
+        }
+        Ok(store)
+    }
+
+    /// Constructs HTLC for the given amount with an empty onion packet: version, session key,
+    /// sphinx packet and HMAC
+    fn htlc(htlc_id: u64, amount_msat: u64) -> UpdateAddHtlc {
+        let mut htlc = vec![0u8; 32];
+        htlc.extend(htlc_id.to_be_bytes());
+        htlc.extend(amount_msat.to_be_bytes());
+        htlc.extend([0u8; 32 + 4 + 1]);
+        htlc.extend(
+            PublicKey::from_secret_key(SECP256K1, &SecretKey::from_slice(&[1; 32]).unwrap())
+                .serialize(),
+        );
+        htlc.extend([0u8; 1300 + 32]);
+        UpdateAddHtlc::lightning_deserialize(htlc).unwrap()
+    }
+
+    #[test]
+    fn fee_affordability() {
+        let mut spec = CommitmentSpec {
+            to_local_msat: 20_000_000,
+            to_remote_msat: 0,
+            feerate_per_kw: 1000,
+            ..Default::default()
+        };
+        assert_eq!(spec.commitment_fee(false), 724);
+        assert_eq!(spec.commitment_fee(true), 1124 + 660);
+        let htlc = htlc(0, 0);
+        spec.offered.push(htlc.clone());
+        spec.received.push(htlc);
+        assert_eq!(spec.commitment_fee(false), 724 + 2 * 172);
+
+        let terms = FeeTerms { funder_reserve_sat: 10_000, anchors: false };
+        assert!(terms.check(&spec, 11_068_000).is_ok());
+        assert!(matches!(terms.check(&spec, 11_067_999), Err(Error::UnaffordableFee(1068))));
+        let terms = FeeTerms { anchors: true, ..terms };
+        assert!(matches!(terms.check(&spec, 11_068_000), Err(Error::UnaffordableFee(2128))));
+    }
+
+    #[test]
+    fn htlc_limits() {
+        let terms = HtlcTerms {
+            max_accepted_htlcs: 2,
+            htlc_minimum_msat: 1000,
+            max_htlc_value_in_flight_msat: 5000,
+        };
+        let mut commitments = CommitmentState {
+            local: CommitmentSpec { to_remote_msat: 10_000, ..Default::default() },
+            ..Default::default()
+        };
+        assert!(matches!(
+            commitments.receive_htlc(htlc(0, 0), terms),
+            Err(Error::HtlcBelowMinimum { amount_msat: 0, minimum_msat: 1000 })
+        ));
+        assert!(matches!(
+            commitments.receive_htlc(htlc(0, 999), terms),
+            Err(Error::HtlcBelowMinimum { amount_msat: 999, minimum_msat: 1000 })
+        ));
+        commitments.receive_htlc(htlc(0, 3000), terms).unwrap();
+        assert!(matches!(
+            commitments.receive_htlc(htlc(1, 2001), terms),
+            Err(Error::HtlcValueInFlight { in_flight_msat: 5001, max_msat: 5000 })
+        ));
+        commitments.receive_htlc(htlc(1, 2000), terms).unwrap();
+        assert!(matches!(
+            commitments.receive_htlc(htlc(2, 1000), terms),
+            Err(Error::TooManyHtlcs(2))
+        ));
+        assert_eq!(commitments.remote_next_htlc_id, 2);
+
+        // The limit of HTLCs accepted by a peer can't exceed BOLT-2 maximum
+        let terms = HtlcTerms { max_accepted_htlcs: u16::MAX, ..terms };
+        let htlcs = (0..=MAX_ACCEPTED_HTLCS as u64).map(|id| htlc(id, 0)).collect::<Vec<_>>();
+        assert!(matches!(
+            terms.check(&htlc(0, 1000), &htlcs),
+            Err(Error::TooManyHtlcs(MAX_ACCEPTED_HTLCS))
+        ));
+    }
+
+    #[test]
+    fn commitment_index_range() {
+        assert_eq!(commitment_index(0), Some(COMMITMENT_NUMBER_LIMIT - 1));
+        assert_eq!(commitment_index(COMMITMENT_NUMBER_LIMIT - 1), Some(0));
+        assert_eq!(commitment_index(COMMITMENT_NUMBER_LIMIT), None);
+        assert_eq!(commitment_index(u64::MAX), None);
+        assert!(RevocationStore::default().secret(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn shachain_generation() {
+        // BOLT-3 Appendix D "Generation Tests"
+        let vectors = [
+            ([0x00; 32], 281474976710655, WRONG_SECRETS[0]),
+            ([0xFF; 32], 281474976710655, SECRETS[0]),
+            (
+                [0xFF; 32],
+                0xaaaaaaaaaaa,
+                "56f4008fb007ca9acf0e15b054d5c9fd12ee06cea347914ddbaed70d1c13a528",
+            ),
+            (
+                [0xFF; 32],
+                0x555555555555,
+                "9015daaeb06dba4ccc05b91b2f73bd54405f2be9f217fbacd3c5ac2e62327d31",
+            ),
+            ([0x01; 32], 1, "915c75942a26bb3a433a8ce2cb0427c29ec6c1775cfc78328b57f6ba7bfeaa9c"),
+        ];
+        for (seed, index, output) in vectors {
+            let secret = derive_secret(Slice32::from_inner(seed), 48, index);
+            assert_eq!(secret.as_inner().to_vec(), Vec::from_hex(output).unwrap());
+        }
+    }
+
+    #[test]
+    fn shachain_insertion() {
+        let store = insert_secrets(&[]).unwrap();
+        assert!(store.known.len() <= 49);
+        for (number, hex) in SECRETS.iter().enumerate() {
+            assert_eq!(store.secret(number as u64), Some(secret(hex)));
+        }
+        assert_eq!(store.secret(8), None);
+
+        // Secrets can't be inserted if there are gaps in the sequence preventing their validation
+        let mut store = RevocationStore::default();
+        assert!(store.insert(3, &secret(SECRETS[3])).is_err());
+    }
+
+    #[test]
+    fn shachain_bad_insertion() {
+        // BOLT-3 Appendix D "insert_secret #1-#8 incorrect" cases: commitment numbers with secrets
+        // from the wrong seed and the number of the secret which must be rejected
+        let cases: [(&[u64], u64); 8] = [
+            (&[0], 1),
+            (&[0, 1], 3),
+            (&[2], 3),
+            (&[4, 5, 6], 7),
+            (&[4], 5),
+            (&[4, 5], 7),
+            (&[6], 7),
+            (&[7], 7),
+        ];
+        for (wrong, rejected) in cases {
+            assert_eq!(insert_secrets(wrong).unwrap_err(), rejected);
+        }
+    }
+
+    #[test]
+    fn stored_versions() {
+        let data = ChannelState::default().strict_serialize().unwrap();
+        // Empty remote address and short channel id are encoded with a single byte each, empty
+        // justice state - with three bytes for its options and two bytes for the txid list length,
+        // and each of the empty lists and maps added later - with two bytes for its length
+        let len = data.len();
+        for (version, stored) in [(1u16, &data[..len - 17]), (2, &data[..])] {
+            let state = ChannelState::from_stored(version, stored).unwrap();
+            assert_eq!(state.remote_addr, None);
+            assert_eq!(state.short_channel_id, None);
+            assert_eq!(state.justice, JusticeState::default());
+            assert!(state.htlc_sigs.is_empty());
+            assert!(state.revoked_htlcs.is_empty());
+            assert!(state.htlc_spends.is_empty());
+            assert!(state.preimages.is_empty());
+            assert!(state.htlc_claims.is_empty());
+            assert_eq!(state.strict_serialize().unwrap(), data);
+        }
+
+        assert!(ChannelState::from_stored(2, &data[..len - 1]).is_err());
+        assert!(ChannelState::from_stored(1, &data).is_err());
+        assert!(ChannelState::from_stored(storage::VERSION + 1, &data).is_err());
+    }
+
+    #[test]
+    fn stored_legacy() {
+        let state = ChannelState::default();
+        let mut data = vec![3u8];
+        data.extend(state.channel.strict_serialize().unwrap());
+        let remote_key =
+            PublicKey::from_secret_key(SECP256K1, &SecretKey::from_slice(&[1; 32]).unwrap());
+        data.extend(Some(NodeId::from(remote_key)).strict_serialize().unwrap());
+        let restored = ChannelState::from_stored(0, &data).unwrap();
+        assert_eq!(restored.state_machine, ChannelStateMachine::Active(ChannelActive::Ready));
+        assert!(restored.remote_id.is_some());
+        assert_eq!(restored.funding_outpoint, None);
+
+        // Legacy files may contain leftovers of the previous state
+        data.extend([0xFF; 16]);
+        assert!(ChannelState::from_stored(0, &data).is_ok());
+
+        // Closing channels were never stored in the legacy format
+        data[0] = 5;
+        assert!(ChannelState::from_stored(0, &data).is_err());
+    }
 }
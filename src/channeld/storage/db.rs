@@ -0,0 +1,332 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::any::Any;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use amplify::{Slice32, Wrapper};
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, Transaction, WriteFlags};
+use lnp::p2p::bolt::ChannelId;
+use once_cell::sync::Lazy;
+use serde_json::json;
+
+use super::driver::{seal, unseal};
+use super::{Driver, Error, HistoryRecord, HtlcRecord};
+
+/// Name of the database keeping current channel states, indexed by channel id
+const STATE_DB: &str = "state";
+
+/// Name of the database keeping channel history records in JSON format, indexed by history id of
+/// the channel followed by big-endian record number
+const HISTORY_DB: &str = "history";
+
+/// Name of the database mapping ids of the channels which were renamed to their history id: the
+/// channel id under which the history was started. This keeps history records append-only, since
+/// they are never moved under the new channel id.
+const HISTORY_ID_DB: &str = "history_id";
+
+/// Maximal size of the channel database. On 32-bit platforms it is limited by the address space
+/// available for memory-mapping the database file.
+#[cfg(target_pointer_width = "64")]
+const MAP_SIZE: usize = 1 << 32;
+#[cfg(not(target_pointer_width = "64"))]
+const MAP_SIZE: usize = 1 << 30;
+
+/// LMDB environments opened by the current process. LMDB does not allow opening the same
+/// environment twice within a process, which happens when channel daemons are run as threads.
+static ENVIRONMENTS: Lazy<Mutex<Vec<Env>>> = Lazy::new(Mutex::default);
+
+/// LMDB environment together with the path it was opened at
+type Env = (PathBuf, Arc<Environment>);
+
+fn environment(path: PathBuf) -> Result<Arc<Environment>, Error> {
+    let mut environments = ENVIRONMENTS.lock().expect("poisoned LMDB environment registry");
+    if let Some((_, env)) = environments.iter().find(|(p, _)| *p == path) {
+        return Ok(env.clone());
+    }
+    fs::create_dir_all(&path)?;
+    let env = Arc::new(Environment::new().set_max_dbs(3).set_map_size(MAP_SIZE).open(&path)?);
+    environments.push((path, env.clone()));
+    Ok(env)
+}
+
+/// Configuration of the embedded database storage driver
+pub struct DbConfig {
+    /// Directory containing database files
+    pub path: PathBuf,
+}
+
+/// Storage driver keeping states of all channels in an embedded key-value database together
+/// with the append-only history of their changes.
+///
+/// The database can be accessed by multiple processes simultaneously. History records are stored
+/// in JSON format, so external tools can read them without decoding the channel state.
+pub struct DbDriver {
+    channel_id: ChannelId,
+    history_id: ChannelId,
+    env: Arc<Environment>,
+    state_db: Database,
+    history_db: Database,
+    history_id_db: Database,
+    next_record: u64,
+    /// The latest history record without its timestamp
+    last_record: Option<serde_json::Value>,
+}
+
+impl DbDriver {
+    fn history_key(&self, no: u64) -> Vec<u8> {
+        let mut key = self.history_id.as_inner().to_vec();
+        key.extend(no.to_be_bytes());
+        key
+    }
+
+    /// Reads number and content of the latest history record of the current channel, removing the
+    /// timestamp from it
+    fn last_history_record(
+        &self,
+        txn: &impl Transaction,
+    ) -> Result<Option<(u64, serde_json::Value)>, Error> {
+        let prefix = self.history_id.as_inner().to_vec();
+        let mut cursor = txn.open_ro_cursor(self.history_db)?;
+        let mut last = None;
+        for item in cursor.iter_from(&prefix) {
+            let (key, value) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            last = Some((key, value));
+        }
+        let (key, value) = match last {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        let no = <[u8; 8]>::try_from(&key[prefix.len()..]).map_err(|_| Error::UnknownFormat)?;
+        let mut record: serde_json::Value =
+            serde_json::from_slice(value).map_err(|_| Error::UnknownFormat)?;
+        if let Some(fields) = record.as_object_mut() {
+            fields.remove("timestamp");
+        }
+        Ok(Some((u64::from_be_bytes(no), record)))
+    }
+}
+
+/// Converts history record into JSON, without the timestamp, which is added only when the record
+/// is written
+fn record_json(record: &HistoryRecord) -> serde_json::Value {
+    let htlcs = |htlcs: &[HtlcRecord]| {
+        htlcs
+            .iter()
+            .map(|htlc| {
+                json!({
+                    "htlc_id": htlc.htlc_id,
+                    "amount_msat": htlc.amount_msat,
+                    "payment_hash": htlc.payment_hash.to_string(),
+                    "cltv_expiry": htlc.cltv_expiry,
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    json!({
+        "state": record.state,
+        "lifecycle": record.lifecycle,
+        "local_commitment": record.local_commitment,
+        "remote_commitment": record.remote_commitment,
+        "to_local_msat": record.to_local_msat,
+        "to_remote_msat": record.to_remote_msat,
+        "offered": htlcs(&record.offered),
+        "received": htlcs(&record.received),
+    })
+}
+
+/// Serializes history record without the timestamp, adding the current time to it
+fn timestamped(record: &serde_json::Value) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .as_secs();
+    let mut record = record.clone();
+    record["timestamp"] = json!(timestamp);
+    record.to_string()
+}
+
+impl Driver for DbDriver {
+    fn init(channel_id: ChannelId, config: Box<dyn Any>) -> Result<Self, Error> {
+        let config: DbConfig = *config.downcast().map_err(|_| Error::WrongConfig)?;
+        let env = environment(config.path)?;
+        let state_db = env.create_db(Some(STATE_DB), DatabaseFlags::empty())?;
+        let history_db = env.create_db(Some(HISTORY_DB), DatabaseFlags::empty())?;
+        let history_id_db = env.create_db(Some(HISTORY_ID_DB), DatabaseFlags::empty())?;
+        let txn = env.begin_ro_txn()?;
+        let history_id = match txn.get(history_id_db, channel_id.as_inner()) {
+            Ok(id) => {
+                Slice32::from_slice(id).map(ChannelId::from_inner).ok_or(Error::UnknownFormat)?
+            }
+            Err(lmdb::Error::NotFound) => channel_id,
+            Err(err) => return Err(err.into()),
+        };
+        let mut driver = DbDriver {
+            channel_id,
+            history_id,
+            env: env.clone(),
+            state_db,
+            history_db,
+            history_id_db,
+            next_record: 0,
+            last_record: None,
+        };
+        // Continue the history after restart without repeating its latest record
+        if let Some((no, record)) = driver.last_history_record(&txn)? {
+            driver.next_record = no + 1;
+            driver.last_record = Some(record);
+        }
+        txn.commit()?;
+        Ok(driver)
+    }
+
+    fn list(config: Box<dyn Any>) -> Result<Vec<ChannelId>, Error> {
+        let config: DbConfig = *config.downcast().map_err(|_| Error::WrongConfig)?;
+        let env = environment(config.path)?;
+        let state_db = env.create_db(Some(STATE_DB), DatabaseFlags::empty())?;
+        let txn = env.begin_ro_txn()?;
+        let mut channels = vec![];
+        {
+            let mut cursor = txn.open_ro_cursor(state_db)?;
+            for item in cursor.iter_start() {
+                let (key, _) = item?;
+                if let Some(slice) = Slice32::from_slice(key) {
+                    channels.push(ChannelId::from_inner(slice));
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(channels)
+    }
+
+    fn load(&self) -> Result<Option<(u16, Vec<u8>)>, Error> {
+        let txn = self.env.begin_ro_txn()?;
+        let data = match txn.get(self.state_db, self.channel_id.as_inner()) {
+            Ok(sealed) => unseal(sealed).map(|(version, data)| (version, data.to_vec()))?,
+            Err(lmdb::Error::NotFound) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        debug!("Loading channel state from the channel database");
+        Ok(Some(data))
+    }
+
+    fn store(&mut self, data: &[u8], record: &HistoryRecord) -> Result<(), Error> {
+        let record = record_json(record);
+        let append = self.last_record.as_ref() != Some(&record);
+        let mut txn = self.env.begin_rw_txn()?;
+        txn.put(self.state_db, self.channel_id.as_inner(), &seal(data), WriteFlags::empty())?;
+        if append {
+            let key = self.history_key(self.next_record);
+            txn.put(self.history_db, &key, &timestamped(&record), WriteFlags::NO_OVERWRITE)?;
+        }
+        txn.commit()?;
+        if append {
+            self.next_record += 1;
+            self.last_record = Some(record);
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, channel_id: ChannelId) -> Result<(), Error> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let state = match txn.get(self.state_db, self.channel_id.as_inner()) {
+            Ok(sealed) => Some(sealed.to_vec()),
+            // Channel state may have not been stored under the previous id yet
+            Err(lmdb::Error::NotFound) => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(ref state) = state {
+            txn.del(self.state_db, self.channel_id.as_inner(), None)?;
+            txn.put(self.state_db, channel_id.as_inner(), state, WriteFlags::empty())?;
+        }
+        // History records stay under the history id, which is linked to the new channel id
+        match txn.del(self.history_id_db, self.channel_id.as_inner(), None) {
+            Ok(()) | Err(lmdb::Error::NotFound) => {}
+            Err(err) => return Err(err.into()),
+        }
+        txn.put(
+            self.history_id_db,
+            channel_id.as_inner(),
+            self.history_id.as_inner(),
+            WriteFlags::empty(),
+        )?;
+        txn.commit()?;
+        self.channel_id = channel_id;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::VERSION;
+    use super::*;
+
+    fn record(local_commitment: u64) -> HistoryRecord {
+        HistoryRecord {
+            state: "Active".to_owned(),
+            lifecycle: "Active".to_owned(),
+            local_commitment,
+            remote_commitment: local_commitment,
+            to_local_msat: 1_000_000,
+            to_remote_msat: 0,
+            offered: vec![],
+            received: vec![],
+        }
+    }
+
+    /// Returns keys of all history records in the database
+    fn history_keys(driver: &DbDriver) -> Vec<Vec<u8>> {
+        let txn = driver.env.begin_ro_txn().unwrap();
+        let mut cursor = txn.open_ro_cursor(driver.history_db).unwrap();
+        let keys = cursor.iter_start().map(|item| item.unwrap().0.to_vec()).collect();
+        keys
+    }
+
+    #[test]
+    fn history_append_only() {
+        let path = std::env::temp_dir().join(format!("lnp-channel-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let config = || Box::new(DbConfig { path: path.clone() }) as Box<dyn Any>;
+        let temp_id = ChannelId::from_inner(Slice32::from_inner([1u8; 32]));
+        let channel_id = ChannelId::from_inner(Slice32::from_inner([2u8; 32]));
+
+        let mut driver = DbDriver::init(temp_id, config()).unwrap();
+        driver.store(b"state", &record(0)).unwrap();
+        driver.store(b"state", &record(0)).unwrap();
+        let keys = history_keys(&driver);
+        assert_eq!(keys.len(), 1);
+
+        // History records are not moved under the new channel id
+        driver.rename(channel_id).unwrap();
+        assert_eq!(history_keys(&driver), keys);
+
+        // Reopened driver does not repeat the latest record
+        let mut driver = DbDriver::init(channel_id, config()).unwrap();
+        assert_eq!(driver.load().unwrap(), Some((VERSION, b"state".to_vec())));
+        driver.store(b"state", &record(0)).unwrap();
+        assert_eq!(history_keys(&driver), keys);
+        driver.store(b"state", &record(1)).unwrap();
+        let keys = history_keys(&driver);
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|key| key.starts_with(&temp_id.as_inner()[..])));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
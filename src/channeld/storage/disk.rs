@@ -12,27 +12,115 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 use std::any::Any;
+use std::fs;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use lnp::p2p::bolt::ChannelId;
 
-use super::Driver;
-use crate::Error;
+use super::driver::{seal, unseal};
+use super::{Driver, Error, HistoryRecord};
 
+/// Configuration of the disk storage driver
 pub struct DiskConfig {
+    /// Directory containing channel files
     pub path: PathBuf,
 }
 
+/// Storage driver keeping channel state in a file within the data directory.
+///
+/// The file is never modified in place: a new state is written to a temporary file, which then
+/// atomically replaces the previous one.
 pub struct DiskDriver {
     channel_id: ChannelId,
     config: DiskConfig,
 }
 
+impl DiskDriver {
+    fn file_path(&self, channel_id: ChannelId) -> PathBuf {
+        let mut path = self.config.path.clone();
+        path.push(channel_id.to_string());
+        path.set_extension("channel");
+        path
+    }
+
+    /// Flushes directory entries, so the file renaming survives a system crash
+    fn sync_dir(&self) {
+        // Directories can't be opened for syncing on some platforms, in which case renaming is
+        // already durable
+        if let Ok(dir) = fs::File::open(&self.config.path) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
 impl Driver for DiskDriver {
     fn init(channel_id: ChannelId, config: Box<dyn Any>) -> Result<Self, Error> {
-        let config = *config.downcast().map_err(|_| Error::Other(s!("")))?;
+        let config: DiskConfig = *config.downcast().map_err(|_| Error::WrongConfig)?;
+        fs::create_dir_all(&config.path)?;
         Ok(Self { channel_id, config })
     }
 
-    fn store(&mut self) -> Result<(), Error> { unimplemented!() }
+    fn list(config: Box<dyn Any>) -> Result<Vec<ChannelId>, Error> {
+        let config: DiskConfig = *config.downcast().map_err(|_| Error::WrongConfig)?;
+        let entries = match fs::read_dir(&config.path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut channels = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("channel") {
+                continue;
+            }
+            // Files not named by channel id are not created by the driver and are ignored
+            if let Some(channel_id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| ChannelId::from_str(stem).ok())
+            {
+                channels.push(channel_id);
+            }
+        }
+        Ok(channels)
+    }
+
+    fn load(&self) -> Result<Option<(u16, Vec<u8>)>, Error> {
+        let path = self.file_path(self.channel_id);
+        let sealed = match fs::read(&path) {
+            Ok(sealed) => sealed,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        debug!("Loading channel state from {}", path.display());
+        unseal(&sealed).map(|(version, data)| Some((version, data.to_vec())))
+    }
+
+    // Channel files keep only the current channel state, so the history record is ignored
+    fn store(&mut self, data: &[u8], _: &HistoryRecord) -> Result<(), Error> {
+        let path = self.file_path(self.channel_id);
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("channel.tmp");
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&seal(data))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        self.sync_dir();
+        Ok(())
+    }
+
+    fn rename(&mut self, channel_id: ChannelId) -> Result<(), Error> {
+        let prev_path = self.file_path(self.channel_id);
+        self.channel_id = channel_id;
+        match fs::rename(prev_path, self.file_path(channel_id)) {
+            // Channel state may have not been stored under the previous id yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+        self.sync_dir();
+        Ok(())
+    }
 }
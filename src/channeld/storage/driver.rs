@@ -13,14 +13,135 @@
 
 use std::any::Any;
 
+use bitcoin::hashes::{sha256, Hash};
 use lnp::p2p::bolt::ChannelId;
 
-use crate::Error;
+use super::{Error, HistoryRecord};
 
+/// Magic bytes starting each stored channel state
+const MAGIC: [u8; 4] = *b"LNPC";
+
+/// Current version of the stored channel state format. Each change of the channel state layout
+/// must increase it, so the states stored by the previous versions can still be decoded:
+/// - 0: legacy channel files written before introduction of storage drivers, which have no header;
+/// - 1: the first sealed format;
+/// - 2: adds address of the remote peer, short channel id, justice transaction rebroadcast state,
+///   remote signatures for HTLC transactions of the latest local commitment, HTLCs of the revoked
+///   remote commitments and second-stage transactions spending them, payment preimages and HTLC
+///   transactions of the published local commitment.
+pub const VERSION: u16 = 2;
+
+/// Length of the header preceding stored channel state data: magic bytes, version and checksum
+const HEADER_LEN: usize = MAGIC.len() + 2 + sha256::Hash::LEN;
+
+/// Storage backend persisting serialized state of a single channel
 pub trait Driver {
     fn init(channel_id: ChannelId, config: Box<dyn Any>) -> Result<Self, Error>
     where
         Self: Sized;
 
-    fn store(&mut self) -> Result<(), Error>;
+    /// Lists ids of all channels which have their state stored
+    fn list(config: Box<dyn Any>) -> Result<Vec<ChannelId>, Error>
+    where
+        Self: Sized;
+
+    /// Loads stored channel state data, verifying their integrity, together with the version of
+    /// the format they were stored with. Returns `None` if the channel state was never stored.
+    fn load(&self) -> Result<Option<(u16, Vec<u8>)>, Error>;
+
+    /// Replaces stored channel state data. Implementations must guarantee that a crash during the
+    /// operation leaves either the previous or the new data in the storage.
+    ///
+    /// Drivers keeping channel history append the provided record to it, unless it matches the
+    /// previous one.
+    fn store(&mut self, data: &[u8], record: &HistoryRecord) -> Result<(), Error>;
+
+    /// Moves stored channel state under a new channel id, which happens once the temporary
+    /// channel id is replaced with the permanent one
+    fn rename(&mut self, channel_id: ChannelId) -> Result<(), Error>;
+}
+
+/// Prepends channel state data with the header containing format version and data checksum
+pub(super) fn seal(data: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(HEADER_LEN + data.len());
+    sealed.extend(MAGIC);
+    sealed.extend(VERSION.to_be_bytes());
+    sealed.extend(sha256::Hash::hash(data).into_inner());
+    sealed.extend(data);
+    sealed
+}
+
+/// Verifies header of the stored channel state and returns the state data following it together
+/// with the format version. Data without the header are legacy channel state of version 0; they
+/// are sealed with the current version once the channel state is stored again.
+pub(super) fn unseal(sealed: &[u8]) -> Result<(u16, &[u8]), Error> {
+    if sealed.is_empty() {
+        return Err(Error::UnknownFormat);
+    }
+    if sealed.len() < HEADER_LEN || sealed[..MAGIC.len()] != MAGIC {
+        return Ok((0, sealed));
+    }
+    let (header, data) = sealed.split_at(HEADER_LEN);
+    let version = u16::from_be_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]]);
+    if version == 0 || version > VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    if header[MAGIC.len() + 2..] != sha256::Hash::hash(data)[..] {
+        return Err(Error::ChecksumMismatch);
+    }
+    Ok((version, data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seal_roundtrip() {
+        let data = b"channel state data";
+        let sealed = seal(data);
+        assert_eq!(&sealed[..4], b"LNPC");
+        assert_eq!(sealed.len(), HEADER_LEN + data.len());
+        assert_eq!(unseal(&sealed).unwrap(), (VERSION, &data[..]));
+        assert_eq!(unseal(&seal(&[])).unwrap(), (VERSION, &[][..]));
+    }
+
+    #[test]
+    fn unseal_corrupted() {
+        let mut sealed = seal(b"channel state data");
+        let last = sealed.len() - 1;
+        sealed[last] ^= 0x01;
+        assert_eq!(unseal(&sealed), Err(Error::ChecksumMismatch));
+
+        let mut sealed = seal(b"channel state data");
+        sealed[HEADER_LEN - 1] ^= 0x01;
+        assert_eq!(unseal(&sealed), Err(Error::ChecksumMismatch));
+
+        let mut sealed = seal(b"channel state data");
+        sealed.truncate(HEADER_LEN + 3);
+        assert_eq!(unseal(&sealed), Err(Error::ChecksumMismatch));
+
+        assert_eq!(unseal(&[]), Err(Error::UnknownFormat));
+    }
+
+    #[test]
+    fn unseal_versions() {
+        let mut sealed = seal(b"channel state data");
+        sealed[4..6].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        assert_eq!(unseal(&sealed), Err(Error::UnsupportedVersion(VERSION + 1)));
+        sealed[4..6].copy_from_slice(&0u16.to_be_bytes());
+        assert_eq!(unseal(&sealed), Err(Error::UnsupportedVersion(0)));
+
+        // Older versions are unsealed as they are, leaving decoding to the channel state
+        sealed[4..6].copy_from_slice(&1u16.to_be_bytes());
+        assert_eq!(unseal(&sealed).unwrap(), (1, &b"channel state data"[..]));
+    }
+
+    #[test]
+    fn unseal_legacy() {
+        let legacy = b"\x03legacy channel state which is long enough to contain a header";
+        assert_eq!(unseal(legacy).unwrap(), (0, &legacy[..]));
+        assert_eq!(unseal(b"LNPC").unwrap(), (0, &b"LNPC"[..]));
+        assert_eq!(unseal(&[0x01]).unwrap(), (0, &[0x01][..]));
+    }
 }
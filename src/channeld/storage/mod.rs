@@ -11,8 +11,115 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+//! Persistent storage of the channel state with pluggable storage backends
+
+mod db;
 mod disk;
 mod driver;
 
+use amplify::IoError;
+use bitcoin_scripts::hlc::HashLock;
+pub use db::{DbConfig, DbDriver};
 pub use disk::{DiskConfig, DiskDriver};
-pub use driver::Driver;
+pub use driver::{Driver, VERSION};
+use lnp::p2p::bolt::UpdateAddHtlc;
+
+/// Errors of the channel state storage
+#[derive(Clone, PartialEq, Eq, Debug, Display, From, Error)]
+#[display(doc_comments)]
+pub enum Error {
+    /// I/O error accessing channel state storage. Details: {0}
+    #[from(std::io::Error)]
+    Io(IoError),
+
+    /// configuration provided to the storage driver has a wrong type
+    WrongConfig,
+
+    /// stored channel state has unknown format
+    UnknownFormat,
+
+    /// stored channel state has unsupported version {0}
+    UnsupportedVersion(u16),
+
+    /// stored channel state is corrupted: its checksum does not match the data
+    ChecksumMismatch,
+
+    /// channel database failure. Details: {0}
+    #[from]
+    Database(lmdb::Error),
+}
+
+/// Storage backends which can be used for persisting channel state
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum Backend {
+    /// Separate file for each of the channels
+    #[display("disk")]
+    Disk,
+
+    /// Embedded key-value database shared by all channels, which also keeps channel history
+    #[display("db")]
+    Db,
+}
+
+impl Default for Backend {
+    fn default() -> Self { Backend::Disk }
+}
+
+#[cfg(feature = "server")]
+impl From<crate::opts::StorageBackend> for Backend {
+    fn from(backend: crate::opts::StorageBackend) -> Self {
+        match backend {
+            crate::opts::StorageBackend::Disk => Backend::Disk,
+            crate::opts::StorageBackend::Db => Backend::Db,
+        }
+    }
+}
+
+/// Summary of the channel state, which is appended to the channel history by the storage drivers
+/// supporting it each time the state changes
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HistoryRecord {
+    /// State of the channel state machine
+    pub state: String,
+
+    /// Channel lifecycle stage
+    pub lifecycle: String,
+
+    /// Number of the latest local commitment
+    pub local_commitment: u64,
+
+    /// Number of the latest remote commitment which is not revoked yet
+    pub remote_commitment: u64,
+
+    /// Local balance in the latest local commitment
+    pub to_local_msat: u64,
+
+    /// Remote balance in the latest local commitment
+    pub to_remote_msat: u64,
+
+    /// HTLCs offered by the local node in the latest local commitment
+    pub offered: Vec<HtlcRecord>,
+
+    /// HTLCs offered by the remote peer in the latest local commitment
+    pub received: Vec<HtlcRecord>,
+}
+
+/// HTLC details kept in the channel history
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HtlcRecord {
+    pub htlc_id: u64,
+    pub amount_msat: u64,
+    pub payment_hash: HashLock,
+    pub cltv_expiry: u32,
+}
+
+impl From<&UpdateAddHtlc> for HtlcRecord {
+    fn from(htlc: &UpdateAddHtlc) -> Self {
+        HtlcRecord {
+            htlc_id: htlc.htlc_id,
+            amount_msat: htlc.amount_msat,
+            payment_hash: htlc.payment_hash,
+            cltv_expiry: htlc.cltv_expiry,
+        }
+    }
+}
@@ -13,14 +13,20 @@
 
 #![allow(clippy::needless_borrow)] // due to a bug in `display(Debug)`
 
-use std::fmt::Debug;
-use std::path::PathBuf;
+use std::fmt::{Debug, Display};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use internet2::addr::ServiceAddr;
-use lnp::p2p::bolt::ActiveChannelId;
+use internet2::addr::{NodeId, ServiceAddr};
+use lnp::channel::bolt::{CommonParams, PeerParams, Policy};
+use lnp::p2p::bolt::{ActiveChannelId, ChannelType};
 use lnpbp::chain::Chain;
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer};
 
-use crate::opts::Options;
+use crate::channeld::storage;
+use crate::opts::{Options, LNP_NODE_CONFIG};
+use crate::LNP_NODE_CHANNEL_DB;
 
 /// Final configuration resulting from data contained in config file environment
 /// variables and command-line options. For security reasons node key is kept
@@ -52,6 +58,19 @@ where
     /// Indicates whether deamons should be spawned as threads (true) or as child processes (false)
     pub threaded: bool,
 
+    /// Storage backend used for persisting channel states
+    pub storage: storage::Backend,
+
+    /// Drift of the on-chain fee estimate from the channel fee rate, in percents, above which
+    /// channel funder updates commitment fee
+    pub fee_update_threshold: u16,
+
+    /// Channel policy and parameters from the node configuration file
+    pub channel: ChannelConf,
+
+    /// Rules for accepting channels proposed by remote peers, from the node configuration file
+    pub acceptance: AcceptanceConf,
+
     /// Daemon-specific config extensions
     pub ext: Ext,
 }
@@ -84,6 +103,10 @@ where
             rpc_endpoint: orig.rpc_endpoint,
             electrum_url: orig.electrum_url,
             threaded: orig.threaded,
+            storage: orig.storage,
+            fee_update_threshold: orig.fee_update_threshold,
+            channel: orig.channel,
+            acceptance: orig.acceptance,
             ext,
         }
     }
@@ -94,6 +117,12 @@ where
         channel_dir
     }
 
+    pub fn channel_db(&self) -> PathBuf {
+        let mut channel_db = self.data_dir.clone();
+        channel_db.push(LNP_NODE_CHANNEL_DB);
+        channel_db
+    }
+
     pub fn channel_file(&self, channel_id: ActiveChannelId) -> PathBuf {
         let mut channel_file = self.channel_dir();
         channel_file.push(channel_id.to_string());
@@ -102,13 +131,52 @@ where
     }
 }
 
+/// Errors in the node configuration file
 #[cfg(feature = "server")]
-impl<Opt> From<Opt> for Config<Opt::Conf>
+#[derive(Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum ConfigError {
+    /// unable to read node configuration file. Details: {0}
+    File(settings::ConfigError),
+
+    /// invalid `[{0}]` section of the node configuration file. Details: {1}
+    Section(&'static str, settings::ConfigError),
+}
+
+/// Implements conversion of daemon command-line options into the configuration. A blanket
+/// implementation over [`Options`] is not possible, since it conflicts with the blanket
+/// `TryFrom` implementation in `core`.
+#[cfg(feature = "server")]
+macro_rules! impl_try_from_opts {
+    ($($opts:ty),+) => {$(
+        impl TryFrom<$opts> for Config<<$opts as Options>::Conf> {
+            type Error = ConfigError;
+
+            fn try_from(opts: $opts) -> Result<Self, Self::Error> { Config::with_opts(opts) }
+        }
+    )+};
+}
+
+#[cfg(feature = "server")]
+impl_try_from_opts!(
+    crate::lnpd::Opts,
+    crate::peerd::Opts,
+    crate::channeld::Opts,
+    crate::routed::Opts,
+    crate::signd::Opts,
+    crate::watchd::Opts
+);
+
+#[cfg(feature = "server")]
+impl<Ext> Config<Ext>
 where
-    Opt: Options,
-    Opt::Conf: Clone + Eq + Debug,
+    Ext: Clone + Eq + Debug,
 {
-    fn from(opt: Opt) -> Self {
+    /// Constructs configuration from the command-line options and the node configuration file
+    fn with_opts<Opt>(opt: Opt) -> Result<Self, ConfigError>
+    where
+        Opt: Options<Conf = Ext>,
+    {
         let opts = opt.shared();
 
         let electrum_url = format!(
@@ -117,12 +185,23 @@ where
             opts.electrum_port.unwrap_or_else(|| default_electrum_port(&opts.chain))
         );
 
+        // Configuration file is optional unless its location is explicitly provided
+        let config_file = opts.config.clone().unwrap_or_else(|| {
+            PathBuf::from(LNP_NODE_CONFIG.replace("{data_dir}", &opts.data_dir.to_string_lossy()))
+        });
+        let config_file =
+            read_config_file(&config_file, opts.config.is_some()).map_err(ConfigError::File)?;
+        let channel = config_section(&config_file, "channel")
+            .map_err(|err| ConfigError::Section("channel", err))?;
+        let acceptance = config_section(&config_file, "acceptance")
+            .map_err(|err| ConfigError::Section("acceptance", err))?;
+
         let ctl_endpoint = match opts.threaded_daemons {
             true => ServiceAddr::Inproc(s!("lnp-ctl")),
             false => opts.ctl_endpoint.clone(),
         };
 
-        Config {
+        Ok(Config {
             chain: opts.chain.clone(),
             data_dir: opts.data_dir.clone(),
             msg_endpoint: opts.msg_endpoint.clone(),
@@ -130,7 +209,243 @@ where
             rpc_endpoint: opts.rpc_endpoint.clone(),
             electrum_url,
             threaded: opts.threaded_daemons,
+            storage: opts.storage.into(),
+            fee_update_threshold: opts.fee_update_threshold,
+            channel,
+            acceptance,
             ext: opt.config(),
+        })
+    }
+}
+
+/// Reads node configuration file. If the file is not required, its absence results in empty
+/// configuration.
+#[cfg(feature = "server")]
+fn read_config_file(
+    path: &Path,
+    required: bool,
+) -> Result<settings::Config, settings::ConfigError> {
+    let mut settings = settings::Config::default();
+    settings
+        .merge(settings::File::from(path).format(settings::FileFormat::Toml).required(required))?;
+    Ok(settings)
+}
+
+/// Reads section of the node configuration file, using default configuration if the section is
+/// absent
+#[cfg(feature = "server")]
+fn config_section<T>(settings: &settings::Config, name: &str) -> Result<T, settings::ConfigError>
+where
+    T: DeserializeOwned + Default,
+{
+    match settings.get::<T>(name) {
+        Err(settings::ConfigError::NotFound(_)) => Ok(T::default()),
+        res => res,
+    }
+}
+
+/// Deserializes value from its string representation
+fn parse_value<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+}
+
+/// Deserializes list of values from their string representations
+fn parse_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(D::Error::custom))
+        .collect()
+}
+
+/// Channel policy and parameters, read from the `[channel]` section of the node configuration
+/// file. Parameters which are absent in the file take default values.
+///
+/// Local parameters are requested from the remote peer in `open_channel` and `accept_channel`
+/// messages; limits define which parameters requested by the remote peer are acceptable.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Deserialize)]
+#[serde(default)]
+pub struct ChannelConf {
+    /// Threshold below which outputs of our transactions are omitted, in satoshis
+    pub dust_limit: u64,
+
+    /// Maximal dust limit which may be required by the remote peer, in satoshis
+    pub dust_limit_max: u64,
+
+    /// Number of blocks the remote peer has to wait to claim its funds from its commitment
+    /// transaction
+    pub to_self_delay: u16,
+
+    /// Maximal number of blocks which the remote peer may require us to wait to claim our funds
+    /// from our commitment transaction
+    pub to_self_delay_max: u16,
+
+    /// Smallest HTLC value we accept, in millisatoshis
+    pub htlc_minimum_msat: u64,
+
+    /// Maximal value of the smallest HTLC the remote peer may accept, in millisatoshis
+    pub htlc_minimum_msat_max: u64,
+
+    /// Maximal value of inbound HTLCs in flight, in millisatoshis
+    pub max_htlc_value_in_flight_msat: u64,
+
+    /// Minimal limit of the outbound HTLCs value in flight which the remote peer may set, in
+    /// millisatoshis
+    pub max_htlc_value_in_flight_msat_min: u64,
+
+    /// Maximal number of inbound HTLCs
+    pub max_accepted_htlcs: u16,
+
+    /// Minimal limit of the outbound HTLCs number which the remote peer may set
+    pub max_accepted_htlcs_min: u16,
+
+    /// Channel reserve which must be kept by the remote peer, in satoshis
+    pub channel_reserve: u64,
+
+    /// Maximal channel reserve which may be required by the remote peer, in percents of the
+    /// channel funding
+    pub channel_reserve_max_percent: u8,
+
+    /// Number of confirmations of the funding transaction we require before the channel
+    /// becomes operational
+    pub minimum_depth: u32,
+
+    /// Maximal number of confirmations of the funding transaction which may be required by the
+    /// remote peer
+    pub maximum_depth: u32,
+
+    /// Type of the channels opened by us, defining the structure of commitment transactions
+    /// (`basic`, `static_remotekey` or `anchored`)
+    #[serde(deserialize_with = "parse_value")]
+    pub channel_type: ChannelType,
+
+    /// Remote peers trusted with zero-confirmation channels, which become operational before
+    /// their funding transaction is mined. Short channel id aliases are not supported, so other
+    /// nodes can route payments through such channels only after the funding transaction is mined
+    #[serde(deserialize_with = "parse_list")]
+    pub zeroconf_peers: Vec<NodeId>,
+}
+
+impl Default for ChannelConf {
+    fn default() -> Self {
+        let policy = Policy::default();
+        let common_params = CommonParams::default();
+        let local_params = PeerParams::default();
+        ChannelConf {
+            dust_limit: local_params.dust_limit_satoshis,
+            dust_limit_max: policy.dust_limit_satoshis_max.unwrap_or(u64::MAX),
+            to_self_delay: local_params.to_self_delay,
+            to_self_delay_max: policy.to_self_delay_max,
+            htlc_minimum_msat: local_params.htlc_minimum_msat,
+            htlc_minimum_msat_max: policy.htlc_minimum_msat_max.unwrap_or(u64::MAX),
+            max_htlc_value_in_flight_msat: local_params.max_htlc_value_in_flight_msat,
+            max_htlc_value_in_flight_msat_min: policy
+                .max_htlc_value_in_flight_msat_min
+                .unwrap_or_default(),
+            max_accepted_htlcs: local_params.max_accepted_htlcs,
+            max_accepted_htlcs_min: policy.max_accepted_htlcs_min.unwrap_or_default(),
+            channel_reserve: local_params.channel_reserve_satoshis,
+            channel_reserve_max_percent: policy.channel_reserve_satoshis_max_percent.unwrap_or(100),
+            minimum_depth: common_params.minimum_depth,
+            maximum_depth: policy.maximum_depth.unwrap_or(u32::MAX),
+            channel_type: common_params.channel_type,
+            zeroconf_peers: vec![],
+        }
+    }
+}
+
+impl ChannelConf {
+    /// Policy used to validate parameters requested by the remote peer
+    pub fn policy(&self) -> Policy {
+        Policy {
+            to_self_delay_max: self.to_self_delay_max,
+            minimum_depth: self.minimum_depth,
+            maximum_depth: Some(self.maximum_depth),
+            htlc_minimum_msat_max: Some(self.htlc_minimum_msat_max),
+            max_htlc_value_in_flight_msat_min: Some(self.max_htlc_value_in_flight_msat_min),
+            channel_reserve_satoshis_max_percent: Some(self.channel_reserve_max_percent),
+            max_accepted_htlcs_min: Some(self.max_accepted_htlcs_min),
+            dust_limit_satoshis_max: Some(self.dust_limit_max),
+            ..Policy::default()
+        }
+    }
+
+    /// Common parameters proposed to the remote peer for the channels opened by us
+    pub fn common_params(&self) -> CommonParams {
+        CommonParams {
+            minimum_depth: self.minimum_depth,
+            channel_type: self.channel_type,
+            ..CommonParams::default()
+        }
+    }
+
+    /// Parameters which we require from the remote peer
+    pub fn local_params(&self) -> PeerParams {
+        PeerParams {
+            dust_limit_satoshis: self.dust_limit,
+            to_self_delay: self.to_self_delay,
+            htlc_minimum_msat: self.htlc_minimum_msat,
+            max_htlc_value_in_flight_msat: self.max_htlc_value_in_flight_msat,
+            channel_reserve_satoshis: self.channel_reserve,
+            max_accepted_htlcs: self.max_accepted_htlcs,
+        }
+    }
+}
+
+/// Rules for accepting channels proposed by remote peers, read from the `[acceptance]` section of
+/// the node configuration file. Absent rules do not restrict channel proposals.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(default)]
+pub struct AcceptanceConf {
+    /// Minimal channel funding, in satoshis
+    pub funding_min: Option<u64>,
+
+    /// Maximal channel funding, in satoshis
+    pub funding_max: Option<u64>,
+
+    /// Maximal number of channels with a single remote peer
+    pub max_channels_per_peer: Option<usize>,
+
+    /// If not empty, channels are accepted only from the listed nodes
+    #[serde(deserialize_with = "parse_list")]
+    pub allow_list: Vec<NodeId>,
+
+    /// Nodes from which channels are never accepted
+    #[serde(deserialize_with = "parse_list")]
+    pub deny_list: Vec<NodeId>,
+
+    /// If not empty, channels are accepted only if they have one of the listed types
+    #[serde(deserialize_with = "parse_list")]
+    pub channel_types: Vec<ChannelType>,
+
+    /// Number of seconds to wait for the decision of an external channel acceptor
+    pub acceptor_timeout: u64,
+
+    /// Whether channels are accepted when the external channel acceptor does not decide on them
+    /// in time
+    pub accept_on_timeout: bool,
+}
+
+impl Default for AcceptanceConf {
+    fn default() -> Self {
+        AcceptanceConf {
+            funding_min: None,
+            funding_max: None,
+            max_channels_per_peer: None,
+            allow_list: vec![],
+            deny_list: vec![],
+            channel_types: vec![],
+            acceptor_timeout: 30,
+            accept_on_timeout: false,
         }
     }
 }
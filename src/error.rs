@@ -22,10 +22,12 @@ use wallet::psbt::sign::SignError;
 
 use crate::bus::ServiceBus;
 use crate::channeld;
+use crate::channeld::storage;
 use crate::lnpd::automata::launch;
-use crate::lnpd::{funding, Daemon};
-use crate::routed::PaymentError;
+use crate::lnpd::{address_book, backup, funding, invoices, Daemon};
+use crate::routed::{graph, PaymentError};
 use crate::rpc::{self, ServiceId};
+use crate::watchd::watch_list;
 
 #[derive(Debug, Display, From, Error)]
 #[display(doc_comments)]
@@ -71,6 +73,10 @@ pub enum Error {
     /// failing to restore channel state. Details: {0}
     Persistence(strict_encoding::Error),
 
+    /// channel state storage failure. Details: {0}
+    #[from]
+    Storage(storage::Error),
+
     /// encoding failure
     ///
     /// Details: {0}
@@ -88,6 +94,31 @@ pub enum Error {
     #[display(inner)]
     FundingWallet(funding::Error),
 
+    /// Error working with invoices issued by the node
+    #[from]
+    #[display(inner)]
+    Invoices(invoices::Error),
+
+    /// Error working with static channel backup
+    #[from]
+    #[display(inner)]
+    Backup(backup::Error),
+
+    /// Error working with the address book of remote peers
+    #[from]
+    #[display(inner)]
+    AddressBook(address_book::Error),
+
+    /// Error working with the persistent routing graph
+    #[from]
+    #[display(inner)]
+    RoutingGraph(graph::Error),
+
+    /// Error working with the persistent list of watched outputs
+    #[from]
+    #[display(inner)]
+    WatchList(watch_list::Error),
+
     /// unable to deriving keys: {0}
     #[from]
     Derivation(bip32::Error),
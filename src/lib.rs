@@ -55,12 +55,23 @@ mod service;
 pub mod signd;
 pub mod watchd;
 
-pub use config::Config;
+#[cfg(feature = "server")]
+pub use config::ConfigError;
+pub use config::{AcceptanceConf, ChannelConf, Config};
 pub use error::Error;
 pub use service::{BridgeHandler, Endpoints, Responder, Service, TryToServiceId};
 
 pub const LNP_NODE_MASTER_KEY_FILE: &str = "master.key";
 pub const LNP_NODE_FUNDING_WALLET: &str = "funding.wallet";
+pub const LNP_NODE_INVOICES: &str = "invoices.dat";
+pub const LNP_NODE_ADDRESS_BOOK: &str = "address_book.dat";
+pub const LNP_NODE_CHANNEL_DB: &str = "channels.db";
+pub const LNP_NODE_CHANNEL_BACKUP: &str = "channels.backup";
+pub const LNP_NODE_ROUTING_GRAPH: &str = "routing_graph.dat";
+pub const LNP_NODE_WATCH_LIST: &str = "watch_list.dat";
+
+/// Minimal fee rate accepted for relay by bitcoin nodes, in satoshis per 1000 weight units
+pub const FEERATE_PER_KW_MIN: u32 = 253;
 
 #[cfg(not(any(feature = "bolt", feature = "bifrost")))]
 compile_error!("either 'bolt' or 'bifrost' feature must be used");
@@ -0,0 +1,288 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Policy for accepting channels proposed by remote peers, which is checked before a channel
+//! daemon is launched for the proposal.
+
+use std::collections::HashSet;
+
+use internet2::addr::NodeId;
+use lnp::p2p::bolt::{ChannelType, OpenChannel};
+
+use crate::AcceptanceConf;
+
+/// Reasons for rejecting a channel proposed by a remote peer. The text of the reason is sent to
+/// the remote peer within BOLT `error` message.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Rejection {
+    /// channel funding of {proposed} sat is below the minimum of {required} sat
+    FundingTooSmall { proposed: u64, required: u64 },
+
+    /// channel funding of {proposed} sat exceeds the maximum of {allowed} sat
+    FundingTooLarge { proposed: u64, allowed: u64 },
+
+    /// the node already has {0} channels with the peer, which is the maximum allowed
+    TooManyChannels(usize),
+
+    /// the node does not accept channels from the peer
+    PeerNotAllowed,
+
+    /// channel type `{0}` is not accepted by the node
+    ChannelTypeNotAccepted(ChannelType),
+}
+
+/// Channel proposal received from a remote peer
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ChannelProposal<'msg> {
+    /// Node proposing the channel
+    pub remote_id: NodeId,
+
+    /// Number of channels with the remote peer known to the node
+    pub peer_channels: usize,
+
+    /// Request received from the remote peer to open channel
+    pub open_channel: &'msg OpenChannel,
+}
+
+/// Single rule of the channel acceptance policy. Custom rules may be added to the policy with
+/// [`AcceptancePolicy::push`].
+pub trait AcceptanceRule {
+    /// Checks whether the proposal satisfies the rule
+    fn check(&self, proposal: &ChannelProposal) -> Result<(), Rejection>;
+}
+
+/// Limits on the channel funding amount
+pub struct FundingLimits {
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl AcceptanceRule for FundingLimits {
+    fn check(&self, proposal: &ChannelProposal) -> Result<(), Rejection> {
+        let proposed = proposal.open_channel.funding_satoshis;
+        match (self.min, self.max) {
+            (Some(required), _) if proposed < required => {
+                Err(Rejection::FundingTooSmall { proposed, required })
+            }
+            (_, Some(allowed)) if proposed > allowed => {
+                Err(Rejection::FundingTooLarge { proposed, allowed })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Limit on the number of channels with a single remote peer
+pub struct PeerChannelLimit(pub usize);
+
+impl AcceptanceRule for PeerChannelLimit {
+    fn check(&self, proposal: &ChannelProposal) -> Result<(), Rejection> {
+        if proposal.peer_channels >= self.0 {
+            return Err(Rejection::TooManyChannels(proposal.peer_channels));
+        }
+        Ok(())
+    }
+}
+
+/// Restriction of remote peers which may open channels with the node
+pub enum PeerList {
+    /// Only the listed peers may open channels
+    Allow(HashSet<NodeId>),
+
+    /// The listed peers may not open channels
+    Deny(HashSet<NodeId>),
+}
+
+impl AcceptanceRule for PeerList {
+    fn check(&self, proposal: &ChannelProposal) -> Result<(), Rejection> {
+        match self {
+            PeerList::Allow(list) if !list.contains(&proposal.remote_id) => {
+                Err(Rejection::PeerNotAllowed)
+            }
+            PeerList::Deny(list) if list.contains(&proposal.remote_id) => {
+                Err(Rejection::PeerNotAllowed)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Channel types which are accepted by the node
+pub struct ChannelTypes(pub HashSet<ChannelType>);
+
+impl AcceptanceRule for ChannelTypes {
+    fn check(&self, proposal: &ChannelProposal) -> Result<(), Rejection> {
+        let channel_type = proposal.open_channel.channel_type.unwrap_or_default();
+        if !self.0.contains(&channel_type) {
+            return Err(Rejection::ChannelTypeNotAccepted(channel_type));
+        }
+        Ok(())
+    }
+}
+
+/// Set of rules which must be satisfied by a channel proposal for it to be accepted
+#[derive(Default)]
+pub struct AcceptancePolicy {
+    rules: Vec<Box<dyn AcceptanceRule>>,
+}
+
+impl From<&AcceptanceConf> for AcceptancePolicy {
+    fn from(conf: &AcceptanceConf) -> Self {
+        let mut policy = AcceptancePolicy::default();
+        if conf.funding_min.is_some() || conf.funding_max.is_some() {
+            policy.push(FundingLimits { min: conf.funding_min, max: conf.funding_max });
+        }
+        if let Some(limit) = conf.max_channels_per_peer {
+            policy.push(PeerChannelLimit(limit));
+        }
+        if !conf.allow_list.is_empty() {
+            policy.push(PeerList::Allow(conf.allow_list.iter().copied().collect()));
+        }
+        if !conf.deny_list.is_empty() {
+            policy.push(PeerList::Deny(conf.deny_list.iter().copied().collect()));
+        }
+        if !conf.channel_types.is_empty() {
+            policy.push(ChannelTypes(conf.channel_types.iter().copied().collect()));
+        }
+        policy
+    }
+}
+
+impl AcceptancePolicy {
+    /// Adds rule to the policy
+    pub fn push(&mut self, rule: impl AcceptanceRule + 'static) { self.rules.push(Box::new(rule)) }
+
+    /// Checks proposal against all rules of the policy, returning the first violated one
+    pub fn check(&self, proposal: &ChannelProposal) -> Result<(), Rejection> {
+        self.rules.iter().try_for_each(|rule| rule.check(proposal))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::DumbDefault;
+    use bitcoin::secp256k1::{PublicKey, SecretKey, SECP256K1};
+
+    use super::*;
+
+    fn node_id(key: u8) -> NodeId {
+        let secret_key = SecretKey::from_slice(&[key; 32]).unwrap();
+        NodeId::from(PublicKey::from_secret_key(SECP256K1, &secret_key))
+    }
+
+    fn open_channel(funding_satoshis: u64, channel_type: Option<ChannelType>) -> OpenChannel {
+        OpenChannel { funding_satoshis, channel_type, ..OpenChannel::dumb_default() }
+    }
+
+    fn check(
+        policy: &AcceptancePolicy,
+        remote: u8,
+        peer_channels: usize,
+        open_channel: &OpenChannel,
+    ) -> Result<(), Rejection> {
+        policy.check(&ChannelProposal { remote_id: node_id(remote), peer_channels, open_channel })
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = AcceptancePolicy::from(&AcceptanceConf::default());
+        let open_channel = open_channel(1, Some(ChannelType::AnchorsZeroFeeHtlcTxStaticRemotekey));
+        assert_eq!(check(&policy, 1, 100, &open_channel), Ok(()));
+    }
+
+    #[test]
+    fn funding_limits() {
+        let conf = AcceptanceConf {
+            funding_min: Some(100_000),
+            funding_max: Some(1_000_000),
+            ..Default::default()
+        };
+        let policy = AcceptancePolicy::from(&conf);
+        assert_eq!(
+            check(&policy, 1, 0, &open_channel(99_999, None)),
+            Err(Rejection::FundingTooSmall { proposed: 99_999, required: 100_000 })
+        );
+        assert_eq!(check(&policy, 1, 0, &open_channel(100_000, None)), Ok(()));
+        assert_eq!(check(&policy, 1, 0, &open_channel(1_000_000, None)), Ok(()));
+        assert_eq!(
+            check(&policy, 1, 0, &open_channel(1_000_001, None)),
+            Err(Rejection::FundingTooLarge { proposed: 1_000_001, allowed: 1_000_000 })
+        );
+    }
+
+    #[test]
+    fn peer_rules() {
+        let open_channel = open_channel(100_000, None);
+
+        let conf = AcceptanceConf { max_channels_per_peer: Some(2), ..Default::default() };
+        let policy = AcceptancePolicy::from(&conf);
+        assert_eq!(check(&policy, 1, 1, &open_channel), Ok(()));
+        assert_eq!(check(&policy, 1, 2, &open_channel), Err(Rejection::TooManyChannels(2)));
+
+        let conf = AcceptanceConf { allow_list: vec![node_id(1)], ..Default::default() };
+        let policy = AcceptancePolicy::from(&conf);
+        assert_eq!(check(&policy, 1, 0, &open_channel), Ok(()));
+        assert_eq!(check(&policy, 2, 0, &open_channel), Err(Rejection::PeerNotAllowed));
+
+        let conf = AcceptanceConf { deny_list: vec![node_id(1)], ..Default::default() };
+        let policy = AcceptancePolicy::from(&conf);
+        assert_eq!(check(&policy, 1, 0, &open_channel), Err(Rejection::PeerNotAllowed));
+        assert_eq!(check(&policy, 2, 0, &open_channel), Ok(()));
+    }
+
+    #[test]
+    fn channel_types() {
+        let conf = AcceptanceConf {
+            channel_types: vec![ChannelType::Basic, ChannelType::StaticRemotekey],
+            ..Default::default()
+        };
+        let policy = AcceptancePolicy::from(&conf);
+        // Absent channel type means the basic one
+        assert_eq!(check(&policy, 1, 0, &open_channel(100_000, None)), Ok(()));
+        assert_eq!(
+            check(&policy, 1, 0, &open_channel(100_000, Some(ChannelType::StaticRemotekey))),
+            Ok(())
+        );
+        let anchored = Some(ChannelType::AnchorOutputsStaticRemotekey);
+        assert_eq!(
+            check(&policy, 1, 0, &open_channel(100_000, anchored)),
+            Err(Rejection::ChannelTypeNotAccepted(ChannelType::AnchorOutputsStaticRemotekey))
+        );
+    }
+
+    #[test]
+    fn first_violated_rule() {
+        let conf = AcceptanceConf {
+            funding_min: Some(100_000),
+            deny_list: vec![node_id(1)],
+            ..Default::default()
+        };
+        let mut policy = AcceptancePolicy::from(&conf);
+        assert_eq!(
+            check(&policy, 1, 0, &open_channel(1, None)),
+            Err(Rejection::FundingTooSmall { proposed: 1, required: 100_000 })
+        );
+        assert_eq!(
+            check(&policy, 1, 0, &open_channel(100_000, None)),
+            Err(Rejection::PeerNotAllowed)
+        );
+
+        // Custom rules are checked after the configured ones
+        policy.push(PeerChannelLimit(0));
+        assert_eq!(
+            check(&policy, 2, 0, &open_channel(100_000, None)),
+            Err(Rejection::TooManyChannels(0))
+        );
+    }
+}
@@ -0,0 +1,131 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Timer for the channel proposals forwarded to an external channel acceptor. Runs in a separate
+//! thread and notifies lnpd runtime over the bridge when the acceptor has not decided on a
+//! proposal in time.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use internet2::zeromq;
+use lnp::p2p::bolt::ChannelId;
+use microservices::esb;
+use microservices::node::TryService;
+
+use crate::bus::{BusMsg, CtlMsg, ServiceBus};
+use crate::rpc::ServiceId;
+use crate::{BridgeHandler, Error};
+
+/// Handle to the channel acceptor timer thread
+pub(super) struct AcceptorTimer {
+    sender: mpsc::Sender<ChannelId>,
+}
+
+impl AcceptorTimer {
+    /// Spawns timer thread sending timeout notifications over the bridge socket
+    pub fn with(timeout: Duration, tx: zmq::Socket) -> Result<AcceptorTimer, Error> {
+        let (sender, receiver) = mpsc::channel();
+        let timer_runtime = TimerRuntime::with(timeout, receiver, tx)?;
+        thread::Builder::new()
+            .name(s!("acceptor_timer"))
+            .spawn(move || timer_runtime.run_or_panic("channel acceptor timer"))
+            .expect("unable to start channel acceptor timer thread");
+        Ok(AcceptorTimer { sender })
+    }
+
+    /// Starts countdown for the decision on the channel proposal
+    pub fn start(&self, channel_id: ChannelId) {
+        self.sender.send(channel_id).expect("channel acceptor timer thread is halted");
+    }
+}
+
+struct TimerRuntime {
+    bridge: esb::Controller<ServiceBus, BusMsg, BridgeHandler>,
+    receiver: mpsc::Receiver<ChannelId>,
+    timeout: Duration,
+    /// Proposals awaiting decision, ordered by their deadlines
+    pending: VecDeque<(Instant, ChannelId)>,
+}
+
+impl TimerRuntime {
+    pub fn with(
+        timeout: Duration,
+        receiver: mpsc::Receiver<ChannelId>,
+        tx: zmq::Socket,
+    ) -> Result<TimerRuntime, Error> {
+        let bridge = esb::Controller::with(
+            map! {
+                ServiceBus::Bridge => esb::BusConfig {
+                    api_type: zeromq::ZmqSocketType::Rep,
+                    carrier: zeromq::Carrier::Socket(tx),
+                    router: None,
+                    queued: true,
+                    topic: None,
+                }
+            },
+            BridgeHandler,
+        )?;
+
+        Ok(TimerRuntime { bridge, receiver, timeout, pending: empty!() })
+    }
+
+    fn run(&mut self) -> Result<(), mpsc::RecvError> {
+        let received = match self.pending.front() {
+            None => Some(self.receiver.recv()?),
+            Some((deadline, _)) => {
+                match self.receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                {
+                    Ok(channel_id) => Some(channel_id),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return Err(mpsc::RecvError),
+                }
+            }
+        };
+        // Since all proposals have the same timeout, new deadlines never precede existing ones
+        if let Some(channel_id) = received {
+            self.pending.push_back((Instant::now() + self.timeout, channel_id));
+        }
+
+        let now = Instant::now();
+        while let Some((deadline, channel_id)) = self.pending.front().copied() {
+            if deadline > now {
+                break;
+            }
+            self.pending.pop_front();
+            debug!("Channel acceptor decision on {} has timed out", channel_id);
+            self.bridge
+                .send_to(
+                    ServiceBus::Bridge,
+                    ServiceId::LnpBroker,
+                    BusMsg::Ctl(CtlMsg::AcceptorTimeout(channel_id)),
+                )
+                .expect("unable to forward channel acceptor timeout over the bridge");
+        }
+
+        Ok(())
+    }
+}
+
+impl TryService for TimerRuntime {
+    type ErrorType = mpsc::RecvError;
+
+    fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
+        trace!("Entering event loop of the channel acceptor timer");
+        loop {
+            self.run()?;
+        }
+    }
+}
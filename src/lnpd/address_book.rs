@@ -0,0 +1,83 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use amplify::IoError;
+use internet2::addr::{NodeAddr, NodeId};
+use strict_encoding::{StrictDecode, StrictEncode};
+
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Error {
+    /// error accessing address book file. Details: {0}
+    #[from(io::Error)]
+    Io(IoError),
+
+    /// error reading or writing address book data. Details: {0}
+    #[from]
+    StrictEncoding(strict_encoding::Error),
+}
+
+/// Addresses of the remote peers the node has connected to, used for reconnecting to the channel
+/// peers once the node restarts
+pub struct AddressBook {
+    path: PathBuf,
+    addrs: BTreeMap<NodeId, NodeAddr>,
+}
+
+impl AddressBook {
+    /// Opens address book file, creating a new empty one if it does not exist
+    pub fn with(path: impl AsRef<Path>) -> Result<AddressBook, Error> {
+        let path = path.as_ref().to_path_buf();
+        match fs::File::open(&path) {
+            Ok(file) => {
+                debug!("Loading peer addresses from '{}'", path.display());
+                let addrs = BTreeMap::strict_decode(io::BufReader::new(file))?;
+                Ok(AddressBook { path, addrs })
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("Creating address book at '{}'", path.display());
+                let book = AddressBook { path, addrs: none!() };
+                book.save()?;
+                Ok(book)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes the address book to a temporary file, which then atomically replaces the previous
+    /// one
+    fn save(&self) -> Result<(), Error> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        self.addrs.strict_encode(&mut file)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Stores address of the remote peer, replacing the previously known one
+    pub fn insert(&mut self, node_addr: NodeAddr) -> Result<(), Error> {
+        if self.addrs.insert(node_addr.id, node_addr) == Some(node_addr) {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Returns known address of the remote peer
+    pub fn get(&self, node_id: NodeId) -> Option<NodeAddr> { self.addrs.get(&node_id).copied() }
+}
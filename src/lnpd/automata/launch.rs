@@ -102,7 +102,7 @@ pub enum ChannelLauncher {
     /// Keyset for channel is derived. Still awaiting for channeld to come online and report back
     /// to lnpd.
     #[display("LAUNCHING")]
-    Launching(TempChannelId, CreateChannel, ClientId, LocalKeyset),
+    Launching(TempChannelId, CreateChannel, ClientId, LocalKeyset, Slice32),
 
     /// Channel daemon is launched, awaiting for keyset to be derived.
     #[display("DERIVING")]
@@ -144,9 +144,9 @@ impl StateMachine<CtlMsg, Runtime> for ChannelLauncher {
         let state = match self {
             ChannelLauncher::Init(temp_channel_id, request, enquirer) => match event.message {
                 CtlMsg::Hello => complete_launch(event, temp_channel_id, request, enquirer),
-                CtlMsg::Keyset(_, ref keyset) => {
+                CtlMsg::Keyset(_, ref keyset, seed) => {
                     let keyset = keyset.clone();
-                    complete_derivation(event, temp_channel_id, keyset, request, enquirer)
+                    complete_derivation(event, temp_channel_id, keyset, seed, request, enquirer)
                 }
                 _ => {
                     let err = Error::UnexpectedMessage(event.message.clone(), "INIT");
@@ -157,19 +157,14 @@ impl StateMachine<CtlMsg, Runtime> for ChannelLauncher {
             ChannelLauncher::Deriving(temp_channel_id, request, enquirer) => {
                 start_negotiation1(event, runtime, temp_channel_id, request, enquirer)
             }
-            ChannelLauncher::Launching(temp_channel_id, request, enquirer, keyset) => {
-                start_negotiation2(event, runtime, temp_channel_id, keyset, request, enquirer)
+            ChannelLauncher::Launching(temp_channel_id, request, enquirer, keyset, seed) => {
+                start_negotiation2(event, runtime, temp_channel_id, keyset, seed, request, enquirer)
             }
             ChannelLauncher::Negotiating(temp_channel_id, enquirer) => {
                 complete_negotiation(event, runtime, temp_channel_id, enquirer)
             }
-            ChannelLauncher::Committing(_, ref txid, ref enquirer) => {
-                match event.message {
-                    // Since we changed channeld id we send hello request once again, but this does
-                    // not influence state machine
-                    CtlMsg::Hello => Ok(self),
-                    _ => complete_commitment(event, runtime, *txid, *enquirer),
-                }
+            ChannelLauncher::Committing(_, txid, enquirer) => {
+                complete_commitment(event, runtime, txid, enquirer)
             }
             ChannelLauncher::Signing(channel_id, txid, enquirer) => {
                 complete_signatures(event, runtime, txid, enquirer)?;
@@ -198,7 +193,7 @@ impl ChannelLauncher {
     pub fn funding_txid(&self) -> Option<Txid> {
         match self {
             ChannelLauncher::Init(_, _, _)
-            | ChannelLauncher::Launching(_, _, _, _)
+            | ChannelLauncher::Launching(_, _, _, _, _)
             | ChannelLauncher::Deriving(_, _, _)
             | ChannelLauncher::Negotiating(_, _) => None,
             ChannelLauncher::Committing(_, txid, _) | ChannelLauncher::Signing(_, txid, _) => {
@@ -210,7 +205,7 @@ impl ChannelLauncher {
     pub fn enquirer(&self) -> ClientId {
         match self {
             ChannelLauncher::Init(_, _, enquirer)
-            | ChannelLauncher::Launching(_, _, enquirer, _)
+            | ChannelLauncher::Launching(_, _, enquirer, _, _)
             | ChannelLauncher::Deriving(_, _, enquirer)
             | ChannelLauncher::Negotiating(_, enquirer)
             | ChannelLauncher::Committing(_, _, enquirer)
@@ -233,8 +228,9 @@ impl ChannelLauncher {
         debug!("Generated {} as a temporary channel id", temp_channel_id);
         debug!("ChannelLauncher {:#} is instantiated", temp_channel_id);
 
+        let channeld = Daemon::Channeld(temp_channel_id.into(), runtime.node_key_path.clone());
         let report = runtime
-            .launch_daemon(Daemon::Channeld(temp_channel_id.into()), runtime.config.clone())
+            .launch_daemon(channeld, runtime.config.clone())
             .map(|handle| format!("Launched new instance of {}", handle))
             .map_err(Error::from);
         report_progress_or_failure(enquirer, endpoints, report)?;
@@ -286,6 +282,7 @@ fn complete_derivation(
     event: Event<CtlMsg>,
     temp_channel_id: TempChannelId,
     keyset: LocalKeyset,
+    commitment_seed: Slice32,
     create_channel: CreateChannel,
     enquirer: ClientId,
 ) -> Result<ChannelLauncher, Error> {
@@ -296,7 +293,13 @@ fn complete_derivation(
          sign daemon"
     );
     report_progress(enquirer, event.endpoints, "Key derivation complete");
-    Ok(ChannelLauncher::Launching(temp_channel_id, create_channel, enquirer, keyset))
+    Ok(ChannelLauncher::Launching(
+        temp_channel_id,
+        create_channel,
+        enquirer,
+        keyset,
+        commitment_seed,
+    ))
 }
 
 fn start_negotiation1(
@@ -312,8 +315,8 @@ fn start_negotiation1(
         "channel_launcher workflow inconsistency: `Keyset` RPC CTL message originating not from a \
          sign daemon"
     );
-    let keyset = match &event.message {
-        CtlMsg::Keyset(_, keyset) => keyset.clone(),
+    let (keyset, commitment_seed) = match &event.message {
+        CtlMsg::Keyset(_, keyset, seed) => (keyset.clone(), *seed),
         _ => {
             let err = Error::UnexpectedMessage(event.message.clone(), "LAUNCHING");
             report_failure(enquirer, event.endpoints, err)?;
@@ -321,7 +324,15 @@ fn start_negotiation1(
         }
     };
     report_progress(enquirer, event.endpoints, "Key derivation complete");
-    start_negotiation(event, runtime, temp_channel_id, keyset, create_channel, enquirer)
+    start_negotiation(
+        event,
+        runtime,
+        temp_channel_id,
+        keyset,
+        commitment_seed,
+        create_channel,
+        enquirer,
+    )
 }
 
 fn start_negotiation2(
@@ -329,6 +340,7 @@ fn start_negotiation2(
     runtime: &Runtime,
     temp_channel_id: TempChannelId,
     keyset: LocalKeyset,
+    commitment_seed: Slice32,
     create_channel: CreateChannel,
     enquirer: ClientId,
 ) -> Result<ChannelLauncher, Error> {
@@ -345,7 +357,15 @@ fn start_negotiation2(
             create_channel.remote_peer
         ),
     );
-    start_negotiation(event, runtime, temp_channel_id, keyset, create_channel, enquirer)
+    start_negotiation(
+        event,
+        runtime,
+        temp_channel_id,
+        keyset,
+        commitment_seed,
+        create_channel,
+        enquirer,
+    )
 }
 
 fn start_negotiation(
@@ -353,6 +373,7 @@ fn start_negotiation(
     runtime: &Runtime,
     temp_channel_id: TempChannelId,
     keyset: LocalKeyset,
+    commitment_seed: Slice32,
     create_channel: CreateChannel,
     enquirer: ClientId,
 ) -> Result<ChannelLauncher, Error> {
@@ -368,6 +389,7 @@ fn start_negotiation(
         common_params: common,
         local_params: local,
         local_keys: keyset,
+        commitment_seed,
     };
     event
         .send_ctl(CtlMsg::OpenChannelWith(request))
@@ -0,0 +1,206 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Static channel backup, containing information sufficient to recover channel funds with the help
+//! of remote peers after the loss of the node data directory.
+//!
+//! The backup is encrypted with ChaCha20 under a key derived from the node master key and
+//! authenticated with HMAC-SHA256 over the ciphertext, so it can be restored only by a node
+//! initialized from the same master key.
+
+use std::path::Path;
+use std::io::Write;
+use std::{fs, io};
+
+use amplify::IoError;
+use bitcoin::hashes::cmp::fixed_time_eq;
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{rand, SecretKey};
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::bus::ChannelBackup;
+
+/// Magic bytes starting static channel backup data
+const BACKUP_MAGIC: [u8; 4] = *b"LNPB";
+
+/// Version of the static channel backup format
+const BACKUP_VERSION: u8 = 1;
+
+const NONCE_LEN: usize = 12;
+const HMAC_LEN: usize = 32;
+const HEADER_LEN: usize = BACKUP_MAGIC.len() + 1 + NONCE_LEN;
+
+/// Errors working with static channel backups
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Error {
+    /// error accessing static channel backup file. Details: {0}
+    #[from(io::Error)]
+    Io(IoError),
+
+    /// the provided data are not a static channel backup
+    UnknownFormat,
+
+    /// static channel backup version {0} is not supported
+    UnsupportedVersion(u8),
+
+    /// static channel backup is corrupted or was created by a node with a different key
+    Authentication,
+
+    /// error encoding or decoding static channel backup data. Details: {0}
+    #[from]
+    StrictEncoding(strict_encoding::Error),
+
+    /// static channel backup key is not received from the signing daemon yet
+    KeyUnknown,
+}
+
+/// Static backup of the node channels
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub struct StaticBackup {
+    pub channels: Vec<ChannelBackup>,
+}
+
+impl StaticBackup {
+    /// Encrypts backup with the keys derived from the backup key
+    pub fn encrypt(&self, backup_key: &SecretKey) -> Result<Vec<u8>, Error> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut data = self.strict_serialize()?;
+        cipher(backup_key, &nonce).apply_keystream(&mut data);
+
+        let mut backup = Vec::with_capacity(HEADER_LEN + data.len() + HMAC_LEN);
+        backup.extend(BACKUP_MAGIC);
+        backup.push(BACKUP_VERSION);
+        backup.extend(nonce);
+        backup.extend(data);
+        let hmac = authenticate(backup_key, &backup);
+        backup.extend(hmac.into_inner());
+        Ok(backup)
+    }
+
+    /// Verifies and decrypts backup with the keys derived from the backup key
+    pub fn decrypt(backup: &[u8], backup_key: &SecretKey) -> Result<StaticBackup, Error> {
+        if backup.len() < HEADER_LEN + HMAC_LEN || backup[..BACKUP_MAGIC.len()] != BACKUP_MAGIC {
+            return Err(Error::UnknownFormat);
+        }
+        let version = backup[BACKUP_MAGIC.len()];
+        if version != BACKUP_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let (authenticated, hmac) = backup.split_at(backup.len() - HMAC_LEN);
+        // MAC is compared in constant time, so timing does not reveal how much of it is valid
+        if !fixed_time_eq(&authenticate(backup_key, authenticated)[..], hmac) {
+            return Err(Error::Authentication);
+        }
+
+        let nonce = &authenticated[BACKUP_MAGIC.len() + 1..HEADER_LEN];
+        let mut data = authenticated[HEADER_LEN..].to_vec();
+        cipher(backup_key, nonce).apply_keystream(&mut data);
+        Ok(StaticBackup::strict_deserialize(data)?)
+    }
+
+    /// Encrypts backup and writes it to a file, replacing the previous backup atomically
+    pub fn write_file(&self, path: impl AsRef<Path>, backup_key: &SecretKey) -> Result<(), Error> {
+        let path = path.as_ref();
+        let backup = self.encrypt(backup_key)?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&backup)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn derive_key(backup_key: &SecretKey, key_type: &[u8]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key_type);
+    engine.input(&backup_key.secret_bytes());
+    Hmac::from_engine(engine).into_inner()
+}
+
+fn cipher(backup_key: &SecretKey, nonce: &[u8]) -> ChaCha20 {
+    ChaCha20::new_from_slices(&derive_key(backup_key, b"backup_encryption"), nonce)
+        .expect("incorrect ChaCha20 initialization")
+}
+
+fn authenticate(backup_key: &SecretKey, data: &[u8]) -> Hmac<sha256::Hash> {
+    let mut engine =
+        HmacEngine::<sha256::Hash>::new(&derive_key(backup_key, b"backup_authentication"));
+    engine.input(data);
+    Hmac::from_engine(engine)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_key() -> SecretKey { SecretKey::from_slice(&[7; 32]).unwrap() }
+
+    #[test]
+    fn encryption_roundtrip() {
+        let backup = StaticBackup::default();
+        let encrypted = backup.encrypt(&test_key()).unwrap();
+        assert_eq!(&encrypted[..4], b"LNPB");
+        assert_eq!(StaticBackup::decrypt(&encrypted, &test_key()).unwrap(), backup);
+
+        // Each encryption uses a fresh nonce
+        assert_ne!(backup.encrypt(&test_key()).unwrap(), encrypted);
+    }
+
+    #[test]
+    fn authentication() {
+        let encrypted = StaticBackup::default().encrypt(&test_key()).unwrap();
+        for pos in [HEADER_LEN - 1, HEADER_LEN, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[pos] ^= 0x01;
+            assert!(matches!(
+                StaticBackup::decrypt(&tampered, &test_key()),
+                Err(Error::Authentication)
+            ));
+        }
+
+        let other_key = SecretKey::from_slice(&[8; 32]).unwrap();
+        assert!(matches!(
+            StaticBackup::decrypt(&encrypted, &other_key),
+            Err(Error::Authentication)
+        ));
+    }
+
+    #[test]
+    fn format() {
+        let encrypted = StaticBackup::default().encrypt(&test_key()).unwrap();
+        assert!(matches!(
+            StaticBackup::decrypt(&encrypted[..HEADER_LEN + HMAC_LEN - 1], &test_key()),
+            Err(Error::UnknownFormat)
+        ));
+
+        let mut wrong_magic = encrypted.clone();
+        wrong_magic[0] = b'X';
+        assert!(matches!(
+            StaticBackup::decrypt(&wrong_magic, &test_key()),
+            Err(Error::UnknownFormat)
+        ));
+
+        let mut wrong_version = encrypted;
+        wrong_version[4] = BACKUP_VERSION + 1;
+        assert!(matches!(
+            StaticBackup::decrypt(&wrong_version, &test_key()),
+            Err(Error::UnsupportedVersion(version)) if version == BACKUP_VERSION + 1
+        ));
+    }
+}
@@ -11,10 +11,10 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::fs;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::{fs, mem};
 
 use amplify::hex::ToHex;
 use bitcoin::secp256k1::{SecretKey, SECP256K1};
@@ -63,7 +63,7 @@ pub enum Daemon {
     PeerdBifrost(PeerSocket, PathBuf),
 
     #[display("channeld")]
-    Channeld(ActiveChannelId),
+    Channeld(ActiveChannelId, PathBuf),
 
     #[display("routed")]
     Routed,
@@ -99,8 +99,16 @@ impl Launcher for Daemon {
     }
 
     fn cmd_args(&self, cmd: &mut Command) -> Result<(), LauncherError<Self>> {
+        let mut skip_value = false;
         cmd.args(std::env::args().skip(1).filter(|arg| {
-            !["--listen", "--bolt", "--bifrost"].iter().any(|pat| arg.starts_with(pat))
+            // Values of lnpd-specific options may be given as separate arguments
+            if mem::take(&mut skip_value) {
+                return false;
+            }
+            skip_value = arg == "--restore" || arg == "--backup-dir";
+            !["--listen", "--bolt", "--bifrost", "--restore", "--backup-dir"]
+                .iter()
+                .any(|pat| arg.starts_with(pat))
         }));
 
         match self.protocol() {
@@ -167,7 +175,10 @@ impl Launcher for Daemon {
                     peerd::runtime::run,
                 )
             }
-            Daemon::Channeld(channel_id) => channeld::run(config, channel_id),
+            Daemon::Channeld(channel_id, key_file) => {
+                let local_node = read_node_key_file(&key_file);
+                channeld::run(config, local_node, channel_id)
+            }
             Daemon::Routed => routed::run(config),
             Daemon::Watchd => watchd::run(config),
         }
@@ -21,7 +21,7 @@ use amplify::{IoError, Slice32, Wrapper};
 use bitcoin::psbt::PartiallySignedTransaction;
 use bitcoin::secp256k1::{self, Secp256k1};
 use bitcoin::util::bip32::ChildNumber;
-use bitcoin::{EcdsaSighashType, Network, OutPoint, Txid};
+use bitcoin::{EcdsaSighashType, Network, OutPoint, Sequence, TxIn, TxOut, Txid};
 use bitcoin_blockchain::locks::SeqNo;
 use bitcoin_scripts::address::AddressCompat;
 use bitcoin_scripts::PubkeyScript;
@@ -38,11 +38,14 @@ use wallet::hd::{
     DerivationAccount, DerivationSubpath, DeriveError, SegmentIndexes, UnhardenedIndex,
 };
 use wallet::onchain::ResolveDescriptor;
-use wallet::psbt::Psbt;
+use wallet::psbt::{self, Psbt};
 
 // The default fee rate is 2 sats per kilo-vbyte
 const DEFAULT_FEERATE_PER_KW: u32 = 2u32 * 1000 * 4;
 
+/// Minimal value of the change output, below which it is not relayed by bitcoin nodes
+const DUST_LIMIT: u64 = 546;
+
 /// Errors working with funding wallet
 #[derive(Debug, Display, Error, From)]
 #[display(doc_comments)]
@@ -82,6 +85,9 @@ pub enum Error {
     /// Insufficient funds for the funding transaction
     InsufficientFunds,
 
+    /// commitment transaction already pays the target fee rate, so no fee bumping is required
+    FeeSufficient,
+
     /// error finalizing transaction, probably not all signatures are present.
     // TODO: Print out details once apmplify library will have `DisplayVec` type.
     Finalizing(Vec<miniscript::psbt::Error>),
@@ -226,6 +232,11 @@ impl FundingWallet {
         Ok(self.feerate_per_kw)
     }
 
+    /// Requests the height of the current chain tip from the electrum server
+    pub fn block_height(&self) -> Result<u32, Error> {
+        Ok(self.resolver.block_headers_subscribe()?.height as u32)
+    }
+
     #[inline]
     pub fn network(&self) -> Network { self.network }
 
@@ -320,49 +331,10 @@ impl FundingWallet {
         // We start with the assumption that we will have four-five inputs and two outputs,
         // i.e. it is a 2-kw transaction
         let mut fee_upper_est = 2u64 * feerate_per_kw as u64;
-        let amount_and_fee = amount + fee_upper_est;
-        // Do coin selection:
-        let mut funds = self.list_funds()?;
-        funds.sort_by_key(|f| f.amount);
-
-        let mut acc = 0u64;
-        let inputs = funds
-            .iter()
-            .rev()
-            .take_while(|funding| {
-                if acc >= amount_and_fee {
-                    return false;
-                }
-                acc += funding.amount;
-                true
-            })
-            .map(|funds| InputDescriptor {
-                outpoint: funds.outpoint,
-                terminal: DerivationSubpath::from(funds.terminal.clone()),
-                seq_no: SeqNo::rbf(),
-                tweak: None,
-                sighash_type: EcdsaSighashType::All,
-            })
-            .collect::<Vec<_>>();
-        if acc < amount_and_fee {
-            return Err(Error::InsufficientFunds);
-        }
-
-        let change_index = self.wallet_data.last_change_index;
-        self.wallet_data.last_change_index =
-            change_index.checked_inc().unwrap_or_else(UnhardenedIndex::zero);
-
+        let (inputs, _) = self.select_coins(amount + fee_upper_est)?;
+        let change_index = self.next_change_index();
         let descriptor = &self.wallet_data.descriptor;
 
-        let mut root_derivations = map![];
-        descriptor.for_each_key(|account| {
-            if let Some(fingerprint) = account.master_fingerprint() {
-                root_derivations
-                    .insert(account.account_fingerprint(), (fingerprint, &account.account_path));
-            }
-            true
-        });
-
         let script_pubkey = script_pubkey.into_inner();
         let psbt = loop {
             trace!("Constructing PSBT with fee {}", fee_upper_est);
@@ -376,19 +348,7 @@ impl FundingWallet {
                 &self.resolver,
             )
             .expect("funding PSBT construction is broken");
-            // Adding full derivation information to each of the inputs
-            for input in &mut psbt.inputs {
-                for source in input.bip32_derivation.values_mut() {
-                    if let Some((fingerprint, path)) = root_derivations.get(&source.0) {
-                        source.0 = *fingerprint;
-                        source.1 = path
-                            .iter()
-                            .map(ChildNumber::from)
-                            .chain(source.1.into_iter().copied())
-                            .collect();
-                    }
-                }
-            }
+            self.fill_root_derivations(&mut psbt);
             psbt.set_channel_funding_output(0).expect("hardcoded funding output number");
             let transaction = psbt.to_unsigned_tx();
             // If we use non-standard descriptor we assume its witness will weight 256 bytes per
@@ -419,6 +379,145 @@ impl FundingWallet {
         Ok(psbt)
     }
 
+    /// Constructs child transaction spending the anchor output of a commitment transaction
+    /// together with the funding wallet UTXOs, such that the package of both transactions pays
+    /// the target fee rate (CPFP). All funds from the wallet UTXOs, except the fee, are returned
+    /// to a change output.
+    ///
+    /// The `package_weight` covers the commitment transaction and the witness of the anchor
+    /// input, which is not known to the wallet; `commitment_fee` is the fee already paid by the
+    /// commitment transaction. The anchor input is added without the witness script and key
+    /// derivation, which must be provided by the channel.
+    pub fn construct_cpfp_psbt(
+        &mut self,
+        anchor: OutPoint,
+        anchor_txout: TxOut,
+        package_weight: u64,
+        commitment_fee: u64,
+        feerate_per_kw: Option<u32>,
+    ) -> Result<Psbt, Error> {
+        let feerate_per_kw = feerate_per_kw.unwrap_or(self.feerate_per_kw) as u64;
+        if package_weight * feerate_per_kw / 1000 <= commitment_fee {
+            return Err(Error::FeeSufficient);
+        }
+        let child_fee = |child_weight: u64| {
+            ((package_weight + child_weight) * feerate_per_kw / 1000).saturating_sub(commitment_fee)
+        };
+
+        // We start with the assumption that the child transaction will have up to four inputs and
+        // a single output, i.e. it is a 1-kw transaction
+        let mut child_weight = 1000u64;
+        let (inputs, acc) = self.select_coins(
+            (child_fee(child_weight) + DUST_LIMIT).saturating_sub(anchor_txout.value),
+        )?;
+        let change_index = self.next_change_index();
+        let descriptor = &self.wallet_data.descriptor;
+
+        let psbt = loop {
+            let fee = child_fee(child_weight);
+            if acc + anchor_txout.value < fee + DUST_LIMIT {
+                return Err(Error::InsufficientFunds);
+            }
+            trace!("Constructing CPFP PSBT with fee {}", fee);
+            // Anchor value goes to the fee, so the wallet inputs have to cover only the rest
+            let mut psbt: Psbt = Psbt::construct(
+                descriptor,
+                &inputs,
+                &[],
+                change_index,
+                fee.saturating_sub(anchor_txout.value),
+                None,
+                &self.resolver,
+            )
+            .expect("CPFP PSBT construction is broken");
+            self.fill_root_derivations(&mut psbt);
+            let mut anchor_input = psbt::Input::new(psbt.inputs.len(), TxIn {
+                previous_output: anchor,
+                script_sig: empty!(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: empty!(),
+            })
+            .expect("anchor input has empty script_sig and witness");
+            anchor_input.witness_utxo = Some(anchor_txout.clone());
+            psbt.inputs.push(anchor_input);
+
+            let tx_weight = psbt.to_unsigned_tx().weight() as u64;
+            let witness_weight = descriptor.max_satisfaction_weight().unwrap_or(256) * inputs.len();
+            let precise_weight = tx_weight + witness_weight as u64;
+            if precise_weight == child_weight {
+                trace!("Resulting weight matched estimate; exiting PSBT construction cycle");
+                break psbt;
+            }
+            child_weight = precise_weight;
+        };
+
+        Ok(psbt)
+    }
+
+    /// Selects the largest available UTXOs until their total value covers the `amount`. Returns
+    /// descriptors of the selected inputs and their total value.
+    fn select_coins(&mut self, amount: u64) -> Result<(Vec<InputDescriptor>, u64), Error> {
+        let mut funds = self.list_funds()?;
+        funds.sort_by_key(|f| f.amount);
+
+        let mut acc = 0u64;
+        let inputs = funds
+            .iter()
+            .rev()
+            .take_while(|funding| {
+                if acc >= amount {
+                    return false;
+                }
+                acc += funding.amount;
+                true
+            })
+            .map(|funds| InputDescriptor {
+                outpoint: funds.outpoint,
+                terminal: DerivationSubpath::from(funds.terminal.clone()),
+                seq_no: SeqNo::rbf(),
+                tweak: None,
+                sighash_type: EcdsaSighashType::All,
+            })
+            .collect::<Vec<_>>();
+        if acc < amount {
+            return Err(Error::InsufficientFunds);
+        }
+        Ok((inputs, acc))
+    }
+
+    /// Reserves derivation index for a change output
+    fn next_change_index(&mut self) -> UnhardenedIndex {
+        let change_index = self.wallet_data.last_change_index;
+        self.wallet_data.last_change_index =
+            change_index.checked_inc().unwrap_or_else(UnhardenedIndex::zero);
+        change_index
+    }
+
+    /// Adds full derivation information, starting from the master key, to each of the inputs
+    fn fill_root_derivations(&self, psbt: &mut Psbt) {
+        let mut root_derivations = map![];
+        self.wallet_data.descriptor.for_each_key(|account| {
+            if let Some(fingerprint) = account.master_fingerprint() {
+                root_derivations
+                    .insert(account.account_fingerprint(), (fingerprint, &account.account_path));
+            }
+            true
+        });
+
+        for input in &mut psbt.inputs {
+            for source in input.bip32_derivation.values_mut() {
+                if let Some((fingerprint, path)) = root_derivations.get(&source.0) {
+                    source.0 = *fingerprint;
+                    source.1 = path
+                        .iter()
+                        .map(ChildNumber::from)
+                        .chain(source.1.into_iter().copied())
+                        .collect();
+                }
+            }
+        }
+    }
+
     #[inline]
     pub fn get_funding_psbt(&self, txid: Txid) -> Option<&Psbt> {
         self.wallet_data.pending_fundings.get(&txid).map(|funding| &funding.psbt)
@@ -426,8 +525,21 @@ impl FundingWallet {
 
     #[inline]
     pub fn publish(&self, psbt: Psbt) -> Result<(), Error> {
-        let psbt = PartiallySignedTransaction::from(psbt);
-        let psbt = psbt.finalize(&self.secp).map_err(|(_, errs)| Error::Finalizing(errs))?;
+        let mut psbt = PartiallySignedTransaction::from(psbt);
+        // Inputs spending non-miniscript outputs (like channel `to_local` outputs) arrive already
+        // finalized, so we skip them
+        let mut errs = vec![];
+        for index in 0..psbt.inputs.len() {
+            if psbt.inputs[index].final_script_witness.is_some() {
+                continue;
+            }
+            if let Err(err) = psbt.finalize_inp_mut(&self.secp, index) {
+                errs.push(err);
+            }
+        }
+        if !errs.is_empty() {
+            return Err(Error::Finalizing(errs));
+        }
         let tx = psbt.extract_tx();
         self.resolver.transaction_broadcast(&tx)?;
         Ok(())
@@ -0,0 +1,188 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+
+use amplify::{IoError, Slice32, Wrapper};
+use bitcoin::bech32::{FromBase32, ToBase32};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::{rand, SecretKey, SECP256K1};
+use bitcoin::Network;
+use bitcoin_scripts::hlc::{HashLock, HashPreimage};
+use lightning_invoice::{
+    CreationError, Currency, Invoice, InvoiceBuilder, DEFAULT_EXPIRY_TIME,
+    DEFAULT_MIN_FINAL_CLTV_EXPIRY,
+};
+use lnp_rpc::CreateInvoice;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::bus::IssuedInvoice;
+
+/// Errors working with invoices issued by the local node
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Error {
+    /// error accessing invoice storage file. Details: {0}
+    #[from(io::Error)]
+    Io(IoError),
+
+    /// error reading or writing invoice storage data. Details: {0}
+    #[from]
+    StrictEncoding(strict_encoding::Error),
+
+    /// unable to construct invoice. Details: {0}
+    #[from]
+    Creation(CreationError),
+}
+
+/// Persistent storage of invoices issued by the local node, keeping payment preimages required to
+/// settle incoming HTLCs
+pub struct InvoiceStore {
+    path: PathBuf,
+    invoices: BTreeMap<HashLock, IssuedInvoice>,
+}
+
+impl InvoiceStore {
+    /// Opens invoice storage file, creating a new empty one if it does not exist
+    pub fn with(path: impl AsRef<Path>) -> Result<InvoiceStore, Error> {
+        let path = path.as_ref().to_path_buf();
+        match fs::File::open(&path) {
+            Ok(file) => {
+                debug!("Loading issued invoices from '{}'", path.display());
+                let invoices = BTreeMap::strict_decode(io::BufReader::new(file))?;
+                Ok(InvoiceStore { path, invoices })
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("Creating invoice storage at '{}'", path.display());
+                let mut store = InvoiceStore { path, invoices: none!() };
+                store.save()?;
+                Ok(store)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Removes expired invoices and writes the rest to a temporary file, which then atomically
+    /// replaces the previous one
+    fn save(&mut self) -> Result<(), Error> {
+        let now = unix_time();
+        self.invoices.retain(|_, invoice| invoice.expires_at > now);
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        self.invoices.strict_encode(&mut file)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Issues new invoice payable to the node with the given key, generating fresh payment
+    /// preimage and secret.
+    pub fn issue(
+        &mut self,
+        request: &CreateInvoice,
+        network: Network,
+        node_secret: &SecretKey,
+    ) -> Result<Invoice, Error> {
+        let preimage = HashPreimage::from_inner(Slice32::from_inner(rand::random()));
+        let payment_secret = HashPreimage::from_inner(Slice32::from_inner(rand::random()));
+        let payment_hash = HashLock::from(preimage);
+        let expiry = request.expiry.unwrap_or(DEFAULT_EXPIRY_TIME);
+        // Invoice builder accepts payment secret only as a type from the `lightning` crate, which
+        // we decode from the base32 form used in invoices instead of depending on that crate
+        let payment_secret_field = FromBase32::from_base32(&payment_secret.as_inner().to_base32())
+            .expect("payment secret is always 32 bytes long");
+
+        let currency = match network {
+            Network::Bitcoin => Currency::Bitcoin,
+            Network::Testnet => Currency::BitcoinTestnet,
+            Network::Signet => Currency::Signet,
+            Network::Regtest => Currency::Regtest,
+        };
+        let mut builder = InvoiceBuilder::new(currency)
+            .description(request.description.clone())
+            .payment_hash(sha256::Hash::from_inner(payment_hash.into_inner().into_inner()))
+            .payment_secret(payment_secret_field)
+            .current_timestamp()
+            .min_final_cltv_expiry(DEFAULT_MIN_FINAL_CLTV_EXPIRY)
+            .expiry_time(Duration::from_secs(expiry));
+        if let Some(amount_msat) = request.amount_msat {
+            builder = builder.amount_milli_satoshis(amount_msat);
+        }
+        let invoice =
+            builder.build_signed(|msg| SECP256K1.sign_ecdsa_recoverable(msg, node_secret))?;
+
+        let expires_at = invoice.duration_since_epoch().as_secs() + expiry;
+        self.invoices.insert(payment_hash, IssuedInvoice {
+            preimage,
+            payment_secret,
+            amount_msat: request.amount_msat,
+            min_final_cltv_expiry: DEFAULT_MIN_FINAL_CLTV_EXPIRY as u32,
+            expires_at,
+        });
+        self.save()?;
+        Ok(invoice)
+    }
+
+    /// Returns invoice with the given payment hash unless it has expired
+    pub fn get(&self, payment_hash: HashLock) -> Option<IssuedInvoice> {
+        let now = unix_time();
+        self.invoices.get(&payment_hash).filter(|invoice| invoice.expires_at > now).copied()
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn issue_and_prune() {
+        let path = std::env::temp_dir().join(format!("lnp-invoices-{}.dat", std::process::id()));
+        let node_secret = SecretKey::from_slice(&[1; 32]).unwrap();
+        let mut store = InvoiceStore::with(&path).unwrap();
+
+        let request =
+            CreateInvoice { amount_msat: Some(1000), description: s!("test"), expiry: None };
+        let invoice = store.issue(&request, Network::Testnet, &node_secret).unwrap();
+        let payment_hash =
+            HashLock::from_inner(Slice32::from_inner(invoice.payment_hash().into_inner()));
+        let issued = store.get(payment_hash).unwrap();
+        assert_eq!(invoice.payment_secret().0, issued.payment_secret.into_inner().into_inner());
+        assert_eq!(HashLock::from(issued.preimage), payment_hash);
+        assert_eq!(issued.amount_msat, Some(1000));
+
+        // Expired invoices are removed from the storage once it is saved
+        let request =
+            CreateInvoice { amount_msat: None, description: s!("expired"), expiry: Some(0) };
+        let expired = store.issue(&request, Network::Testnet, &node_secret).unwrap();
+        let expired_hash =
+            HashLock::from_inner(Slice32::from_inner(expired.payment_hash().into_inner()));
+        assert_eq!(store.get(expired_hash), None);
+        store.save().unwrap();
+        assert!(!store.invoices.contains_key(&expired_hash));
+
+        let reloaded = InvoiceStore::with(&path).unwrap();
+        assert_eq!(reloaded.get(payment_hash), Some(issued));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -11,9 +11,14 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+pub mod acceptance;
+mod acceptor;
+pub mod address_book;
 pub mod automata;
+pub mod backup;
 pub(self) mod daemons;
 pub mod funding;
+pub mod invoices;
 #[cfg(feature = "server")]
 mod opts;
 mod runtime;
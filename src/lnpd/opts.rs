@@ -11,6 +11,8 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use std::path::PathBuf;
+
 use lnp_rpc::ListenAddr;
 
 use crate::opts::Options;
@@ -55,6 +57,22 @@ pub struct Opts {
     #[clap(long, conflicts_with = "listen", requires = "listen-all")]
     pub bifrost: Option<Option<u16>>,
 
+    /// Restore channels from the static channel backup file exported with `lnp-cli backup
+    /// export`.
+    ///
+    /// For each of the channels unknown to the node, the node connects to the remote peer, asks
+    /// it to force-close the channel and sweeps the local funds to the funding wallet.
+    #[clap(long, value_name = "FILE")]
+    pub restore: Option<PathBuf>,
+
+    /// Directory for the encrypted channel backup, which is rewritten each time a channel is
+    /// opened or closed.
+    ///
+    /// The backup is encrypted with a key derived from the node master key, so the directory may
+    /// be synced to an untrusted storage. The backup can be restored with `--restore` option.
+    #[clap(long, value_name = "DIR", env = "LNP_NODE_BACKUP_DIR")]
+    pub backup_dir: Option<PathBuf>,
+
     /// Optional command to execute and exit
     #[clap(subcommand)]
     pub command: Option<Command>,
@@ -14,8 +14,10 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
+use std::{fs, mem};
 
 use amplify::{DumbDefault, Wrapper};
+use bitcoin::secp256k1::SecretKey;
 use bitcoin::Txid;
 use bitcoin_scripts::address::AddressCompat;
 use internet2::addr::{NodeAddr, NodeId};
@@ -23,7 +25,8 @@ use lnp::addr::LnpAddr;
 use lnp::channel::bolt::{CommonParams, LocalKeyset, PeerParams, Policy};
 use lnp::p2p;
 use lnp::p2p::bolt::{
-    ActiveChannelId, ChannelId, ChannelReestablish, Messages as LnMsg, TempChannelId,
+    self, ActiveChannelId, ChannelId, ChannelReestablish, Messages as LnMsg, OpenChannel,
+    TempChannelId,
 };
 use lnp::p2p::Protocol;
 use lnp_rpc::{FailureCode, ListenAddr};
@@ -31,27 +34,63 @@ use microservices::cli::LogStyle;
 use microservices::esb::{self, ClientId, Handler};
 use microservices::peer::PeerSocket;
 use microservices::util::OptionDetails;
-use microservices::{DaemonHandle, LauncherError};
+use microservices::{DaemonHandle, LauncherError, ZMQ_CONTEXT};
 
 use crate::automata::{Event, StateMachine};
 use crate::bus::{
-    AcceptChannelFrom, BusMsg, CtlMsg, IntoSuccessOrFalure, ServiceBus, Status, ToProgressOrFalure,
+    AcceptChannelFrom, BumpCommitment, BusMsg, ChannelBackup, CtlMsg, IntoSuccessOrFalure,
+    InvoiceInfo, ServiceBus, Status, ToProgressOrFalure,
 };
+use crate::lnpd::acceptance::{AcceptancePolicy, ChannelProposal};
+use crate::lnpd::acceptor::AcceptorTimer;
+use crate::lnpd::address_book::{self, AddressBook};
 use crate::lnpd::automata::ChannelLauncher;
+use crate::lnpd::backup::{self, StaticBackup};
 use crate::lnpd::daemons::{read_node_key_file, Daemon};
 use crate::lnpd::funding::{self, FundingWallet};
-use crate::rpc::{Failure, FundsInfo, ListPeerInfo, NodeInfo, RpcMsg, ServiceId};
-use crate::{Config, Endpoints, Error, Responder, Service, LNP_NODE_FUNDING_WALLET};
+use crate::lnpd::invoices::{self, InvoiceStore};
+use crate::rpc::{Failure, FundsInfo, ListPeerInfo, NodeInfo, ProposedChannel, RpcMsg, ServiceId};
+use crate::{
+    channeld, Config, Endpoints, Error, Responder, Service, LNP_NODE_ADDRESS_BOOK,
+    LNP_NODE_CHANNEL_BACKUP, LNP_NODE_FUNDING_WALLET, LNP_NODE_INVOICES,
+};
 
 pub fn run<'a>(
     config: Config,
     key_file: PathBuf,
     listen: impl IntoIterator<Item = &'a ListenAddr>,
+    restore: Option<PathBuf>,
+    backup_dir: Option<PathBuf>,
 ) -> Result<(), Error> {
     let node_id = read_node_key_file(&key_file).node_id();
 
+    if let Some(backup_dir) = &backup_dir {
+        info!("Channel backups are saved to {}", backup_dir.display());
+        fs::create_dir_all(backup_dir)?;
+    }
+
+    // Backup is decrypted once signd provides the backup key
+    let restore = match restore {
+        Some(path) => {
+            info!("Reading static channel backup from {}", path.display());
+            Some(fs::read(path)?)
+        }
+        None => None,
+    };
+
+    let relaunching = channeld::persisted_channels(&config)?;
+    info!("Node has {} persisted channels to relaunch", relaunching.len());
+
     let listens = listen.into_iter().copied().collect();
 
+    debug!("Opening bridge between channel acceptor timer and main service threads");
+    let tx = ZMQ_CONTEXT.socket(zmq::PAIR)?;
+    let rx = ZMQ_CONTEXT.socket(zmq::PAIR)?;
+    tx.connect("inproc://acceptor-bridge")?;
+    rx.bind("inproc://acceptor-bridge")?;
+    let acceptor_timeout = Duration::from_secs(config.acceptance.acceptor_timeout);
+    let acceptor_timer = AcceptorTimer::with(acceptor_timeout, tx)?;
+
     let runtime = Runtime {
         config: config.clone(),
         node_key_path: key_file,
@@ -60,18 +99,36 @@ pub fn run<'a>(
         started: SystemTime::now(),
         handles: vec![],
         funding_wallet: config.funding_wallet()?,
+        invoices: config.invoice_store()?,
+        address_book: config.address_book()?,
         channel_params: config.channel_params()?,
+        acceptance: AcceptancePolicy::from(&config.acceptance),
+        channel_acceptor: None,
+        acceptor_timer,
+        awaiting_acceptor: none!(),
         bolt_connections: none!(),
         bifrost_connections: none!(),
         channels: none!(),
+        channel_peers: none!(),
         spawning_peers: none!(),
         creating_channels: none!(),
         funding_channels: none!(),
         accepting_channels: none!(),
         reestablishing_channels: none!(),
+        relaunching,
+        starting_channels: none!(),
+        watching: false,
+        restore,
+        restoring: none!(),
+        restoring_channels: none!(),
+        backup_key: None,
+        backup_dir,
     };
 
-    Service::run(config, runtime, true)
+    let mut service = Service::broker(config, runtime)?;
+    service.add_loopback(rx)?;
+    service.run_loop()?;
+    unreachable!()
 }
 
 impl Config {
@@ -84,29 +141,68 @@ impl Config {
         Ok(funding_wallet)
     }
 
+    fn invoice_store(&self) -> Result<InvoiceStore, invoices::Error> {
+        let mut store_path = self.data_dir.clone();
+        store_path.push(LNP_NODE_INVOICES);
+        InvoiceStore::with(store_path)
+    }
+
+    fn address_book(&self) -> Result<AddressBook, address_book::Error> {
+        let mut book_path = self.data_dir.clone();
+        book_path.push(LNP_NODE_ADDRESS_BOOK);
+        AddressBook::with(book_path)
+    }
+
     fn channel_params(&self) -> Result<(Policy, CommonParams, PeerParams), Error> {
-        // TODO: Read params from config
-        Ok((Policy::default(), CommonParams::default(), PeerParams::default()))
+        Ok((self.channel.policy(), self.channel.common_params(), self.channel.local_params()))
     }
 }
 
 pub struct Runtime {
     pub(super) config: Config,
-    node_key_path: PathBuf,
+    pub(super) node_key_path: PathBuf,
     node_id: NodeId,
     listens: HashSet<ListenAddr>,
     started: SystemTime,
     handles: Vec<DaemonHandle<Daemon>>,
     pub(super) funding_wallet: FundingWallet,
+    invoices: InvoiceStore,
+    /// Addresses of the remote peers, used for reconnecting to them once the node restarts
+    address_book: AddressBook,
     pub(super) channel_params: (Policy, CommonParams, PeerParams),
+    /// Rules for accepting channels proposed by remote peers
+    acceptance: AcceptancePolicy,
+    /// Client deciding on channels proposed by remote peers, if subscribed
+    channel_acceptor: Option<ClientId>,
+    acceptor_timer: AcceptorTimer,
+    /// Channel proposals awaiting decision of the channel acceptor
+    awaiting_acceptor: HashMap<ChannelId, (NodeId, OpenChannel)>,
     bolt_connections: HashSet<NodeId>,
     bifrost_connections: HashSet<NodeId>,
     channels: HashSet<ChannelId>,
+    /// Remote peers of the channels launched by the node
+    channel_peers: HashMap<ChannelId, NodeId>,
     spawning_peers: HashMap<ServiceId, ClientId>,
     creating_channels: HashMap<ServiceId, ChannelLauncher>,
     funding_channels: HashMap<Txid, ChannelLauncher>,
     accepting_channels: HashMap<ServiceId, AcceptChannelFrom>,
     reestablishing_channels: HashMap<ServiceId, (NodeId, ChannelReestablish)>,
+    /// Persisted channels which are relaunched once the chain watch daemon is ready
+    relaunching: Vec<(ChannelId, Option<NodeId>)>,
+    /// Relaunched channel daemons which have not connected yet
+    starting_channels: HashSet<ServiceId>,
+    /// Whether the chain watch daemon is connected, which is required for launching channels
+    watching: bool,
+    /// Encrypted static channel backup to restore, which is decrypted once the backup key is known
+    restore: Option<Vec<u8>>,
+    /// Channels from the static backup which are restored once the chain watch daemon is ready
+    restoring: Vec<ChannelBackup>,
+    /// Channel daemons launched to recover funds from the channels restored from the backup
+    restoring_channels: HashMap<ServiceId, ChannelBackup>,
+    /// Key encrypting static channel backups, derived by signd from the node master key
+    backup_key: Option<SecretKey>,
+    /// Directory for the channel backup updated on each channel opening and closing
+    backup_dir: Option<PathBuf>,
 }
 
 impl Responder for Runtime {}
@@ -141,6 +237,9 @@ impl esb::Handler<ServiceBus> for Runtime {
         message: BusMsg,
     ) -> Result<(), Self::Error> {
         match (bus, message, source) {
+            (ServiceBus::Bridge, BusMsg::Ctl(msg), ServiceId::Loopback) => {
+                self.handle_bridge(endpoints, msg)
+            }
             (ServiceBus::Msg, BusMsg::Bolt(msg), ServiceId::PeerBolt(remote_id)) => {
                 self.handle_p2p(endpoints, remote_id, msg)
             }
@@ -194,24 +293,39 @@ impl Runtime {
             // Lisnening peerd forwards this request to lnpd so it can launch a new channeld
             // instance.
             LnMsg::OpenChannel(open_channel) => {
-                // TODO: Replace with state machine-based workflow
-                info!("Creating channel by peer request from {}", remote_id);
-                self.launch_daemon(
-                    Daemon::Channeld(open_channel.temporary_channel_id.into()),
-                    self.config.clone(),
-                )?;
-                let channeld_id = ServiceId::Channel(open_channel.temporary_channel_id.into());
-                let accept_channel = AcceptChannelFrom {
+                let channel_id = ChannelId::from(open_channel.temporary_channel_id);
+                let proposal = ChannelProposal {
                     remote_id,
-                    report_to: None,
-                    channel_req: open_channel,
-                    policy: self.channel_params.0.clone(),
-                    common_params: self.channel_params.1,
-                    local_params: self.channel_params.2,
-                    // TODO: Remove this field, channeld will derive keyset itself
-                    local_keys: LocalKeyset::dumb_default(),
+                    peer_channels: self.peer_channels(remote_id),
+                    open_channel: &open_channel,
                 };
-                self.accepting_channels.insert(channeld_id, accept_channel);
+                if let Err(rejection) = self.acceptance.check(&proposal) {
+                    warn!(
+                        "Rejecting channel {} proposed by {}: {}",
+                        channel_id, remote_id, rejection
+                    );
+                    return self.reject_channel(endpoints, remote_id, channel_id, rejection);
+                }
+
+                if let Some(client_id) = self.channel_acceptor {
+                    debug!("Forwarding channel {} proposal to the channel acceptor", channel_id);
+                    let proposal = ProposedChannel {
+                        channel_id,
+                        remote_id,
+                        funding_sat: open_channel.funding_satoshis,
+                        push_msat: open_channel.push_msat,
+                        feerate_per_kw: open_channel.feerate_per_kw,
+                        to_self_delay: open_channel.to_self_delay,
+                        announce_channel: open_channel.should_announce_channel(),
+                        channel_type: open_channel.channel_type.unwrap_or_default(),
+                    };
+                    self.send_rpc(endpoints, client_id, RpcMsg::ChannelProposal(proposal))?;
+                    self.awaiting_acceptor.insert(channel_id, (remote_id, open_channel));
+                    self.acceptor_timer.start(channel_id);
+                    return Ok(());
+                }
+
+                self.accept_channel(remote_id, open_channel)?;
             }
 
             LnMsg::ChannelReestablish(channel_reestablish) => {
@@ -223,13 +337,24 @@ impl Runtime {
                         ServiceId::Channel(*channeld),
                         BusMsg::Bolt(LnMsg::ChannelReestablish(channel_reestablish)),
                     )?;
+                } else if self.restoring_channels.contains_key(&ServiceId::Channel(channel_id))
+                    || self.starting_channels.contains(&ServiceId::Channel(channel_id))
+                {
+                    // Channel daemon is already launched and will receive the message once it is
+                    // ready
+                    self.reestablishing_channels
+                        .insert(ServiceId::Channel(channel_id), (remote_id, channel_reestablish));
                 } else {
                     self.launch_daemon(
-                        Daemon::Channeld(ActiveChannelId::Static(channel_id)),
+                        Daemon::Channeld(
+                            ActiveChannelId::Static(channel_id),
+                            self.node_key_path.clone(),
+                        ),
                         self.config.clone(),
                     )?;
                     self.reestablishing_channels
                         .insert(ServiceId::Channel(channel_id), (remote_id, channel_reestablish));
+                    self.channel_peers.insert(channel_id, remote_id);
                 }
             }
 
@@ -351,6 +476,9 @@ impl Runtime {
                 };
                 let resp = match self.launch_daemon(peerd, self.config.clone()) {
                     Ok(handle) => {
+                        if protocol == p2p::Protocol::Bolt {
+                            self.remember_peer(node_addr);
+                        }
                         self.spawning_peers.insert(peer_service_id, client_id);
                         Ok(format!("Launched new instance of {}", handle))
                     }
@@ -364,11 +492,82 @@ impl Runtime {
 
             RpcMsg::CreateChannel(create_channel) => {
                 info!("Creating channel with {}", create_channel.remote_peer);
+                let remote_id = create_channel.remote_peer.id;
+                self.remember_peer(create_channel.remote_peer);
                 let launcher = ChannelLauncher::with(endpoints, client_id, create_channel, self)?;
+                self.channel_peers.insert(ChannelId::from_inner(launcher.channel_id()), remote_id);
                 let channeld_id = ServiceId::Channel(launcher.channel_id().into());
                 self.creating_channels.insert(channeld_id, launcher);
             }
 
+            RpcMsg::CreateInvoice(create_invoice) => {
+                info!("Issuing invoice for {:?} msat", create_invoice.amount_msat);
+                let node_secret = read_node_key_file(&self.node_key_path).private_key();
+                let network = self.funding_wallet.network();
+                let resp = match self.invoices.issue(&create_invoice, network, &node_secret) {
+                    Ok(invoice) => {
+                        info!(
+                            "Invoice with payment hash {} is {}",
+                            invoice.payment_hash(),
+                            "issued".ended()
+                        );
+                        RpcMsg::Success(OptionDetails::with(invoice))
+                    }
+                    Err(err) => {
+                        error!("Unable to issue invoice: {}", err.err_details());
+                        RpcMsg::Failure(Failure { code: FailureCode::Lnpd, info: err.to_string() })
+                    }
+                };
+                self.send_rpc(endpoints, client_id, resp)?;
+            }
+
+            RpcMsg::ExportBackup => {
+                let resp = match self.export_backup() {
+                    Ok(backup) => RpcMsg::StaticBackup(backup),
+                    Err(err) => {
+                        error!("Unable to export static channel backup: {}", err.err_details());
+                        RpcMsg::Failure(Failure { code: FailureCode::Lnpd, info: err.to_string() })
+                    }
+                };
+                self.send_rpc(endpoints, client_id, resp)?;
+            }
+
+            RpcMsg::SubscribeChannelAcceptor => {
+                if let Some(prev_id) = self.channel_acceptor.replace(client_id) {
+                    warn!("Channel acceptor {} is replaced with {}", prev_id, client_id);
+                }
+                info!("Client {} subscribed as channel acceptor", client_id);
+                self.send_rpc(endpoints, client_id, RpcMsg::success())?;
+            }
+
+            RpcMsg::ApproveChannel(_) | RpcMsg::RejectChannel { .. }
+                if self.channel_acceptor != Some(client_id) =>
+            {
+                warn!(
+                    "Ignoring channel decision from {} which is not a channel acceptor",
+                    client_id
+                );
+            }
+
+            RpcMsg::ApproveChannel(channel_id) => {
+                if let Some((remote_id, open_channel)) = self.awaiting_acceptor.remove(&channel_id)
+                {
+                    info!("Channel acceptor has approved channel {}", channel_id);
+                    self.accept_channel(remote_id, open_channel)?;
+                } else {
+                    warn!("Channel {} does not await decision of the channel acceptor", channel_id);
+                }
+            }
+
+            RpcMsg::RejectChannel { channel_id, reason } => {
+                if let Some((remote_id, _)) = self.awaiting_acceptor.remove(&channel_id) {
+                    warn!("Channel acceptor has rejected channel {}: {}", channel_id, reason);
+                    self.reject_channel(endpoints, remote_id, channel_id, reason)?;
+                } else {
+                    warn!("Channel {} does not await decision of the channel acceptor", channel_id);
+                }
+            }
+
             wrong_msg => {
                 error!("Request is not supported by the RPC interface");
                 return Err(Error::wrong_esb_msg(ServiceBus::Rpc, &wrong_msg));
@@ -378,6 +577,33 @@ impl Runtime {
         Ok(())
     }
 
+    fn handle_bridge(&mut self, endpoints: &mut Endpoints, message: CtlMsg) -> Result<(), Error> {
+        match message {
+            CtlMsg::AcceptorTimeout(channel_id) => {
+                let (remote_id, open_channel) = match self.awaiting_acceptor.remove(&channel_id) {
+                    Some(proposal) => proposal,
+                    // The channel acceptor has already decided on the proposal
+                    None => return Ok(()),
+                };
+                if self.config.acceptance.accept_on_timeout {
+                    info!("Channel acceptor has not decided on {} in time, accepting", channel_id);
+                    self.accept_channel(remote_id, open_channel)?;
+                } else {
+                    warn!("Channel acceptor has not decided on {} in time, rejecting", channel_id);
+                    let reason = "channel proposal was not approved in time";
+                    self.reject_channel(endpoints, remote_id, channel_id, reason)?;
+                }
+            }
+
+            wrong_msg => {
+                error!("Request is not supported by the BRIDGE interface");
+                return Err(Error::wrong_esb_msg(ServiceBus::Bridge, &wrong_msg));
+            }
+        }
+
+        Ok(())
+    }
+
     fn handle_ctl(
         &mut self,
         endpoints: &mut Endpoints,
@@ -387,7 +613,7 @@ impl Runtime {
         match &message {
             CtlMsg::Hello => self.handle_hello(endpoints, source)?,
 
-            CtlMsg::Keyset(service_id, _) => {
+            CtlMsg::Keyset(service_id, ..) => {
                 let service_id = service_id.clone();
                 let launcher = self
                     .creating_channels
@@ -424,6 +650,73 @@ impl Runtime {
                 self.funding_channels.insert(txid, launcher);
             }
 
+            CtlMsg::GetPayoutScript => {
+                let script_pubkey = self.funding_wallet.next_funding_address()?.script_pubkey();
+                debug!("Providing {} with payout script {}", source, script_pubkey);
+                self.send_ctl(endpoints, source, CtlMsg::PayoutScript(script_pubkey))?;
+            }
+
+            CtlMsg::GetInvoice(payment_hash) => {
+                let payment_hash = *payment_hash;
+                let invoice = self.invoices.get(payment_hash);
+                if invoice.is_none() {
+                    warn!("{} requested unknown or expired invoice {}", source, payment_hash);
+                }
+                // Channel daemon waits for the reply to settle incoming HTLCs, so we must not
+                // return without replying
+                let (height, invoice) = match self.funding_wallet.block_height() {
+                    Ok(height) => (height, invoice),
+                    Err(err) => {
+                        error!(
+                            "Unable to check invoice {} since block height is unknown: {}",
+                            payment_hash, err
+                        );
+                        (0, None)
+                    }
+                };
+                let info = InvoiceInfo { payment_hash, height, invoice };
+                self.send_ctl(endpoints, source, CtlMsg::Invoice(info))?;
+            }
+
+            CtlMsg::PublishTx(psbt) => {
+                let txid = psbt.to_txid();
+                match self.funding_wallet.publish(psbt.clone()) {
+                    Ok(()) => info!("Transaction {} is {}", txid, "published".ended()),
+                    Err(err) => {
+                        error!("Unable to publish transaction {}: {}", txid, err.err_details());
+                        let reply = CtlMsg::with_error(&source, &message, &err);
+                        self.send_ctl(endpoints, source, reply)?;
+                    }
+                }
+            }
+
+            CtlMsg::ConstructCpfp(bump) => {
+                let BumpCommitment {
+                    anchor,
+                    anchor_txout,
+                    package_weight,
+                    commitment_fee,
+                    feerate_per_kw,
+                } = bump.clone();
+                match self.funding_wallet.construct_cpfp_psbt(
+                    anchor,
+                    anchor_txout,
+                    package_weight,
+                    commitment_fee,
+                    feerate_per_kw,
+                ) {
+                    Ok(psbt) => {
+                        debug!("Providing {} with CPFP transaction {}", source, psbt.to_txid());
+                        self.send_ctl(endpoints, source, CtlMsg::CpfpConstructed(psbt))?;
+                    }
+                    Err(err) => {
+                        warn!("Unable to bump fee for {}: {}", anchor.txid, err);
+                        let reply = CtlMsg::with_error(&source, &message, &err);
+                        self.send_ctl(endpoints, source, reply)?;
+                    }
+                }
+            }
+
             CtlMsg::Signed(psbt) => {
                 let txid = psbt.to_txid();
                 let launcher = self
@@ -443,6 +736,8 @@ impl Runtime {
                     .creating_channels
                     .remove(destination)
                     .unwrap_or_else(|| panic!("unregistered channel launcher for {}", destination));
+                // Failed channel must not count against the channel limit of the remote peer
+                self.channel_peers.remove(&ChannelId::from_inner(launcher.channel_id()));
                 // We swallow `None` here
                 let _ = launcher.next(
                     Event::with(endpoints, self.identity(), destination.clone(), message),
@@ -463,9 +758,42 @@ impl Runtime {
                 }
             }
 
+            CtlMsg::ChannelCreated(_) | CtlMsg::ChannelClosed(_) => {
+                let closed = match message {
+                    CtlMsg::ChannelClosed(channel_id) => Some(channel_id),
+                    _ => None,
+                };
+                if let Some(channel_id) = closed {
+                    self.channel_peers.remove(&channel_id);
+                }
+                // Channel operations must not be affected by a failure to write the backup
+                if let Err(err) = self.update_backup(closed) {
+                    error!("Unable to update channel backup: {}", err.err_details());
+                }
+            }
+
+            CtlMsg::BackupKey(backup_key) => {
+                let backup_key = SecretKey::from_slice(backup_key.as_inner())
+                    .map_err(|err| Error::Other(err.to_string()))?;
+                self.backup_key = Some(backup_key);
+                debug!("Static channel backup key is received from {}", source);
+                if let Some(restore) = self.restore.take() {
+                    let backup = StaticBackup::decrypt(&restore, &backup_key)?;
+                    info!(
+                        "Static channel backup contains {} channels to restore",
+                        backup.channels.len()
+                    );
+                    self.restoring = backup.channels;
+                    if self.watching {
+                        self.restore_channels()?;
+                    }
+                }
+            }
+
             CtlMsg::ChannelUpdate { old_id, new_id } => {
-                self.update_chanel_id(old_id.to_owned(), new_id.to_owned());
-                return Ok(());
+                self.update_chanel_id(*old_id, *new_id);
+                // Channel daemon holds its messages until the routing is updated
+                self.send_ctl(endpoints, source, CtlMsg::ChannelUpdated(*new_id))?;
             }
 
             wrong_msg => {
@@ -481,6 +809,18 @@ impl Runtime {
         info!("{} daemon is {}", source.ended(), "connected".ended());
 
         self.register_daemon(source.clone());
+        self.starting_channels.remove(&source);
+
+        if source == ServiceId::Signer {
+            self.send_ctl(endpoints, ServiceId::Signer, CtlMsg::DeriveBackupKey)?;
+        }
+
+        if source == ServiceId::Watch {
+            // Channels require chain watching of their funding outputs
+            self.watching = true;
+            self.relaunch_channels()?;
+            self.restore_channels()?;
+        }
 
         if let Some(channel_launcher) = self.creating_channels.remove(&source) {
             // Tell channeld channel options and link it with the peer daemon
@@ -505,6 +845,28 @@ impl Runtime {
                 source.clone(),
                 BusMsg::Ctl(CtlMsg::AcceptChannelFrom(accept_channel)),
             )?;
+        } else if let Some(channel_backup) = self.restoring_channels.remove(&source) {
+            debug!(
+                "Ordering {} to recover funds from the channel restored from the backup",
+                source
+            );
+            endpoints.send_to(
+                ServiceBus::Ctl,
+                self.identity(),
+                source.clone(),
+                BusMsg::Ctl(CtlMsg::RestoreChannel(channel_backup)),
+            )?;
+            // Remote peer may have already sent us its channel reestablishment message
+            if let Some((remote_id, channel_reestablish)) =
+                self.reestablishing_channels.remove(&source)
+            {
+                endpoints.send_to(
+                    ServiceBus::Msg,
+                    ServiceId::PeerBolt(remote_id),
+                    source.clone(),
+                    BusMsg::Bolt(LnMsg::ChannelReestablish(channel_reestablish)),
+                )?;
+            }
         } else if let Some((remote_id, channel_reestablish)) =
             self.reestablishing_channels.remove(&source)
         {
@@ -600,6 +962,134 @@ impl Runtime {
         Ok(format!("Launched new instance of {}", handle))
     }
 
+    /// Composes static backup of all channels known to the node, except the channel which is
+    /// being closed
+    fn compose_backup(&self, closed: Option<ChannelId>) -> Result<StaticBackup, Error> {
+        let mut backup = StaticBackup::default();
+        for channel_id in self.channels.iter().filter(|id| Some(**id) != closed) {
+            if let Some(channel_backup) = channeld::channel_backup(&self.config, *channel_id)? {
+                backup.channels.push(channel_backup);
+            }
+        }
+        Ok(backup)
+    }
+
+    /// Composes encrypted static backup of all channels known to the node
+    fn export_backup(&self) -> Result<Vec<u8>, Error> {
+        let backup = self.compose_backup(None)?;
+        info!("Exporting static backup of {} channels", backup.channels.len());
+        let backup_key = self.backup_key.as_ref().ok_or(backup::Error::KeyUnknown)?;
+        Ok(backup.encrypt(backup_key)?)
+    }
+
+    /// Rewrites channel backup in the backup directory, if the directory is configured
+    fn update_backup(&self, closed: Option<ChannelId>) -> Result<(), Error> {
+        let mut path = match &self.backup_dir {
+            Some(backup_dir) => backup_dir.clone(),
+            None => return Ok(()),
+        };
+        path.push(LNP_NODE_CHANNEL_BACKUP);
+        let backup_key = self.backup_key.as_ref().ok_or(backup::Error::KeyUnknown)?;
+        let backup = self.compose_backup(closed)?;
+        backup.write_file(&path, backup_key)?;
+        info!("Backup of {} channels is saved to {}", backup.channels.len(), path.display());
+        Ok(())
+    }
+
+    /// Stores address of the remote peer to the address book, so the node can reconnect to the
+    /// peer after restart
+    fn remember_peer(&mut self, node_addr: NodeAddr) {
+        if let Err(err) = self.address_book.insert(node_addr) {
+            error!("Unable to save address of {} to the address book: {}", node_addr, err);
+        }
+    }
+
+    /// Relaunches channel daemons for the channels persisted by the node and reconnects to their
+    /// remote peers with known addresses
+    fn relaunch_channels(&mut self) -> Result<(), Error> {
+        let mut connecting = HashSet::new();
+        for (channel_id, remote_id) in mem::take(&mut self.relaunching) {
+            info!("Relaunching channel {}", channel_id);
+            self.launch_daemon(
+                Daemon::Channeld(ActiveChannelId::Static(channel_id), self.node_key_path.clone()),
+                self.config.clone(),
+            )?;
+            self.starting_channels.insert(ServiceId::Channel(channel_id));
+
+            let remote_id = match remote_id {
+                Some(remote_id) => remote_id,
+                None => continue,
+            };
+            self.channel_peers.insert(channel_id, remote_id);
+            match self.address_book.get(remote_id) {
+                Some(remote_addr) if connecting.insert(remote_id) => {
+                    self.launch_daemon(
+                        Daemon::PeerdBolt(
+                            PeerSocket::Connect(remote_addr),
+                            self.node_key_path.clone(),
+                        ),
+                        self.config.clone(),
+                    )?;
+                }
+                None => warn!(
+                    "Address of {} is unknown; channel {} will be reestablished once the peer \
+                     connects",
+                    remote_id, channel_id
+                ),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Launches channel daemons recovering funds from the channels restored from the static
+    /// backup, connecting to the remote peers with known addresses
+    fn restore_channels(&mut self) -> Result<(), Error> {
+        let mut connecting = HashSet::new();
+        for channel_backup in mem::take(&mut self.restoring) {
+            let channel_id = channel_backup.channel_id;
+            let remote_id = channel_backup.remote_id;
+            if channeld::is_channel_persisted(&self.config, channel_id)? {
+                warn!("Channel {} from the backup is known to the node, skipping it", channel_id);
+                continue;
+            }
+            info!("Restoring channel {} with {} from the static backup", channel_id, remote_id);
+
+            match channel_backup.remote_addr {
+                Some(remote_addr)
+                    if !self.bolt_connections.contains(&remote_id)
+                        && connecting.insert(remote_id) =>
+                {
+                    self.launch_daemon(
+                        Daemon::PeerdBolt(
+                            PeerSocket::Connect(remote_addr),
+                            self.node_key_path.clone(),
+                        ),
+                        self.config.clone(),
+                    )?;
+                }
+                None => warn!(
+                    "Address of {} is unknown; channel {} will be recovered once the peer connects",
+                    remote_id, channel_id
+                ),
+                _ => {}
+            }
+
+            // Channel daemon launched with a temporary id starts with a blank state
+            let temp_channel_id = TempChannelId::from_inner(channel_id.into_inner());
+            self.launch_daemon(
+                Daemon::Channeld(
+                    ActiveChannelId::Temporary(temp_channel_id),
+                    self.node_key_path.clone(),
+                ),
+                self.config.clone(),
+            )?;
+            self.channel_peers.insert(channel_id, remote_id);
+            self.restoring_channels.insert(ServiceId::Channel(channel_id), channel_backup);
+        }
+        Ok(())
+    }
+
     fn available_funding(&mut self) -> Result<BTreeMap<AddressCompat, u64>, Error> {
         self.funding_wallet.list_funds()?.into_iter().try_fold(
             bmap! {},
@@ -617,6 +1107,63 @@ impl Runtime {
         )
     }
 
+    /// Launches channel daemon accepting the channel proposed by the remote peer
+    fn accept_channel(
+        &mut self,
+        remote_id: NodeId,
+        open_channel: OpenChannel,
+    ) -> Result<(), Error> {
+        // TODO: Replace with state machine-based workflow
+        info!("Creating channel by peer request from {}", remote_id);
+        let channel_id = ChannelId::from(open_channel.temporary_channel_id);
+        self.launch_daemon(
+            Daemon::Channeld(open_channel.temporary_channel_id.into(), self.node_key_path.clone()),
+            self.config.clone(),
+        )?;
+        let channeld_id = ServiceId::Channel(open_channel.temporary_channel_id.into());
+        let accept_channel = AcceptChannelFrom {
+            remote_id,
+            report_to: None,
+            channel_req: open_channel,
+            policy: self.channel_params.0.clone(),
+            common_params: self.channel_params.1,
+            local_params: self.channel_params.2,
+            // TODO: Remove this field, channeld will derive keyset itself
+            local_keys: LocalKeyset::dumb_default(),
+        };
+        self.accepting_channels.insert(channeld_id, accept_channel);
+        self.channel_peers.insert(channel_id, remote_id);
+        Ok(())
+    }
+
+    /// Rejects channel proposed by the remote peer by sending it BOLT `error` message
+    fn reject_channel(
+        &self,
+        endpoints: &mut Endpoints,
+        remote_id: NodeId,
+        channel_id: ChannelId,
+        reason: impl ToString,
+    ) -> Result<(), Error> {
+        let error = bolt::Error { channel_id, data: reason.to_string().into_bytes() };
+        endpoints.send_to(
+            ServiceBus::Msg,
+            self.identity(),
+            ServiceId::PeerBolt(remote_id),
+            BusMsg::Bolt(LnMsg::Error(error)),
+        )?;
+        Ok(())
+    }
+
+    /// Counts channels with the remote peer launched by the node or awaiting decision of the
+    /// channel acceptor
+    fn peer_channels(&self, remote_id: NodeId) -> usize {
+        self.channel_peers
+            .values()
+            .chain(self.awaiting_acceptor.values().map(|(peer, _)| peer))
+            .filter(|peer| **peer == remote_id)
+            .count()
+    }
+
     pub fn update_chanel_id(&mut self, old_id: TempChannelId, new_id: ChannelId) -> bool {
         let mut known = true;
         if !self.channels.remove(&ChannelId::from(old_id)) {
@@ -624,6 +1171,9 @@ impl Runtime {
             warn!("Temporary channel id {} was unknown", old_id);
         }
         self.channels.insert(new_id);
+        if let Some(remote_id) = self.channel_peers.remove(&ChannelId::from(old_id)) {
+            self.channel_peers.insert(new_id, remote_id);
+        }
         info!("Channel daemon id registered to change from {} to {}", old_id, new_id);
         known
     }
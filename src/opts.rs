@@ -15,7 +15,7 @@ use std::fmt::Debug;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
-use clap::ValueHint;
+use clap::{ValueEnum, ValueHint};
 use internet2::addr::ServiceAddr;
 use lnp_rpc::LNP_NODE_RPC_ENDPOINT;
 use lnpbp::chain::Chain;
@@ -189,6 +189,31 @@ pub struct Opts {
     /// Spawn daemons as threads and not processes
     #[clap(short = 't', long = "threaded")]
     pub threaded_daemons: bool,
+
+    /// Storage backend for channel states.
+    ///
+    /// `disk` keeps state of each channel in a separate file; `db` uses embedded database
+    /// shared by all channels, which also keeps history of channel state changes.
+    #[clap(long, global = true, value_enum, default_value = "disk", env = "LNP_NODE_STORAGE")]
+    pub storage: StorageBackend,
+
+    /// Commitment fee rate drift, in percents, which triggers fee update.
+    ///
+    /// When the node is a funder of a channel, it sends `update_fee` to the remote peer once
+    /// the on-chain fee estimate differs from the current channel fee rate by more than the
+    /// provided percentage.
+    #[clap(long, global = true, default_value = "20", env = "LNP_NODE_FEE_UPDATE_THRESHOLD")]
+    pub fee_update_threshold: u16,
+}
+
+/// Storage backends for channel states
+#[derive(ValueEnum, Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum StorageBackend {
+    /// Separate file for each of the channels
+    Disk,
+
+    /// Embedded key-value database shared by all channels, which also keeps channel history
+    Db,
 }
 
 impl Opts {
@@ -405,6 +405,17 @@ impl Runtime {
                 endpoints.send_to(ServiceBus::Msg, self.identity(), channeld, BusMsg::Bolt(msg))?;
             }
 
+            bolt::Messages::ChannelAnnouncement(_)
+            | bolt::Messages::ChannelUpdate(_)
+            | bolt::Messages::NodeAnnouncements(_) => {
+                endpoints.send_to(
+                    ServiceBus::Msg,
+                    self.identity(),
+                    ServiceId::Router,
+                    BusMsg::Bolt(msg),
+                )?;
+            }
+
             message => {
                 // TODO:
                 //  1. Check permissions
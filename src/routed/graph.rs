@@ -0,0 +1,397 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Persistent BOLT-7 gossip graph, which allows router to start with the network graph known from
+//! the previous run instead of re-learning it from the peers.
+//!
+//! Only the latest gossip message of each kind is kept: the channel announcement, the most recent
+//! channel update for each of the channel directions and the most recent announcement of each node
+//! taking part in known channels.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+use amplify::IoError;
+use internet2::addr::NodeId;
+use lnp::p2p::bolt::{
+    ChannelAnnouncement, ChannelUpdate, Messages as LnMsg, NodeAnnouncements, ShortChannelId,
+};
+use strict_encoding::{StrictDecode, StrictEncode};
+
+/// Channels which have not received channel update for this period (in seconds) are considered
+/// stale and pruned, as permitted by BOLT-7. Channels without any updates are pruned once their
+/// announcement is older than this period.
+pub const GOSSIP_STALE_PERIOD: u32 = 1209600;
+
+/// Interval between writing graph snapshots to the disk
+pub const GOSSIP_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Errors working with persistent gossip graph
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Error {
+    /// error accessing routing graph file. Details: {0}
+    #[from(io::Error)]
+    Io(IoError),
+
+    /// error reading or writing routing graph data. Details: {0}
+    #[from]
+    StrictEncoding(strict_encoding::Error),
+}
+
+/// Gossip information about a single channel
+#[derive(Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+pub struct GossipChannel {
+    pub announcement: ChannelAnnouncement,
+
+    /// Time (unix timestamp) when the channel announcement was received for the first time
+    pub received: u32,
+
+    /// Latest channel updates for the directions from the first and from the second node
+    pub updates: (Option<ChannelUpdate>, Option<ChannelUpdate>),
+}
+
+impl GossipChannel {
+    /// Timestamp of the most recent channel update in any of the directions
+    pub fn last_update(&self) -> Option<u32> {
+        let (update1, update2) = &self.updates;
+        update1.iter().chain(update2).map(|update| update.timestamp).max()
+    }
+
+    fn nodes(&self) -> [NodeId; 2] { [self.announcement.node_id_1, self.announcement.node_id_2] }
+}
+
+/// Network graph constructed from BOLT-7 gossip messages
+#[derive(Clone, PartialEq, Eq, Debug, Default, StrictEncode, StrictDecode)]
+pub struct GossipGraph {
+    channels: BTreeMap<ShortChannelId, GossipChannel>,
+    nodes: BTreeMap<NodeId, NodeAnnouncements>,
+}
+
+impl GossipGraph {
+    /// Returns all gossip messages constituting the graph, in the order in which they can be
+    /// replayed to the router
+    pub fn messages(&self) -> Vec<LnMsg> {
+        let mut messages = Vec::with_capacity(self.channels.len() * 3 + self.nodes.len());
+        for channel in self.channels.values() {
+            messages.push(LnMsg::ChannelAnnouncement(channel.announcement.clone()));
+            let (update1, update2) = channel.updates;
+            messages.extend(update1.into_iter().chain(update2).map(LnMsg::ChannelUpdate));
+        }
+        messages.extend(self.nodes.values().cloned().map(LnMsg::NodeAnnouncements));
+        messages
+    }
+
+    /// Updates the graph with gossip message received at time `now` (unix timestamp). Returns
+    /// whether the message has changed the graph.
+    ///
+    /// Following BOLT-7, channel updates for unknown channels, node announcements for nodes without
+    /// known channels and messages not newer than the already known ones are ignored.
+    // TODO: Verify gossip message signatures and funding outputs of the announced channels
+    pub fn update(&mut self, message: &LnMsg, now: u32) -> bool {
+        match message {
+            LnMsg::ChannelAnnouncement(announcement) => {
+                if self.channels.contains_key(&announcement.short_channel_id) {
+                    return false;
+                }
+                self.channels.insert(announcement.short_channel_id, GossipChannel {
+                    announcement: announcement.clone(),
+                    received: now,
+                    updates: (None, None),
+                });
+                true
+            }
+
+            LnMsg::ChannelUpdate(update) => {
+                let channel = match self.channels.get_mut(&update.short_channel_id) {
+                    Some(channel) => channel,
+                    None => return false,
+                };
+                let slot = if update.channel_flags & 0x01 == 0 {
+                    &mut channel.updates.0
+                } else {
+                    &mut channel.updates.1
+                };
+                if matches!(slot, Some(known) if known.timestamp >= update.timestamp) {
+                    return false;
+                }
+                *slot = Some(*update);
+                true
+            }
+
+            LnMsg::NodeAnnouncements(announcement) => {
+                let node_id = announcement.node_id;
+                if !self.channels.values().any(|channel| channel.nodes().contains(&node_id)) {
+                    return false;
+                }
+                match self.nodes.get(&node_id) {
+                    Some(known) if known.timestamp >= announcement.timestamp => false,
+                    _ => {
+                        self.nodes.insert(node_id, announcement.clone());
+                        true
+                    }
+                }
+            }
+
+            _ => false,
+        }
+    }
+
+    /// Removes channels which did not receive channel update for [`GOSSIP_STALE_PERIOD`] (or,
+    /// if they have no updates at all, which were announced earlier than that) and announcements
+    /// of the nodes left without channels. Returns number of removed channels.
+    pub fn prune(&mut self, now: u32) -> usize {
+        let threshold = now.saturating_sub(GOSSIP_STALE_PERIOD);
+        let count = self.channels.len();
+        self.channels
+            .retain(|_, channel| channel.last_update().unwrap_or(channel.received) >= threshold);
+
+        let nodes = self.channels.values().flat_map(GossipChannel::nodes).collect::<BTreeSet<_>>();
+        self.nodes.retain(|node_id, _| nodes.contains(node_id));
+        count - self.channels.len()
+    }
+}
+
+/// Gossip graph backed by a file in the node data directory
+pub struct GraphStore {
+    path: PathBuf,
+    graph: GossipGraph,
+    dirty: bool,
+}
+
+impl GraphStore {
+    /// Loads gossip graph from the file, pruning stale entries, or starts with an empty graph if
+    /// the file does not exist or can't be decoded
+    pub fn with(path: impl AsRef<Path>) -> Result<GraphStore, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut graph = match fs::File::open(&path) {
+            Ok(file) => {
+                debug!("Loading routing graph from '{}'", path.display());
+                GossipGraph::strict_decode(io::BufReader::new(file)).unwrap_or_else(|err| {
+                    warn!(
+                        "Routing graph at '{}' can't be read ({}), starting with an empty one",
+                        path.display(),
+                        err
+                    );
+                    GossipGraph::default()
+                })
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("No routing graph found at '{}', starting with an empty one", path.display());
+                GossipGraph::default()
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let pruned = graph.prune(unix_time());
+        info!(
+            "Routing graph contains {} channels and {} nodes; {} stale channels pruned",
+            graph.channels.len(),
+            graph.nodes.len(),
+            pruned
+        );
+        Ok(GraphStore { path, graph, dirty: pruned > 0 })
+    }
+
+    #[inline]
+    pub fn graph(&self) -> &GossipGraph { &self.graph }
+
+    /// Updates the graph with gossip message. Returns whether the message has changed the graph.
+    /// The changes are written to the disk with the next [`GraphStore::snapshot`].
+    pub fn update(&mut self, message: &LnMsg) -> bool {
+        let changed = self.graph.update(message, unix_time());
+        self.dirty |= changed;
+        changed
+    }
+
+    /// Prunes stale graph entries and writes the graph to the file, replacing the previous
+    /// snapshot atomically
+    pub fn snapshot(&mut self) -> Result<(), Error> {
+        let pruned = self.graph.prune(unix_time());
+        if !self.dirty && pruned == 0 {
+            return Ok(());
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&self.graph.strict_serialize()?)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)?;
+        self.dirty = false;
+        debug!(
+            "Routing graph snapshot with {} channels and {} nodes saved; {} stale channels pruned",
+            self.graph.channels.len(),
+            self.graph.nodes.len(),
+            pruned
+        );
+        Ok(())
+    }
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::{Slice32, Wrapper};
+    use bitcoin::secp256k1::ecdsa::Signature;
+    use bitcoin::secp256k1::{PublicKey, SecretKey, SECP256K1};
+    use lnp::p2p::bolt::{Alias, NodeColor};
+
+    use super::*;
+
+    const NOW: u32 = 1_700_000_000;
+
+    fn node_id(key: u8) -> NodeId {
+        let secret_key = SecretKey::from_slice(&[key; 32]).unwrap();
+        NodeId::from(PublicKey::from_secret_key(SECP256K1, &secret_key))
+    }
+
+    fn signature() -> Signature { Signature::from_compact(&[1; 64]).unwrap() }
+
+    fn short_channel_id(block_height: u32) -> ShortChannelId {
+        ShortChannelId::with(block_height, 1, 0).unwrap()
+    }
+
+    fn channel_announcement(block_height: u32) -> LnMsg {
+        LnMsg::ChannelAnnouncement(ChannelAnnouncement {
+            node_signature_1: signature(),
+            node_signature_2: signature(),
+            bitcoin_signature_1: signature(),
+            bitcoin_signature_2: signature(),
+            features: none!(),
+            chain_hash: Slice32::default(),
+            short_channel_id: short_channel_id(block_height),
+            node_id_1: node_id(1),
+            node_id_2: node_id(2),
+            bitcoin_key_1: node_id(3),
+            bitcoin_key_2: node_id(4),
+        })
+    }
+
+    fn channel_update(block_height: u32, channel_flags: u8, timestamp: u32) -> LnMsg {
+        LnMsg::ChannelUpdate(ChannelUpdate {
+            signature: signature(),
+            chain_hash: Slice32::default(),
+            short_channel_id: short_channel_id(block_height),
+            timestamp,
+            message_flags: 0,
+            channel_flags,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: 1000,
+            fee_base_msat: 1000,
+            fee_proportional_millionths: 100,
+            htlc_maximum_msat: 100_000_000,
+        })
+    }
+
+    fn node_announcement(key: u8, timestamp: u32) -> LnMsg {
+        LnMsg::NodeAnnouncements(NodeAnnouncements {
+            signature: signature(),
+            features: none!(),
+            timestamp,
+            node_id: node_id(key),
+            rgb_color: NodeColor::from_inner([0; 3]),
+            alias: Alias::from_inner(Slice32::default()),
+            addresses: none!(),
+        })
+    }
+
+    #[test]
+    fn update_ordering() {
+        let mut graph = GossipGraph::default();
+
+        // Updates and node announcements without known channels are ignored
+        assert!(!graph.update(&channel_update(100, 0, NOW), NOW));
+        assert!(!graph.update(&node_announcement(1, NOW), NOW));
+
+        assert!(graph.update(&channel_announcement(100), NOW));
+        assert!(!graph.update(&channel_announcement(100), NOW + 1));
+        assert_eq!(graph.channels[&short_channel_id(100)].received, NOW);
+
+        // Only updates newer than the known ones are accepted, separately for each direction
+        assert!(graph.update(&channel_update(100, 0, NOW), NOW));
+        assert!(!graph.update(&channel_update(100, 0, NOW), NOW));
+        assert!(!graph.update(&channel_update(100, 0, NOW - 1), NOW));
+        assert!(graph.update(&channel_update(100, 1, NOW - 1), NOW));
+        assert!(graph.update(&channel_update(100, 0, NOW + 1), NOW));
+        assert_eq!(graph.channels[&short_channel_id(100)].last_update(), Some(NOW + 1));
+
+        assert!(graph.update(&node_announcement(1, NOW), NOW));
+        assert!(!graph.update(&node_announcement(1, NOW), NOW));
+        assert!(graph.update(&node_announcement(2, NOW + 1), NOW));
+        assert!(!graph.update(&node_announcement(3, NOW), NOW));
+
+        // Replayed messages reconstruct the same graph
+        let messages = graph.messages();
+        assert_eq!(messages.len(), 5);
+        assert!(matches!(&messages[0], LnMsg::ChannelAnnouncement(_)));
+        let mut replayed = GossipGraph::default();
+        for message in &messages {
+            assert!(replayed.update(message, NOW));
+        }
+        assert_eq!(replayed, graph);
+    }
+
+    #[test]
+    fn prune() {
+        let mut graph = GossipGraph::default();
+        let stale = NOW - GOSSIP_STALE_PERIOD - 1;
+
+        // Channel with a recent update, but announced long ago
+        graph.update(&channel_announcement(100), stale);
+        graph.update(&channel_update(100, 0, NOW - 10), NOW);
+        // Channel which was updated long ago
+        graph.update(&channel_announcement(200), stale);
+        graph.update(&channel_update(200, 1, stale), stale);
+        // Recently announced channel without updates yet
+        graph.update(&channel_announcement(300), NOW - 10);
+        // Channel announced long ago which never got an update
+        graph.update(&channel_announcement(400), stale);
+        graph.update(&node_announcement(1, NOW), NOW);
+
+        assert_eq!(graph.prune(NOW), 2);
+        assert_eq!(graph.channels.keys().copied().collect::<Vec<_>>(), vec![
+            short_channel_id(100),
+            short_channel_id(300)
+        ]);
+        assert_eq!(graph.nodes.len(), 1);
+
+        // Node announcements are removed together with the last channel of the node
+        assert_eq!(graph.prune(NOW + GOSSIP_STALE_PERIOD), 2);
+        assert!(graph.channels.is_empty());
+        assert!(graph.nodes.is_empty());
+    }
+
+    #[test]
+    fn store_snapshot() {
+        let path = std::env::temp_dir().join(format!("lnp-graph-{}.dat", std::process::id()));
+        let mut store = GraphStore::with(&path).unwrap();
+        assert!(store.update(&channel_announcement(100)));
+        assert!(store.update(&channel_update(100, 0, unix_time())));
+        assert!(!path.exists());
+        store.snapshot().unwrap();
+
+        let reloaded = GraphStore::with(&path).unwrap();
+        assert_eq!(reloaded.graph(), store.graph());
+
+        // Unreadable graph file is replaced with an empty graph
+        fs::write(&path, [0xFF; 8]).unwrap();
+        let reloaded = GraphStore::with(&path).unwrap();
+        assert_eq!(reloaded.graph(), &GossipGraph::default());
+        fs::remove_file(path).unwrap();
+    }
+}
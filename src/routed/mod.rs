@@ -11,9 +11,11 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+pub mod graph;
 #[cfg(feature = "server")]
 mod opts;
 mod runtime;
+mod timer;
 
 #[cfg(feature = "server")]
 pub use opts::Opts;
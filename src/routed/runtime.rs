@@ -24,21 +24,46 @@ use lnp::router::Router;
 use lnp::Extension;
 use lnp_rpc::{PayInvoice, RpcMsg};
 use microservices::esb::{self, ClientId};
+use microservices::ZMQ_CONTEXT;
 
 use crate::bus::{BusMsg, CtlMsg, ServiceBus};
-use crate::routed::PaymentError;
+use crate::routed::graph::GraphStore;
+use crate::routed::{timer, PaymentError};
 use crate::rpc::ServiceId;
-use crate::{Config, Endpoints, Error, Responder, Service};
+use crate::{Config, Endpoints, Error, Responder, Service, LNP_NODE_ROUTING_GRAPH};
 
 pub fn run(config: Config) -> Result<(), Error> {
-    let runtime = Runtime { router: Router::default(), enquirer: None };
+    let signals = timer::block_termination()?;
 
-    Service::run(config, runtime, false)
+    let mut graph_path = config.data_dir.clone();
+    graph_path.push(LNP_NODE_ROUTING_GRAPH);
+    let graph = GraphStore::with(graph_path)?;
+
+    let mut router = Router::default();
+    for message in graph.graph().messages() {
+        router.update_from_peer(&message)?;
+    }
+
+    debug!("Opening bridge between snapshot timer and main service threads");
+    let tx = ZMQ_CONTEXT.socket(zmq::PAIR)?;
+    let rx = ZMQ_CONTEXT.socket(zmq::PAIR)?;
+    tx.connect("inproc://snapshot-bridge")?;
+    rx.bind("inproc://snapshot-bridge")?;
+    timer::spawn(signals, tx)?;
+
+    let runtime = Runtime { router, graph, enquirer: None };
+
+    let mut service = Service::service(config, runtime)?;
+    service.add_loopback(rx)?;
+    service.run_loop()?;
+    unreachable!()
 }
 
 pub struct Runtime {
     router: Router<GossipExt>,
 
+    graph: GraphStore,
+
     enquirer: Option<ClientId>,
 }
 
@@ -64,6 +89,9 @@ where
         message: BusMsg,
     ) -> Result<(), Self::Error> {
         match (bus, message, source) {
+            (ServiceBus::Bridge, BusMsg::Ctl(msg), ServiceId::Loopback) => {
+                self.handle_bridge(endpoints, msg)
+            }
             (ServiceBus::Msg, BusMsg::Bolt(msg), source) => self.handle_p2p(endpoints, source, msg),
             (ServiceBus::Ctl, BusMsg::Ctl(msg), source) => self.handle_ctl(endpoints, source, msg),
             (ServiceBus::Rpc, BusMsg::Rpc(msg), ServiceId::Client(client_id)) => {
@@ -97,9 +125,29 @@ impl Runtime {
         _source: ServiceId,
         message: LnMsg,
     ) -> Result<(), Error> {
+        self.graph.update(&message);
         self.router.update_from_peer(&message).map_err(Error::from)
     }
 
+    fn handle_bridge(&mut self, _endpoints: &mut Endpoints, message: CtlMsg) -> Result<(), Error> {
+        match message {
+            CtlMsg::SnapshotGraph => self.graph.snapshot()?,
+
+            CtlMsg::Terminate => {
+                self.graph.snapshot()?;
+                info!("Routing graph is saved, exiting");
+                std::process::exit(0);
+            }
+
+            wrong_msg => {
+                error!("Request is not supported by the BRIDGE interface");
+                return Err(Error::wrong_esb_msg(ServiceBus::Bridge, &wrong_msg));
+            }
+        }
+
+        Ok(())
+    }
+
     fn handle_rpc(
         &mut self,
         endpoints: &mut Endpoints,
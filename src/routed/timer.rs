@@ -0,0 +1,111 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Timer writing routing graph snapshots. Runs in a separate thread and notifies routed runtime
+//! over the bridge each time [`GOSSIP_SNAPSHOT_INTERVAL`] passes, and when the daemon receives a
+//! termination signal, so the graph is saved before exit.
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+
+use internet2::zeromq;
+use microservices::esb;
+use microservices::node::TryService;
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::bus::{BusMsg, CtlMsg, ServiceBus};
+use crate::routed::graph::GOSSIP_SNAPSHOT_INTERVAL;
+use crate::rpc::ServiceId;
+use crate::{BridgeHandler, Error};
+
+/// Blocks termination signals, so they can be received by the snapshot timer instead of killing
+/// the process. Must be called before any other thread is started, since the blocked signals are
+/// inherited by the spawned threads.
+pub(super) fn block_termination() -> Result<SigSet, Error> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block().map_err(std::io::Error::from)?;
+    Ok(signals)
+}
+
+/// Spawns timer thread sending snapshot and termination requests over the bridge socket
+pub(super) fn spawn(signals: SigSet, tx: zmq::Socket) -> Result<(), Error> {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name(s!("signal_listener"))
+        .spawn(move || loop {
+            let signal = signals.wait().expect("unable to wait for termination signals");
+            if sender.send(signal).is_err() {
+                break;
+            }
+        })
+        .expect("unable to start termination signal listener thread");
+
+    let timer_runtime = TimerRuntime::with(receiver, tx)?;
+    thread::Builder::new()
+        .name(s!("snapshot_timer"))
+        .spawn(move || timer_runtime.run_or_panic("routing graph snapshot timer"))
+        .expect("unable to start routing graph snapshot timer thread");
+    Ok(())
+}
+
+struct TimerRuntime {
+    bridge: esb::Controller<ServiceBus, BusMsg, BridgeHandler>,
+    receiver: mpsc::Receiver<Signal>,
+}
+
+impl TimerRuntime {
+    pub fn with(receiver: mpsc::Receiver<Signal>, tx: zmq::Socket) -> Result<TimerRuntime, Error> {
+        let bridge = esb::Controller::with(
+            map! {
+                ServiceBus::Bridge => esb::BusConfig {
+                    api_type: zeromq::ZmqSocketType::Rep,
+                    carrier: zeromq::Carrier::Socket(tx),
+                    router: None,
+                    queued: true,
+                    topic: None,
+                }
+            },
+            BridgeHandler,
+        )?;
+
+        Ok(TimerRuntime { bridge, receiver })
+    }
+
+    fn run(&mut self) -> Result<(), mpsc::RecvError> {
+        let message = match self.receiver.recv_timeout(GOSSIP_SNAPSHOT_INTERVAL) {
+            Ok(signal) => {
+                info!("Received {}, saving routing graph before exit", signal);
+                CtlMsg::Terminate
+            }
+            Err(RecvTimeoutError::Timeout) => CtlMsg::SnapshotGraph,
+            Err(RecvTimeoutError::Disconnected) => return Err(mpsc::RecvError),
+        };
+        self.bridge
+            .send_to(ServiceBus::Bridge, ServiceId::Router, BusMsg::Ctl(message))
+            .expect("unable to send routing graph snapshot request over the bridge");
+        Ok(())
+    }
+}
+
+impl TryService for TimerRuntime {
+    type ErrorType = mpsc::RecvError;
+
+    fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
+        trace!("Entering event loop of the routing graph snapshot timer");
+        loop {
+            self.run()?;
+        }
+    }
+}
@@ -13,16 +13,21 @@
 
 use std::fs;
 
-use amplify::Wrapper;
-use bitcoin::secp256k1::{self, Secp256k1};
-use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey};
+use amplify::{Slice32, Wrapper};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{
+    self, KeyPair, Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey,
+};
+use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, Fingerprint};
 use bitcoin::XpubIdentifier;
 use lnp::channel::bolt::LocalKeyset;
 use lnp::p2p::bolt::ChannelId;
 use lnpbp::chain::Chain;
 use microservices::esb::{self, Handler};
 use strict_encoding::StrictDecode;
-use wallet::psbt::sign::{MemoryKeyProvider, MemorySigningAccount, SecretProvider, SignAll};
+use wallet::psbt::sign::{
+    MemoryKeyProvider, MemorySigningAccount, SecretProvider, SecretProviderError, SignAll,
+};
 
 use crate::bus::{BusMsg, CtlMsg, ServiceBus};
 use crate::rpc::ServiceId;
@@ -140,6 +145,26 @@ where
                 )?;
             }
 
+            CtlMsg::SignRevoked { mut psbt, per_commitment_secret } => {
+                let per_commitment_secret = SecretKey::from_slice(per_commitment_secret.as_inner())
+                    .map_err(|err| Error::Other(err.to_string()))?;
+                let provider =
+                    RevocationKeyProvider { provider: &self.provider, per_commitment_secret };
+                let sig_count = psbt.sign_all(&provider)?;
+                let txid = psbt.to_txid();
+                info!(
+                    "Transaction {} is signed with revocation key ({} signatures added)",
+                    txid, sig_count
+                );
+                trace!("Signed PSBT: {:#?}", psbt);
+                endpoints.send_to(
+                    ServiceBus::Ctl,
+                    ServiceId::Signer,
+                    source,
+                    BusMsg::Ctl(CtlMsg::Signed(psbt)),
+                )?;
+            }
+
             CtlMsg::DeriveKeyset(slice32) => {
                 let mut buf = [0u8; 4];
                 buf.copy_from_slice(&slice32.as_inner()[..4]);
@@ -161,6 +186,13 @@ where
                         // TODO: Use a key from a funding wallet
                         None,
                     );
+                    // Channel daemon generates per-commitment secrets from this seed, replacing
+                    // `first_per_commitment_point` of the keyset with the one matching the seed
+                    let seed_index = ChildNumber::from_hardened_idx(7).expect("hardcoded index");
+                    let commitment_seed = channel_xpriv
+                        .derive_priv(self.provider.secp_context(), &[seed_index])?
+                        .private_key
+                        .secret_bytes();
 
                     endpoints.send_to(
                         ServiceBus::Ctl,
@@ -169,11 +201,32 @@ where
                         BusMsg::Ctl(CtlMsg::Keyset(
                             ServiceId::Channel(ChannelId::from_inner(slice32)),
                             keyset,
+                            Slice32::from(commitment_seed),
                         )),
                     )?;
                 }
             }
 
+            CtlMsg::DeriveBackupKey => {
+                if let Some(account) = self.provider.into_iter().next() {
+                    // The key is derived at `m/9735h/{chain}h/3h`, so the backup can be decrypted
+                    // by the node restored from the seed alone
+                    let chain_index = self.chain.chain_params().is_testnet as u32;
+                    let path = [chain_index, 3]
+                        .map(|idx| ChildNumber::from_hardened_idx(idx).expect("hardcoded index"));
+                    let backup_key = account
+                        .account_xpriv()
+                        .derive_priv(self.provider.secp_context(), &path)?
+                        .private_key;
+                    endpoints.send_to(
+                        ServiceBus::Ctl,
+                        self.identity(),
+                        source,
+                        BusMsg::Ctl(CtlMsg::BackupKey(Slice32::from(backup_key.secret_bytes()))),
+                    )?;
+                }
+            }
+
             wrong_msg => {
                 error!("Request {} is not supported by the CTL interface", wrong_msg);
                 return Err(Error::wrong_esb_msg(ServiceBus::Ctl, &wrong_msg));
@@ -183,3 +236,63 @@ where
         Ok(())
     }
 }
+
+/// Key provider producing revocation secret keys from the revocation basepoint secrets known to
+/// the wrapped provider and per-commitment secret revealed by the remote peer, according to
+/// BOLT-3:
+///
+/// `revocationprivkey = revocation_basepoint_secret * SHA256(revocation_basepoint ||
+/// per_commitment_point) + per_commitment_secret * SHA256(per_commitment_point ||
+/// revocation_basepoint)`
+///
+/// Signatures are added to PSBT under the revocation basepoint key.
+struct RevocationKeyProvider<'provider, 'secp> {
+    provider: &'provider MemoryKeyProvider<'secp, secp256k1::All>,
+    per_commitment_secret: SecretKey,
+}
+
+impl SecretProvider<secp256k1::All> for RevocationKeyProvider<'_, '_> {
+    #[inline]
+    fn secp_context(&self) -> &Secp256k1<secp256k1::All> { self.provider.secp_context() }
+
+    fn secret_key(
+        &self,
+        fingerprint: Fingerprint,
+        derivation: &DerivationPath,
+        pubkey: PublicKey,
+    ) -> Result<SecretKey, SecretProviderError> {
+        let basepoint_secret = self.provider.secret_key(fingerprint, derivation, pubkey)?;
+        let per_commitment_point =
+            PublicKey::from_secret_key(self.secp_context(), &self.per_commitment_secret);
+
+        let basepoint_tweak = revocation_tweak(pubkey, per_commitment_point);
+        let point_tweak = revocation_tweak(per_commitment_point, pubkey);
+        let tweaked_point_secret =
+            self.per_commitment_secret.mul_tweak(&point_tweak).expect("negligible probability");
+        Ok(basepoint_secret
+            .mul_tweak(&basepoint_tweak)
+            .and_then(|secret| secret.add_tweak(&Scalar::from(tweaked_point_secret)))
+            .expect("negligible probability"))
+    }
+
+    fn key_pair(
+        &self,
+        fingerprint: Fingerprint,
+        _: &DerivationPath,
+        pubkey: XOnlyPublicKey,
+    ) -> Result<KeyPair, SecretProviderError> {
+        // Revocation keys are never used in taproot outputs
+        Err(SecretProviderError::AccountUnknown(fingerprint, pubkey.public_key(Parity::Even)))
+    }
+
+    #[inline]
+    fn use_musig(&self) -> bool { false }
+}
+
+fn revocation_tweak(first: PublicKey, second: PublicKey) -> Scalar {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&first.serialize());
+    engine.input(&second.serialize());
+    let tweak = sha256::Hash::from_engine(engine);
+    Scalar::from_be_bytes(tweak.into_inner()).expect("negligible probability")
+}
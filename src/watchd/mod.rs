@@ -14,6 +14,7 @@
 #[cfg(feature = "server")]
 mod opts;
 mod runtime;
+pub mod watch_list;
 mod worker;
 
 #[cfg(feature = "server")]
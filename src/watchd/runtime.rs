@@ -11,10 +11,11 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc;
 use std::thread::spawn;
 
+use amplify::Wrapper;
 use bitcoin::Txid;
 use internet2::zeromq;
 use lnp::p2p::bolt::Messages as LnMsg;
@@ -23,10 +24,17 @@ use microservices::{esb, ZMQ_CONTEXT};
 
 use crate::bus::{BusMsg, CtlMsg, ServiceBus};
 use crate::rpc::ServiceId;
+use crate::watchd::watch_list::WatchList;
 use crate::watchd::{ElectrumUpdate, ElectrumWorker};
-use crate::{BridgeHandler, Config, Endpoints, Error, Service};
+use crate::{
+    BridgeHandler, Config, Endpoints, Error, Service, FEERATE_PER_KW_MIN, LNP_NODE_WATCH_LIST,
+};
 
 pub fn run(config: Config) -> Result<(), Error> {
+    let mut list_path = config.data_dir.clone();
+    list_path.push(LNP_NODE_WATCH_LIST);
+    let spending_list = WatchList::with(list_path)?;
+
     debug!("Opening bridge between electrum watcher and main service threads");
     let tx = ZMQ_CONTEXT.socket(zmq::PAIR)?;
     let rx = ZMQ_CONTEXT.socket(zmq::PAIR)?;
@@ -35,12 +43,24 @@ pub fn run(config: Config) -> Result<(), Error> {
 
     let (sender, receiver) = mpsc::channel::<ElectrumUpdate>();
     let electrum_worker = ElectrumWorker::with(sender, &config.electrum_url, 5)?;
+    for (outpoint, script_pubkey) in spending_list.outputs() {
+        match electrum_worker.track_spending(outpoint, script_pubkey.to_inner()) {
+            Ok(_) => debug!("Restored tracking spending of output {outpoint}"),
+            _ => error!("Unable track output spending in electrum worker"),
+        }
+    }
 
     debug!("Starting electrum watcher thread");
     let watcher_runtime = WatcherRuntime::with(receiver, tx)?;
     spawn(move || watcher_runtime.run_or_panic("electrum watcher"));
 
-    let runtime = Runtime { electrum_worker, track_list: empty!() };
+    let runtime = Runtime {
+        electrum_worker,
+        track_list: empty!(),
+        spending_list,
+        conflict_list: empty!(),
+        block_height: None,
+    };
     let mut service = Service::service(config, runtime)?;
     service.add_loopback(rx)?;
     service.run_loop()?;
@@ -89,20 +109,50 @@ impl WatcherRuntime {
         // self.send_over_bridge(msg.into()).expect("watcher bridge is halted");
         match msg {
             ElectrumUpdate::TxBatch(transactions, _) => {
-                for transaction in transactions {
+                for (transaction, block_pos) in transactions {
                     self.send_over_bridge(BusMsg::Ctl(CtlMsg::TxFound(crate::bus::TxStatus {
                         txid: transaction.txid(),
-                        block_pos: None,
+                        block_pos,
                     })))
                     .expect("unable forward electrum notifications over the bridge");
                 }
             }
+            ElectrumUpdate::SpendingBatch(spendings) => {
+                for (outpoint, tx, block_pos) in spendings {
+                    self.send_over_bridge(BusMsg::Ctl(CtlMsg::Spent(crate::bus::TxSpending {
+                        outpoint,
+                        tx,
+                        block_pos,
+                    })))
+                    .expect("unable forward electrum notifications over the bridge");
+                }
+            }
+            ElectrumUpdate::ConflictBatch(conflicts) => {
+                for (txid, conflicting_txid) in conflicts {
+                    self.send_over_bridge(BusMsg::Ctl(CtlMsg::TxConflict {
+                        txid,
+                        conflicting_txid,
+                    }))
+                    .expect("unable forward electrum notifications over the bridge");
+                }
+            }
+            // Electrum server reports -1 if it has not enough data for the estimation
+            ElectrumUpdate::FeeEstimate(fast, ..) if fast > 0.0 => {
+                // BTC per kilo-vbyte to satoshis per kilo-weight unit
+                let feerate_per_kw = (fast * 100_000_000.0 / 4.0) as u32;
+                self.send_over_bridge(BusMsg::Ctl(CtlMsg::FeeEstimate {
+                    feerate_per_kw: feerate_per_kw.max(FEERATE_PER_KW_MIN),
+                }))
+                .expect("unable forward electrum notifications over the bridge");
+            }
+            ElectrumUpdate::LastBlock(header) | ElectrumUpdate::LastBlockUpdate(header) => {
+                self.send_over_bridge(BusMsg::Ctl(CtlMsg::BlockHeight(header.height as u32)))
+                    .expect("unable forward electrum notifications over the bridge");
+            }
             ElectrumUpdate::Connecting
             | ElectrumUpdate::Connected
             | ElectrumUpdate::Complete
             | ElectrumUpdate::FeeEstimate(..)
-            | ElectrumUpdate::LastBlock(_)
-            | ElectrumUpdate::LastBlockUpdate(_)
             | ElectrumUpdate::ChannelDisconnected
             | ElectrumUpdate::Error(_) => { /* nothing to do here */ }
         }
@@ -126,6 +176,12 @@ impl TryService for WatcherRuntime {
 pub struct Runtime {
     electrum_worker: ElectrumWorker,
     track_list: HashMap<Txid, (u32, ServiceId)>,
+    /// Services awaiting for transactions spending specific outputs
+    spending_list: WatchList,
+    /// Services awaiting for transactions double-spending unconfirmed transactions
+    conflict_list: HashMap<Txid, ServiceId>,
+    /// Height of the current chain tip, once reported by the electrum server
+    block_height: Option<u32>,
 }
 
 impl esb::Handler<ServiceBus> for Runtime {
@@ -169,8 +225,14 @@ impl Runtime {
 
         match request {
             CtlMsg::TxFound(tx_status) => {
-                if let Some((required_height, service_id)) = self.track_list.get(&tx_status.txid) {
-                    if *required_height >= tx_status.block_pos.map(|b| b.pos).unwrap_or_default() {
+                // Electrum worker stops tracking conflicts for mined transactions by itself
+                if tx_status.block_pos.is_some()
+                    && self.conflict_list.remove(&tx_status.txid).is_some()
+                {
+                    debug!("Transaction {} is mined, so it can't be double-spent", tx_status.txid);
+                }
+                if let Some((required_depth, service_id)) = self.track_list.get(&tx_status.txid) {
+                    if tx_status.block_pos.map(|b| b.depth).unwrap_or_default() >= *required_depth {
                         let service_id = service_id.clone();
                         self.untrack(tx_status.txid);
                         match self.electrum_worker.untrack_transaction(tx_status.txid) {
@@ -188,6 +250,61 @@ impl Runtime {
                 Ok(())
             }
 
+            CtlMsg::Spent(spending) => {
+                // Electrum worker stops tracking spent outputs by itself
+                if let Some(service_id) = self.spending_list.remove(spending.outpoint)? {
+                    debug!("Output {} is spent by tx {}", spending.outpoint, spending.tx.txid());
+                    endpoints.send_to(
+                        ServiceBus::Ctl,
+                        ServiceId::Watch,
+                        service_id,
+                        BusMsg::Ctl(CtlMsg::Spent(spending)),
+                    )?;
+                }
+                Ok(())
+            }
+
+            CtlMsg::TxConflict { txid, conflicting_txid } => {
+                // Electrum worker stops tracking conflicts for double-spent transactions by itself
+                if let Some(service_id) = self.conflict_list.remove(&txid) {
+                    warn!("Transaction {} is double-spent by tx {}", txid, conflicting_txid);
+                    endpoints.send_to(
+                        ServiceBus::Ctl,
+                        ServiceId::Watch,
+                        service_id,
+                        BusMsg::Ctl(CtlMsg::TxConflict { txid, conflicting_txid }),
+                    )?;
+                }
+                Ok(())
+            }
+
+            CtlMsg::FeeEstimate { feerate_per_kw } => {
+                debug!("Fee estimate is {} sat/kw", feerate_per_kw);
+                for service_id in self.watching_services() {
+                    endpoints.send_to(
+                        ServiceBus::Ctl,
+                        ServiceId::Watch,
+                        service_id,
+                        BusMsg::Ctl(CtlMsg::FeeEstimate { feerate_per_kw }),
+                    )?;
+                }
+                Ok(())
+            }
+
+            CtlMsg::BlockHeight(height) => {
+                debug!("Chain tip is at height {}", height);
+                self.block_height = Some(height);
+                for service_id in self.watching_services() {
+                    endpoints.send_to(
+                        ServiceBus::Ctl,
+                        ServiceId::Watch,
+                        service_id,
+                        BusMsg::Ctl(CtlMsg::BlockHeight(height)),
+                    )?;
+                }
+                Ok(())
+            }
+
             wrong_msg => {
                 error!("Request is not supported by the BRIDGE interface");
                 Err(Error::wrong_esb_msg(ServiceBus::Bridge, &wrong_msg))
@@ -212,7 +329,7 @@ impl Runtime {
 
     fn handle_ctl(
         &mut self,
-        _: &mut Endpoints,
+        endpoints: &mut Endpoints,
         source: ServiceId,
         message: CtlMsg,
     ) -> Result<(), Error> {
@@ -232,6 +349,39 @@ impl Runtime {
                     _ => error!("Unable untrack transaction in electrum worker"),
                 }
             }
+            CtlMsg::TrackSpending { outpoint, script_pubkey } => {
+                self.spending_list.insert(outpoint, source.clone(), script_pubkey.clone())?;
+                match self.electrum_worker.track_spending(outpoint, script_pubkey.into()) {
+                    Ok(_) => debug!("Tracking spending of output {outpoint}"),
+                    _ => error!("Unable track output spending in electrum worker"),
+                }
+                // The service receives further block heights together with other services
+                // tracking spendings, so we provide it with the current one
+                if let Some(height) = self.block_height {
+                    endpoints.send_to(
+                        ServiceBus::Ctl,
+                        ServiceId::Watch,
+                        source,
+                        BusMsg::Ctl(CtlMsg::BlockHeight(height)),
+                    )?;
+                }
+            }
+            CtlMsg::UntrackSpending(outpoint) => {
+                if self.spending_list.remove(outpoint)?.is_none() {
+                    warn!("Spending of output {} was not tracked before", outpoint);
+                }
+                match self.electrum_worker.untrack_spending(outpoint) {
+                    Ok(_) => debug!("Untracking spending of output {outpoint}"),
+                    _ => error!("Unable untrack output spending in electrum worker"),
+                }
+            }
+            CtlMsg::TrackConflicts(txid) => {
+                self.conflict_list.insert(txid, source);
+                match self.electrum_worker.track_conflicts(txid) {
+                    Ok(_) => debug!("Tracking conflicts for tx {txid}"),
+                    _ => error!("Unable track transaction conflicts in electrum worker"),
+                }
+            }
 
             wrong_msg => {
                 error!("Request {} is not supported by the CTL interface", wrong_msg);
@@ -242,6 +392,16 @@ impl Runtime {
         Ok(())
     }
 
+    /// Services tracking transactions or output spendings, which are provided with on-chain fee
+    /// estimates and block heights
+    fn watching_services(&self) -> HashSet<ServiceId> {
+        self.spending_list
+            .services()
+            .chain(self.track_list.values().map(|(_, service_id)| service_id))
+            .cloned()
+            .collect()
+    }
+
     fn untrack(&mut self, txid: Txid) {
         debug!("Stopping tracking tx {txid}");
         if self.track_list.remove(&txid).is_none() {
@@ -0,0 +1,143 @@
+// LNP Node: node running lightning network protocol and generalized lightning
+// channels.
+// Written in 2020-2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use amplify::IoError;
+use bitcoin::OutPoint;
+use bitcoin_scripts::PubkeyScript;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::rpc::ServiceId;
+
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Error {
+    /// error accessing watch list file. Details: {0}
+    #[from(io::Error)]
+    Io(IoError),
+
+    /// error reading or writing watch list data. Details: {0}
+    #[from]
+    StrictEncoding(strict_encoding::Error),
+}
+
+/// Outputs watched for spending on behalf of other services, persisted so that the watch
+/// survives restarts of the watch daemon
+pub struct WatchList {
+    path: PathBuf,
+    outputs: BTreeMap<OutPoint, (ServiceId, PubkeyScript)>,
+}
+
+impl WatchList {
+    /// Opens watch list file, creating a new empty one if it does not exist
+    pub fn with(path: impl AsRef<Path>) -> Result<WatchList, Error> {
+        let path = path.as_ref().to_path_buf();
+        match fs::File::open(&path) {
+            Ok(file) => {
+                debug!("Loading watched outputs from '{}'", path.display());
+                let outputs = BTreeMap::strict_decode(io::BufReader::new(file))?;
+                Ok(WatchList { path, outputs })
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("Creating watch list at '{}'", path.display());
+                let list = WatchList { path, outputs: none!() };
+                list.save()?;
+                Ok(list)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes the watch list to a temporary file, which then atomically replaces the previous
+    /// one
+    fn save(&self) -> Result<(), Error> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        self.outputs.strict_encode(&mut file)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Starts watching the output on behalf of the service
+    pub fn insert(
+        &mut self,
+        outpoint: OutPoint,
+        service_id: ServiceId,
+        script_pubkey: PubkeyScript,
+    ) -> Result<(), Error> {
+        let entry = (service_id, script_pubkey);
+        if self.outputs.get(&outpoint) == Some(&entry) {
+            return Ok(());
+        }
+        self.outputs.insert(outpoint, entry);
+        self.save()
+    }
+
+    /// Stops watching the output, returning the service which was awaiting its spending
+    pub fn remove(&mut self, outpoint: OutPoint) -> Result<Option<ServiceId>, Error> {
+        match self.outputs.remove(&outpoint) {
+            None => Ok(None),
+            Some((service_id, _)) => {
+                self.save()?;
+                Ok(Some(service_id))
+            }
+        }
+    }
+
+    /// Iterates over the watched outputs together with their scripts
+    pub fn outputs(&self) -> impl Iterator<Item = (OutPoint, &PubkeyScript)> {
+        self.outputs.iter().map(|(outpoint, (_, script))| (*outpoint, script))
+    }
+
+    /// Iterates over the services awaiting for the outputs spendings
+    pub fn services(&self) -> impl Iterator<Item = &ServiceId> {
+        self.outputs.values().map(|(service_id, _)| service_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::Wrapper;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+    use lnp::p2p::bolt::ChannelId;
+
+    use super::*;
+
+    #[test]
+    fn persistence() {
+        let path = std::env::temp_dir().join(format!("lnp-watch-list-{}.dat", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let outpoint = |vout| OutPoint::new(Txid::all_zeros(), vout);
+        let service_id = ServiceId::Channel(ChannelId::from_inner([1u8; 32].into()));
+        let script = PubkeyScript::from(bitcoin::Script::from(vec![0u8, 20]));
+
+        let mut list = WatchList::with(&path).unwrap();
+        list.insert(outpoint(0), service_id.clone(), script.clone()).unwrap();
+        list.insert(outpoint(1), service_id.clone(), script.clone()).unwrap();
+        assert_eq!(list.remove(outpoint(1)).unwrap(), Some(service_id.clone()));
+        assert_eq!(list.remove(outpoint(1)).unwrap(), None);
+
+        let list = WatchList::with(&path).unwrap();
+        assert_eq!(list.outputs().collect::<Vec<_>>(), vec![(outpoint(0), &script)]);
+        assert_eq!(list.services().collect::<Vec<_>>(), vec![&service_id]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
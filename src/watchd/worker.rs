@@ -13,13 +13,23 @@
 
 // TODO: Consider making it part of descriptor wallet onchain library
 
+use std::collections::HashSet;
+use std::mem;
 use std::sync::mpsc;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use bitcoin::{Transaction, Txid};
+use bitcoin::{OutPoint, Script, Transaction, Txid};
 use electrum_client::{Client as ElectrumClient, ElectrumApi, HeaderNotification};
 
+use crate::bus::BlockPos;
+
+/// Number of pacemaker intervals between requesting fee estimates from the electrum server
+const FEE_ESTIMATE_INTERVALS: u64 = 12;
+
+/// Confirmation targets, in blocks, for the fast, normal and slow fee estimates
+const FEE_ESTIMATE_TARGETS: [usize; 3] = [1, 6, 144];
+
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display, Error, From)]
 #[display("failed electrum watcher channel")]
 #[from(mpsc::SendError<ElectrumCmd>)]
@@ -42,11 +52,19 @@ pub enum ElectrumUpdate {
     #[display("last_block_update(...)")]
     LastBlockUpdate(HeaderNotification),
 
+    /// Fee estimates for fast, normal and slow transaction confirmation, in BTC per kilobyte
     #[display("fee_estimate({0}, {1}, {2})")]
     FeeEstimate(f64, f64, f64),
 
     #[display("tx_batch(...)")]
-    TxBatch(Vec<Transaction>, f32),
+    TxBatch(Vec<(Transaction, Option<BlockPos>)>, f32),
+
+    #[display("spending_batch(...)")]
+    SpendingBatch(Vec<(OutPoint, Transaction, Option<BlockPos>)>),
+
+    /// Tracked unconfirmed transactions together with the transactions double-spending them
+    #[display("conflict_batch(...)")]
+    ConflictBatch(Vec<(Txid, Txid)>),
 
     #[display("channel_disconnected")]
     ChannelDisconnected,
@@ -79,10 +97,17 @@ impl ElectrumWorker {
         let sender = tx.clone();
         let pacemaker_thread = thread::Builder::new()
             .name(s!("electrum_pacemaker"))
-            .spawn(move || loop {
-                thread::sleep(Duration::from_secs(interval));
-                sender.send(ElectrumCmd::GetTrasactions).expect("Electrum thread is dead");
-                sender.send(ElectrumCmd::PopHeader).expect("Electrum thread is dead")
+            .spawn(move || {
+                for tick in 0u64.. {
+                    if tick % FEE_ESTIMATE_INTERVALS == 0 {
+                        sender.send(ElectrumCmd::EstimateFee).expect("Electrum thread is dead");
+                    }
+                    thread::sleep(Duration::from_secs(interval));
+                    sender.send(ElectrumCmd::GetTrasactions).expect("Electrum thread is dead");
+                    sender.send(ElectrumCmd::GetSpendings).expect("Electrum thread is dead");
+                    sender.send(ElectrumCmd::GetConflicts).expect("Electrum thread is dead");
+                    sender.send(ElectrumCmd::PopHeader).expect("Electrum thread is dead")
+                }
             })
             .expect("unable to start blockchain watcher pacemaker thread");
 
@@ -107,6 +132,25 @@ impl ElectrumWorker {
     pub fn untrack_transaction(&self, txid: Txid) -> Result<(), WatcherChannelFailure> {
         self.cmd(ElectrumCmd::UntrackTransaction(txid))
     }
+
+    #[inline]
+    pub fn track_spending(
+        &self,
+        outpoint: OutPoint,
+        script_pubkey: Script,
+    ) -> Result<(), WatcherChannelFailure> {
+        self.cmd(ElectrumCmd::TrackSpending(outpoint, script_pubkey))
+    }
+
+    #[inline]
+    pub fn untrack_spending(&self, outpoint: OutPoint) -> Result<(), WatcherChannelFailure> {
+        self.cmd(ElectrumCmd::UntrackSpending(outpoint))
+    }
+
+    #[inline]
+    pub fn track_conflicts(&self, txid: Txid) -> Result<(), WatcherChannelFailure> {
+        self.cmd(ElectrumCmd::TrackConflicts(txid))
+    }
 }
 
 fn connect_electrum(electrum_url: &str) -> Result<ElectrumClient, electrum_client::Error> {
@@ -122,13 +166,28 @@ enum ElectrumCmd {
     GetTrasactions,
     TrackTransaction(Txid),
     UntrackTransaction(Txid),
+    GetSpendings,
+    TrackSpending(OutPoint, Script),
+    UntrackSpending(OutPoint),
+    GetConflicts,
+    TrackConflicts(Txid),
+    EstimateFee,
 }
 
+/// Unconfirmed transaction tracked for conflicts, together with the outputs it spends and
+/// scripts of these outputs, which are known once the transaction is published
+type ConflictTrack = (Txid, Option<Vec<(OutPoint, Script)>>);
+
 struct ElectrumProcessor {
     client: ElectrumClient,
     sender: mpsc::Sender<ElectrumUpdate>,
     rx: mpsc::Receiver<ElectrumCmd>,
     tracks: Vec<Txid>,
+    /// Outputs for which spending transactions are tracked, together with their scripts
+    spendings: Vec<(OutPoint, Script)>,
+    /// Unconfirmed transactions for which conflicting transactions are tracked
+    conflicts: Vec<ConflictTrack>,
+    last_height: u32,
 }
 
 impl ElectrumProcessor {
@@ -137,7 +196,20 @@ impl ElectrumProcessor {
         sender: mpsc::Sender<ElectrumUpdate>,
         rx: mpsc::Receiver<ElectrumCmd>,
     ) -> Result<Self, electrum_client::Error> {
-        Ok(ElectrumProcessor { client, sender, rx, tracks: vec![] })
+        let last_header = client.block_headers_subscribe()?;
+        let last_height = last_header.height as u32;
+        sender
+            .send(ElectrumUpdate::LastBlock(last_header))
+            .expect("electrum watcher channel is broken");
+        Ok(ElectrumProcessor {
+            client,
+            sender,
+            rx,
+            tracks: vec![],
+            spendings: vec![],
+            conflicts: vec![],
+            last_height,
+        })
     }
 
     pub fn run(mut self) {
@@ -163,6 +235,23 @@ impl ElectrumProcessor {
             }
             ElectrumCmd::TrackTransaction(txid) => self.track_transaction(txid),
             ElectrumCmd::UntrackTransaction(txid) => self.untrack_transaction(txid),
+            ElectrumCmd::GetSpendings => self.get_spendings(),
+            ElectrumCmd::TrackSpending(outpoint, script_pubkey) => {
+                self.track_spending(outpoint, script_pubkey);
+                Ok(None)
+            }
+            ElectrumCmd::UntrackSpending(outpoint) => {
+                self.spendings.retain(|(tracked, _)| *tracked != outpoint);
+                Ok(None)
+            }
+            ElectrumCmd::GetConflicts => self.get_conflicts(),
+            ElectrumCmd::TrackConflicts(txid) => {
+                if !self.conflicts.iter().any(|(tracked, _)| *tracked == txid) {
+                    self.conflicts.push((txid, None));
+                }
+                Ok(None)
+            }
+            ElectrumCmd::EstimateFee => self.estimate_fee(),
         };
         match resp {
             Ok(Some(msg)) => {
@@ -182,8 +271,15 @@ impl ElectrumProcessor {
         Ok(())
     }
 
-    fn pop_header(&self) -> Result<Option<ElectrumUpdate>, electrum_client::Error> {
-        self.client.block_headers_pop().map(|res| res.map(ElectrumUpdate::LastBlockUpdate))
+    fn pop_header(&mut self) -> Result<Option<ElectrumUpdate>, electrum_client::Error> {
+        let mut last_header = None;
+        while let Some(header) = self.client.block_headers_pop()? {
+            last_header = Some(header);
+        }
+        if let Some(ref header) = last_header {
+            self.last_height = header.height as u32;
+        }
+        Ok(last_header.map(ElectrumUpdate::LastBlockUpdate))
     }
 
     fn get_transactions(
@@ -193,7 +289,8 @@ impl ElectrumProcessor {
         if self.tracks.is_empty() {
             return Ok(None);
         }
-        self.client.batch_transaction_get(txids).map(|res| Some(ElectrumUpdate::TxBatch(res, 0.0)))
+        let txs = self.client.batch_transaction_get(txids)?;
+        self.tx_batch(txs).map(Some)
     }
 
     fn track_transaction(
@@ -201,9 +298,8 @@ impl ElectrumProcessor {
         txid: Txid,
     ) -> Result<Option<ElectrumUpdate>, electrum_client::Error> {
         self.tracks.push(txid);
-        self.client
-            .transaction_get(&txid.clone())
-            .map(|res| Some(ElectrumUpdate::TxBatch([res].to_vec(), 0.0)))
+        let tx = self.client.transaction_get(&txid)?;
+        self.tx_batch(vec![tx]).map(Some)
     }
 
     fn untrack_transaction(
@@ -212,8 +308,171 @@ impl ElectrumProcessor {
     ) -> Result<Option<ElectrumUpdate>, electrum_client::Error> {
         let index = self.tracks.iter().position(|x| *x == txid).unwrap();
         self.tracks.remove(index);
-        self.client
-            .transaction_get(&txid.clone())
-            .map(|res| Some(ElectrumUpdate::TxBatch([res].to_vec(), 0.0)))
+        let tx = self.client.transaction_get(&txid)?;
+        self.tx_batch(vec![tx]).map(Some)
+    }
+
+    fn track_spending(&mut self, outpoint: OutPoint, script_pubkey: Script) {
+        if !self.spendings.iter().any(|(tracked, _)| *tracked == outpoint) {
+            self.spendings.push((outpoint, script_pubkey));
+        }
+    }
+
+    /// Looks for transactions spending tracked outputs. Outputs which are found to be spent are
+    /// not tracked anymore.
+    fn get_spendings(&mut self) -> Result<Option<ElectrumUpdate>, electrum_client::Error> {
+        let mut batch = vec![];
+        for (outpoint, script_pubkey) in self.spendings.clone() {
+            // Script history contains both the transaction creating the output and the one
+            // spending it
+            for item in self.client.script_get_history(&script_pubkey)? {
+                if item.tx_hash == outpoint.txid {
+                    continue;
+                }
+                let tx = self.client.transaction_get(&item.tx_hash)?;
+                if tx.input.iter().any(|txin| txin.previous_output == outpoint) {
+                    let block_pos = self.block_pos(&tx)?;
+                    batch.push((outpoint, tx, block_pos));
+                    break;
+                }
+            }
+        }
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        self.spendings.retain(|(tracked, _)| !batch.iter().any(|(spent, ..)| spent == tracked));
+        Ok(Some(ElectrumUpdate::SpendingBatch(batch)))
+    }
+
+    /// Looks for transactions double-spending inputs of the tracked unconfirmed transactions.
+    /// Transactions which are found to be mined or double-spent are not tracked anymore; mined
+    /// transactions are reported as a transaction batch.
+    fn get_conflicts(&mut self) -> Result<Option<ElectrumUpdate>, electrum_client::Error> {
+        let mut tracked = mem::take(&mut self.conflicts);
+        let result = self.find_conflicts(&mut tracked);
+        self.conflicts = tracked;
+        let (mined, conflicts) = result?;
+
+        let resolved = mined
+            .iter()
+            .map(|(tx, _)| tx.txid())
+            .chain(conflicts.iter().map(|(txid, _)| *txid))
+            .collect::<HashSet<_>>();
+        self.conflicts.retain(|(txid, _)| !resolved.contains(txid));
+
+        if !mined.is_empty() {
+            self.sender
+                .send(ElectrumUpdate::TxBatch(mined, 0.0))
+                .expect("electrum watcher channel is broken");
+        }
+        if conflicts.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(ElectrumUpdate::ConflictBatch(conflicts)))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn find_conflicts(
+        &self,
+        tracked: &mut [ConflictTrack],
+    ) -> Result<(Vec<(Transaction, Option<BlockPos>)>, Vec<(Txid, Txid)>), electrum_client::Error>
+    {
+        let mut mined = vec![];
+        let mut conflicts = vec![];
+        for (txid, inputs) in tracked {
+            // Electrum server does not provide transactions which are not published yet or were
+            // evicted from the mempool by a conflicting transaction
+            if let Ok(tx) = self.client.transaction_get(txid) {
+                if let Some(block_pos) = self.block_pos(&tx)? {
+                    mined.push((tx, Some(block_pos)));
+                    continue;
+                }
+                if inputs.is_none() {
+                    *inputs = Some(self.spent_outputs(&tx)?);
+                }
+            }
+            let inputs = match inputs {
+                Some(inputs) => inputs,
+                None => continue,
+            };
+            'inputs: for (outpoint, script_pubkey) in inputs.iter() {
+                for item in self.client.script_get_history(script_pubkey)? {
+                    if item.tx_hash == outpoint.txid || item.tx_hash == *txid {
+                        continue;
+                    }
+                    let tx = self.client.transaction_get(&item.tx_hash)?;
+                    if tx.input.iter().any(|txin| txin.previous_output == *outpoint) {
+                        conflicts.push((*txid, item.tx_hash));
+                        break 'inputs;
+                    }
+                }
+            }
+        }
+        Ok((mined, conflicts))
+    }
+
+    /// Resolves outputs spent by the transaction together with their scripts
+    fn spent_outputs(
+        &self,
+        tx: &Transaction,
+    ) -> Result<Vec<(OutPoint, Script)>, electrum_client::Error> {
+        tx.input
+            .iter()
+            .map(|txin| {
+                let outpoint = txin.previous_output;
+                let prev_tx = self.client.transaction_get(&outpoint.txid)?;
+                let script_pubkey = prev_tx
+                    .output
+                    .get(outpoint.vout as usize)
+                    .map(|txout| txout.script_pubkey.clone())
+                    .unwrap_or_default();
+                Ok((outpoint, script_pubkey))
+            })
+            .collect()
+    }
+
+    fn estimate_fee(&mut self) -> Result<Option<ElectrumUpdate>, electrum_client::Error> {
+        let estimates = self.client.batch_estimate_fee(FEE_ESTIMATE_TARGETS)?;
+        Ok(match estimates[..] {
+            [fast, normal, slow] => Some(ElectrumUpdate::FeeEstimate(fast, normal, slow)),
+            _ => None,
+        })
+    }
+
+    fn tx_batch(&self, txs: Vec<Transaction>) -> Result<ElectrumUpdate, electrum_client::Error> {
+        let batch = txs
+            .into_iter()
+            .map(|tx| self.block_pos(&tx).map(|block_pos| (tx, block_pos)))
+            .collect::<Result<_, _>>()?;
+        Ok(ElectrumUpdate::TxBatch(batch, 0.0))
+    }
+
+    /// Detects position of the transaction in the blockchain. Returns `None` for transactions
+    /// which are not mined yet.
+    fn block_pos(&self, tx: &Transaction) -> Result<Option<BlockPos>, electrum_client::Error> {
+        let txid = tx.txid();
+        // Electrum protocol does not provide transaction height by its id, so we look for it in
+        // the history of the first transaction output
+        let txout = match tx.output.first() {
+            Some(txout) => txout,
+            None => return Ok(None),
+        };
+        let height = self
+            .client
+            .script_get_history(&txout.script_pubkey)?
+            .into_iter()
+            .find(|item| item.tx_hash == txid)
+            .map(|item| item.height)
+            .filter(|height| *height > 0);
+        let height = match height {
+            Some(height) => height as u32,
+            None => return Ok(None),
+        };
+        let merkle = self.client.transaction_get_merkle(&txid, height as usize)?;
+        Ok(Some(BlockPos {
+            depth: self.last_height.saturating_sub(height) + 1,
+            height,
+            pos: merkle.pos as u32,
+        }))
     }
 }